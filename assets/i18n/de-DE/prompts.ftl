@@ -0,0 +1,8 @@
+# Common authentication prompts of Check Point gateways, see ../en-US/prompts.ftl.
+
+prompt-username = Benutzername:
+prompt-password = Passwort:
+prompt-new-password = Neues Passwort:
+prompt-otp = Einmalpasswort:
+prompt-sms-code = Code aus der SMS:
+prompt-token-code = Token-Code:
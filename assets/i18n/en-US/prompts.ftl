@@ -0,0 +1,12 @@
+# Common authentication prompts of Check Point gateways.
+# The prompts sent by the server are matched against known patterns and the translation
+# of the corresponding key for the user's locale is shown before the server prompt.
+# The server prompts are in English, so this file is the template for the translations
+# in the sibling directories, which are bundled in snxcore/src/prompt.rs.
+
+prompt-username = User name:
+prompt-password = Password:
+prompt-new-password = New password:
+prompt-otp = One-time password:
+prompt-sms-code = Code from the SMS:
+prompt-token-code = Token code:
@@ -0,0 +1,82 @@
+use std::fmt::Write;
+
+use snxcore::{
+    model::{params::TunnelParams, ConnectionStatus},
+    server_info,
+};
+
+fn os_name() -> Option<String> {
+    let os_release = std::fs::read_to_string("/etc/os-release").ok()?;
+    os_release
+        .lines()
+        .find_map(|line| line.strip_prefix("PRETTY_NAME="))
+        .map(|name| name.trim_matches('"').to_owned())
+}
+
+fn kernel_version() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/osrelease")
+        .ok()
+        .map(|version| version.trim().to_owned())
+}
+
+/// Markdown summary for bug reports: client and OS versions, tunnel settings, the last status poll
+/// and the server info with the cookie and session fields masked
+pub async fn collect(params: &TunnelParams, status: Option<&ConnectionStatus>, last_error: Option<&str>) -> String {
+    let unknown = || "unknown".to_owned();
+    let mut report = String::new();
+
+    let _ = writeln!(report, "### snx-rs diagnostics\n");
+    let _ = writeln!(report, "- Client version: {}", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(report, "- OS: {}", os_name().unwrap_or_else(unknown));
+    let _ = writeln!(report, "- Kernel: {}", kernel_version().unwrap_or_else(unknown));
+    let _ = writeln!(report, "- Tunnel type: {}", params.tunnel_type);
+    let _ = writeln!(
+        report,
+        "- Configured transport: ESP {}, IKE {}{}",
+        params.esp_transport,
+        params.ike_transport,
+        if params.tcpt_only { ", TCPT only" } else { "" }
+    );
+
+    match status {
+        Some(status) if status.connected_since.is_some() => {
+            let _ = writeln!(
+                report,
+                "- Status: connected {}",
+                status.uptime_summary().unwrap_or_default()
+            );
+            if let Some(transport) = status.transport {
+                let _ = writeln!(report, "- Selected transport: {transport}");
+            }
+        }
+        Some(status) => match status.disconnect_reason {
+            Some(reason) => {
+                let _ = writeln!(report, "- Status: disconnected ({reason})");
+            }
+            None => {
+                let _ = writeln!(report, "- Status: disconnected");
+            }
+        },
+        None => {
+            let _ = writeln!(report, "- Status: {}", unknown());
+        }
+    }
+
+    if let Some(error) = last_error {
+        let _ = writeln!(report, "- Last error: {error}");
+    }
+
+    if !params.server_name.is_empty() {
+        let server_info = match server_info::get_raw(params, true).await {
+            Ok(info) => info.to_string(),
+            Err(e) => format!("unavailable: {e}"),
+        };
+        let _ = writeln!(
+            report,
+            "\n<details>\n<summary>Server info</summary>\n\n```\n{}\n```\n\n</details>",
+            server_info.trim_end()
+        );
+    }
+
+    report
+}
@@ -0,0 +1,116 @@
+use std::{cell::Cell, rc::Rc, time::Duration};
+
+use gtk::{
+    glib::{self, ControlFlow},
+    prelude::*,
+    Orientation, PolicyType, WindowPosition, WrapMode,
+};
+use tracing::Level;
+
+use snxcore::logging::{LogBuffer, LogRecord};
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+const LEVELS: [Level; 4] = [Level::ERROR, Level::WARN, Level::INFO, Level::DEBUG];
+
+fn append_records(view: &gtk::TextView, records: &[LogRecord], level: Level) {
+    let Some(text_buffer) = view.buffer() else {
+        return;
+    };
+
+    let mut appended = false;
+    for record in records.iter().filter(|record| record.level <= level) {
+        text_buffer.insert(&mut text_buffer.end_iter(), &format!("{record}\n"));
+        appended = true;
+    }
+
+    if appended {
+        // the mark has the right gravity, so it stays at the end of the text
+        let end = text_buffer
+            .mark("end")
+            .unwrap_or_else(|| text_buffer.create_mark(Some("end"), &text_buffer.end_iter(), false));
+        view.scroll_to_mark(&end, 0.0, false, 0.0, 1.0);
+    }
+}
+
+/// Window with the recent log records of the GUI process, new records are appended while it is open
+pub fn start_log_viewer(buffer: LogBuffer) {
+    glib::idle_add(move || {
+        let window = gtk::Window::builder()
+            .title("Logs")
+            .default_width(900)
+            .default_height(500)
+            .window_position(WindowPosition::Center)
+            .build();
+
+        let level_filter = gtk::ComboBoxText::builder().build();
+        for level in LEVELS {
+            level_filter.append(Some(level.as_str()), &level.as_str().to_lowercase());
+        }
+        level_filter.set_active_id(Some(Level::INFO.as_str()));
+
+        let view = gtk::TextView::builder()
+            .editable(false)
+            .cursor_visible(false)
+            .monospace(true)
+            .wrap_mode(WrapMode::WordChar)
+            .build();
+
+        let scrolled_win = gtk::ScrolledWindow::builder()
+            .hscrollbar_policy(PolicyType::Never)
+            .vexpand(true)
+            .build();
+        scrolled_win.add(&view);
+
+        let header = gtk::Box::builder()
+            .orientation(Orientation::Horizontal)
+            .spacing(6)
+            .build();
+        header.pack_start(&gtk::Label::new(Some("Level:")), false, false, 0);
+        header.pack_start(&level_filter, false, false, 0);
+
+        let inner = gtk::Box::builder()
+            .orientation(Orientation::Vertical)
+            .margin(6)
+            .spacing(6)
+            .build();
+        inner.pack_start(&header, false, false, 0);
+        inner.pack_start(&scrolled_win, true, true, 0);
+        window.add(&inner);
+
+        let selected_level = |combo: &gtk::ComboBoxText| {
+            combo
+                .active_id()
+                .and_then(|id| id.parse::<Level>().ok())
+                .unwrap_or(Level::INFO)
+        };
+
+        let (records, seq) = buffer.records_since(0);
+        append_records(&view, &records, selected_level(&level_filter));
+        let seq = Rc::new(Cell::new(seq));
+
+        // the whole buffer is shown again with the new filter
+        level_filter.connect_changed(glib::clone!(@weak view, @strong buffer, @strong seq => move |combo| {
+            if let Some(text_buffer) = view.buffer() {
+                text_buffer.set_text("");
+            }
+            let (records, next_seq) = buffer.records_since(0);
+            append_records(&view, &records, selected_level(combo));
+            seq.set(next_seq);
+        }));
+
+        glib::timeout_add_local(
+            REFRESH_INTERVAL,
+            // stops when the window is closed and the widgets are gone
+            glib::clone!(@weak view, @weak level_filter, @strong buffer => @default-return ControlFlow::Break, move || {
+                let (records, next_seq) = buffer.records_since(seq.get());
+                append_records(&view, &records, selected_level(&level_filter));
+                seq.set(next_seq);
+                ControlFlow::Continue
+            }),
+        );
+
+        window.show_all();
+
+        ControlFlow::Break
+    });
+}
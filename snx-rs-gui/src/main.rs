@@ -6,43 +6,43 @@ use gtk::{
     prelude::{ApplicationExt, ApplicationExtManual, DialogExt, GtkWindowExt},
     Application, License,
 };
-use tracing::level_filters::LevelFilter;
 use tray_icon::menu::MenuEvent;
 
-use snxcore::{controller::ServiceCommand, model::params::TunnelParams, platform::SingleInstance};
+use snxcore::{controller::ServiceCommand, logging::LogBuffer, model::params::TunnelParams, platform::SingleInstance};
 
 use crate::theme::init_theme_monitoring;
 use crate::tray::TrayCommand;
 
 mod assets;
 mod dbus;
+mod diagnostics;
+mod logs;
 mod params;
 mod prompt;
 mod settings;
+mod status;
 mod theme;
 mod tray;
 const PING_DURATION: Duration = Duration::from_secs(1);
+const INSTANCE_LOCK: &str = "/tmp/snx-rs-gui.s";
 
 fn main() -> anyhow::Result<()> {
     let params = params::CmdlineParams::parse();
 
     let tunnel_params = Arc::new(TunnelParams::load(params.config_file()).unwrap_or_default());
 
-    let instance = SingleInstance::new("/tmp/snx-rs-gui.s")?;
+    let instance = if params.force {
+        SingleInstance::new_forced(INSTANCE_LOCK)?
+    } else {
+        SingleInstance::new(INSTANCE_LOCK)?
+    };
     if !instance.is_single() {
+        eprintln!("Another instance is already running, use --force to start anyway");
         return Ok(());
     }
 
-    let subscriber = tracing_subscriber::fmt()
-        .with_max_level(
-            tunnel_params
-                .log_level
-                .parse::<LevelFilter>()
-                .unwrap_or(LevelFilter::OFF),
-        )
-        .finish();
-
-    tracing::subscriber::set_global_default(subscriber).unwrap();
+    let log_buffer = LogBuffer::default();
+    snxcore::logging::init_logging_with_buffer(&tunnel_params, log_buffer.clone())?;
 
     let _ = init_theme_monitoring();
 
@@ -84,6 +84,11 @@ fn main() -> anyhow::Result<()> {
                             ControlFlow::Break
                         });
                     }
+                    "status" => {
+                        let params = TunnelParams::load(params.config_file()).unwrap_or_default();
+                        status::start_status_dialog(Arc::new(params));
+                    }
+                    "logs" => logs::start_log_viewer(log_buffer.clone()),
                     "about" => {
                         glib::idle_add(|| {
                             let dialog = gtk::AboutDialog::builder()
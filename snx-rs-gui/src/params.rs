@@ -13,6 +13,11 @@ pub struct CmdlineParams {
         help = "Configuration file to use [default: $HOME/.config/snx-rs/snx-rs.conf]"
     )]
     config_file: Option<PathBuf>,
+    #[clap(
+        long = "force",
+        help = "Start even if another instance seems to be running, taking over its lock"
+    )]
+    pub force: bool,
 }
 
 impl CmdlineParams {
@@ -1,4 +1,4 @@
-use std::{net::Ipv4Addr, path::Path, rc::Rc, sync::Arc, time::Duration};
+use std::{cell::RefCell, collections::BTreeMap, net::Ipv4Addr, path::Path, rc::Rc, sync::Arc, time::Duration};
 
 use async_channel::Sender;
 use gtk::{
@@ -6,15 +6,16 @@ use gtk::{
     prelude::*,
     Align, ButtonsType, DialogFlags, MessageType, Orientation, ResponseType, Widget, WindowPosition,
 };
-use ipnet::Ipv4Net;
 use tracing::warn;
 
 use snxcore::{
     model::{
-        params::{TunnelParams, TunnelType},
+        params::{RealmCredentials, TunnelParams},
         proto::LoginOption,
+        uri::{ConnectionUri, URI_SCHEME},
+        SessionState,
     },
-    server_info,
+    server_info, tunnel, util,
 };
 
 use crate::tray::TrayCommand;
@@ -27,6 +28,40 @@ const CSS_ERROR: &str = r"label {
 }
 ";
 
+fn prompt_link(parent: &gtk::Dialog) -> Option<String> {
+    let dialog = gtk::Dialog::with_buttons(
+        Some("Import from link"),
+        Some(parent),
+        DialogFlags::MODAL,
+        &[("OK", ResponseType::Ok), ("Cancel", ResponseType::Cancel)],
+    );
+    dialog.set_default_response(ResponseType::Ok);
+    dialog.set_position(WindowPosition::CenterOnParent);
+
+    let entry = gtk::Entry::builder()
+        .placeholder_text(format!("{URI_SCHEME}://server?realm=<login-type>&tunnel=<ipsec|ssl>"))
+        .activates_default(true)
+        .width_chars(56)
+        .build();
+
+    // prefill with the clipboard contents, e.g. a link decoded by a QR scanner app
+    let clipboard = gtk::Clipboard::get(&gtk::gdk::SELECTION_CLIPBOARD);
+    if let Some(text) = clipboard.wait_for_text().filter(|t| t.trim().starts_with(URI_SCHEME)) {
+        entry.set_text(text.trim());
+    }
+
+    let inner = gtk::Box::builder().orientation(Orientation::Vertical).margin(6).build();
+    inner.pack_start(&entry, false, true, 6);
+    dialog.content_area().pack_start(&inner, false, true, 6);
+    dialog.show_all();
+
+    let result = dialog.run();
+    let text = entry.text().trim().to_owned();
+    dialog.close();
+
+    (result == ResponseType::Ok && !text.is_empty()).then_some(text)
+}
+
 fn set_container_visible(widget: &Widget, flag: bool) {
     if let Some(parent) = widget.parent() {
         if let Some(parent) = parent.parent() {
@@ -43,12 +78,17 @@ struct SettingsDialog {
     params: Arc<TunnelParams>,
     dialog: gtk::Dialog,
     widgets: Rc<MyWidgets>,
+    // credentials entered for the login types which were selected in the dialog
+    realm_credentials: Rc<RefCell<BTreeMap<String, RealmCredentials>>>,
 }
 
 struct MyWidgets {
     server_name: gtk::Entry,
     fetch_info: gtk::Button,
+    test_credentials: gtk::Button,
+    import_link: gtk::Button,
     auth_type: gtk::ComboBoxText,
+    allow_hidden_realms: gtk::CheckButton,
     tunnel_type: gtk::ComboBoxText,
     user_name: gtk::Entry,
     password: gtk::Entry,
@@ -56,6 +96,7 @@ struct MyWidgets {
     search_domains: gtk::Entry,
     ignored_domains: gtk::Entry,
     dns_servers: gtk::Entry,
+    replace_dns_servers: gtk::CheckButton,
     ignored_dns_servers: gtk::Entry,
     no_routing: gtk::CheckButton,
     default_routing: gtk::CheckButton,
@@ -79,6 +120,7 @@ struct MyWidgets {
     esp_transport: gtk::ComboBoxText,
     no_keepalive: gtk::CheckButton,
     icon_theme: gtk::ComboBoxText,
+    notify_rekey: gtk::CheckButton,
     error: gtk::Label,
 }
 
@@ -127,21 +169,21 @@ impl MyWidgets {
         let ignored_dns_servers = self.ignored_dns_servers.text();
         if !ignored_dns_servers.is_empty() {
             for r in ignored_dns_servers.split(',') {
-                r.parse::<Ipv4Addr>()?;
+                util::parse_ipv4_net(r)?;
             }
         }
 
         let add_routes = self.add_routes.text();
         if !add_routes.is_empty() {
             for r in add_routes.split(',') {
-                r.parse::<Ipv4Net>()?;
+                util::parse_ipv4_net(r)?;
             }
         }
 
         let ignored_routes = self.ignored_routes.text();
         if !ignored_routes.is_empty() {
             for r in ignored_routes.split(',') {
-                r.parse::<Ipv4Net>()?;
+                util::parse_ipv4_net(r)?;
             }
         }
 
@@ -171,6 +213,14 @@ impl SettingsDialog {
 
         let server_name = gtk::Entry::builder().text(&params.server_name).hexpand(true).build();
         let fetch_info = gtk::Button::builder().label("Fetch info").halign(Align::End).build();
+        let test_credentials = gtk::Button::builder()
+            .label("Test credentials")
+            .halign(Align::End)
+            .build();
+        let import_link = gtk::Button::builder()
+            .label("Import from link")
+            .halign(Align::End)
+            .build();
         let auth_type = gtk::ComboBoxText::builder().build();
         let tunnel_type = gtk::ComboBoxText::builder().build();
         let user_name = gtk::Entry::builder().text(&params.user_name).build();
@@ -200,13 +250,15 @@ impl SettingsDialog {
             )
             .build();
 
+        let replace_dns_servers = gtk::CheckButton::builder().active(params.replace_dns_servers).build();
+
         let ignored_dns_servers = gtk::Entry::builder()
-            .placeholder_text("Comma-separated IP addresses")
+            .placeholder_text("Comma-separated IP addresses or subnets")
             .text(
                 params
                     .ignore_dns_servers
                     .iter()
-                    .map(|r| r.to_string())
+                    .map(util::format_ipv4_net)
                     .collect::<Vec<_>>()
                     .join(","),
             )
@@ -239,6 +291,7 @@ impl SettingsDialog {
             )
             .build();
 
+        let allow_hidden_realms = gtk::CheckButton::builder().active(params.allow_hidden_realms).build();
         let mfa_prompts = gtk::CheckButton::builder().active(params.server_prompt).build();
         let no_keychain = gtk::CheckButton::builder().active(params.no_keychain).build();
         let no_cert_name_check = gtk::CheckButton::builder().active(params.no_cert_check).build();
@@ -284,6 +337,7 @@ impl SettingsDialog {
         let ike_transport = gtk::ComboBoxText::builder().build();
         let no_keepalive = gtk::CheckButton::builder().active(params.no_keepalive).build();
         let icon_theme = gtk::ComboBoxText::builder().build();
+        let notify_rekey = gtk::CheckButton::builder().active(params.notify_rekey).build();
 
         let provider = gtk::CssProvider::new();
         provider.load_from_data(CSS_ERROR.as_bytes()).unwrap();
@@ -291,13 +345,40 @@ impl SettingsDialog {
         let error = gtk::Label::new(None);
         error.style_context().add_provider(&provider, 100);
 
+        let realm_credentials = Rc::new(RefCell::new(params.realm_credentials.clone()));
+        let current_realm = Rc::new(RefCell::new(params.login_type.clone()));
+        let default_credentials = params.default_credentials.clone().unwrap_or_else(|| RealmCredentials {
+            user_name: params.user_name.clone(),
+            password: params.password.clone(),
+        });
+
         auth_type.connect_active_notify(clone!(@weak dialog,
             @weak auth_type,
             @weak user_name,
+            @weak password,
             @weak tunnel_type,
             @weak cert_path,
-            @weak cert_type => move |widget| {
+            @weak cert_type,
+            @strong realm_credentials,
+            @strong current_realm => move |widget| {
             if let Some(id) = widget.active_id() {
+                // remember the credentials of the previous login type and recall the ones of the selected type
+                let previous = current_realm.replace(id.to_string());
+                if previous != id.as_str() {
+                    let mut credentials = realm_credentials.borrow_mut();
+                    if !previous.is_empty() {
+                        credentials.insert(
+                            previous,
+                            RealmCredentials {
+                                user_name: user_name.text().into(),
+                                password: password.text().into(),
+                            },
+                        );
+                    }
+                    let recalled = credentials.get(id.as_str()).unwrap_or(&default_credentials);
+                    user_name.set_text(&recalled.user_name);
+                    password.set_text(&recalled.password);
+                }
                 let factors = unsafe { auth_type.data::<Vec<String>>(&id).map(|p| p.as_ref()) };
                 if let Some(factors) = factors {
                     let is_saml = factors.iter().any(|f| f == "identity_provider");
@@ -342,7 +423,10 @@ impl SettingsDialog {
                         .build()
                         .unwrap();
                     let response = rt
-                        .spawn(async move { server_info::get(&params).await })
+                        .spawn(async move {
+                            server_info::invalidate(&params.server_name);
+                            server_info::get(&params).await
+                        })
                         .await
                         .unwrap();
                     let _ = sender.send(response).await;
@@ -351,9 +435,113 @@ impl SettingsDialog {
             }
         }));
 
-        let params2 = params.clone();
+        let (test_sender, test_receiver) = async_channel::bounded(1);
+        let params3 = params.clone();
+
+        // authenticates without creating a tunnel, the session is deleted right away
+        test_credentials.connect_clicked(clone!(@weak dialog,
+            @weak server_name,
+            @weak auth_type,
+            @weak tunnel_type,
+            @weak user_name,
+            @weak password,
+            @weak no_cert_name_check,
+            @weak no_cert_check,
+            @weak error => move |_| {
+            if server_name.text().is_empty() || auth_type.active_id().is_none() {
+                error.set_label("No server address or authentication method specified");
+                error.set_visible(true);
+                return;
+            }
+            dialog.set_sensitive(false);
+            let params = Arc::new(TunnelParams {
+                server_name: server_name.text().into(),
+                login_type: auth_type.active_id().unwrap_or_default().into(),
+                tunnel_type: tunnel_type.active().unwrap_or_default().into(),
+                user_name: user_name.text().into(),
+                password: password.text().into(),
+                no_cert_check: no_cert_name_check.is_active(),
+                ignore_server_cert: no_cert_check.is_active(),
+                ike_persist: false,
+                ..(*params3).clone()
+            });
+            glib::spawn_future_local(clone!(@strong test_sender => async move {
+                let rt = tokio::runtime::Builder::new_multi_thread()
+                    .enable_all()
+                    .build()
+                    .unwrap();
+                let response = rt
+                    .spawn(async move {
+                        let mut connector = tunnel::new_tunnel_connector(params).await?;
+                        let result = connector.authenticate().await;
+                        connector.delete_session().await;
+                        result
+                    })
+                    .await
+                    .unwrap();
+                let _ = test_sender.send(response).await;
+                Ok::<_, anyhow::Error>(())
+            }));
+        }));
+
+        glib::spawn_future_local(clone!(@weak dialog, @weak error => async move {
+            while let Ok(result) = test_receiver.recv().await {
+                let message = match result {
+                    Ok(session) => match session.state {
+                        SessionState::PendingChallenge(ref challenge) => {
+                            format!("Credentials accepted, the server asks for: {}", challenge.prompt)
+                        }
+                        _ => "Credentials are valid".to_owned(),
+                    },
+                    Err(e) => format!("Authentication failed: {e}"),
+                };
+                error.set_label(&message);
+                error.set_visible(true);
+                dialog.set_sensitive(true);
+            }
+        }));
+
+        // login type to preselect once the server info arrives, updated by the link import
+        let login_type = Rc::new(RefCell::new(params.login_type.clone()));
+
+        import_link.connect_clicked(clone!(@weak dialog,
+            @weak server_name,
+            @weak tunnel_type,
+            @weak user_name,
+            @weak fetch_info,
+            @weak error,
+            @strong login_type => move |_| {
+            let Some(link) = prompt_link(&dialog) else {
+                return;
+            };
+            match link.parse::<ConnectionUri>() {
+                Ok(uri) => {
+                    server_name.set_text(&uri.server_name);
+                    if let Some(tunnel) = uri.tunnel_type {
+                        tunnel_type.set_active(Some(tunnel.as_u32()));
+                    }
+                    if let Some(ref user) = uri.user_name {
+                        user_name.set_text(user);
+                    }
+                    if let Some(realm) = uri.login_type {
+                        *login_type.borrow_mut() = realm;
+                    }
+                    fetch_info.emit_clicked();
+                }
+                Err(e) => {
+                    error.set_label(&e.to_string());
+                    error.set_visible(true);
+                }
+            }
+        }));
 
-        glib::spawn_future_local(clone!(@weak dialog, @weak auth_type, @weak error => async move {
+        allow_hidden_realms.connect_toggled(clone!(@weak fetch_info => move |_| fetch_info.emit_clicked()));
+
+        glib::spawn_future_local(clone!(@weak dialog,
+            @weak auth_type,
+            @weak allow_hidden_realms,
+            @weak error,
+            @strong login_type => async move {
             while let Ok(result) = receiver.recv().await {
                 auth_type.remove_all();
                 match result {
@@ -364,6 +552,9 @@ impl SettingsDialog {
                             .login_options_data
                             .map(|d| d.login_options_list)
                             .unwrap_or_default();
+                        options_list.retain(|_, option| {
+                            !option.is_hidden() || allow_hidden_realms.is_active() || option.id == *login_type.borrow()
+                        });
                         if options_list.is_empty() {
                             options_list.insert(String::new(), LoginOption::unspecified());
                         }
@@ -375,7 +566,7 @@ impl SettingsDialog {
                                 .collect::<Vec<_>>();
                             unsafe { auth_type.set_data(&option.id, factors); }
                             auth_type.append(Some(&option.id), &option.display_name);
-                            if params2.login_type == option.id {
+                            if *login_type.borrow() == option.id {
                                 auth_type.set_active(Some(i as _));
                             }
                         }
@@ -395,7 +586,10 @@ impl SettingsDialog {
         let widgets = Rc::new(MyWidgets {
             server_name,
             fetch_info,
+            test_credentials,
+            import_link,
             auth_type,
+            allow_hidden_realms,
             tunnel_type,
             user_name,
             password,
@@ -403,6 +597,7 @@ impl SettingsDialog {
             search_domains,
             ignored_domains,
             dns_servers,
+            replace_dns_servers,
             ignored_dns_servers,
             no_routing,
             default_routing,
@@ -426,6 +621,7 @@ impl SettingsDialog {
             ike_transport,
             no_keepalive,
             icon_theme,
+            notify_rekey,
             error,
         });
 
@@ -452,6 +648,7 @@ impl SettingsDialog {
             params,
             dialog,
             widgets,
+            realm_credentials,
         };
 
         result.create_layout();
@@ -467,12 +664,29 @@ impl SettingsDialog {
         let mut params = (*self.params).clone();
         params.server_name = self.widgets.server_name.text().into();
         params.login_type = self.widgets.auth_type.active_id().unwrap_or_default().into();
-        params.tunnel_type = match self.widgets.tunnel_type.active().unwrap_or_default() {
-            0 => TunnelType::Ipsec,
-            _ => TunnelType::Ssl,
-        };
+        params.tunnel_type = self.widgets.tunnel_type.active().unwrap_or_default().into();
         params.user_name = self.widgets.user_name.text().into();
         params.password = self.widgets.password.text().into();
+        params.realm_credentials = self.realm_credentials.borrow().clone();
+        if !params.realm_credentials.is_empty() {
+            // the flat credentials from the file stay the fallback for the other login types
+            let default_credentials = self
+                .params
+                .default_credentials
+                .clone()
+                .unwrap_or_else(|| RealmCredentials {
+                    user_name: self.params.user_name.clone(),
+                    password: self.params.password.clone(),
+                });
+            params.default_credentials = Some(default_credentials);
+            params.realm_credentials.insert(
+                params.login_type.clone(),
+                RealmCredentials {
+                    user_name: params.user_name.clone(),
+                    password: params.password.clone(),
+                },
+            );
+        }
         params.no_dns = self.widgets.no_dns.is_active();
         params.search_domains = self
             .widgets
@@ -495,12 +709,13 @@ impl SettingsDialog {
             .split(',')
             .flat_map(|s| s.trim().parse().ok())
             .collect();
+        params.replace_dns_servers = self.widgets.replace_dns_servers.is_active();
         params.ignore_dns_servers = self
             .widgets
             .ignored_dns_servers
             .text()
             .split(',')
-            .flat_map(|s| s.trim().parse().ok())
+            .flat_map(|s| util::parse_ipv4_net(s).ok())
             .collect();
         params.no_routing = self.widgets.no_routing.is_active();
         params.default_route = self.widgets.default_routing.is_active();
@@ -509,15 +724,16 @@ impl SettingsDialog {
             .add_routes
             .text()
             .split(',')
-            .flat_map(|s| s.trim().parse().ok())
+            .flat_map(|s| util::parse_ipv4_net(s).ok())
             .collect();
         params.ignore_routes = self
             .widgets
             .ignored_routes
             .text()
             .split(',')
-            .flat_map(|s| s.trim().parse().ok())
+            .flat_map(|s| util::parse_ipv4_net(s).ok())
             .collect();
+        params.allow_hidden_realms = self.widgets.allow_hidden_realms.is_active();
         params.server_prompt = self.widgets.mfa_prompts.is_active();
         params.no_keychain = self.widgets.no_keychain.is_active();
         params.no_cert_check = self.widgets.no_cert_name_check.is_active();
@@ -562,6 +778,7 @@ impl SettingsDialog {
         params.ike_persist = self.widgets.ike_persist.is_active();
         params.no_keepalive = self.widgets.no_keepalive.is_active();
         params.icon_theme = self.widgets.icon_theme.active().unwrap_or_default().into();
+        params.notify_rekey = self.widgets.notify_rekey.is_active();
         params.ike_transport = self.widgets.ike_transport.active().unwrap_or_default().into();
 
         params.save()?;
@@ -592,6 +809,8 @@ impl SettingsDialog {
             .build();
         entry_box.pack_start(&self.widgets.server_name, false, true, 0);
         entry_box.pack_start(&self.widgets.fetch_info, false, false, 0);
+        entry_box.pack_start(&self.widgets.test_credentials, false, false, 0);
+        entry_box.pack_start(&self.widgets.import_link, false, false, 0);
 
         let server_box = self.form_box("Check Point VPN server");
         server_box.pack_start(&entry_box, false, true, 0);
@@ -608,13 +827,11 @@ impl SettingsDialog {
         let tunnel_box = self.form_box("Tunnel type");
         self.widgets.tunnel_type.insert_text(0, "IPSec");
         self.widgets.tunnel_type.insert_text(1, "SSL");
+        // not supported by the connectors, but kept so that saving the profile doesn't change it
+        self.widgets.tunnel_type.insert_text(2, "L2TP");
         self.widgets
             .tunnel_type
-            .set_active(if self.params.tunnel_type == TunnelType::Ipsec {
-                Some(0)
-            } else {
-                Some(1)
-            });
+            .set_active(Some(self.params.tunnel_type.as_u32()));
         tunnel_box.pack_start(&self.widgets.tunnel_type, false, true, 0);
         tunnel_box
     }
@@ -657,6 +874,7 @@ impl SettingsDialog {
         let esp_transport_box = self.form_box("ESP transport");
         self.widgets.esp_transport.insert_text(0, "UDP");
         self.widgets.esp_transport.insert_text(1, "TCPT");
+        self.widgets.esp_transport.insert_text(2, "Auto");
         self.widgets
             .esp_transport
             .set_active(Some(self.params.esp_transport.as_u32()));
@@ -692,6 +910,10 @@ impl SettingsDialog {
         dns_servers.pack_start(&self.widgets.dns_servers, false, true, 0);
         dns_box.pack_start(&dns_servers, false, true, 6);
 
+        let replace_dns_servers = self.form_box("Use them instead of the acquired DNS servers");
+        replace_dns_servers.pack_start(&self.widgets.replace_dns_servers, false, true, 0);
+        dns_box.pack_start(&replace_dns_servers, false, true, 6);
+
         let ignored_dns_servers = self.form_box("Ignored DNS servers");
         ignored_dns_servers.pack_start(&self.widgets.ignored_dns_servers, false, true, 0);
         dns_box.pack_start(&ignored_dns_servers, false, true, 6);
@@ -719,7 +941,8 @@ impl SettingsDialog {
         ca_cert.pack_start(&self.widgets.ca_cert, false, true, 0);
         certs_box.pack_start(&ca_cert, false, true, 6);
 
-        let no_cert_name_check = self.form_box("Disable TLS server hostname check");
+        let no_cert_name_check =
+            self.form_box("Disable TLS server hostname check (the certificate chain is still validated)");
         no_cert_name_check.pack_start(&self.widgets.no_cert_name_check, false, true, 0);
         certs_box.pack_start(&no_cert_name_check, false, true, 6);
 
@@ -742,6 +965,10 @@ impl SettingsDialog {
             .margin_end(16)
             .build();
 
+        let allow_hidden_realms = self.form_box("Show hidden authentication methods");
+        allow_hidden_realms.pack_start(&self.widgets.allow_hidden_realms, false, true, 0);
+        misc_box.pack_start(&allow_hidden_realms, false, true, 6);
+
         let mfa_prompts = self.form_box("Ask server for MFA prompts");
         mfa_prompts.pack_start(&self.widgets.mfa_prompts, false, true, 0);
         misc_box.pack_start(&mfa_prompts, false, true, 6);
@@ -779,6 +1006,10 @@ impl SettingsDialog {
         let icon_theme_box = self.icon_theme_box();
         misc_box.pack_start(&icon_theme_box, false, true, 6);
 
+        let notify_rekey = self.form_box("Notify about tunnel rekeys and address renewals");
+        notify_rekey.pack_start(&self.widgets.notify_rekey, false, true, 0);
+        misc_box.pack_start(&notify_rekey, false, true, 6);
+
         misc_box
     }
 
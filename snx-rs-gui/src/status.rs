@@ -0,0 +1,257 @@
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use gtk::{
+    glib::{self, ControlFlow},
+    prelude::*,
+    Align, Orientation, WindowPosition,
+};
+
+use snxcore::{
+    browser::SystemBrowser,
+    controller::{ServiceCommand, ServiceController},
+    model::{params::TunnelParams, ConnectionStatus, TrafficStats},
+    util,
+};
+
+use crate::{diagnostics, prompt::GtkPrompt};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const COPIED_LABEL_DURATION: Duration = Duration::from_secs(2);
+const COPY_LABEL: &str = "Copy diagnostics";
+
+// outcome of the status polls, attached to the diagnostics
+#[derive(Default)]
+struct LastPoll {
+    status: Option<ConnectionStatus>,
+    error: Option<String>,
+}
+
+struct StatusView {
+    state: gtk::Label,
+    server: gtk::Label,
+    uptime: gtk::Label,
+    received: gtk::Label,
+    sent: gtk::Label,
+    download_rate: gtk::Label,
+    upload_rate: gtk::Label,
+}
+
+impl StatusView {
+    fn new(grid: &gtk::Grid) -> Self {
+        let rows = [
+            "State:",
+            "Server:",
+            "Uptime:",
+            "Received:",
+            "Sent:",
+            "Download rate:",
+            "Upload rate:",
+        ];
+
+        let values = rows
+            .iter()
+            .enumerate()
+            .map(|(row, title)| {
+                let name = gtk::Label::builder().label(*title).halign(Align::End).build();
+                let value = gtk::Label::builder().halign(Align::Start).selectable(true).build();
+                grid.attach(&name, 0, row as _, 1, 1);
+                grid.attach(&value, 1, row as _, 1, 1);
+                value
+            })
+            .collect::<Vec<_>>();
+
+        Self {
+            state: values[0].clone(),
+            server: values[1].clone(),
+            uptime: values[2].clone(),
+            received: values[3].clone(),
+            sent: values[4].clone(),
+            download_rate: values[5].clone(),
+            upload_rate: values[6].clone(),
+        }
+    }
+
+    fn update(&self, status: &anyhow::Result<ConnectionStatus>, rate: Option<TrafficStats>) {
+        let status = match status {
+            Ok(status) if status.connected_since.is_some() && status.mfa.is_none() => status,
+            Ok(status) => {
+                let state = if status.mfa.is_some() {
+                    "Pending MFA prompt".to_owned()
+                } else if let Some(reason) = status.disconnect_reason {
+                    format!("Disconnected: {reason}")
+                } else {
+                    "Disconnected".to_owned()
+                };
+                self.clear(&state);
+                return;
+            }
+            Err(e) => {
+                self.clear(&e.to_string());
+                return;
+            }
+        };
+
+        self.state.set_label("Connected");
+        self.server.set_label(status.server_name.as_deref().unwrap_or_default());
+        self.uptime.set_label(&status.uptime_summary().unwrap_or_default());
+
+        let traffic = status.traffic.unwrap_or_default();
+        self.received.set_label(&util::format_bytes(traffic.bytes_in));
+        self.sent.set_label(&util::format_bytes(traffic.bytes_out));
+
+        // the rate needs two polls of the same tunnel
+        match rate {
+            Some(rate) => {
+                self.download_rate.set_label(&util::format_rate(rate.bytes_in));
+                self.upload_rate.set_label(&util::format_rate(rate.bytes_out));
+            }
+            None => {
+                self.download_rate.set_label("...");
+                self.upload_rate.set_label("...");
+            }
+        }
+    }
+
+    fn clear(&self, state: &str) {
+        self.state.set_label(state);
+        for label in [
+            &self.server,
+            &self.uptime,
+            &self.received,
+            &self.sent,
+            &self.download_rate,
+            &self.upload_rate,
+        ] {
+            label.set_label("");
+        }
+    }
+}
+
+// polls the service until the window is closed and the receiver is gone
+fn start_status_polling(params: Arc<TunnelParams>, sender: async_channel::Sender<anyhow::Result<ConnectionStatus>>) {
+    std::thread::spawn(move || {
+        let Ok(rt) = tokio::runtime::Builder::new_multi_thread().enable_all().build() else {
+            return;
+        };
+
+        loop {
+            let params = params.clone();
+            let status = rt.block_on(async move {
+                ServiceController::new(GtkPrompt, SystemBrowser, params)?
+                    .command(ServiceCommand::Status)
+                    .await
+            });
+            if sender.send_blocking(status).is_err() {
+                break;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    });
+}
+
+/// Window with the live state of the tunnel: uptime, traffic totals and the current transfer rates.
+/// The diagnostics for bug reports are copied from here to the clipboard.
+pub fn start_status_dialog(params: Arc<TunnelParams>) {
+    glib::idle_add(move || {
+        let window = gtk::Window::builder()
+            .title("Connection status")
+            .default_width(360)
+            .window_position(WindowPosition::Center)
+            .build();
+
+        let grid = gtk::Grid::builder().row_spacing(6).column_spacing(12).build();
+        let view = StatusView::new(&grid);
+        view.clear("...");
+
+        let inner = gtk::Box::builder()
+            .orientation(Orientation::Vertical)
+            .margin(12)
+            .build();
+        inner.pack_start(&grid, true, true, 0);
+        window.add(&inner);
+
+        let copy_diagnostics = gtk::Button::builder().label(COPY_LABEL).halign(Align::End).build();
+        inner.pack_end(&copy_diagnostics, false, false, 12);
+
+        let last_poll = Rc::new(RefCell::new(LastPoll::default()));
+
+        copy_diagnostics.connect_clicked(glib::clone!(@strong last_poll, @strong params => move |button| {
+            button.set_sensitive(false);
+            let params = params.clone();
+            let (status, last_error) = {
+                let last_poll = last_poll.borrow();
+                (last_poll.status.clone(), last_poll.error.clone())
+            };
+            glib::spawn_future_local(glib::clone!(@weak button => async move {
+                let rt = tokio::runtime::Builder::new_multi_thread()
+                    .enable_all()
+                    .build()
+                    .unwrap();
+                let report = rt
+                    .spawn(async move { diagnostics::collect(&params, status.as_ref(), last_error.as_deref()).await })
+                    .await
+                    .unwrap_or_default();
+                gtk::Clipboard::get(&gtk::gdk::SELECTION_CLIPBOARD).set_text(&report);
+                button.set_label("Copied to clipboard");
+                button.set_sensitive(true);
+                glib::timeout_add_local_once(COPIED_LABEL_DURATION, glib::clone!(@weak button => move || {
+                    button.set_label(COPY_LABEL);
+                }));
+            }));
+        }));
+
+        let (sender, receiver) = async_channel::bounded(1);
+        start_status_polling(params.clone(), sender);
+
+        window.connect_destroy(glib::clone!(@strong receiver => move |_| {
+            receiver.close();
+        }));
+
+        glib::spawn_future_local(async move {
+            let mut prev: Option<(Instant, ConnectionStatus)> = None;
+
+            while let Ok(status) = receiver.recv().await {
+                let now = Instant::now();
+
+                // a reconnect starts a new tunnel with new counters
+                let rate = match (&prev, &status) {
+                    (Some((then, prev_status)), Ok(status))
+                        if prev_status.connected_since == status.connected_since =>
+                    {
+                        status
+                            .traffic
+                            .zip(prev_status.traffic)
+                            .map(|(traffic, prev_traffic)| traffic.rate_since(&prev_traffic, now - *then))
+                    }
+                    _ => None,
+                };
+
+                view.update(&status, rate);
+
+                match status {
+                    Ok(status) => {
+                        let mut last_poll = last_poll.borrow_mut();
+                        if let Some(reason) = status.disconnect_reason {
+                            last_poll.error = Some(reason.to_string());
+                        }
+                        last_poll.status = Some(status.clone());
+                        prev = Some((now, status));
+                    }
+                    Err(e) => {
+                        last_poll.borrow_mut().error = Some(e.to_string());
+                        prev = None;
+                    }
+                }
+            }
+        });
+
+        window.show_all();
+
+        ControlFlow::Break
+    });
+}
@@ -23,6 +23,24 @@ fn browser(_params: Arc<TunnelParams>) -> impl BrowserController {
     snxcore::browser::SystemBrowser
 }
 
+// notify about the rekeys and address renewals which happened since the previous status poll
+fn notify_tunnel_events(prev: &anyhow::Result<ConnectionStatus>, status: &anyhow::Result<ConnectionStatus>) {
+    let (Ok(prev), Ok(status)) = (prev, status) else {
+        return;
+    };
+
+    if status.last_rekey.is_some() && status.last_rekey != prev.last_rekey {
+        let _ = prompt::GtkPrompt.show_notification("Tunnel rekeyed", "New IPSec keys are in use");
+    }
+
+    if status.lease_renewed.is_some() && status.lease_renewed != prev.lease_renewed {
+        let _ = prompt::GtkPrompt.show_notification(
+            "Address renewed",
+            "Office mode address was renewed from the saved IKE session",
+        );
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TrayCommand {
     Service(ServiceCommand),
@@ -76,8 +94,14 @@ impl AppTray {
                         if status.mfa.is_some() {
                             "Pending MFA prompt".to_owned()
                         } else {
-                            format!("Connected since: {}", since.to_rfc2822())
+                            format!(
+                                "Connected since: {} [{}]",
+                                since.to_rfc2822(),
+                                status.uptime_summary().unwrap_or_default()
+                            )
                         }
+                    } else if let Some(reason) = status.disconnect_reason {
+                        format!("Tunnel disconnected: {reason}")
                     } else {
                         "Tunnel disconnected".to_owned()
                     }
@@ -90,6 +114,15 @@ impl AppTray {
     fn menu(&self) -> anyhow::Result<Box<dyn ContextMenu>> {
         let menu = Menu::new();
         menu.append(&MenuItem::new(self.status_label(), false, None))?;
+        if let Some(address) = self
+            .status
+            .as_ref()
+            .ok()
+            .filter(|status| status.mfa.is_none())
+            .and_then(|status| status.address)
+        {
+            menu.append(&MenuItem::new(format!("Address: {address}"), false, None))?;
+        }
         menu.append(&PredefinedMenuItem::separator())?;
         menu.append(&MenuItem::with_id(
             "connect",
@@ -109,7 +142,9 @@ impl AppTray {
             None,
         ))?;
 
+        menu.append(&MenuItem::with_id("status", "Status...", true, None))?;
         menu.append(&MenuItem::with_id("settings", "Settings...", true, None))?;
+        menu.append(&MenuItem::with_id("logs", "Logs...", true, None))?;
         menu.append(&MenuItem::with_id("about", "About...", true, None))?;
         menu.append(&MenuItem::with_id("exit", "Exit", true, None))?;
 
@@ -153,9 +188,21 @@ impl AppTray {
         Ok(Icon::from_rgba(data, 256, 256)?)
     }
 
+    fn tooltip(&self) -> String {
+        match self.status {
+            Ok(ref status) if status.mfa.is_none() => match (status.uptime_summary(), status.traffic) {
+                (Some(uptime), Some(traffic)) => format!("{TITLE}: connected {uptime}, {traffic}"),
+                (Some(uptime), None) => format!("{TITLE}: connected {uptime}"),
+                (None, _) => TITLE.to_owned(),
+            },
+            _ => TITLE.to_owned(),
+        }
+    }
+
     fn update(&self) -> anyhow::Result<()> {
         self.tray_icon.set_icon(Some(self.icon()?))?;
         self.tray_icon.set_menu(Some(self.menu()?));
+        self.tray_icon.set_tooltip(Some(self.tooltip()))?;
         Ok(())
     }
 
@@ -187,6 +234,7 @@ impl AppTray {
             }
 
             let tunnel_params = Arc::new(TunnelParams::load(&self.config_file).unwrap_or_default());
+            let notify_rekey = tunnel_params.notify_rekey;
 
             if let Ok(mut controller) =
                 ServiceController::new(prompt::GtkPrompt, browser(tunnel_params.clone()), tunnel_params)
@@ -196,14 +244,22 @@ impl AppTray {
                     self.update()?;
                 }
 
-                let result = rt.spawn(async move { controller.command(command).await }).await;
+                let service_command = command.clone();
+                let result = rt.spawn(async move { controller.command(service_command).await }).await;
 
                 let status = match result {
                     Ok(result) => result,
                     Err(_) => Err(anyhow!("Internal error")),
                 };
 
-                let status_str = format!("{status:?}");
+                // traffic counters change with every status poll and are shown in the tooltip only
+                let status_str = format!(
+                    "{:?}",
+                    status.as_ref().map(|status| ConnectionStatus {
+                        traffic: None,
+                        ..status.clone()
+                    })
+                );
 
                 match status {
                     Err(ref e) if command == ServiceCommand::Connect => {
@@ -212,10 +268,18 @@ impl AppTray {
                     _ => {}
                 }
 
+                if notify_rekey {
+                    notify_tunnel_events(&self.status, &status);
+                }
+
                 if command != prev_command || status_str != prev_status {
                     self.connecting = false;
                     self.status = status;
                     self.update()?;
+                } else {
+                    // the uptime and traffic change with every status poll
+                    self.status = status;
+                    self.tray_icon.set_tooltip(Some(self.tooltip()))?;
                 }
                 prev_command = command;
                 prev_status = status_str;
@@ -1,14 +1,31 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use ipnet::Ipv4Net;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
 use std::{path::PathBuf, time::Duration};
 use tracing::level_filters::LevelFilter;
 
-use snxcore::model::params::{CertType, OperationMode, TransportType, TunnelParams, TunnelType};
+use snxcore::{
+    model::params::{
+        CertType, OperationMode, OtpSource, RouteConflict, SearchDomainPolicy, TransportType, TunnelParams, TunnelType,
+    },
+    util,
+};
+
+#[derive(Subcommand)]
+pub enum CmdlineCommand {
+    #[clap(hide = true, about = "Parse a saved CCC or IKE response and print the result")]
+    Parse {
+        #[clap(help = "File with the response to parse")]
+        file: PathBuf,
+    },
+}
 
 #[derive(Parser)]
 #[clap(about = "VPN client for Checkpoint security gateway", name = "snx-rs")]
 pub struct CmdlineParams {
+    #[clap(subcommand)]
+    pub command: Option<CmdlineCommand>,
+
     #[clap(long = "server-name", short = 's', help = "Server name")]
     pub server_name: Option<String>,
 
@@ -26,9 +43,52 @@ pub struct CmdlineParams {
     #[clap(long = "password", short = 'p', help = "Password in base64-encoded form")]
     pub password: Option<String>,
 
+    #[clap(
+        long = "password-env",
+        help = "Name of the environment variable to take the password from, e.g. SNX_PASSWORD"
+    )]
+    pub password_env: Option<String>,
+
+    #[clap(
+        long = "password-factor",
+        help = "Index of the password prompt, counting from 1, which is answered with the generated TOTP code"
+    )]
+    pub password_factor: Option<usize>,
+
+    #[clap(
+        long = "otp-source",
+        help = "Read the code for the password-factor prompt from pipe:/path or file:/path instead of asking for it"
+    )]
+    pub otp_source: Option<OtpSource>,
+
     #[clap(long = "config-file", short = 'c', help = "Read parameters from config file")]
     pub config_file: Option<PathBuf>,
 
+    #[clap(
+        long = "config-check",
+        help = "Print the effective configuration including drop-in fragments and command line options, then exit"
+    )]
+    pub config_check: bool,
+
+    #[clap(
+        long = "raw",
+        help = "In the info mode, print the unparsed server response, e.g. to attach it to a bug report"
+    )]
+    pub raw: bool,
+
+    #[clap(
+        long = "no-redact",
+        requires = "raw",
+        help = "Do not mask the cookie and session fields in the raw server response"
+    )]
+    pub no_redact: bool,
+
+    #[clap(
+        long = "print-config",
+        help = "Print the effective configuration with masked secrets and the source of each value, then exit"
+    )]
+    pub print_config: bool,
+
     #[clap(
         long = "log-level",
         short = 'l',
@@ -36,6 +96,15 @@ pub struct CmdlineParams {
     )]
     pub log_level: Option<LevelFilter>,
 
+    #[clap(
+        long = "log-file",
+        help = "Write the log to this file instead of stdout, rotated daily"
+    )]
+    pub log_file: Option<PathBuf>,
+
+    #[clap(long = "log-file-count", help = "Number of rotated log files to keep [default: 7]")]
+    pub log_file_count: Option<usize>,
+
     #[clap(
         long = "search-domains",
         short = 'd',
@@ -52,6 +121,18 @@ pub struct CmdlineParams {
     )]
     pub ignore_search_domains: Vec<String>,
 
+    #[clap(
+        long = "max-search-domains",
+        help = "Maximum number of search domains to apply, 0 means unlimited"
+    )]
+    pub max_search_domains: Option<usize>,
+
+    #[clap(
+        long = "search-domains-policy",
+        help = "How to limit search domains, one of: truncate, keep-configured"
+    )]
+    pub search_domains_policy: Option<SearchDomainPolicy>,
+
     #[clap(
         long = "dns-servers",
         short = 'D',
@@ -60,13 +141,20 @@ pub struct CmdlineParams {
     )]
     pub dns_servers: Vec<Ipv4Addr>,
 
+    #[clap(
+        long = "replace-dns-servers",
+        help = "Use the DNS servers from the dns-servers option instead of the acquired ones"
+    )]
+    pub replace_dns_servers: Option<bool>,
+
     #[clap(
         long = "ignore-dns-servers",
         short = 'G',
         value_delimiter = ',',
-        help = "Ignore specified DNS servers from the acquired list"
+        value_parser = util::parse_ipv4_net,
+        help = "Ignore specified DNS servers or subnets from the acquired list"
     )]
-    pub ignore_dns_servers: Vec<Ipv4Addr>,
+    pub ignore_dns_servers: Vec<Ipv4Net>,
 
     #[clap(
         long = "default-route",
@@ -75,6 +163,12 @@ pub struct CmdlineParams {
     )]
     pub default_route: Option<bool>,
 
+    #[clap(
+        long = "keep-fallback-default-route",
+        help = "Keep the original default route as a fallback when the tunnel goes down"
+    )]
+    pub keep_fallback_default_route: Option<bool>,
+
     #[clap(long = "no-routing", short = 'n', help = "Ignore all routes from the acquired list")]
     pub no_routing: Option<bool>,
 
@@ -82,6 +176,7 @@ pub struct CmdlineParams {
         long = "add-routes",
         short = 'a',
         value_delimiter = ',',
+        value_parser = util::parse_ipv4_net,
         help = "Additional routes through the tunnel"
     )]
     pub add_routes: Vec<Ipv4Net>,
@@ -90,13 +185,45 @@ pub struct CmdlineParams {
         long = "ignore-routes",
         short = 'I',
         value_delimiter = ',',
+        value_parser = util::parse_ipv4_net,
         help = "Ignore specified routes from the acquired list"
     )]
     pub ignore_routes: Vec<Ipv4Net>,
 
+    #[clap(
+        long = "on-route-conflict",
+        help = "Action when a route already exists via another interface, one of: fail, skip, replace"
+    )]
+    pub on_route_conflict: Option<RouteConflict>,
+
+    #[clap(
+        long = "app-routing-users",
+        value_delimiter = ',',
+        help = "Route only the traffic of the specified users (names or UIDs) through the tunnel"
+    )]
+    pub app_routing_users: Vec<String>,
+
+    #[clap(
+        long = "app-routing-cgroup",
+        help = "Route only the traffic of the specified cgroup v2 path through the tunnel"
+    )]
+    pub app_routing_cgroup: Option<String>,
+
     #[clap(long = "no-dns", short = 'N', help = "Do not change DNS resolver configuration")]
     pub no_dns: Option<bool>,
 
+    #[clap(
+        long = "disable-ipv6",
+        help = "Do not configure IPv6 DNS servers provided by the gateway"
+    )]
+    pub disable_ipv6: Option<bool>,
+
+    #[clap(
+        long = "teardown-grace",
+        help = "Delay in seconds before restoring DNS configuration after disconnect"
+    )]
+    pub teardown_grace: Option<u64>,
+
     #[clap(
         long = "no-cert-check",
         short = 'H',
@@ -118,6 +245,12 @@ pub struct CmdlineParams {
     )]
     pub ipsec_cert_check: Option<bool>,
 
+    #[clap(
+        long = "server-cert-fingerprint",
+        help = "SHA-256 fingerprint of the gateway TLS certificate in hex form, other certificates are rejected"
+    )]
+    pub server_cert_fingerprint: Option<String>,
+
     #[clap(long = "tunnel-type", short = 'e', help = "Tunnel type, one of: ssl, ipsec")]
     pub tunnel_type: Option<TunnelType>,
 
@@ -136,6 +269,12 @@ pub struct CmdlineParams {
     )]
     pub login_type: Option<String>,
 
+    #[clap(
+        long = "allow-hidden-realms",
+        help = "Include login types which are marked as hidden by the server"
+    )]
+    pub allow_hidden_realms: Option<bool>,
+
     #[clap(
         long = "cert-type",
         short = 'y',
@@ -157,12 +296,60 @@ pub struct CmdlineParams {
     )]
     pub cert_password: Option<String>,
 
+    #[clap(
+        long = "cert-pkcs12-b64",
+        help = "Base64-encoded PKCS12 certificate or @env:VAR to read it from the environment"
+    )]
+    pub cert_pkcs12_b64: Option<String>,
+
     #[clap(long = "cert-id", short = 'w', help = "Certificate ID in hexadecimal form")]
     pub cert_id: Option<String>,
 
     #[clap(long = "if-name", short = 'f', help = "Interface name for tun or xfrm device")]
     pub if_name: Option<String>,
 
+    #[clap(
+        long = "if-txqueuelen",
+        help = "Transmit queue length of the tunnel interface, 0 keeps the system default"
+    )]
+    pub if_txqueuelen: Option<u32>,
+
+    #[clap(long = "if-mtu", help = "MTU of the tunnel interface, 0 keeps the system default")]
+    pub if_mtu: Option<u16>,
+
+    #[clap(
+        long = "mtu-discovery",
+        help = "Probe the path MTU through the IPSec tunnel after it is up and apply it to the interface"
+    )]
+    pub mtu_discovery: Option<bool>,
+
+    #[clap(
+        long = "mtu-discovery-fallback",
+        help = "MTU of the tunnel interface during the MTU discovery and when it fails"
+    )]
+    pub mtu_discovery_fallback: Option<u16>,
+
+    #[clap(long = "if-gro", help = "Enable or disable GRO on the tunnel interface")]
+    pub if_gro: Option<bool>,
+
+    #[clap(long = "if-gso", help = "Enable or disable GSO on the tunnel interface")]
+    pub if_gso: Option<bool>,
+
+    #[clap(
+        long = "run-as",
+        help = "Drop root privileges and switch to the given user after the tunnel is up (standalone mode only)"
+    )]
+    pub run_as: Option<String>,
+
+    #[clap(long = "post-connect-script", help = "Script to run after the tunnel is connected")]
+    pub post_connect_script: Option<PathBuf>,
+
+    #[clap(
+        long = "post-disconnect-script",
+        help = "Script to run after the tunnel is disconnected"
+    )]
+    pub post_disconnect_script: Option<PathBuf>,
+
     #[clap(
         long = "no-keychain",
         short = 'K',
@@ -180,15 +367,49 @@ pub struct CmdlineParams {
     #[clap(long = "esp-lifetime", short = 'E', help = "IPSec ESP lifetime in seconds")]
     pub esp_lifetime: Option<u64>,
 
-    #[clap(long = "esp-transport", short = 'Q', help = "ESP transport type, one of: udp, tcpt")]
+    #[clap(
+        long = "esp-transport",
+        short = 'Q',
+        help = "ESP transport type, one of: auto, udp, tcpt"
+    )]
     pub esp_transport: Option<TransportType>,
 
     #[clap(long = "ike-lifetime", short = 'L', help = "IPSec IKE lifetime in seconds")]
     pub ike_lifetime: Option<u64>,
 
+    #[clap(
+        long = "identity-timeout",
+        help = "Timeout in seconds for the IPSec authentication exchange [default: provided by the server or 120]"
+    )]
+    pub identity_timeout: Option<u64>,
+
+    #[clap(
+        long = "connect-timeout",
+        help = "Overall timeout in seconds for the authentication and tunnel setup, 0 to disable [default: 0]"
+    )]
+    pub connect_timeout: Option<u64>,
+
     #[clap(long = "ike-port", short = 'R', help = "IPSec IKE communication port [default: 500]")]
     pub ike_port: Option<u16>,
 
+    #[clap(
+        long = "natt-source-port",
+        help = "Local UDP port for the ESP-in-UDP (NAT-T) socket, 0 for a random port [default: 0]"
+    )]
+    pub natt_source_port: Option<u16>,
+
+    #[clap(
+        long = "natt-probe-count",
+        help = "Number of NAT-T probe retries before the ESP-in-UDP transport is considered unavailable [default: 3]"
+    )]
+    pub natt_probe_count: Option<usize>,
+
+    #[clap(
+        long = "natt-probe-timeout",
+        help = "Timeout in seconds for a single NAT-T probe [default: 2]"
+    )]
+    pub natt_probe_timeout: Option<u64>,
+
     #[clap(
         long = "ike-persist",
         short = 'W',
@@ -196,9 +417,50 @@ pub struct CmdlineParams {
     )]
     pub ike_persist: Option<bool>,
 
-    #[clap(long = "ike-transport", short = 'T', help = "IKE transport type, one of: udp, tcpt")]
+    #[clap(
+        long = "signout-on-disconnect",
+        help = "Sign out from the server on disconnect or cancel, also before the tunnel is created"
+    )]
+    pub signout_on_disconnect: Option<bool>,
+
+    #[clap(
+        long = "session-path",
+        help = "Directory for the persistent IKE sessions and the transport cache"
+    )]
+    pub session_path: Option<PathBuf>,
+
+    #[clap(
+        long = "ike-transport",
+        short = 'T',
+        value_parser = TransportType::parse_ike,
+        help = "IKE transport type, one of: udp, tcpt"
+    )]
     pub ike_transport: Option<TransportType>,
 
+    #[clap(
+        long = "tcpt-only",
+        help = "Use TCPT transport for both IKE and ESP without any UDP probing"
+    )]
+    pub tcpt_only: Option<bool>,
+
+    #[clap(
+        long = "no-server-redirect",
+        help = "Do not follow the gateway node address advertised by the server"
+    )]
+    pub no_server_redirect: Option<bool>,
+
+    #[clap(
+        long = "server-info-retries",
+        help = "Number of retries of the initial server request after transient network errors [default: 2]"
+    )]
+    pub server_info_retries: Option<u32>,
+
+    #[clap(
+        long = "server-info-cache-ttl",
+        help = "Seconds to reuse the server info reply, 0 to disable the caching [default: 300]"
+    )]
+    pub server_info_cache_ttl: Option<u64>,
+
     #[clap(
         long = "client-mode",
         short = 'C',
@@ -206,12 +468,138 @@ pub struct CmdlineParams {
     )]
     pub client_mode: Option<String>,
 
+    #[clap(
+        long = "client-os",
+        help = "Operating system name reported to the gateway [default: Windows]"
+    )]
+    pub client_os: Option<String>,
+
+    #[clap(
+        long = "device-id",
+        value_parser = util::parse_device_id,
+        help = "Device id in the UUID form reported to the gateway instead of the one derived from the machine id"
+    )]
+    pub device_id: Option<String>,
+
+    #[clap(long = "user-agent", help = "Custom User-Agent header for the CCC HTTP requests")]
+    pub user_agent: Option<String>,
+
+    #[clap(
+        long = "proxy-url",
+        help = "HTTP proxy for the gateway connections, in the form http://[user:password@]host:port"
+    )]
+    pub proxy_url: Option<String>,
+
+    #[clap(
+        long = "bind-address",
+        help = "Local IP address for the outgoing gateway connections, to pin the tunnel to one uplink"
+    )]
+    pub bind_address: Option<IpAddr>,
+
     #[clap(long = "no-keepalive", short = 'A', help = "Disable keepalive packets")]
     pub no_keepalive: Option<bool>,
+
+    #[clap(
+        long = "keepalive-interval",
+        help = "IPSec keepalive interval in seconds, 0 to disable keepalive [default: 20]"
+    )]
+    pub keepalive_interval: Option<u64>,
+
+    #[clap(
+        long = "keepalive-max-retries",
+        help = "Number of failed IPSec keepalive requests before the tunnel is disconnected [default: 5]"
+    )]
+    pub keepalive_max_retries: Option<u32>,
+
+    #[clap(
+        long = "keepalive-port",
+        help = "UDP port of the IPSec keepalive requests [default: 18234]"
+    )]
+    pub keepalive_port: Option<u16>,
+
+    #[clap(
+        long = "auto-reconnect",
+        help = "Reconnect the tunnel with exponential backoff when it goes down (standalone mode only)"
+    )]
+    pub auto_reconnect: Option<bool>,
+
+    #[clap(
+        long = "reconnect-max-attempts",
+        help = "Number of reconnect attempts before giving up, 0 for unlimited [default: 0]"
+    )]
+    pub reconnect_max_attempts: Option<u32>,
+
+    #[clap(
+        long = "reconnect-max-delay",
+        help = "Maximum delay between the reconnect attempts in seconds [default: 60]"
+    )]
+    pub reconnect_max_delay: Option<u64>,
+
+    #[clap(
+        long = "keepalive-thread",
+        help = "Run keepalive and rekey timers on a dedicated thread"
+    )]
+    pub keepalive_thread: Option<bool>,
+
+    #[clap(
+        long = "verify-connectivity",
+        help = "Verify that the tunnel carries traffic before reporting it as connected"
+    )]
+    pub verify_connectivity: Option<bool>,
+
+    #[clap(
+        long = "verify-timeout",
+        help = "Timeout in seconds for the connectivity check [default: 10]"
+    )]
+    pub verify_timeout: Option<u64>,
+
+    #[clap(
+        long = "otp-listen-address",
+        help = "Local address of the SAML OTP redirect listener [default: 127.0.0.1]"
+    )]
+    pub otp_listen_address: Option<IpAddr>,
+
+    #[clap(
+        long = "otp-listen-port",
+        help = "Local port of the SAML OTP redirect listener, 0 selects a free port [default: 7779]"
+    )]
+    pub otp_listen_port: Option<u16>,
+
+    #[clap(
+        long = "events-port",
+        help = "Local TCP port which streams the tunnel events in command mode [default: 7780]"
+    )]
+    pub events_port: Option<u16>,
+
+    #[clap(
+        long = "otp-https",
+        help = "Serve the SAML OTP redirect listener over HTTPS with a self-signed certificate"
+    )]
+    pub otp_https: Option<bool>,
+
+    #[clap(
+        long = "otp-redirect-path",
+        help = "URL path of the SAML OTP redirect, must match the IdP app registration [default: /]"
+    )]
+    pub otp_redirect_path: Option<String>,
+
+    #[clap(
+        long = "dump-ccc",
+        help = "Write all CCC requests and responses to timestamped files in the given directory"
+    )]
+    pub dump_ccc: Option<PathBuf>,
+
+    #[clap(
+        long = "dump-raw",
+        help = "Do not redact passwords and session keys in the CCC dump files (NOT SECURE!)"
+    )]
+    pub dump_raw: Option<bool>,
 }
 
 impl CmdlineParams {
     pub fn merge_into_tunnel_params(self, other: &mut TunnelParams) {
+        let explicit_credentials = self.user_name.is_some() || self.password.is_some() || self.password_env.is_some();
+
         if let Some(server_name) = self.server_name {
             other.server_name = server_name;
         }
@@ -225,10 +613,31 @@ impl CmdlineParams {
             let _ = other.decode_password();
         }
 
+        if let Some(password_env) = self.password_env {
+            other.password_env = Some(password_env);
+            other.resolve_password_env();
+        }
+
+        if let Some(password_factor) = self.password_factor.filter(|v| *v > 0) {
+            other.password_factor = password_factor;
+        }
+
+        if let Some(otp_source) = self.otp_source {
+            other.otp_source = Some(otp_source);
+        }
+
         if let Some(log_level) = self.log_level {
             other.log_level = log_level.to_string();
         }
 
+        if self.log_file.is_some() {
+            other.log_file = self.log_file;
+        }
+
+        if let Some(log_file_count) = self.log_file_count {
+            other.log_file_count = log_file_count.max(1);
+        }
+
         if !self.search_domains.is_empty() {
             other.search_domains = self.search_domains;
         }
@@ -237,10 +646,22 @@ impl CmdlineParams {
             other.ignore_search_domains = self.ignore_search_domains;
         }
 
+        if let Some(max_search_domains) = self.max_search_domains {
+            other.max_search_domains = max_search_domains;
+        }
+
+        if let Some(search_domains_policy) = self.search_domains_policy {
+            other.search_domains_policy = search_domains_policy;
+        }
+
         if !self.dns_servers.is_empty() {
             other.dns_servers = self.dns_servers;
         }
 
+        if let Some(replace_dns_servers) = self.replace_dns_servers {
+            other.replace_dns_servers = replace_dns_servers;
+        }
+
         if !self.ignore_dns_servers.is_empty() {
             other.ignore_dns_servers = self.ignore_dns_servers;
         }
@@ -249,6 +670,10 @@ impl CmdlineParams {
             other.default_route = default_route;
         }
 
+        if let Some(keep_fallback_default_route) = self.keep_fallback_default_route {
+            other.keep_fallback_default_route = keep_fallback_default_route;
+        }
+
         if let Some(no_routing) = self.no_routing {
             other.no_routing = no_routing;
         }
@@ -257,6 +682,14 @@ impl CmdlineParams {
             other.no_dns = no_dns;
         }
 
+        if let Some(disable_ipv6) = self.disable_ipv6 {
+            other.disable_ipv6 = disable_ipv6;
+        }
+
+        if let Some(teardown_grace) = self.teardown_grace {
+            other.teardown_grace = Duration::from_secs(teardown_grace);
+        }
+
         if !self.add_routes.is_empty() {
             other.add_routes = self.add_routes;
         }
@@ -265,6 +698,18 @@ impl CmdlineParams {
             other.ignore_routes = self.ignore_routes;
         }
 
+        if let Some(on_route_conflict) = self.on_route_conflict {
+            other.on_route_conflict = on_route_conflict;
+        }
+
+        if !self.app_routing_users.is_empty() {
+            other.app_routing_users = self.app_routing_users;
+        }
+
+        if let Some(app_routing_cgroup) = self.app_routing_cgroup {
+            other.app_routing_cgroup = Some(app_routing_cgroup);
+        }
+
         if let Some(tunnel_type) = self.tunnel_type {
             other.tunnel_type = tunnel_type;
         }
@@ -285,8 +730,19 @@ impl CmdlineParams {
             other.ipsec_cert_check = ipsec_cert_check;
         }
 
+        if let Some(fingerprint) = self.server_cert_fingerprint {
+            other.server_cert_fingerprint = Some(fingerprint).filter(|v| !v.is_empty());
+        }
+
         if let Some(login_type) = self.login_type {
             other.login_type = login_type;
+            if !explicit_credentials {
+                other.apply_realm_credentials();
+            }
+        }
+
+        if let Some(allow_hidden_realms) = self.allow_hidden_realms {
+            other.allow_hidden_realms = allow_hidden_realms;
         }
 
         if let Some(cert_type) = self.cert_type {
@@ -301,6 +757,10 @@ impl CmdlineParams {
             other.cert_password = Some(cert_password);
         }
 
+        if let Some(cert_pkcs12_b64) = self.cert_pkcs12_b64 {
+            other.cert_pkcs12_b64 = Some(cert_pkcs12_b64);
+        }
+
         if let Some(cert_id) = self.cert_id {
             other.cert_id = Some(cert_id);
         }
@@ -309,6 +769,42 @@ impl CmdlineParams {
             other.if_name = Some(if_name);
         }
 
+        if let Some(if_txqueuelen) = self.if_txqueuelen {
+            other.if_txqueuelen = if_txqueuelen;
+        }
+
+        if let Some(if_mtu) = self.if_mtu {
+            other.if_mtu = if_mtu;
+        }
+
+        if let Some(mtu_discovery) = self.mtu_discovery {
+            other.mtu_discovery = mtu_discovery;
+        }
+
+        if let Some(mtu_discovery_fallback) = self.mtu_discovery_fallback {
+            other.mtu_discovery_fallback = mtu_discovery_fallback;
+        }
+
+        if self.if_gro.is_some() {
+            other.if_gro = self.if_gro;
+        }
+
+        if self.if_gso.is_some() {
+            other.if_gso = self.if_gso;
+        }
+
+        if let Some(run_as) = self.run_as {
+            other.run_as = run_as;
+        }
+
+        if self.post_connect_script.is_some() {
+            other.post_connect_script = self.post_connect_script;
+        }
+
+        if self.post_disconnect_script.is_some() {
+            other.post_disconnect_script = self.post_disconnect_script;
+        }
+
         if let Some(no_keychain) = self.no_keychain {
             other.no_keychain = no_keychain;
         }
@@ -329,24 +825,152 @@ impl CmdlineParams {
             other.ike_lifetime = Duration::from_secs(ike_lifetime);
         }
 
+        if let Some(identity_timeout) = self.identity_timeout {
+            other.identity_timeout = Some(Duration::from_secs(identity_timeout)).filter(|t| !t.is_zero());
+        }
+
+        if let Some(connect_timeout) = self.connect_timeout {
+            other.connect_timeout = Some(Duration::from_secs(connect_timeout)).filter(|t| !t.is_zero());
+        }
+
         if let Some(ike_port) = self.ike_port {
             other.ike_port = ike_port;
         }
 
+        if let Some(natt_source_port) = self.natt_source_port {
+            other.natt_source_port = natt_source_port;
+        }
+
+        if let Some(natt_probe_count) = self.natt_probe_count {
+            other.natt_probe_count = natt_probe_count.max(1);
+        }
+
+        if let Some(natt_probe_timeout) = self.natt_probe_timeout {
+            other.natt_probe_timeout = Duration::from_secs(natt_probe_timeout.max(1));
+        }
+
         if let Some(ike_persist) = self.ike_persist {
             other.ike_persist = ike_persist;
         }
 
+        if let Some(signout_on_disconnect) = self.signout_on_disconnect {
+            other.signout_on_disconnect = signout_on_disconnect;
+        }
+
+        if let Some(session_path) = self.session_path {
+            other.session_path = session_path;
+        }
+
         if let Some(ike_transport) = self.ike_transport {
             other.ike_transport = ike_transport;
         }
 
+        if let Some(tcpt_only) = self.tcpt_only {
+            other.tcpt_only = tcpt_only;
+        }
+
+        if let Some(no_server_redirect) = self.no_server_redirect {
+            other.no_server_redirect = no_server_redirect;
+        }
+
+        if let Some(server_info_retries) = self.server_info_retries {
+            other.server_info_retries = server_info_retries;
+        }
+
+        if let Some(server_info_cache_ttl) = self.server_info_cache_ttl {
+            other.server_info_cache_ttl = Duration::from_secs(server_info_cache_ttl);
+        }
+
         if let Some(client_mode) = self.client_mode {
             other.client_mode = client_mode;
         }
 
+        if let Some(client_os) = self.client_os.filter(|v| !v.is_empty()) {
+            other.client_os = client_os;
+        }
+
+        if self.device_id.is_some() {
+            other.device_id = self.device_id;
+        }
+
+        if let Some(user_agent) = self.user_agent {
+            other.user_agent = user_agent;
+        }
+
+        if let Some(proxy_url) = self.proxy_url {
+            other.proxy_url = Some(proxy_url).filter(|v| !v.is_empty());
+        }
+
+        if let Some(bind_address) = self.bind_address {
+            other.bind_address = Some(bind_address);
+        }
+
         if let Some(no_keepalive) = self.no_keepalive {
             other.no_keepalive = no_keepalive;
         }
+
+        if let Some(keepalive_interval) = self.keepalive_interval {
+            other.keepalive_interval = Duration::from_secs(keepalive_interval);
+        }
+
+        if let Some(keepalive_max_retries) = self.keepalive_max_retries {
+            other.keepalive_max_retries = keepalive_max_retries.max(1);
+        }
+
+        if let Some(keepalive_port) = self.keepalive_port.filter(|v| *v > 0) {
+            other.keepalive_port = keepalive_port;
+        }
+
+        if let Some(auto_reconnect) = self.auto_reconnect {
+            other.auto_reconnect = auto_reconnect;
+        }
+
+        if let Some(reconnect_max_attempts) = self.reconnect_max_attempts {
+            other.reconnect_max_attempts = reconnect_max_attempts;
+        }
+
+        if let Some(reconnect_max_delay) = self.reconnect_max_delay {
+            other.reconnect_max_delay = Duration::from_secs(reconnect_max_delay.max(1));
+        }
+
+        if let Some(keepalive_thread) = self.keepalive_thread {
+            other.keepalive_thread = keepalive_thread;
+        }
+
+        if let Some(verify_connectivity) = self.verify_connectivity {
+            other.verify_connectivity = verify_connectivity;
+        }
+
+        if let Some(verify_timeout) = self.verify_timeout {
+            other.verify_timeout = Duration::from_secs(verify_timeout);
+        }
+
+        if let Some(otp_listen_address) = self.otp_listen_address {
+            other.otp_listen_address = otp_listen_address;
+        }
+
+        if let Some(otp_listen_port) = self.otp_listen_port {
+            other.otp_listen_port = otp_listen_port;
+        }
+
+        if let Some(events_port) = self.events_port {
+            other.events_port = events_port;
+        }
+
+        if let Some(otp_https) = self.otp_https {
+            other.otp_https = otp_https;
+        }
+
+        if let Some(otp_redirect_path) = self.otp_redirect_path {
+            other.otp_redirect_path = otp_redirect_path;
+        }
+
+        if let Some(dump_ccc) = self.dump_ccc {
+            other.dump_ccc = Some(dump_ccc);
+        }
+
+        if let Some(dump_raw) = self.dump_raw {
+            other.dump_raw = dump_raw;
+        }
     }
 }
@@ -1,38 +1,53 @@
-use std::{collections::VecDeque, future::Future, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashSet},
+    future::Future,
+    net::Ipv4Addr,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
 
 use clap::Parser;
 use futures::pin_mut;
-use tokio::{
-    signal::unix,
-    sync::{mpsc, oneshot},
-};
-use tracing::{debug, metadata::LevelFilter, warn};
+use tokio::signal::unix;
+use tracing::{debug, warn};
 
 use snxcore::{
-    browser::run_otp_listener,
+    browser::BrowserController,
     ccc::CccHttpClient,
     model::{
-        params::{OperationMode, TunnelParams},
-        MfaType, SessionState,
+        params::{ConfigSource, OperationMode, TunnelParams},
+        proto::{AuthResponse, ClientSettingsResponse, ServerInfoResponse},
     },
     platform,
-    prompt::{SecurePrompt, TtyPrompt, OTP_TIMEOUT},
+    prompt::TtyPrompt,
+    runner,
     server::CommandServer,
-    server_info, tunnel,
+    sexpr::SExpression,
+    tunnel::TunnelEvent,
+    util,
 };
 
-use crate::cmdline::CmdlineParams;
+use crate::cmdline::{CmdlineCommand, CmdlineParams};
 
 mod cmdline;
 
+const ONLINE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+// systemd waits 90 seconds by default before killing the service
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
 fn is_root() -> bool {
     unsafe { libc::geteuid() == 0 }
 }
 
-async fn await_termination<F, R>(f: F) -> anyhow::Result<()>
-where
-    F: Future<Output = anyhow::Result<R>>,
-{
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Termination {
+    Completed,
+    Signal,
+}
+
+// completes on Ctrl-C or SIGTERM
+async fn termination_signal() -> anyhow::Result<()> {
     let ctrl_c = tokio::signal::ctrl_c();
     pin_mut!(ctrl_c);
 
@@ -40,166 +55,380 @@ where
     let term = sig.recv();
     pin_mut!(term);
 
-    let select = futures::future::select(ctrl_c, term);
+    futures::future::select(ctrl_c, term).await;
+    debug!("Application terminated due to a signal");
 
+    Ok(())
+}
+
+async fn await_termination<F, R>(f: F) -> anyhow::Result<Termination>
+where
+    F: Future<Output = anyhow::Result<R>>,
+{
     tokio::select! {
         result = f => {
             result?;
-            Ok(())
+            Ok(Termination::Completed)
         }
 
-        _ = select => {
-            debug!("Application terminated due to a signal");
-            Ok(())
+        result = termination_signal() => {
+            result?;
+            Ok(Termination::Signal)
         }
     }
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<()> {
     let cmdline_params = CmdlineParams::parse();
 
-    if cmdline_params.mode != OperationMode::Info && !is_root() {
-        anyhow::bail!("This program should be run as a root user!");
+    if let Some(CmdlineCommand::Parse { ref file }) = cmdline_params.command {
+        return main_parse(file);
     }
 
-    platform::init();
-
     let mode = cmdline_params.mode;
+    let config_check = cmdline_params.config_check;
+    let print_config = cmdline_params.print_config;
+    let (raw_info, no_redact) = (cmdline_params.raw, cmdline_params.no_redact);
+    let config_file = cmdline_params.config_file.clone();
 
-    let mut params = if let Some(ref config_file) = cmdline_params.config_file {
+    let mut params = if let Some(ref config_file) = config_file {
         TunnelParams::load(config_file)?
     } else {
         TunnelParams::default()
     };
+    let loaded_params = params.clone();
     cmdline_params.merge_into_tunnel_params(&mut params);
 
-    let subscriber = tracing_subscriber::fmt()
-        .with_max_level(params.log_level.parse::<LevelFilter>().unwrap_or(LevelFilter::OFF))
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)?;
+    if config_check {
+        return main_config_check(params);
+    }
+
+    if print_config {
+        return main_print_config(&loaded_params, &params);
+    }
+
+    if mode != OperationMode::Info && !is_root() {
+        anyhow::bail!("This program should be run as a root user!");
+    }
+
+    platform::init();
+
+    snxcore::logging::init_logging(&params)?;
 
     debug!(">>> Starting snx-rs client version {}", env!("CARGO_PKG_VERSION"));
 
-    match mode {
-        OperationMode::Standalone => {
-            debug!("Running in standalone mode");
-            main_standalone(params).await
+    // only the standalone client owns its config file, other modes never write it
+    if let (OperationMode::Standalone, Some(config_file)) = (mode, &config_file) {
+        if let Err(e) = TunnelParams::migrate_file(config_file) {
+            warn!("{:#}", e);
         }
-        OperationMode::Command => {
-            debug!("Running in command mode");
-            main_command().await
+    }
+
+    // capabilities are retained only by the thread which drops privileges,
+    // so all tunnel tasks must run on the main thread in this case
+    let mut builder = if mode == OperationMode::Standalone && !params.run_as.is_empty() {
+        tokio::runtime::Builder::new_current_thread()
+    } else {
+        tokio::runtime::Builder::new_multi_thread()
+    };
+    let runtime = builder.enable_all().build()?;
+
+    runtime.block_on(async move {
+        match mode {
+            OperationMode::Standalone => {
+                debug!("Running in standalone mode");
+                main_standalone(params).await
+            }
+            OperationMode::Command => {
+                debug!("Running in command mode");
+                if !params.run_as.is_empty() {
+                    warn!("The run-as option is supported only in standalone mode");
+                }
+                if params.auto_reconnect {
+                    warn!("The auto-reconnect option is supported only in standalone mode");
+                }
+                main_command(params.events_port).await
+            }
+            OperationMode::Info if raw_info => main_raw_info(params, !no_redact).await,
+            OperationMode::Info => main_info(params).await,
+        }
+    })
+}
+
+fn main_config_check(params: TunnelParams) -> anyhow::Result<()> {
+    if params.config_file.is_file() {
+        println!("# {}", params.config_file.display());
+        let drop_in_dir = TunnelParams::drop_in_dir(&params.config_file);
+        if drop_in_dir.is_dir() {
+            println!("# drop-in fragments: {}/*.conf", drop_in_dir.display());
+        }
+    }
+
+    print!("{}", params.to_redacted_config()?);
+
+    let routes = util::tunnel_routes(&params, std::iter::empty(), Ipv4Addr::UNSPECIFIED.into());
+    let routes = routes.iter().map(ToString::to_string).collect::<Vec<_>>();
+    println!("# routes (without server-provided ones): {}", routes.join(","));
+
+    Ok(())
+}
+
+fn main_print_config(loaded_params: &TunnelParams, params: &TunnelParams) -> anyhow::Result<()> {
+    let mut sources = if params.config_file.is_file() {
+        TunnelParams::config_sources(&params.config_file)?
+    } else {
+        BTreeMap::new()
+    };
+
+    // command line options are merged over the loaded configuration, so every changed value comes from them
+    let loaded = loaded_params.to_config()?;
+    let loaded = loaded.lines().collect::<HashSet<_>>();
+
+    for line in params.to_config()?.lines() {
+        if let Some((key, _)) = line.split_once('=').filter(|_| !loaded.contains(line)) {
+            sources.insert(key.to_owned(), ConfigSource::CommandLine);
         }
-        OperationMode::Info => main_info(params).await,
     }
+
+    print!("{}", params.to_annotated_config(&sources)?);
+
+    Ok(())
+}
+
+fn main_parse(path: &Path) -> anyhow::Result<()> {
+    let data = std::fs::read_to_string(path)?;
+    let expr = data.parse::<SExpression>()?;
+
+    println!("{}", expr);
+
+    match expr.object_name() {
+        Some("CCCserverResponse") => {
+            let response_type = expr
+                .get_value::<String>("CCCserverResponse:ResponseHeader:type")
+                .unwrap_or_default();
+            let data = expr
+                .get("CCCserverResponse:ResponseData")
+                .cloned()
+                .unwrap_or(SExpression::Null);
+
+            match response_type.as_str() {
+                "ClientHello" => println!("{:#?}", data.try_into::<ServerInfoResponse>()?),
+                "ClientSettings" => println!("{:#?}", data.try_into::<ClientSettingsResponse>()?),
+                "Signout" => {}
+                _ => println!("{:#?}", data.try_into::<AuthResponse>()?),
+            }
+        }
+        Some("msg_obj") => {
+            let state = expr
+                .get_value::<String>("msg_obj:authentication_state")
+                .unwrap_or_else(|| "challenge".to_owned());
+            println!("Authentication state: {}", state);
+
+            if let Some(inner) = expr.get("msg_obj:arguments:0:val") {
+                println!(
+                    "Challenge ID: {}",
+                    inner.get_value::<String>("msg_obj:id").unwrap_or_default()
+                );
+                println!(
+                    "Challenge prompt: {}",
+                    inner.get_value::<String>("msg_obj:def_msg").unwrap_or_default()
+                );
+            }
+        }
+        name => println!("No model for object: {}", name.unwrap_or("<unnamed>")),
+    }
+
+    Ok(())
 }
 
 async fn main_info(params: TunnelParams) -> anyhow::Result<()> {
     if params.server_name.is_empty() {
         anyhow::bail!("Missing required parameters: server name!");
     }
+    let allow_hidden_realms = params.allow_hidden_realms;
     let client = CccHttpClient::new(Arc::new(params), None);
     let info = client.get_server_info().await?;
-    snxcore::util::print_login_options(&info);
+    snxcore::util::print_login_options(&info, allow_hidden_realms);
 
     Ok(())
 }
 
-async fn main_command() -> anyhow::Result<()> {
+async fn main_raw_info(params: TunnelParams, redact: bool) -> anyhow::Result<()> {
+    if params.server_name.is_empty() {
+        anyhow::bail!("Missing required parameters: server name!");
+    }
+    println!("{}", snxcore::server_info::get_raw(&params, redact).await?);
+
+    Ok(())
+}
+
+async fn main_command(events_port: u16) -> anyhow::Result<()> {
     if let Err(e) = platform::start_network_state_monitoring().await {
         warn!("Unable to start network monitoring: {}", e);
     }
-    let server = CommandServer::new(snxcore::server::LISTEN_PORT);
+    let mut server = CommandServer::new(snxcore::server::LISTEN_PORT, events_port);
+
+    let result = await_termination(server.run()).await;
+
+    if let Ok(Termination::Signal) = result {
+        server.shutdown(SHUTDOWN_TIMEOUT).await;
+    }
+
+    platform::flush_resolver_cleanup();
+
+    result.map(|_| ())
+}
 
-    await_termination(server.run()).await
+// state carried over the reconnect attempts in standalone mode
+#[derive(Default)]
+struct StandaloneState {
+    // the tunnel of the last attempt was up
+    connected: bool,
+    // gateway of the last connected tunnel
+    gateway: Option<String>,
+    privileges_dropped: bool,
 }
 
 async fn main_standalone(params: TunnelParams) -> anyhow::Result<()> {
-    // TODO: reuse code from CommandServer and ServiceController
+    if params.server_name.is_empty() {
+        anyhow::bail!("Missing required parameters: server name");
+    }
+
+    // once the privileges are dropped, a new tunnel cannot configure the DNS or the routes from scratch
+    if params.auto_reconnect && !params.run_as.is_empty() {
+        anyhow::bail!("The auto-reconnect option cannot be combined with run-as");
+    }
 
-    let (command_sender, command_receiver) = mpsc::channel(16);
+    let params = snxcore::server_info::resolve_login_type(&params).await?;
 
-    if params.server_name.is_empty() || params.login_type.is_empty() {
+    if params.login_type.is_empty() {
         anyhow::bail!("Missing required parameters: server name and/or login type");
     }
 
-    let mut mfa_prompts = if params.server_prompt {
-        server_info::get_mfa_prompts(&params).await.unwrap_or_default()
-    } else {
-        VecDeque::default()
-    };
+    if let Err(e) = platform::start_network_state_monitoring().await {
+        warn!("Unable to start network monitoring: {}", e);
+    }
 
-    let params = Arc::new(params);
-    let mut connector = tunnel::new_tunnel_connector(params.clone()).await?;
+    let mut params = Arc::new(params);
+    let mut state = StandaloneState::default();
+    let mut attempt = 0;
 
-    let mut session = if params.ike_persist {
-        debug!("Attempting to load IKE session");
-        match connector.restore_session().await {
-            Ok(session) => session,
-            Err(_) => {
-                connector = tunnel::new_tunnel_connector(params.clone()).await?;
-                connector.authenticate().await?
-            }
+    loop {
+        state.connected = false;
+
+        let result = match run_standalone_tunnel(params.clone(), &mut state).await {
+            Ok(Termination::Signal) => return Ok(()),
+            other => other.map(|_| ()),
+        };
+
+        if !params.auto_reconnect {
+            return result;
         }
-    } else {
-        connector.authenticate().await?
-    };
 
-    while let SessionState::PendingChallenge(challenge) = session.state.clone() {
-        match challenge.mfa_type {
-            MfaType::PasswordInput => {
-                let prompt = mfa_prompts.pop_front().unwrap_or_else(|| challenge.prompt.clone());
-                match TtyPrompt.get_secure_input(&prompt) {
-                    Ok(input) => {
-                        session = connector.challenge_code(session, &input).await?;
-                    }
-                    Err(e) => {
-                        return Err(e);
-                    }
-                }
-            }
-            MfaType::SamlSso => {
-                println!("For SAML authentication open the following URL in your browser:");
-                println!("{}", challenge.prompt);
-                let (tx, rx) = oneshot::channel();
-                tokio::spawn(run_otp_listener(tx));
-                let otp = tokio::time::timeout(OTP_TIMEOUT, rx).await??;
-                session = connector.challenge_code(session, &otp).await?;
-            }
-            MfaType::UserNameInput => {
-                let input = TtyPrompt.get_plain_input(&challenge.prompt)?;
-                session = connector.challenge_code(session, &input).await?;
-            }
+        match result {
+            Ok(()) => warn!("Tunnel closed"),
+            Err(ref e) => warn!("Tunnel failed: {}", e),
+        }
+
+        attempt = if state.connected { 1 } else { attempt + 1 };
+
+        // fail over to the next gateway of the server-name list
+        if let Some(gateway) = state.gateway.take().filter(|_| params.server_names().len() > 1) {
+            let mut next = (*params).clone();
+            next.rotate_servers(&gateway);
+            params = Arc::new(next);
+        }
+
+        if params.reconnect_max_attempts != 0 && attempt > params.reconnect_max_attempts {
+            warn!("Giving up after {} reconnect attempts", params.reconnect_max_attempts);
+            return result;
+        }
+
+        let delay = util::backoff_delay(attempt, params.reconnect_max_delay);
+        println!("Reconnecting in {} seconds, attempt {}", delay.as_secs(), attempt);
+
+        if wait_for_reconnect(delay).await? == Termination::Signal {
+            return Ok(());
         }
     }
+}
 
-    let tunnel = connector.create_tunnel(session, command_sender).await?;
+// waits for the backoff delay to expire and then for the network to be online again
+async fn wait_for_reconnect(delay: Duration) -> anyhow::Result<Termination> {
+    await_termination(async move {
+        tokio::time::sleep(delay).await;
+        platform::poll_online();
+        while !platform::is_online() {
+            debug!("Network is offline, postponing the reconnect");
+            tokio::time::sleep(ONLINE_POLL_INTERVAL).await;
+        }
+        Ok(())
+    })
+    .await
+}
 
-    if let Err(e) = platform::start_network_state_monitoring().await {
-        warn!("Unable to start network monitoring: {}", e);
+// SAML authentication in the standalone mode is done by the user
+struct ConsoleBrowser;
+
+impl BrowserController for ConsoleBrowser {
+    fn open(&self, url: &str) -> anyhow::Result<()> {
+        println!("For SAML authentication open the following URL in your browser:");
+        println!("{url}");
+        Ok(())
     }
 
-    println!(
-        "Connected to {} via {}, press Ctrl-C to exit.",
-        params.server_name, params.tunnel_type
-    );
+    fn close(&self) {}
+}
+
+async fn run_standalone_tunnel(params: Arc<TunnelParams>, state: &mut StandaloneState) -> anyhow::Result<Termination> {
+    let mut handle = runner::run_tunnel(params.clone(), &TtyPrompt, &ConsoleBrowser).await?;
 
-    let (event_sender, event_receiver) = mpsc::channel(16);
-    let tunnel_fut = await_termination(tunnel.run(command_receiver, event_sender));
+    let mut connection_info = None;
 
-    pin_mut!(tunnel_fut);
-    pin_mut!(event_receiver);
+    let signal = termination_signal();
+    pin_mut!(signal);
 
-    loop {
+    let result = loop {
         tokio::select! {
-            event = event_receiver.recv() => {
-                if let Some(event) = event {
-                    let _ = connector.handle_tunnel_event(event).await;
+            event = handle.next_event() => {
+                let Some(event) = event else {
+                    break handle.finish().await.map(|_| Termination::Completed);
+                };
+                if let TunnelEvent::Connected(ref info) = event {
+                    println!(
+                        "Connected to {} via {}, press Ctrl-C to exit.",
+                        info.server_name, info.transport
+                    );
+                    // the script runs before the privileges are dropped
+                    if let Some(ref script) = params.post_connect_script {
+                        util::run_connection_script(script, "connect", info).await;
+                    }
+                    connection_info = Some(info.clone());
+                    state.connected = true;
+                    state.gateway = Some(info.server_name.clone());
+                    if !params.run_as.is_empty() && !state.privileges_dropped {
+                        if let Err(e) = platform::drop_privileges(&params.run_as) {
+                            break Err(e);
+                        }
+                        state.privileges_dropped = true;
+                    }
                 }
             }
-            result = &mut tunnel_fut => {
-                break result;
+            result = &mut signal => {
+                if let Err(e) = result {
+                    break Err(e);
+                }
+                handle.shutdown(SHUTDOWN_TIMEOUT).await;
+                break Ok(Termination::Signal);
             }
         }
+    };
+
+    platform::flush_resolver_cleanup();
+
+    if let (Some(script), Some(info)) = (params.post_disconnect_script.as_ref(), connection_info) {
+        util::run_connection_script(script, "disconnect", &info).await;
     }
+
+    result
 }
@@ -1,11 +1,27 @@
+use std::sync::Arc;
+
 use anyhow::anyhow;
-use once_cell::sync::Lazy;
+use openssl::{
+    asn1::Asn1Time,
+    bn::BigNum,
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::PKey,
+    rsa::Rsa,
+    x509::{extension::SubjectAlternativeName, X509Builder, X509NameBuilder},
+};
 use regex::Regex;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpListener,
     sync::oneshot,
 };
+use tokio_native_tls::native_tls::{self, Identity};
+use tracing::debug;
+
+use crate::model::params::TunnelParams;
+
+const MAX_REQUEST_LINE: usize = 8192;
 
 pub trait BrowserController {
     fn open(&self, url: &str) -> anyhow::Result<()>;
@@ -22,30 +38,163 @@ impl BrowserController for SystemBrowser {
     fn close(&self) {}
 }
 
-pub async fn run_otp_listener(sender: oneshot::Sender<String>) -> anyhow::Result<()> {
-    static OTP_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^GET /(?<otp>[0-9a-f]{60}|[0-9A-F]{60}).*").unwrap());
+// The redirect path is normalized to always start and end with a slash,
+// the OTP value is expected to immediately follow it.
+fn redirect_prefix(path: &str) -> String {
+    let path = path.trim().trim_matches('/');
+    if path.is_empty() {
+        "/".to_owned()
+    } else {
+        format!("/{path}/")
+    }
+}
+
+fn otp_regex(path: &str) -> anyhow::Result<Regex> {
+    Ok(Regex::new(&format!(
+        r"^GET {}(?<otp>[0-9a-f]{{60}}|[0-9A-F]{{60}}).*",
+        regex::escape(&redirect_prefix(path))
+    ))?)
+}
+
+fn self_signed_identity() -> anyhow::Result<Identity> {
+    let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, "localhost")?;
+    let name = name.build();
 
-    let tcp = TcpListener::bind("127.0.0.1:7779").await?;
-    let (mut stream, _) = tcp.accept().await?;
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&BigNum::from_u32(1)?.to_asn1_integer()?)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(&Asn1Time::days_from_now(0)?)?;
+    builder.set_not_after(&Asn1Time::days_from_now(1)?)?;
 
-    let mut buf = [0u8; 65];
-    stream.read_exact(&mut buf).await?;
+    let san = SubjectAlternativeName::new()
+        .dns("localhost")
+        .ip("127.0.0.1")
+        .ip("::1")
+        .build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(san)?;
 
-    let mut data = String::from_utf8_lossy(&buf).into_owned();
+    builder.sign(&key, MessageDigest::sha256())?;
 
-    while stream.read(&mut buf[0..1]).await.is_ok() && buf[0] != b'\n' && buf[0] != b'\r' {
-        data.push(buf[0].into());
+    let cert = builder.build();
+
+    Ok(Identity::from_pkcs8(&cert.to_pem()?, &key.private_key_to_pem_pkcs8()?)?)
+}
+
+async fn read_request_line<S>(stream: &mut S) -> anyhow::Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut data = Vec::new();
+    let mut buf = [0u8; 1];
+
+    while data.len() < MAX_REQUEST_LINE && stream.read(&mut buf).await.is_ok_and(|size| size > 0) {
+        if buf[0] == b'\n' || buf[0] == b'\r' {
+            break;
+        }
+        data.push(buf[0]);
     }
 
     let _ = stream.shutdown().await;
-    drop(stream);
-    drop(tcp);
 
-    if let Some(captures) = OTP_RE.captures(&data) {
-        if let Some(otp) = captures.name("otp") {
-            let _ = sender.send(otp.as_str().to_owned());
-            return Ok(());
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
+
+/// Local listener which receives the OTP when the browser is redirected back after the SAML authentication
+pub struct OtpListener {
+    tcp: TcpListener,
+    params: Arc<TunnelParams>,
+}
+
+impl OtpListener {
+    /// Bind the listener to the configured address, with `otp-listen-port=0` the port is chosen by the system
+    pub async fn bind(params: Arc<TunnelParams>) -> anyhow::Result<Self> {
+        let tcp = TcpListener::bind((params.otp_listen_address, params.otp_listen_port)).await?;
+        Ok(Self { tcp, params })
+    }
+
+    /// Redirect URL served by the listener, including the actually bound port
+    pub fn callback_url(&self) -> anyhow::Result<String> {
+        Ok(format!(
+            "{}://{}{}",
+            if self.params.otp_https { "https" } else { "http" },
+            self.tcp.local_addr()?,
+            redirect_prefix(&self.params.otp_redirect_path)
+        ))
+    }
+
+    pub async fn run(self, sender: oneshot::Sender<String>) -> anyhow::Result<()> {
+        let otp_re = otp_regex(&self.params.otp_redirect_path)?;
+
+        debug!("Listening for OTP on {}", self.callback_url()?);
+
+        let (mut stream, _) = self.tcp.accept().await?;
+
+        let data = if self.params.otp_https {
+            let acceptor = tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(self_signed_identity()?)?);
+            let mut stream = acceptor.accept(stream).await?;
+            read_request_line(&mut stream).await?
+        } else {
+            read_request_line(&mut stream).await?
+        };
+
+        drop(self.tcp);
+
+        if let Some(captures) = otp_re.captures(&data) {
+            if let Some(otp) = captures.name("otp") {
+                let _ = sender.send(otp.as_str().to_owned());
+                return Ok(());
+            }
         }
+        Err(anyhow!("No OTP acquired!"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OTP: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789ab";
+
+    #[test]
+    fn test_redirect_prefix() {
+        assert_eq!(redirect_prefix(""), "/");
+        assert_eq!(redirect_prefix("/"), "/");
+        assert_eq!(redirect_prefix("saml"), "/saml/");
+        assert_eq!(redirect_prefix("/saml/callback/"), "/saml/callback/");
+    }
+
+    #[test]
+    fn test_otp_regex() {
+        let re = otp_regex("/").unwrap();
+        let captures = re.captures(&format!("GET /{OTP} HTTP/1.1")).unwrap();
+        assert_eq!(captures.name("otp").unwrap().as_str(), OTP);
+
+        let re = otp_regex("/saml").unwrap();
+        assert!(re.captures(&format!("GET /{OTP} HTTP/1.1")).is_none());
+        let captures = re.captures(&format!("GET /saml/{OTP} HTTP/1.1")).unwrap();
+        assert_eq!(captures.name("otp").unwrap().as_str(), OTP);
+    }
+
+    #[tokio::test]
+    async fn test_ephemeral_callback_url() {
+        let params = TunnelParams {
+            otp_listen_port: 0,
+            otp_redirect_path: "saml".to_owned(),
+            ..Default::default()
+        };
+        let listener = OtpListener::bind(Arc::new(params)).await.unwrap();
+        let port = listener.tcp.local_addr().unwrap().port();
+
+        assert_ne!(port, 0);
+        assert_eq!(
+            listener.callback_url().unwrap(),
+            format!("http://127.0.0.1:{port}/saml/")
+        );
     }
-    Err(anyhow!("No OTP acquired!"))
 }
@@ -1,40 +1,234 @@
 use std::{
+    path::Path,
     sync::{
         atomic::{AtomicU32, Ordering},
-        Arc,
+        Arc, Mutex,
     },
     time::Duration,
 };
 
-use anyhow::anyhow;
-use reqwest::{Certificate, Identity};
-use tracing::{trace, warn};
+use anyhow::{anyhow, Context};
+use reqwest::{
+    header::{COOKIE, SET_COOKIE},
+    Certificate, Identity, Proxy,
+};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio_native_tls::native_tls::{self, TlsConnector};
+use tracing::{debug, trace, warn};
 
 use crate::{
     model::{
         params::{CertType, TunnelParams},
         proto::*,
+        wrappers::SecretBytes,
         VpnSession,
     },
+    server_info,
     sexpr::SExpression,
+    tunnel, util,
 };
 
 static REQUEST_ID: AtomicU32 = AtomicU32::new(2);
+
 const REQUEST_TIMEOUT: Duration = Duration::from_secs(600);
 const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REPLY_SIZE: usize = 16 * 1024 * 1024;
+const REDACTED_FIELDS: &[&str] = &["password", "user_input", "active_key", "cookie", "username"];
 
 fn new_request_id() -> u32 {
     REQUEST_ID.fetch_add(1, Ordering::SeqCst)
 }
 
+fn redact(expr: &mut SExpression) {
+    match expr {
+        SExpression::Object(_, fields) => {
+            for (name, value) in fields.iter_mut() {
+                if REDACTED_FIELDS.contains(&name.as_str()) && value.is_value() {
+                    *value = SExpression::Value("****".to_owned());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        SExpression::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+fn find_cookie<'a, I>(set_cookies: I, name: &str) -> Option<String>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    set_cookies.into_iter().find_map(|header| {
+        let (pair, _) = header.split_once(';').unwrap_or((header, ""));
+        let (k, v) = pair.split_once('=')?;
+        (k.trim() == name).then(|| v.trim().to_owned())
+    })
+}
+
+struct HttpReply {
+    set_cookies: Vec<String>,
+    body: String,
+}
+
+// minimal HTTP/1.1 response reader for the pinned requests which are sent with "Connection: close"
+async fn read_http_reply<R>(reader: &mut R) -> anyhow::Result<HttpReply>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line).await?;
+
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .with_context(|| format!("Invalid HTTP status line: {}", status_line.trim_end()))?;
+
+    let mut set_cookies = Vec::new();
+    let mut content_length = None;
+    let mut chunked = false;
+
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            anyhow::bail!("Unexpected end of the HTTP response headers");
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "set-cookie" => set_cookies.push(value.to_owned()),
+                "content-length" => content_length = Some(value.parse::<usize>()?),
+                "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+                _ => {}
+            }
+        }
+    }
+
+    if !(200..300).contains(&status) {
+        anyhow::bail!("HTTP status {} from the server", status);
+    }
+
+    let mut body = Vec::new();
+
+    if chunked {
+        loop {
+            let mut size_line = String::new();
+            reader.read_line(&mut size_line).await?;
+            let size_hex = size_line.trim_end().split(';').next().unwrap_or_default();
+            let size = usize::from_str_radix(size_hex.trim(), 16)
+                .with_context(|| format!("Invalid chunk size: {}", size_line.trim_end()))?;
+
+            if size == 0 {
+                break;
+            }
+            anyhow::ensure!(body.len() + size <= MAX_REPLY_SIZE, "HTTP response is too large");
+
+            let offset = body.len();
+            body.resize(offset + size, 0);
+            reader.read_exact(&mut body[offset..]).await?;
+
+            let mut crlf = String::new();
+            reader.read_line(&mut crlf).await?;
+        }
+    } else if let Some(length) = content_length {
+        anyhow::ensure!(length <= MAX_REPLY_SIZE, "HTTP response is too large");
+        body.resize(length, 0);
+        reader.read_exact(&mut body).await?;
+    } else {
+        reader.take(MAX_REPLY_SIZE as u64 + 1).read_to_end(&mut body).await?;
+        anyhow::ensure!(body.len() <= MAX_REPLY_SIZE, "HTTP response is too large");
+    }
+
+    Ok(HttpReply {
+        set_cookies,
+        body: String::from_utf8(body)?,
+    })
+}
+
+fn dump_to_file(dir: &Path, id: u32, suffix: &str, data: &str) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir)?;
+
+    let filename = dir.join(format!(
+        "{}-{:04}-{}.txt",
+        chrono::Local::now().format("%Y%m%d-%H%M%S%.3f"),
+        id,
+        suffix
+    ));
+
+    std::fs::write(filename, data)?;
+
+    Ok(())
+}
+
+// session cookie of the gateway, its name is advertised in the connectivity info
+#[derive(Default)]
+struct SessionCookie {
+    name: Option<String>,
+    // "name=value" as sent back to the server
+    value: Option<String>,
+}
+
+impl SessionCookie {
+    fn from_session(session: Option<&VpnSession>) -> Self {
+        let value = session.and_then(|s| s.ccc_cookie.clone());
+        let name = value
+            .as_deref()
+            .and_then(|v| v.split_once('='))
+            .map(|(name, _)| name.to_owned());
+        Self { name, value }
+    }
+}
+
 pub struct CccHttpClient {
     params: Arc<TunnelParams>,
     session: Option<Arc<VpnSession>>,
+    cookie: Mutex<SessionCookie>,
 }
 
 impl CccHttpClient {
     pub fn new(params: Arc<TunnelParams>, session: Option<Arc<VpnSession>>) -> Self {
-        Self { params, session }
+        let cookie = Mutex::new(SessionCookie::from_session(session.as_deref()));
+        Self {
+            params,
+            session,
+            cookie,
+        }
+    }
+
+    /// Session cookie received from the gateway in the "name=value" form, to be kept in the `VpnSession`
+    pub fn session_cookie(&self) -> Option<String> {
+        self.cookie.lock().unwrap().value.clone()
+    }
+
+    fn remember_cookie_name(&self, name: &str) {
+        self.cookie.lock().unwrap().name = Some(name.to_owned());
+    }
+
+    fn store_session_cookie<'a, I>(&self, set_cookies: I)
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut cookie = self.cookie.lock().unwrap();
+
+        let Some(name) = cookie.name.clone() else {
+            trace!(
+                "No session cookie name known for {}, ignoring cookies",
+                self.params.server_name
+            );
+            return;
+        };
+
+        if let Some(value) = find_cookie(set_cookies, &name) {
+            debug!("Received session cookie {} from {}", name, self.params.server_name);
+            cookie.value = Some(format!("{name}={value}"));
+        }
     }
 
     fn session_id(&self) -> Option<String> {
@@ -64,8 +258,8 @@ impl CccHttpClient {
                 username,
                 password,
                 client_logging_data: Some(ClientLoggingData {
-                    os_name: Some("Windows".into()),
-                    device_id: Some(crate::util::get_device_id()),
+                    os_name: Some(self.params.client_os.clone()),
+                    device_id: Some(self.params.device_id.clone().unwrap_or_else(util::get_device_id)),
                     ..Default::default()
                 }),
                 selected_login_option: Some(self.params.login_type.clone()),
@@ -132,13 +326,92 @@ impl CccHttpClient {
         }
     }
 
+    fn dump(&self, id: u32, suffix: &str, expr: &SExpression) {
+        if let Some(ref dir) = self.params.dump_ccc {
+            let data = if self.params.dump_raw {
+                expr.to_string()
+            } else {
+                let mut expr = expr.clone();
+                redact(&mut expr);
+                expr.to_string()
+            };
+            if let Err(e) = dump_to_file(dir, id, suffix, &data) {
+                warn!("Cannot dump CCC {} to {}: {}", suffix, dir.display(), e);
+            }
+        }
+    }
+
+    // client certificate for the authentication request, only PKCS#8 and PKCS#12 files are sent with TLS
+    fn client_cert_data(&self) -> anyhow::Result<Option<SecretBytes>> {
+        match self.params.cert_type {
+            CertType::Pkcs12 if self.params.cert_pkcs12_b64.is_some() => self.params.inline_pkcs12(),
+            CertType::Pkcs8 | CertType::Pkcs12 => match self.params.cert_path {
+                Some(ref client_cert) => Ok(Some(SecretBytes(std::fs::read(client_cert)?))),
+                None => Ok(None),
+            },
+            _ => Ok(None),
+        }
+    }
+
     async fn send_request(&self, request: CccClientRequestData) -> anyhow::Result<SExpression> {
         let with_cert = matches!(request.data, RequestData::Auth(_));
+        let request_id = request.header.id;
         let expr = SExpression::from(CccClientRequest { data: request });
 
-        let mut builder = reqwest::Client::builder().connect_timeout(CONNECT_TIMEOUT);
+        let cert_data = if with_cert { self.client_cert_data()? } else { None };
 
-        for ca_cert in &self.params.ca_cert {
+        let path = if cert_data.is_some() {
+            "/clients/cert/"
+        } else {
+            "/clients/"
+        };
+
+        trace!("Request to server: {}", expr);
+
+        self.dump(request_id, "request", &expr);
+
+        let body = expr.to_string();
+
+        let response = if self.params.server_cert_fingerprint.is_some() {
+            tokio::time::timeout(REQUEST_TIMEOUT, self.send_pinned(path, body, cert_data)).await??
+        } else {
+            tokio::time::timeout(REQUEST_TIMEOUT, self.send_http(path, body, cert_data)).await??
+        };
+
+        self.store_session_cookie(response.set_cookies.iter().map(String::as_str));
+
+        let reply = response.body;
+
+        trace!("Reply from server: {}", reply);
+
+        let result = reply.parse::<SExpression>();
+
+        if let Ok(ref expr) = result {
+            self.dump(request_id, "response", expr);
+        } else if let Some(ref dir) = self.params.dump_ccc {
+            // unparseable replies cannot be redacted, store them only in raw mode
+            if self.params.dump_raw {
+                let _ = dump_to_file(dir, request_id, "response", &reply);
+            }
+        }
+
+        result
+    }
+
+    async fn send_http(&self, path: &str, body: String, cert_data: Option<SecretBytes>) -> anyhow::Result<HttpReply> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .local_address(self.params.bind_address);
+
+        if !self.params.user_agent.is_empty() {
+            builder = builder.user_agent(&self.params.user_agent);
+        }
+
+        if let Some(ref proxy_url) = self.params.proxy_url {
+            builder = builder.proxy(Proxy::all(proxy_url)?);
+        }
+
+        for ca_cert in self.params.ca_cert_files()? {
             let data = tokio::fs::read(ca_cert).await?;
             let cert = Certificate::from_pem(&data).or_else(|_| Certificate::from_der(&data))?;
             builder = builder.add_root_certificate(cert);
@@ -153,42 +426,103 @@ impl CccHttpClient {
             builder = builder.danger_accept_invalid_certs(true);
         }
 
-        let mut path = "/clients/";
-
-        if let (true, Some(client_cert)) = (with_cert, &self.params.cert_path) {
-            let data = std::fs::read(client_cert)?;
+        if let Some(data) = cert_data {
             let identity = match self.params.cert_type {
-                CertType::Pkcs8 => Some(Identity::from_pkcs8_pem(&data, &data)?),
-                CertType::Pkcs12 => Some(Identity::from_pkcs12_der(
-                    &data,
-                    self.params.cert_password.as_deref().unwrap_or_default(),
-                )?),
-                _ => None,
+                CertType::Pkcs8 => Identity::from_pkcs8_pem(&data, &data)?,
+                _ => Identity::from_pkcs12_der(&data, self.params.cert_password.as_deref().unwrap_or_default())?,
             };
-            if let Some(identity) = identity {
-                builder = builder.identity(identity);
-                path = "/clients/cert/";
-            }
+            builder = builder.identity(identity);
         }
 
         let client = builder.build()?;
 
-        trace!("Request to server: {}", expr);
-
-        let req = client
+        let mut req = client
             .post(format!("https://{}{}", self.params.server_name, path))
-            .body(expr.to_string())
-            .build()?;
+            .body(body);
 
-        let reply = tokio::time::timeout(REQUEST_TIMEOUT, client.execute(req))
-            .await??
-            .error_for_status()?
-            .text()
-            .await?;
+        if let Some(cookie) = self.session_cookie() {
+            req = req.header(COOKIE, cookie);
+        }
 
-        trace!("Reply from server: {}", reply);
+        let response = client.execute(req.build()?).await?.error_for_status()?;
+
+        let set_cookies = response
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .map(ToOwned::to_owned)
+            .collect();
 
-        reply.parse::<SExpression>()
+        Ok(HttpReply {
+            set_cookies,
+            body: response.text().await?,
+        })
+    }
+
+    // The request to a pinned server goes over a connection whose certificate fingerprint is verified
+    // right after the handshake, so nothing is sent to a server with a different certificate.
+    async fn send_pinned(&self, path: &str, body: String, cert_data: Option<SecretBytes>) -> anyhow::Result<HttpReply> {
+        let mut builder = TlsConnector::builder();
+
+        for ca_cert in self.params.ca_cert_files()? {
+            let data = tokio::fs::read(ca_cert).await?;
+            let cert =
+                native_tls::Certificate::from_pem(&data).or_else(|_| native_tls::Certificate::from_der(&data))?;
+            builder.add_root_certificate(cert);
+        }
+
+        if self.params.no_cert_check {
+            builder.danger_accept_invalid_hostnames(true);
+        }
+
+        if self.params.ignore_server_cert {
+            warn!("Disabling all certificate checks!!!");
+            builder.danger_accept_invalid_certs(true);
+        }
+
+        if let Some(data) = cert_data {
+            let identity = match self.params.cert_type {
+                CertType::Pkcs8 => native_tls::Identity::from_pkcs8(&data, &data)?,
+                _ => {
+                    native_tls::Identity::from_pkcs12(&data, self.params.cert_password.as_deref().unwrap_or_default())?
+                }
+            };
+            builder.identity(identity);
+        }
+
+        let tls: tokio_native_tls::TlsConnector = builder.build()?.into();
+
+        let tcp = tokio::time::timeout(CONNECT_TIMEOUT, tunnel::proxy::connect_gateway(&self.params, None)).await??;
+        let (host, _) = util::split_host_port(&self.params.server_name);
+        let mut stream = tokio::time::timeout(CONNECT_TIMEOUT, tls.connect(host, tcp)).await??;
+
+        let peer_cert = stream
+            .get_ref()
+            .peer_certificate()?
+            .map(|cert| cert.to_der())
+            .transpose()?;
+        util::check_server_fingerprint(&self.params, peer_cert.as_deref())?;
+
+        let mut head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nAccept: */*\r\nContent-Length: {}\r\nConnection: close\r\n",
+            path,
+            self.params.server_name,
+            body.len()
+        );
+        if !self.params.user_agent.is_empty() {
+            head.push_str(&format!("User-Agent: {}\r\n", self.params.user_agent));
+        }
+        if let Some(cookie) = self.session_cookie() {
+            head.push_str(&format!("Cookie: {}\r\n", cookie));
+        }
+        head.push_str("\r\n");
+
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body.as_bytes()).await?;
+        stream.flush().await?;
+
+        read_http_reply(&mut BufReader::new(stream)).await
     }
 
     async fn send_ccc_request(&self, req: CccClientRequestData) -> anyhow::Result<ResponseData> {
@@ -199,7 +533,25 @@ impl CccHttpClient {
             .into_data()
     }
 
+    // the session cookie is issued with the authentication response, so its name must be known beforehand.
+    // It is taken from the cached server info to avoid an extra ClientHello for every client.
+    async fn learn_cookie_name(&self) {
+        if self.cookie.lock().unwrap().name.is_some() {
+            return;
+        }
+
+        match server_info::get(&self.params).await {
+            Ok(info) if !info.connectivity_info.cookie_name.is_empty() => {
+                self.remember_cookie_name(&info.connectivity_info.cookie_name);
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Cannot retrieve server info: {}", e),
+        }
+    }
+
     pub async fn authenticate(&self) -> anyhow::Result<AuthResponse> {
+        self.learn_cookie_name().await;
+
         let req = self.new_auth_request();
 
         match self.send_ccc_request(req).await? {
@@ -227,14 +579,114 @@ impl CccHttpClient {
     }
 
     pub async fn get_server_info(&self) -> anyhow::Result<SExpression> {
-        self.send_request(self.new_client_hello_request()).await
+        let info = self.send_request(self.new_client_hello_request()).await?;
+
+        if let Some(name) = info.get_value::<String>("CCCserverResponse:ResponseData:connectivity_info:cookie_name") {
+            self.remember_cookie_name(&name);
+        }
+
+        Ok(info)
     }
 
     pub async fn signout(&self) -> anyhow::Result<()> {
         let req = self.new_signout_request();
 
-        self.send_ccc_request(req).await?;
+        let result = self.send_ccc_request(req).await;
+
+        self.cookie.lock().unwrap().value = None;
+
+        result.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let mut expr = "(CCCclientRequest :RequestData (:username (user1) :password (secret) :client_type (TRAC)))"
+            .parse::<SExpression>()
+            .unwrap();
+
+        redact(&mut expr);
+
+        assert_eq!(
+            expr.get_value::<String>("CCCclientRequest:RequestData:password")
+                .unwrap(),
+            "****"
+        );
+        assert_eq!(
+            expr.get_value::<String>("CCCclientRequest:RequestData:username")
+                .unwrap(),
+            "****"
+        );
+        assert_eq!(
+            expr.get_value::<String>("CCCclientRequest:RequestData:client_type")
+                .unwrap(),
+            "TRAC"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_http_reply() {
+        let mut data: &[u8] = b"HTTP/1.1 200 OK\r\nSet-Cookie: A=1; path=/\r\nset-cookie: B=2\r\n\
+            Content-Length: 5\r\n\r\nhello trailing";
+        let reply = read_http_reply(&mut data).await.unwrap();
+        assert_eq!(reply.set_cookies, ["A=1; path=/", "B=2"]);
+        assert_eq!(reply.body, "hello");
+
+        let mut data: &[u8] =
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        let reply = read_http_reply(&mut data).await.unwrap();
+        assert!(reply.set_cookies.is_empty());
+        assert_eq!(reply.body, "hello world");
+
+        let mut data: &[u8] = b"HTTP/1.1 200 OK\r\n\r\nuntil the end";
+        assert_eq!(read_http_reply(&mut data).await.unwrap().body, "until the end");
+
+        let mut data: &[u8] = b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n";
+        assert!(read_http_reply(&mut data).await.is_err());
+
+        let mut data: &[u8] = b"garbage\r\n\r\n";
+        assert!(read_http_reply(&mut data).await.is_err());
+    }
 
-        Ok(())
+    #[test]
+    fn test_session_cookie_name() {
+        let set_cookies = [
+            "CPCVPN_SESSION_ID=default-value; path=/; secure",
+            "CUSTOM_SESSION=custom-value; path=/; HttpOnly",
+        ];
+
+        assert_eq!(
+            find_cookie(set_cookies, "CPCVPN_SESSION_ID").as_deref(),
+            Some("default-value")
+        );
+        assert_eq!(find_cookie(set_cookies, "CUSTOM").as_deref(), None);
+
+        let client = CccHttpClient::new(Arc::new(TunnelParams::default()), None);
+        client.store_session_cookie(set_cookies);
+        assert!(client.session_cookie().is_none());
+
+        client.remember_cookie_name("CUSTOM_SESSION");
+        client.store_session_cookie(set_cookies);
+        assert_eq!(client.session_cookie().as_deref(), Some("CUSTOM_SESSION=custom-value"));
+
+        // cookies are owned by the client and the session, other clients do not see them
+        let other = CccHttpClient::new(Arc::new(TunnelParams::default()), None);
+        assert!(other.session_cookie().is_none());
+
+        let session = Arc::new(VpnSession {
+            ccc_cookie: client.session_cookie(),
+            ..VpnSession::empty()
+        });
+        let restored = CccHttpClient::new(Arc::new(TunnelParams::default()), Some(session));
+        assert_eq!(
+            restored.session_cookie().as_deref(),
+            Some("CUSTOM_SESSION=custom-value")
+        );
+        restored.store_session_cookie(["CUSTOM_SESSION=renewed; path=/"]);
+        assert_eq!(restored.session_cookie().as_deref(), Some("CUSTOM_SESSION=renewed"));
     }
 }
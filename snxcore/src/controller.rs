@@ -1,30 +1,41 @@
-use std::{collections::VecDeque, str::FromStr, sync::Arc, time::Duration};
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use tokio::sync::oneshot;
-use tracing::warn;
+use tracing::{debug, warn};
 
 use crate::{
-    browser::{run_otp_listener, BrowserController},
+    browser::{BrowserController, OtpListener},
     ccc::CccHttpClient,
     model::{
         params::TunnelParams, ConnectionStatus, MfaChallenge, MfaType, TunnelServiceRequest, TunnelServiceResponse,
     },
     platform::{self, UdpSocketExt},
-    prompt::{SecurePrompt, OTP_TIMEOUT},
+    prompt::{localize_prompt, SecurePrompt, OTP_TIMEOUT},
     server_info,
 };
 
 const RECV_TIMEOUT: Duration = Duration::from_secs(2);
 const CONNECT_TIMEOUT: Duration = Duration::from_secs(120);
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ServiceCommand {
     Status,
     Connect,
     Disconnect,
     Reconnect,
+    Reprobe,
+    Reauth,
+    Cancel,
     Info,
+    GetActiveProfile,
+    SwitchProfile(PathBuf),
 }
 
 impl FromStr for ServiceCommand {
@@ -36,7 +47,11 @@ impl FromStr for ServiceCommand {
             "connect" => Ok(Self::Connect),
             "disconnect" => Ok(Self::Disconnect),
             "reconnect" => Ok(Self::Reconnect),
+            "reprobe" => Ok(Self::Reprobe),
+            "reauth" => Ok(Self::Reauth),
+            "cancel" => Ok(Self::Cancel),
             "info" => Ok(Self::Info),
+            "active-profile" => Ok(Self::GetActiveProfile),
             other => Err(anyhow!("Invalid command: {}", other)),
         }
     }
@@ -67,6 +82,14 @@ where
         })
     }
 
+    // the service may wait for the gateway up to the identity protection or the connect timeout
+    fn connect_timeout(&self) -> Duration {
+        [self.params.identity_timeout, self.params.connect_timeout]
+            .into_iter()
+            .flatten()
+            .fold(CONNECT_TIMEOUT, |result, timeout| result.max(timeout + RECV_TIMEOUT))
+    }
+
     pub async fn command(&mut self, command: ServiceCommand) -> anyhow::Result<ConnectionStatus> {
         match command {
             ServiceCommand::Status => self.do_status().await,
@@ -82,7 +105,17 @@ where
                 let _ = self.do_disconnect().await;
                 self.do_connect().await
             }
+            ServiceCommand::Reprobe => {
+                let _ = self.do_disconnect().await;
+                let request = TunnelServiceRequest::ClearTransportCache((*self.params).clone());
+                self.send_receive(request, RECV_TIMEOUT).await?;
+                self.do_connect().await
+            }
+            ServiceCommand::Reauth => self.do_reauth().await,
+            ServiceCommand::Cancel => self.do_cancel().await,
             ServiceCommand::Info => self.do_info().await,
+            ServiceCommand::GetActiveProfile => self.do_status().await,
+            ServiceCommand::SwitchProfile(config_file) => self.do_switch_profile(&config_file).await,
         }
     }
 
@@ -114,8 +147,7 @@ where
                     Ok(status)
                 }
             }
-            TunnelServiceResponse::Error(e) => Err(anyhow!(e)),
-            TunnelServiceResponse::Ok => Err(anyhow!("Unexpected response")),
+            other => Err(other.into_error().unwrap_or_else(|| anyhow!("Unexpected response"))),
         }
     }
 
@@ -127,6 +159,7 @@ where
                     .as_mut()
                     .and_then(|p| p.pop_front())
                     .unwrap_or_else(|| mfa.prompt.clone());
+                let prompt = localize_prompt(&prompt, &self.prompt);
                 if !self.password.is_empty() && self.first_password {
                     self.first_password = false;
                     Ok(self.password.clone())
@@ -140,8 +173,11 @@ where
                 }
             }
             MfaType::SamlSso => {
+                let listener = OtpListener::bind(self.params.clone()).await?;
+                debug!("Waiting for the SAML redirect to {}", listener.callback_url()?);
+
                 let (tx, rx) = oneshot::channel();
-                tokio::spawn(run_otp_listener(tx));
+                tokio::spawn(listener.run(tx));
 
                 self.browser_controller.open(&mfa.prompt)?;
 
@@ -157,13 +193,19 @@ where
                 }
             }
             MfaType::UserNameInput => {
-                let input = self.prompt.get_plain_input(&mfa.prompt)?;
+                let input = self
+                    .prompt
+                    .get_plain_input(&localize_prompt(&mfa.prompt, &self.prompt))?;
                 Ok(input)
             }
         }
     }
 
     async fn do_connect(&mut self) -> anyhow::Result<ConnectionStatus> {
+        if !self.params.server_name.is_empty() && self.params.login_type.is_empty() {
+            self.params = Arc::new(server_info::resolve_login_type(&self.params).await?);
+        }
+
         self.fill_mfa_prompts().await;
 
         let params = self.params.clone();
@@ -172,6 +214,8 @@ where
             anyhow::bail!("Missing required parameters in the config file: server name and/or login type");
         }
 
+        params.check_password_env()?;
+
         if !params.user_name.is_empty() && !params.no_keychain && params.password.is_empty() {
             if let Ok(password) = platform::acquire_password(&self.params.user_name).await {
                 self.password = password;
@@ -179,12 +223,14 @@ where
         }
 
         let response = self
-            .send_receive(TunnelServiceRequest::Connect((*self.params).clone()), CONNECT_TIMEOUT)
+            .send_receive(
+                TunnelServiceRequest::Connect((*self.params).clone()),
+                self.connect_timeout(),
+            )
             .await;
         match response {
             Ok(TunnelServiceResponse::Ok) => self.do_status().await,
-            Ok(TunnelServiceResponse::Error(error)) => Err(anyhow!(error)),
-            Ok(_) => Err(anyhow!("Invalid response!")),
+            Ok(other) => Err(other.into_error().unwrap_or_else(|| anyhow!("Invalid response!"))),
             Err(e) => Err(e),
         }
     }
@@ -193,17 +239,19 @@ where
         let response = self
             .send_receive(
                 TunnelServiceRequest::ChallengeCode(code, (*self.params).clone()),
-                CONNECT_TIMEOUT,
+                self.connect_timeout(),
             )
             .await;
         match response {
             Ok(TunnelServiceResponse::Ok) => self.do_status().await,
-            Ok(TunnelServiceResponse::Error(e)) => {
-                self.send_receive(TunnelServiceRequest::Disconnect, RECV_TIMEOUT)
-                    .await?;
-                Err(anyhow!(e))
-            }
-            Ok(_) => Err(anyhow!("Invalid response!")),
+            Ok(other) => match other.into_error() {
+                Some(e) => {
+                    self.send_receive(TunnelServiceRequest::Disconnect, RECV_TIMEOUT)
+                        .await?;
+                    Err(e)
+                }
+                None => Err(anyhow!("Invalid response!")),
+            },
             Err(e) => Err(e),
         }
     }
@@ -214,6 +262,51 @@ where
         self.do_status().await
     }
 
+    async fn do_reauth(&mut self) -> anyhow::Result<ConnectionStatus> {
+        match self
+            .send_receive(TunnelServiceRequest::Reauth, CONNECT_TIMEOUT)
+            .await?
+            .into_error()
+        {
+            Some(e) => Err(e),
+            None => self.do_status().await,
+        }
+    }
+
+    async fn do_cancel(&mut self) -> anyhow::Result<ConnectionStatus> {
+        match self
+            .send_receive(TunnelServiceRequest::Cancel, RECV_TIMEOUT)
+            .await?
+            .into_error()
+        {
+            Some(e) => Err(e),
+            None => self.do_status().await,
+        }
+    }
+
+    async fn do_switch_profile(&mut self, config_file: &Path) -> anyhow::Result<ConnectionStatus> {
+        let config_file = config_file
+            .canonicalize()
+            .with_context(|| format!("Profile not found: {}", config_file.display()))?;
+        let params = TunnelParams::load(&config_file)?;
+
+        let status = self.do_status().await?;
+        let active = status.profile.as_ref().and_then(|profile| profile.canonicalize().ok());
+
+        if status.connected_since.is_some() && active.as_ref() == Some(&config_file) {
+            return Ok(status);
+        }
+
+        let _ = self.do_disconnect().await;
+
+        self.params = Arc::new(params);
+        self.mfa_prompts = None;
+        self.password.clear();
+        self.first_password = true;
+
+        self.do_connect().await
+    }
+
     async fn send_receive(
         &self,
         request: TunnelServiceRequest,
@@ -242,7 +335,7 @@ where
         let client = CccHttpClient::new(self.params.clone(), None);
         let info = client.get_server_info().await?;
 
-        crate::util::print_login_options(&info);
+        crate::util::print_login_options(&info, self.params.allow_hidden_realms);
 
         Ok(ConnectionStatus::default())
     }
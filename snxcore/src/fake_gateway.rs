@@ -0,0 +1,450 @@
+//! Fake Check Point gateway for the tests.
+//! It accepts CCC requests over HTTPS and replies with the canned responses from the fixture directory,
+//! the response file is selected by the request type, e.g. `ClientHello.txt` or `UserPass.txt`.
+//! A numbered file such as `MultiChallange.2.txt` takes precedence for the given occurrence of the request type.
+//! Scenarios are the subdirectories of the fixture directory which override some of its responses.
+//! IKE and the SSL tunnel protocol are not emulated.
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    task::JoinHandle,
+};
+use tokio_native_tls::native_tls::{Identity, TlsAcceptor};
+use tracing::warn;
+
+use crate::{model::params::TunnelParams, sexpr::SExpression};
+
+pub const FIXTURES_DIR: &str = "tests/gateway";
+
+const MAX_REQUEST_SIZE: usize = 1_000_000;
+
+pub struct FakeGateway {
+    address: SocketAddr,
+    requests: Arc<Mutex<Vec<SExpression>>>,
+    handle: JoinHandle<()>,
+}
+
+impl FakeGateway {
+    /// Start the gateway on a random local port with the fixtures from the given directory
+    pub async fn start<P: AsRef<Path>>(fixtures: P) -> anyhow::Result<Self> {
+        Self::start_with(vec![fixtures.as_ref().to_owned()]).await
+    }
+
+    /// Start the gateway with the responses of the scenario, falling back to the default fixtures
+    pub async fn start_scenario(scenario: &str) -> anyhow::Result<Self> {
+        let fixtures = Path::new(FIXTURES_DIR);
+        anyhow::ensure!(fixtures.join(scenario).is_dir(), "No such scenario: {}", scenario);
+        Self::start_with(vec![fixtures.join(scenario), fixtures.to_owned()]).await
+    }
+
+    // the directories are searched in order, the certificate is taken from the last one
+    async fn start_with(fixtures: Vec<PathBuf>) -> anyhow::Result<Self> {
+        let base = fixtures.last().context("No fixture directory")?;
+
+        let cert = std::fs::read(base.join("cert.pem"))?;
+        let key = std::fs::read(base.join("key.pem"))?;
+        let acceptor: tokio_native_tls::TlsAcceptor = TlsAcceptor::new(Identity::from_pkcs8(&cert, &key)?)?.into();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests2 = requests.clone();
+
+        let handle = tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                let fixtures = fixtures.clone();
+                let requests = requests2.clone();
+
+                tokio::spawn(async move {
+                    let result = match acceptor.accept(tcp).await {
+                        Ok(stream) => serve(stream, &fixtures, &requests).await,
+                        Err(e) => Err(e.into()),
+                    };
+                    if let Err(e) = result {
+                        warn!("Fake gateway error: {}", e);
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            address,
+            requests,
+            handle,
+        })
+    }
+
+    /// Server name to connect to, in the form of `127.0.0.1:port`
+    pub fn server_name(&self) -> String {
+        self.address.to_string()
+    }
+
+    /// Tunnel parameters pointing to this gateway, the self-signed certificate is accepted
+    pub fn params(&self) -> TunnelParams {
+        TunnelParams {
+            server_name: self.server_name(),
+            user_name: "testuser".to_owned(),
+            password: "testpassword".to_owned(),
+            login_type: "vpn_Username_Password".to_owned(),
+            ignore_server_cert: true,
+            no_cert_check: true,
+            ..Default::default()
+        }
+    }
+
+    /// All requests received so far, in order
+    pub fn requests(&self) -> Vec<SExpression> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Types of the requests received so far, in order
+    pub fn request_types(&self) -> Vec<String> {
+        self.requests()
+            .iter()
+            .filter_map(|r| r.get_value::<String>("CCCclientRequest:RequestHeader:type"))
+            .collect()
+    }
+}
+
+impl Drop for FakeGateway {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+async fn read_request<S>(stream: &mut S) -> anyhow::Result<String>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+
+    let header_end = loop {
+        let size = stream.read(&mut buf).await?;
+        anyhow::ensure!(size > 0, "Connection closed");
+        data.extend_from_slice(&buf[..size]);
+
+        if let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        anyhow::ensure!(data.len() < MAX_REQUEST_SIZE, "Request is too large");
+    };
+
+    let headers = String::from_utf8_lossy(&data[..header_end]).into_owned();
+    let content_length = headers
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or_default();
+    anyhow::ensure!(content_length < MAX_REQUEST_SIZE, "Request is too large");
+
+    while data.len() < header_end + content_length {
+        let size = stream.read(&mut buf).await?;
+        anyhow::ensure!(size > 0, "Connection closed");
+        data.extend_from_slice(&buf[..size]);
+    }
+
+    Ok(String::from_utf8_lossy(&data[header_end..header_end + content_length]).into_owned())
+}
+
+fn find_fixture(fixtures: &[PathBuf], request_type: &str, occurrence: usize) -> Option<PathBuf> {
+    fixtures.iter().find_map(|dir| {
+        [
+            dir.join(format!("{request_type}.{occurrence}.txt")),
+            dir.join(format!("{request_type}.txt")),
+        ]
+        .into_iter()
+        .find(|path| path.is_file())
+    })
+}
+
+async fn serve<S>(mut stream: S, fixtures: &[PathBuf], requests: &Mutex<Vec<SExpression>>) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let request = read_request(&mut stream).await?.parse::<SExpression>()?;
+
+    let request_type = request
+        .get_value::<String>("CCCclientRequest:RequestHeader:type")
+        .context("No request type")?;
+
+    let occurrence = {
+        let mut requests = requests.lock().unwrap();
+        requests.push(request);
+        requests
+            .iter()
+            .filter(|r| {
+                r.get_value::<String>("CCCclientRequest:RequestHeader:type").as_deref() == Some(request_type.as_str())
+            })
+            .count()
+    };
+
+    let body = find_fixture(fixtures, &request_type, occurrence).map(std::fs::read_to_string);
+
+    let response = match body {
+        Some(Ok(body)) => format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        ),
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned(),
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ccc::CccHttpClient,
+        model::{params::TunnelType, ConnectionError, SessionState, VpnSession},
+        server_info, tunnel, util,
+    };
+
+    fn fixture_fingerprint() -> String {
+        let pem = std::fs::read(Path::new(FIXTURES_DIR).join("cert.pem")).unwrap();
+        util::cert_fingerprint(&openssl::x509::X509::from_pem(&pem).unwrap().to_der().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_server_info() {
+        let gateway = FakeGateway::start(FIXTURES_DIR).await.unwrap();
+        let params = gateway.params();
+
+        let info = server_info::get(&params).await.unwrap();
+        assert_eq!(info.connectivity_info.tcpt_port, 443);
+        assert_eq!(info.connectivity_info.natt_port, 4500);
+        assert_eq!(info.connectivity_info.cookie_name, "FAKE_GW_SESSION");
+
+        let options = info.login_options_data.unwrap().login_options_list;
+        assert!(options.contains_key("vpn_Username_Password"));
+
+        let factors = server_info::get_login_factors(&params).await.unwrap();
+        assert_eq!(factors.len(), 1);
+        assert_eq!(factors[0].factor_type, "password");
+
+        // the login factors are resolved from the cached reply
+        assert_eq!(gateway.request_types(), ["ClientHello"]);
+
+        server_info::invalidate(&params.server_name);
+        server_info::get(&params).await.unwrap();
+        assert_eq!(gateway.request_types(), ["ClientHello", "ClientHello"]);
+
+        // the reply fetched without the certificate checks is not reused for a pinned certificate
+        let pinned = TunnelParams {
+            server_cert_fingerprint: Some(fixture_fingerprint()),
+            ..params
+        };
+        server_info::get(&pinned).await.unwrap();
+        assert_eq!(gateway.request_types(), ["ClientHello", "ClientHello", "ClientHello"]);
+    }
+
+    #[tokio::test]
+    async fn test_client_decide_login_type() {
+        let gateway = FakeGateway::start(FIXTURES_DIR).await.unwrap();
+        let params = TunnelParams {
+            login_type: String::new(),
+            ..gateway.params()
+        };
+
+        let params = server_info::resolve_login_type(&params).await.unwrap();
+        assert_eq!(params.login_type, "vpn_Username_Password");
+    }
+
+    #[tokio::test]
+    async fn test_auth_challenge() {
+        let gateway = FakeGateway::start(FIXTURES_DIR).await.unwrap();
+        let params = Arc::new(gateway.params());
+
+        let client = CccHttpClient::new(params.clone(), None);
+        let reply = client.authenticate().await.unwrap();
+        assert_eq!(reply.authn_status, "continue");
+        assert_eq!(reply.prompt.unwrap().0, "Enter the code:");
+
+        let session = Arc::new(VpnSession {
+            ccc_session_id: reply.session_id.unwrap(),
+            ..VpnSession::empty()
+        });
+        let client = CccHttpClient::new(params.clone(), Some(session));
+        let reply = client.challenge_code("123456").await.unwrap();
+        assert_eq!(reply.authn_status, "done");
+        assert_eq!(reply.is_authenticated, Some(true));
+        assert_eq!(reply.session_id.as_deref(), Some("fake_ccc_session"));
+
+        let settings = client.get_client_settings().await.unwrap();
+        assert_eq!(
+            settings.gw_internal_ip,
+            "10.0.0.1".parse::<std::net::Ipv4Addr>().unwrap()
+        );
+        assert_eq!(
+            util::ranges_to_subnets(&settings.updated_policies.range.settings).count(),
+            2
+        );
+
+        assert_eq!(
+            gateway.request_types(),
+            ["ClientHello", "UserPass", "MultiChallange", "ClientSettings"]
+        );
+
+        let challenge = &gateway.requests()[2];
+        let input = challenge
+            .get_value::<String>("CCCclientRequest:RequestData:user_input")
+            .unwrap();
+        assert_eq!(util::snx_decrypt(input).unwrap(), b"123456");
+        assert_eq!(
+            challenge
+                .get_value::<String>("CCCclientRequest:RequestData:auth_session_id")
+                .as_deref(),
+            Some("fake_auth_session")
+        );
+
+        // the cookie name of a new client comes from the cached server info
+        let client = CccHttpClient::new(params, None);
+        client.authenticate().await.unwrap();
+        assert_eq!(
+            gateway.request_types(),
+            [
+                "ClientHello",
+                "UserPass",
+                "MultiChallange",
+                "ClientSettings",
+                "UserPass"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_multiple_challenges() {
+        let gateway = FakeGateway::start_scenario("mfa").await.unwrap();
+        let params = TunnelParams {
+            tunnel_type: TunnelType::Ssl,
+            ..gateway.params()
+        };
+        let mut connector = tunnel::new_tunnel_connector(Arc::new(params)).await.unwrap();
+
+        let session = connector.authenticate().await.unwrap();
+        assert!(matches!(session.state, SessionState::PendingChallenge(ref c) if c.prompt == "Enter the code:"));
+
+        let session = connector.challenge_code(session, "password").await.unwrap();
+        assert!(matches!(session.state, SessionState::PendingChallenge(ref c) if c.prompt == "Enter the SMS code:"));
+
+        let session = connector.challenge_code(session, "123456").await.unwrap();
+        assert!(matches!(session.state, SessionState::Authenticated(_)));
+        assert_eq!(session.ccc_session_id, "fake_ccc_session");
+
+        let inputs = gateway
+            .requests()
+            .iter()
+            .filter_map(|r| r.get_value::<String>("CCCclientRequest:RequestData:user_input"))
+            .map(|input| util::snx_decrypt(input).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(inputs, [b"password".to_vec(), b"123456".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_authentication() {
+        let gateway = FakeGateway::start_scenario("rejected").await.unwrap();
+        let params = TunnelParams {
+            tunnel_type: TunnelType::Ssl,
+            ..gateway.params()
+        };
+        let mut connector = tunnel::new_tunnel_connector(Arc::new(params)).await.unwrap();
+
+        let error = connector.authenticate().await.unwrap_err();
+        let Some(ConnectionError::ServerRejected { code, id, message }) = ConnectionError::find(&error) else {
+            panic!("Unexpected error: {error:#}");
+        };
+        assert_eq!(*code, 101);
+        assert_eq!(id, "0x1a2b3c4d");
+        assert_eq!(message, "Access denied - wrong user name or password");
+        assert_eq!(
+            error.to_string(),
+            "[101 0x1a2b3c4d] Access denied - wrong user name or password"
+        );
+        assert_eq!(gateway.request_types(), ["ClientHello", "UserPass"]);
+    }
+
+    #[tokio::test]
+    async fn test_multiple_realms() {
+        let gateway = FakeGateway::start_scenario("realms").await.unwrap();
+        let params = TunnelParams {
+            login_type: String::new(),
+            ..gateway.params()
+        };
+
+        // the hidden certificate realm is skipped, the multi-factor one is preferred
+        let params = server_info::resolve_login_type(&params).await.unwrap();
+        assert_eq!(params.login_type, "vpn_Microsoft_Authenticator");
+
+        let factors = server_info::get_login_factors(&params).await.unwrap();
+        assert_eq!(
+            factors.iter().map(|f| f.factor_type.as_str()).collect::<Vec<_>>(),
+            ["password", "challenge_response"]
+        );
+
+        let prompts = server_info::get_mfa_prompts(&params).await.unwrap();
+        assert_eq!(prompts, ["Password: ", "Authenticator code: "]);
+
+        let params = TunnelParams {
+            login_type: "vpn_Certificate".to_owned(),
+            ..params
+        };
+        let factors = server_info::get_login_factors(&params).await.unwrap();
+        assert_eq!(factors.len(), 1);
+        assert_eq!(factors[0].factor_type, "certificate");
+        assert!(server_info::get_mfa_prompts(&params).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_scenario() {
+        assert!(FakeGateway::start_scenario("nonexistent").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_request() {
+        let gateway = FakeGateway::start(FIXTURES_DIR).await.unwrap();
+        let client = CccHttpClient::new(Arc::new(gateway.params()), None);
+
+        assert!(client.signout().await.is_err());
+        assert_eq!(gateway.request_types(), ["Signout"]);
+    }
+
+    #[tokio::test]
+    async fn test_pinned_certificate() {
+        let gateway = FakeGateway::start(FIXTURES_DIR).await.unwrap();
+
+        let params = TunnelParams {
+            server_cert_fingerprint: Some(fixture_fingerprint()),
+            ..gateway.params()
+        };
+        let client = CccHttpClient::new(Arc::new(params), None);
+        let reply = client.authenticate().await.unwrap();
+        assert_eq!(reply.authn_status, "continue");
+
+        // nothing is sent to a server with another certificate
+        let gateway = FakeGateway::start(FIXTURES_DIR).await.unwrap();
+        let params = TunnelParams {
+            server_cert_fingerprint: Some("00".repeat(32)),
+            ..gateway.params()
+        };
+        let client = CccHttpClient::new(Arc::new(params), None);
+        let error = client.authenticate().await.unwrap_err();
+        assert!(format!("{error:#}").contains("fingerprint mismatch"));
+        assert!(gateway.requests().is_empty());
+    }
+}
@@ -3,11 +3,16 @@
 pub mod browser;
 pub mod ccc;
 pub mod controller;
+#[cfg(test)]
+pub(crate) mod fake_gateway;
+pub mod logging;
 pub mod model;
 pub mod platform;
 pub mod prompt;
+pub mod runner;
 pub mod server;
 pub mod server_info;
 pub mod sexpr;
+pub mod totp;
 pub mod tunnel;
 pub mod util;
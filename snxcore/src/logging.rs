@@ -0,0 +1,195 @@
+use std::{
+    collections::VecDeque,
+    fmt::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Local};
+use tracing::{
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    Event, Level, Subscriber,
+};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{layer, prelude::*, Layer, Registry};
+
+use crate::model::params::TunnelParams;
+
+const LOG_BUFFER_SIZE: usize = 2000;
+
+fn file_appender(path: &Path, count: usize) -> anyhow::Result<RollingFileAppender> {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let name = path.file_name().context("Invalid log file name")?;
+
+    std::fs::create_dir_all(dir)?;
+
+    // the date of the rotation is appended to the file name
+    Ok(RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(name.to_string_lossy().into_owned())
+        .max_log_files(count.max(1))
+        .build(dir)?)
+}
+
+/// Log record kept in a [`LogBuffer`]
+#[derive(Debug, Clone)]
+pub struct LogRecord {
+    pub time: DateTime<Local>,
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+}
+
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:>5} {}: {}",
+            self.time.format("%H:%M:%S%.3f"),
+            self.level,
+            self.target,
+            self.message
+        )
+    }
+}
+
+#[derive(Default)]
+struct LogBufferInner {
+    records: VecDeque<LogRecord>,
+    next_seq: u64,
+}
+
+/// Ring buffer with the most recent log records, e.g. for a log viewer
+#[derive(Clone, Default)]
+pub struct LogBuffer(Arc<Mutex<LogBufferInner>>);
+
+impl LogBuffer {
+    fn push(&self, record: LogRecord) {
+        let mut inner = self.0.lock().unwrap();
+        if inner.records.len() >= LOG_BUFFER_SIZE {
+            inner.records.pop_front();
+        }
+        inner.records.push_back(record);
+        inner.next_seq += 1;
+    }
+
+    /// Records added since the given sequence number, together with the sequence number for the next call.
+    /// Records which were already dropped from the buffer are skipped.
+    pub fn records_since(&self, seq: u64) -> (Vec<LogRecord>, u64) {
+        let inner = self.0.lock().unwrap();
+        let first_seq = inner.next_seq - inner.records.len() as u64;
+        let skip = seq.saturating_sub(first_seq) as usize;
+
+        (inner.records.iter().skip(skip).cloned().collect(), inner.next_seq)
+    }
+}
+
+#[derive(Default)]
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, "{value:?}");
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+struct BufferLayer(LogBuffer);
+
+impl<S: Subscriber> Layer<S> for BufferLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: layer::Context<'_, S>) {
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+
+        self.0.push(LogRecord {
+            time: Local::now(),
+            level: *event.metadata().level(),
+            target: event.metadata().target().to_owned(),
+            message: visitor.0,
+        });
+    }
+}
+
+/// Install the global tracing subscriber, logging goes to the rotated log file if one is configured
+/// and to stdout otherwise.
+pub fn init_logging(params: &TunnelParams) -> anyhow::Result<()> {
+    init(params, None)
+}
+
+/// Same as [`init_logging`], additionally the records up to the debug level are kept in the buffer
+/// regardless of the configured log level.
+pub fn init_logging_with_buffer(params: &TunnelParams, buffer: LogBuffer) -> anyhow::Result<()> {
+    init(params, Some(buffer))
+}
+
+fn init(params: &TunnelParams, buffer: Option<LogBuffer>) -> anyhow::Result<()> {
+    let level = params.log_level.parse::<LevelFilter>().unwrap_or(LevelFilter::OFF);
+
+    let output: Box<dyn Layer<Registry> + Send + Sync> = match params.log_file {
+        Some(ref log_file) if level != LevelFilter::OFF => {
+            let appender = file_appender(log_file, params.log_file_count)
+                .with_context(|| format!("Cannot open log file {}", log_file.display()))?;
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(appender)
+                .boxed()
+        }
+        _ => tracing_subscriber::fmt::layer().boxed(),
+    };
+
+    let subscriber = Registry::default()
+        .with(output.with_filter(level))
+        .with(buffer.map(|buffer| BufferLayer(buffer).with_filter(LevelFilter::DEBUG)));
+
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(message: &str) -> LogRecord {
+        LogRecord {
+            time: Local::now(),
+            level: Level::INFO,
+            target: "snxcore".to_owned(),
+            message: message.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_log_buffer() {
+        let buffer = LogBuffer::default();
+        buffer.push(record("first"));
+        buffer.push(record("second"));
+
+        let (records, seq) = buffer.records_since(0);
+        assert_eq!(records.len(), 2);
+        assert_eq!(seq, 2);
+
+        buffer.push(record("third"));
+        let (records, seq) = buffer.records_since(seq);
+        assert_eq!(
+            records.iter().map(|r| r.message.as_str()).collect::<Vec<_>>(),
+            ["third"]
+        );
+        assert_eq!(seq, 3);
+
+        for i in 0..LOG_BUFFER_SIZE {
+            buffer.push(record(&i.to_string()));
+        }
+        let (records, _) = buffer.records_since(0);
+        assert_eq!(records.len(), LOG_BUFFER_SIZE);
+        assert_eq!(records[0].message, "0");
+    }
+}
@@ -1,7 +1,13 @@
 use std::sync::Arc;
-use std::{net::Ipv4Addr, time::Duration};
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+    time::Duration,
+};
 
 use chrono::{DateTime, Local};
+use ipnet::{Ipv4Net, Ipv6Net};
 use isakmp::model::EspCryptMaterial;
 use serde::{Deserialize, Serialize};
 
@@ -9,6 +15,7 @@ use crate::model::params::TunnelParams;
 
 pub mod params;
 pub mod proto;
+pub mod uri;
 pub mod wrappers;
 
 #[derive(Debug, Default, Clone, PartialEq)]
@@ -24,7 +31,9 @@ pub struct IpsecSession {
     pub lifetime: Duration,
     pub address: Ipv4Addr,
     pub netmask: Ipv4Addr,
-    pub dns: Vec<Ipv4Addr>,
+    pub address_v6: Option<Ipv6Net>,
+    pub dns: Vec<IpAddr>,
+    pub wins: Vec<Ipv4Addr>,
     pub domains: Vec<String>,
     pub esp_in: Arc<EspCryptMaterial>,
     pub esp_out: Arc<EspCryptMaterial>,
@@ -36,7 +45,9 @@ impl Default for IpsecSession {
             lifetime: Duration::default(),
             address: Ipv4Addr::new(0, 0, 0, 0),
             netmask: Ipv4Addr::new(0, 0, 0, 0),
+            address_v6: None,
             dns: Vec::new(),
+            wins: Vec::new(),
             domains: Vec::new(),
             esp_in: Arc::default(),
             esp_out: Arc::default(),
@@ -47,6 +58,7 @@ impl Default for IpsecSession {
 #[derive(Debug, Clone, PartialEq)]
 pub struct VpnSession {
     pub ccc_session_id: String,
+    pub ccc_cookie: Option<String>,
     pub ipsec_session: Option<IpsecSession>,
     pub state: SessionState,
 }
@@ -55,6 +67,7 @@ impl VpnSession {
     pub fn empty() -> Self {
         Self {
             ccc_session_id: String::new(),
+            ccc_cookie: None,
             ipsec_session: None,
             state: SessionState::default(),
         }
@@ -92,16 +105,227 @@ pub struct MfaChallenge {
     pub prompt: String,
 }
 
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, PartialOrd)]
+pub enum DisconnectReason {
+    User,
+    KeepaliveTimeout,
+    SessionExpired,
+    ConnectivityCheckFailed,
+    Error,
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::User => "disconnected by user",
+            Self::KeepaliveTimeout => "gateway is not responding",
+            Self::SessionExpired => "session expired",
+            Self::ConnectivityCheckFailed => "tunnel does not carry traffic",
+            Self::Error => "tunnel error",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// Classified connection failure. The connectors return it wrapped into `anyhow::Error`,
+/// use [`ConnectionError::find`] to get it back from the error chain.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ConnectionError {
+    /// Credentials or MFA input were rejected
+    AuthFailed(String),
+    /// User input is required where it cannot be asked for, e.g. during re-authentication
+    Challenge(String),
+    /// Client certificate is missing or cannot be used
+    CertError(String),
+    NetworkUnreachable(String),
+    Timeout(String),
+    /// The gateway refused the request with an error code
+    ServerRejected {
+        code: u32,
+        id: String,
+        message: String,
+    },
+    /// The gateway supports none of the offered IKE or ESP ciphers
+    ProposalRejected {
+        stage: String,
+    },
+}
+
+impl ConnectionError {
+    /// Connection error from the chain of the given error, if any
+    pub fn find(error: &anyhow::Error) -> Option<&ConnectionError> {
+        error.chain().find_map(|cause| cause.downcast_ref::<ConnectionError>())
+    }
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AuthFailed(message)
+            | Self::Challenge(message)
+            | Self::CertError(message)
+            | Self::NetworkUnreachable(message)
+            | Self::Timeout(message) => write!(f, "{message}"),
+            Self::ServerRejected { code, id, message } => write!(f, "[{code} {id}] {message}"),
+            Self::ProposalRejected { stage } => write!(
+                f,
+                "The gateway rejected the {stage} proposal: none of the offered ciphers are supported by the server"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {}
+
+/// Data transport of the connected tunnel
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, PartialOrd)]
+pub enum TunnelTransport {
+    IpsecNative,
+    IpsecTcpt,
+    Ssl,
+}
+
+impl fmt::Display for TunnelTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::IpsecNative => "IPSec over ESP-in-UDP",
+            Self::IpsecTcpt => "IPSec over TCPT",
+            Self::Ssl => "SSL",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// Properties of the connected tunnel
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionInfo {
+    /// Gateway of the tunnel, selected from the `server-name` list
+    pub server_name: String,
+    pub transport: TunnelTransport,
+    pub if_name: String,
+    pub address: Ipv4Addr,
+    pub dns_servers: Vec<IpAddr>,
+    pub search_domains: Vec<String>,
+    /// Routes imported from the network ranges advertised by the gateway
+    pub routes: Vec<Ipv4Net>,
+    /// Interval after which the gateway expects the user to authenticate again
+    pub reauth_timeout: Option<Duration>,
+}
+
+/// Tunnel event streamed by the command server to the event subscribers, one JSON object per line
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum ServiceEvent {
+    Connected {
+        server_name: String,
+        transport: TunnelTransport,
+        if_name: String,
+        address: Ipv4Addr,
+        dns_servers: Vec<IpAddr>,
+        search_domains: Vec<String>,
+        routes: Vec<Ipv4Net>,
+    },
+    Disconnected {
+        reason: DisconnectReason,
+    },
+    Rekeyed,
+}
+
+impl From<&ConnectionInfo> for ServiceEvent {
+    fn from(info: &ConnectionInfo) -> Self {
+        Self::Connected {
+            server_name: info.server_name.clone(),
+            transport: info.transport,
+            if_name: info.if_name.clone(),
+            address: info.address,
+            dns_servers: info.dns_servers.clone(),
+            search_domains: info.search_domains.clone(),
+            routes: info.routes.clone(),
+        }
+    }
+}
+
+/// Byte counters of the tunnel interface
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, PartialOrd)]
+pub struct TrafficStats {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+impl TrafficStats {
+    /// Average transfer rate in bytes per second since the earlier counters.
+    /// The counters of a reconnected tunnel start from zero again, a decrease gives a zero rate.
+    pub fn rate_since(&self, prev: &TrafficStats, elapsed: Duration) -> TrafficStats {
+        let secs = elapsed.as_secs_f64();
+        if secs <= 0.0 {
+            return TrafficStats::default();
+        }
+
+        let rate = |now: u64, prev: u64| (now.saturating_sub(prev) as f64 / secs).round() as u64;
+
+        TrafficStats {
+            bytes_in: rate(self.bytes_in, prev.bytes_in),
+            bytes_out: rate(self.bytes_out, prev.bytes_out),
+        }
+    }
+}
+
+impl fmt::Display for TrafficStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "received {}, sent {}",
+            crate::util::format_bytes(self.bytes_in),
+            crate::util::format_bytes(self.bytes_out)
+        )
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, PartialOrd)]
 pub struct ConnectionStatus {
     pub connected_since: Option<DateTime<Local>>,
     pub mfa: Option<MfaChallenge>,
+    #[serde(default)]
+    pub disconnect_reason: Option<DisconnectReason>,
+    #[serde(default)]
+    pub reconnects: u32,
+    /// Configuration file of the active or pending connection
+    #[serde(default)]
+    pub profile: Option<PathBuf>,
+    /// Traffic of the connected tunnel
+    #[serde(default)]
+    pub traffic: Option<TrafficStats>,
+    /// Gateway of the connected tunnel
+    #[serde(default)]
+    pub server_name: Option<String>,
+    /// Effective transport of the connected tunnel
+    #[serde(default)]
+    pub transport: Option<TunnelTransport>,
+    /// Routes imported from the network ranges advertised by the gateway
+    #[serde(default)]
+    pub routes: Vec<Ipv4Net>,
+    /// Office mode address assigned by the gateway
+    #[serde(default)]
+    pub address: Option<Ipv4Addr>,
+    /// DNS servers advertised by the gateway
+    #[serde(default)]
+    pub dns_servers: Vec<IpAddr>,
+    /// Search domains advertised by the gateway
+    #[serde(default)]
+    pub search_domains: Vec<String>,
+    /// Time of the last IPSec rekey
+    #[serde(default)]
+    pub last_rekey: Option<DateTime<Local>>,
+    /// Time when the office mode address was renewed from the persisted IKE session
+    #[serde(default)]
+    pub lease_renewed: Option<DateTime<Local>>,
 }
 
 impl ConnectionStatus {
-    pub fn connected() -> Self {
+    pub fn connected(reconnects: u32) -> Self {
         Self {
             connected_since: Some(Local::now()),
+            reconnects,
             ..Default::default()
         }
     }
@@ -110,12 +334,34 @@ impl ConnectionStatus {
         Self::default()
     }
 
+    pub fn disconnected_with_reason(reason: DisconnectReason) -> Self {
+        Self {
+            disconnect_reason: Some(reason),
+            ..Default::default()
+        }
+    }
+
     pub fn mfa(challenge: MfaChallenge) -> Self {
         Self {
             mfa: Some(challenge),
             ..Default::default()
         }
     }
+
+    /// Connection uptime and reconnect count, e.g. "1h23m (2 reconnects)"
+    pub fn uptime_summary(&self) -> Option<String> {
+        let since = self.connected_since?;
+        let uptime = (Local::now() - since).to_std().unwrap_or_default();
+        let mut summary = crate::util::format_uptime(uptime);
+
+        match self.reconnects {
+            0 => {}
+            1 => summary.push_str(" (1 reconnect)"),
+            n => summary.push_str(&format!(" ({n} reconnects)")),
+        }
+
+        Some(summary)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -124,11 +370,135 @@ pub enum TunnelServiceRequest {
     ChallengeCode(String, TunnelParams),
     Disconnect,
     GetStatus,
+    Cancel,
+    ClearTransportCache(TunnelParams),
+    Reauth,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TunnelServiceResponse {
     Ok,
     Error(String),
+    /// Classified connection failure, the message contains the whole error chain
+    ConnectionError(String, ConnectionError),
     ConnectionStatus(ConnectionStatus),
 }
+
+impl TunnelServiceResponse {
+    /// Error response which keeps the classification of the connection failure for the client
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        let message = format!("{:#}", error);
+        match ConnectionError::find(error) {
+            Some(connection_error) => Self::ConnectionError(message, connection_error.clone()),
+            None => Self::Error(message),
+        }
+    }
+
+    /// Error of the failed request, [`ConnectionError::find`] returns the classification of a connection failure
+    pub fn into_error(self) -> Option<anyhow::Error> {
+        match self {
+            Self::Error(message) => Some(anyhow::anyhow!(message)),
+            Self::ConnectionError(message, error) => Some(anyhow::Error::new(error).context(message)),
+            Self::Ok | Self::ConnectionStatus(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn test_find_connection_error() {
+        let error = anyhow::Error::from(ConnectionError::ServerRejected {
+            code: 101,
+            id: "0x1234".to_owned(),
+            message: "Access denied".to_owned(),
+        });
+        let error = Err::<(), _>(error).context("Connection failed").unwrap_err();
+
+        assert_eq!(format!("{:#}", error), "Connection failed: [101 0x1234] Access denied");
+        assert!(matches!(
+            ConnectionError::find(&error),
+            Some(ConnectionError::ServerRejected { code: 101, .. })
+        ));
+        assert!(ConnectionError::find(&anyhow::anyhow!("Other error")).is_none());
+    }
+
+    #[test]
+    fn test_service_error_response() {
+        let error = Err::<(), _>(anyhow::Error::from(ConnectionError::Timeout("No reply".to_owned())))
+            .context("Connection failed")
+            .unwrap_err();
+
+        let response = TunnelServiceResponse::from_error(&error);
+        let data = serde_json::to_vec(&response).unwrap();
+        let response = serde_json::from_slice::<TunnelServiceResponse>(&data).unwrap();
+
+        let error = response.into_error().unwrap();
+        assert_eq!(error.to_string(), "Connection failed: No reply");
+        assert_eq!(
+            ConnectionError::find(&error),
+            Some(&ConnectionError::Timeout("No reply".to_owned()))
+        );
+
+        let error = TunnelServiceResponse::from_error(&anyhow::anyhow!("Other error"))
+            .into_error()
+            .unwrap();
+        assert_eq!(error.to_string(), "Other error");
+        assert!(ConnectionError::find(&error).is_none());
+
+        assert!(TunnelServiceResponse::Ok.into_error().is_none());
+    }
+
+    #[test]
+    fn test_service_event_json() {
+        let event = ServiceEvent::Connected {
+            server_name: "vpn.example.com".to_owned(),
+            transport: TunnelTransport::IpsecNative,
+            if_name: "snx-xfrm".to_owned(),
+            address: Ipv4Addr::new(10, 0, 0, 2),
+            dns_servers: vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 53))],
+            search_domains: vec!["example.com".to_owned()],
+            routes: vec!["10.0.0.0/8".parse().unwrap()],
+        };
+        let json = serde_json::to_string(&event).unwrap();
+        assert_eq!(
+            json,
+            r#"{"event":"connected","server_name":"vpn.example.com","transport":"IpsecNative","if_name":"snx-xfrm","address":"10.0.0.2","dns_servers":["10.0.0.53"],"search_domains":["example.com"],"routes":["10.0.0.0/8"]}"#
+        );
+        assert_eq!(serde_json::from_str::<ServiceEvent>(&json).unwrap(), event);
+
+        let event = ServiceEvent::Disconnected {
+            reason: DisconnectReason::KeepaliveTimeout,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"disconnected","reason":"KeepaliveTimeout"}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&ServiceEvent::Rekeyed).unwrap(),
+            r#"{"event":"rekeyed"}"#
+        );
+    }
+
+    #[test]
+    fn test_traffic_rate() {
+        let prev = TrafficStats {
+            bytes_in: 1000,
+            bytes_out: 500,
+        };
+        let now = TrafficStats {
+            bytes_in: 4000,
+            bytes_out: 400,
+        };
+
+        let rate = now.rate_since(&prev, Duration::from_secs(2));
+        assert_eq!(rate.bytes_in, 1500);
+        assert_eq!(rate.bytes_out, 0);
+
+        assert_eq!(now.rate_since(&prev, Duration::ZERO), TrafficStats::default());
+    }
+}
@@ -1,23 +1,58 @@
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use base64::Engine;
 use directories_next::ProjectDirs;
 use ipnet::Ipv4Net;
 use serde::{Deserialize, Serialize};
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::{
+    collections::{BTreeMap, HashMap},
     fmt, fs,
     io::{Cursor, Write},
     path::{Path, PathBuf},
     str::FromStr,
     time::Duration,
 };
-use tracing::warn;
+use tracing::{info, warn};
 
-use crate::util;
+use crate::{model::wrappers::SecretBytes, util};
 
 const DEFAULT_ESP_LIFETIME: Duration = Duration::from_secs(3600);
 const DEFAULT_IKE_LIFETIME: Duration = Duration::from_secs(28800);
+const DEFAULT_VERIFY_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_NATT_PROBE_COUNT: usize = 3;
+pub(crate) const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(20);
+pub(crate) const DEFAULT_KEEPALIVE_MAX_RETRIES: u32 = 5;
+const DEFAULT_NATT_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
 const DEFAULT_IKE_PORT: u16 = 500;
+const DEFAULT_OTP_LISTEN_PORT: u16 = 7779;
+const DEFAULT_EVENTS_PORT: u16 = 7780;
+const DEFAULT_SESSION_PATH: &str = "/var/cache/snx-rs/sessions";
+const DEFAULT_RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+const DEFAULT_LOG_FILE_COUNT: usize = 7;
+const DEFAULT_PASSWORD_FACTOR: usize = 1;
+const DEFAULT_CLIENT_OS: &str = "Windows";
+const DEFAULT_SERVER_INFO_RETRIES: u32 = 2;
+const DEFAULT_SERVER_INFO_CACHE_TTL: Duration = Duration::from_secs(300);
+const DEFAULT_MTU_DISCOVERY_FALLBACK: u16 = 1280;
+
+// layout of the config file, files without the config-version key are version 0
+const CONFIG_VERSION: u32 = 1;
+
+// keys which were renamed since the first releases: (old name, current name)
+const RENAMED_CONFIG_KEYS: &[(&str, &str)] = &[
+    ("transport-type", "esp-transport"),
+    ("client-cert", "cert-path"),
+    ("no-cert-name-check", "no-cert-check"),
+];
+
+const SECRET_CONFIG_KEYS: &[&str] = &[
+    "password",
+    "cert-password",
+    "cert-pkcs12-b64",
+    "realm-credentials",
+    "proxy-url",
+    "totp-secret",
+];
 
 #[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub enum OperationMode {
@@ -45,6 +80,8 @@ pub enum TunnelType {
     #[default]
     Ipsec,
     Ssl,
+    /// L2TP over IPSec, advertised by some gateways but not implemented
+    L2tp,
 }
 
 impl TunnelType {
@@ -60,6 +97,15 @@ impl TunnelType {
         match self {
             TunnelType::Ipsec => "ipsec",
             TunnelType::Ssl => "ssl",
+            TunnelType::L2tp => "l2tp",
+        }
+    }
+
+    pub fn as_u32(&self) -> u32 {
+        match self {
+            Self::Ipsec => 0,
+            Self::Ssl => 1,
+            Self::L2tp => 2,
         }
     }
 }
@@ -71,6 +117,7 @@ impl FromStr for TunnelType {
         match s.to_lowercase().as_str() {
             "ipsec" => Ok(TunnelType::Ipsec),
             "ssl" => Ok(TunnelType::Ssl),
+            "l2tp" => Ok(TunnelType::L2tp),
             _ => Err(anyhow!("Invalid tunnel type!")),
         }
     }
@@ -81,6 +128,17 @@ impl fmt::Display for TunnelType {
         match self {
             Self::Ssl => write!(f, "SSL"),
             Self::Ipsec => write!(f, "IPSec"),
+            Self::L2tp => write!(f, "L2TP"),
+        }
+    }
+}
+
+impl From<u32> for TunnelType {
+    fn from(value: u32) -> Self {
+        match value {
+            1 => Self::Ssl,
+            2 => Self::L2tp,
+            _ => Self::Ipsec,
         }
     }
 }
@@ -199,6 +257,8 @@ pub enum TransportType {
     #[default]
     Udp,
     Tcpt,
+    /// UDP if the gateway answers the NAT-T probes, TCPT otherwise, used for ESP only
+    Auto,
 }
 
 impl TransportType {
@@ -206,6 +266,7 @@ impl TransportType {
         match self {
             TransportType::Udp => "udp",
             TransportType::Tcpt => "tcpt",
+            TransportType::Auto => "auto",
         }
     }
 
@@ -213,6 +274,15 @@ impl TransportType {
         match self {
             Self::Udp => 0,
             Self::Tcpt => 1,
+            Self::Auto => 2,
+        }
+    }
+
+    /// Parse the IKE transport type, the automatic detection is only supported for ESP
+    pub fn parse_ike(s: &str) -> anyhow::Result<Self> {
+        match s.parse()? {
+            TransportType::Auto => Err(anyhow!("Automatic transport detection is not supported for IKE!")),
+            other => Ok(other),
         }
     }
 }
@@ -224,6 +294,7 @@ impl FromStr for TransportType {
         match s.to_lowercase().as_str() {
             "udp" => Ok(TransportType::Udp),
             "tcpt" => Ok(TransportType::Tcpt),
+            "auto" => Ok(TransportType::Auto),
             _ => Err(anyhow!("Invalid transport type!")),
         }
     }
@@ -234,6 +305,7 @@ impl fmt::Display for TransportType {
         match self {
             Self::Udp => write!(f, "UDP"),
             Self::Tcpt => write!(f, "TCPT"),
+            Self::Auto => write!(f, "auto"),
         }
     }
 }
@@ -242,48 +314,348 @@ impl From<u32> for TransportType {
     fn from(value: u32) -> Self {
         match value {
             1 => Self::Tcpt,
+            2 => Self::Auto,
             _ => Self::Udp,
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum RouteConflict {
+    Fail,
+    #[default]
+    Skip,
+    Replace,
+}
+
+impl RouteConflict {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Fail => "fail",
+            Self::Skip => "skip",
+            Self::Replace => "replace",
+        }
+    }
+}
+
+impl FromStr for RouteConflict {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fail" => Ok(RouteConflict::Fail),
+            "skip" => Ok(RouteConflict::Skip),
+            "replace" => Ok(RouteConflict::Replace),
+            _ => Err(anyhow!("Invalid route conflict action!")),
+        }
+    }
+}
+
+impl fmt::Display for RouteConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum SearchDomainPolicy {
+    #[default]
+    Truncate,
+    KeepConfigured,
+}
+
+impl SearchDomainPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Truncate => "truncate",
+            Self::KeepConfigured => "keep-configured",
+        }
+    }
+}
+
+impl FromStr for SearchDomainPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "truncate" => Ok(SearchDomainPolicy::Truncate),
+            "keep-configured" => Ok(SearchDomainPolicy::KeepConfigured),
+            _ => Err(anyhow!("Invalid search domain policy!")),
+        }
+    }
+}
+
+impl fmt::Display for SearchDomainPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Source of the OTP code for automated logins
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum OtpSource {
+    /// Named pipe, the code is read when a writer opens it
+    Pipe(PathBuf),
+    /// Regular file, the code is read once the file has some content, then the file is removed
+    File(PathBuf),
+}
+
+impl FromStr for OtpSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((kind, path)) if !path.is_empty() => match kind.to_lowercase().as_str() {
+                "pipe" => Ok(OtpSource::Pipe(path.into())),
+                "file" => Ok(OtpSource::File(path.into())),
+                _ => Err(anyhow!("Invalid OTP source, expected pipe:/path or file:/path!")),
+            },
+            _ => Err(anyhow!("Invalid OTP source, expected pipe:/path or file:/path!")),
+        }
+    }
+}
+
+impl fmt::Display for OtpSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pipe(path) => write!(f, "pipe:{}", path.display()),
+            Self::File(path) => write!(f, "file:{}", path.display()),
+        }
+    }
+}
+
+/// Origin of an effective configuration value
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigSource {
+    Default,
+    File(PathBuf),
+    CommandLine,
+    Keychain,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Default => write!(f, "default"),
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::CommandLine => write!(f, "command line"),
+            Self::Keychain => write!(f, "keychain"),
+        }
+    }
+}
+
+/// Credentials remembered for a specific login type
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RealmCredentials {
+    pub user_name: String,
+    pub password: String,
+}
+
+fn current_key_name(key: &str) -> &str {
+    RENAMED_CONFIG_KEYS
+        .iter()
+        .find(|(old, _)| *old == key)
+        .map_or(key, |(_, new)| *new)
+}
+
+fn parse_config_keys(data: &str) -> anyhow::Result<HashMap<String, String>> {
+    Ok(util::parse_config(data)?
+        .into_iter()
+        .map(|(k, v)| (current_key_name(&k).to_owned(), v))
+        .collect())
+}
+
+// Rewrites the contents of a config file written by an older version: renamed keys get their current names,
+// comments and the order of the lines are kept. Returns None if no renamed key was found.
+fn migrate_config(config: &str) -> Option<String> {
+    let parsed = util::parse_config(config).ok()?;
+    let version = parsed
+        .get("config-version")
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or_default();
+
+    if version >= CONFIG_VERSION {
+        return None;
+    }
+
+    let mut lines = vec![format!("config-version={CONFIG_VERSION}")];
+    let mut renamed = false;
+
+    for line in config.lines() {
+        let key = line
+            .split_once('=')
+            .map(|(k, _)| k.trim())
+            .filter(|k| !k.starts_with('#'));
+
+        match key {
+            Some("config-version") => {}
+            Some(key) if current_key_name(key) != key => {
+                let new_key = current_key_name(key);
+                renamed = true;
+                // the current name takes precedence if both are present
+                if !parsed.contains_key(new_key) {
+                    lines.push(line.replacen(key, new_key, 1));
+                }
+            }
+            _ => lines.push(line.to_owned()),
+        }
+    }
+
+    renamed.then(|| lines.join("\n") + "\n")
+}
+
+// comma-separated addresses and subnets, invalid entries are logged and skipped
+fn parse_ipv4_nets(key: &str, value: &str) -> Vec<Ipv4Net> {
+    value
+        .split(',')
+        .filter(|s| !s.trim().is_empty())
+        .filter_map(|s| match util::parse_ipv4_net(s) {
+            Ok(net) => Some(net),
+            Err(e) => {
+                warn!("Ignoring invalid {} entry: {}", key, e);
+                None
+            }
+        })
+        .collect()
+}
+
+// entries in the form of <login-type>:<user-name>:<base64-encoded password>, separated by commas
+fn parse_realm_credentials(value: &str) -> BTreeMap<String, RealmCredentials> {
+    value
+        .split(',')
+        .filter_map(|entry| {
+            let (realm, rest) = entry.trim().split_once(':')?;
+            let (user_name, password) = rest.rsplit_once(':').unwrap_or((rest, ""));
+            let password = base64::engine::general_purpose::STANDARD
+                .decode(password)
+                .map(|password| String::from_utf8_lossy(&password).into_owned())
+                .unwrap_or_default();
+            Some((
+                realm.to_owned(),
+                RealmCredentials {
+                    user_name: user_name.to_owned(),
+                    password,
+                },
+            ))
+        })
+        .filter(|(realm, _)| !realm.is_empty())
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TunnelParams {
     pub server_name: String,
     pub user_name: String,
     pub password: String,
+    /// Credentials of the individual login types, the flat user name and password are used for the others
+    pub realm_credentials: BTreeMap<String, RealmCredentials>,
+    /// The flat user name and password from the config file, kept when they are replaced by the realm credentials
+    pub default_credentials: Option<RealmCredentials>,
+    pub password_env: Option<String>,
+    /// Base32-encoded secret for generating the TOTP codes instead of prompting for them
+    pub totp_secret: Option<String>,
+    /// 1-based index of the password prompt which is answered with the TOTP code
+    pub password_factor: usize,
+    /// Pipe or file to read the code for the `password_factor` prompt from, if no TOTP secret is set
+    pub otp_source: Option<OtpSource>,
     pub log_level: String,
+    pub log_file: Option<PathBuf>,
+    pub log_file_count: usize,
     pub search_domains: Vec<String>,
     pub ignore_search_domains: Vec<String>,
+    pub max_search_domains: usize,
+    pub search_domains_policy: SearchDomainPolicy,
     pub dns_servers: Vec<Ipv4Addr>,
-    pub ignore_dns_servers: Vec<Ipv4Addr>,
+    /// Use the `dns-servers` instead of the acquired ones rather than in addition to them
+    pub replace_dns_servers: bool,
+    pub ignore_dns_servers: Vec<Ipv4Net>,
     pub default_route: bool,
+    pub keep_fallback_default_route: bool,
     pub no_routing: bool,
     pub add_routes: Vec<Ipv4Net>,
     pub ignore_routes: Vec<Ipv4Net>,
+    pub on_route_conflict: RouteConflict,
+    pub app_routing_users: Vec<String>,
+    pub app_routing_cgroup: Option<String>,
     pub no_dns: bool,
+    pub disable_ipv6: bool,
+    pub teardown_grace: Duration,
     pub no_cert_check: bool,
     pub ignore_server_cert: bool,
     pub ipsec_cert_check: bool,
+    pub server_cert_fingerprint: Option<String>,
     pub tunnel_type: TunnelType,
     pub ca_cert: Vec<PathBuf>,
     pub login_type: String,
+    pub allow_hidden_realms: bool,
     pub cert_type: CertType,
     pub cert_path: Option<PathBuf>,
+    pub cert_pkcs12_b64: Option<String>,
     pub cert_password: Option<String>,
     pub cert_id: Option<String>,
     pub if_name: Option<String>,
+    pub if_txqueuelen: u32,
+    pub if_mtu: u16,
+    pub mtu_discovery: bool,
+    /// MTU of the tunnel interface during the discovery and when it fails
+    pub mtu_discovery_fallback: u16,
+    pub if_gro: Option<bool>,
+    pub if_gso: Option<bool>,
+    pub run_as: String,
+    pub post_connect_script: Option<PathBuf>,
+    pub post_disconnect_script: Option<PathBuf>,
     pub no_keychain: bool,
     pub server_prompt: bool,
     pub esp_lifetime: Duration,
     pub esp_transport: TransportType,
     pub ike_lifetime: Duration,
+    pub identity_timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
     pub ike_port: u16,
+    pub natt_source_port: u16,
+    pub natt_probe_count: usize,
+    pub natt_probe_timeout: Duration,
     pub ike_persist: bool,
+    pub signout_on_disconnect: bool,
+    pub session_path: PathBuf,
     pub client_mode: String,
+    /// Operating system name reported to the gateway, which may apply policies based on it
+    pub client_os: String,
+    /// Device id reported to the gateway instead of the one derived from the machine id
+    pub device_id: Option<String>,
+    pub user_agent: String,
+    pub proxy_url: Option<String>,
+    /// Local address for the outgoing gateway connections on multi-homed hosts
+    pub bind_address: Option<IpAddr>,
     pub no_keepalive: bool,
+    pub keepalive_interval: Duration,
+    pub keepalive_max_retries: u32,
+    /// UDP port of the IPSec keepalive requests and replies
+    pub keepalive_port: u16,
+    pub keepalive_thread: bool,
+    pub verify_connectivity: bool,
+    pub verify_timeout: Duration,
     pub icon_theme: IconTheme,
+    pub notify_rekey: bool,
+    pub auto_reload: bool,
+    pub auto_reauth: bool,
+    pub auto_reconnect: bool,
+    pub reconnect_max_attempts: u32,
+    pub reconnect_max_delay: Duration,
     pub ike_transport: TransportType,
+    pub tcpt_only: bool,
+    pub no_server_redirect: bool,
+    /// Number of retries of the server info request after transient network errors
+    pub server_info_retries: u32,
+    /// How long the server info reply is reused, zero disables the caching
+    pub server_info_cache_ttl: Duration,
+    pub otp_listen_address: IpAddr,
+    pub otp_listen_port: u16,
+    pub otp_https: bool,
+    pub otp_redirect_path: String,
+    pub events_port: u16,
+    pub dump_ccc: Option<PathBuf>,
+    pub dump_raw: bool,
     pub config_file: PathBuf,
 }
 
@@ -293,87 +665,251 @@ impl Default for TunnelParams {
             server_name: String::new(),
             user_name: String::new(),
             password: String::new(),
+            realm_credentials: BTreeMap::new(),
+            default_credentials: None,
+            password_env: None,
+            totp_secret: None,
+            otp_source: None,
+            password_factor: DEFAULT_PASSWORD_FACTOR,
             log_level: "off".to_owned(),
+            log_file: None,
+            log_file_count: DEFAULT_LOG_FILE_COUNT,
             search_domains: Vec::new(),
             ignore_search_domains: Vec::new(),
+            max_search_domains: 0,
+            search_domains_policy: SearchDomainPolicy::default(),
             dns_servers: Vec::new(),
+            replace_dns_servers: false,
             ignore_dns_servers: Vec::new(),
             default_route: false,
+            keep_fallback_default_route: false,
             no_routing: false,
             add_routes: Vec::new(),
             ignore_routes: Vec::new(),
+            on_route_conflict: RouteConflict::default(),
+            app_routing_users: Vec::new(),
+            app_routing_cgroup: None,
             no_dns: false,
+            disable_ipv6: false,
+            teardown_grace: Duration::ZERO,
             no_cert_check: false,
             ignore_server_cert: false,
             ipsec_cert_check: false,
+            server_cert_fingerprint: None,
             tunnel_type: TunnelType::default(),
             ca_cert: Vec::new(),
             login_type: String::new(),
+            allow_hidden_realms: false,
             cert_type: CertType::default(),
             cert_path: None,
+            cert_pkcs12_b64: None,
             cert_password: None,
             cert_id: None,
             if_name: None,
+            if_txqueuelen: 0,
+            if_mtu: 0,
+            mtu_discovery: false,
+            mtu_discovery_fallback: DEFAULT_MTU_DISCOVERY_FALLBACK,
+            if_gro: None,
+            if_gso: None,
+            run_as: String::new(),
+            post_connect_script: None,
+            post_disconnect_script: None,
             no_keychain: false,
             server_prompt: true,
             esp_lifetime: DEFAULT_ESP_LIFETIME,
             esp_transport: TransportType::default(),
             ike_lifetime: DEFAULT_IKE_LIFETIME,
+            identity_timeout: None,
+            connect_timeout: None,
             ike_port: DEFAULT_IKE_PORT,
+            natt_source_port: 0,
+            natt_probe_count: DEFAULT_NATT_PROBE_COUNT,
+            natt_probe_timeout: DEFAULT_NATT_PROBE_TIMEOUT,
             ike_persist: false,
+            signout_on_disconnect: false,
+            session_path: DEFAULT_SESSION_PATH.into(),
             client_mode: TunnelType::Ipsec.as_client_mode().to_owned(),
+            client_os: DEFAULT_CLIENT_OS.to_owned(),
+            device_id: None,
+            user_agent: String::new(),
+            proxy_url: None,
+            bind_address: None,
             no_keepalive: false,
+            keepalive_interval: DEFAULT_KEEPALIVE_INTERVAL,
+            keepalive_max_retries: DEFAULT_KEEPALIVE_MAX_RETRIES,
+            keepalive_port: TunnelParams::IPSEC_KEEPALIVE_PORT,
+            keepalive_thread: false,
+            verify_connectivity: false,
+            verify_timeout: DEFAULT_VERIFY_TIMEOUT,
             icon_theme: IconTheme::default(),
+            notify_rekey: false,
+            auto_reload: false,
+            auto_reauth: false,
+            auto_reconnect: false,
+            reconnect_max_attempts: 0,
+            reconnect_max_delay: DEFAULT_RECONNECT_MAX_DELAY,
             ike_transport: TransportType::default(),
+            tcpt_only: false,
+            no_server_redirect: false,
+            server_info_retries: DEFAULT_SERVER_INFO_RETRIES,
+            server_info_cache_ttl: DEFAULT_SERVER_INFO_CACHE_TTL,
+            otp_listen_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            otp_listen_port: DEFAULT_OTP_LISTEN_PORT,
+            otp_https: false,
+            otp_redirect_path: "/".to_owned(),
+            events_port: DEFAULT_EVENTS_PORT,
+            dump_ccc: None,
+            dump_raw: false,
             config_file: Self::default_config_path(),
         }
     }
 }
 
 impl TunnelParams {
+    /// Default UDP port of the IPSec keepalive requests
     pub const IPSEC_KEEPALIVE_PORT: u16 = 18234;
+    /// Routing table for the tunnel default route and the keepalive rule
+    pub const ROUTING_TABLE: u16 = 18234;
     pub const DEFAULT_IPSEC_IF_NAME: &'static str = "snx-xfrm";
     pub const DEFAULT_SSL_IF_NAME: &'static str = "snx-tun";
 
+    /// Local socket address for the outgoing gateway connections, unspecified IPv4 address without `bind-address`
+    pub fn local_address(&self, port: u16) -> SocketAddr {
+        SocketAddr::new(self.bind_address.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)), port)
+    }
+
+    /// Gateways from the comma-separated `server-name` list in the configured order
+    pub fn server_names(&self) -> Vec<&str> {
+        self.server_name
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect()
+    }
+
+    /// Reorder the `server-name` list so that it starts with the gateway following the given one,
+    /// which moves to the end of the list
+    pub fn rotate_servers(&mut self, gateway: &str) {
+        let names = self.server_names();
+        if let Some(index) = names.iter().position(|name| name.eq_ignore_ascii_case(gateway)) {
+            let rotated = names[index + 1..]
+                .iter()
+                .chain(&names[..=index])
+                .copied()
+                .collect::<Vec<_>>()
+                .join(",");
+            self.server_name = rotated;
+        }
+    }
+
+    /// Name of the tunnel interface: the native IPSec tunnel uses an xfrm interface, others use a TUN device
+    pub fn tunnel_if_name(&self) -> &str {
+        let native_ipsec =
+            self.tunnel_type == TunnelType::Ipsec && !self.tcpt_only && self.esp_transport != TransportType::Tcpt;
+
+        match self.if_name {
+            Some(ref name) => name,
+            None if native_ipsec => Self::DEFAULT_IPSEC_IF_NAME,
+            None => Self::DEFAULT_SSL_IF_NAME,
+        }
+    }
+
+    /// Load the parameters from the config file and its drop-in fragments. The files are never modified,
+    /// renamed keys of older versions are read with their current names.
     pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
         let mut params = Self::default();
-        let data = fs::read_to_string(&path)?;
-        let config = util::parse_config(data)?;
+        let mut config = HashMap::new();
+
+        for (_, fragment) in Self::read_config_files(path.as_ref())? {
+            config.extend(fragment);
+        }
 
         for (k, v) in config.into_iter() {
             match k.as_str() {
+                "config-version" => {
+                    if v.parse::<u32>().is_ok_and(|v| v > CONFIG_VERSION) {
+                        warn!("The configuration was written by a newer version, some options may be ignored");
+                    }
+                }
                 "server-name" => params.server_name = v,
                 "user-name" => params.user_name = v,
                 "password" => params.password = v,
+                "realm-credentials" => params.realm_credentials = parse_realm_credentials(&v),
+                "password-env" => params.password_env = Some(v),
+                "totp-secret" => params.totp_secret = Some(v).filter(|v| !v.is_empty()),
+                "otp-source" => params.otp_source = v.parse().ok(),
+                "password-factor" => {
+                    params.password_factor = v.parse().ok().filter(|v| *v > 0).unwrap_or(DEFAULT_PASSWORD_FACTOR);
+                }
                 "log-level" => params.log_level = v,
+                "log-file" => params.log_file = Some(v.into()),
+                "log-file-count" => {
+                    params.log_file_count = v.parse().ok().filter(|v| *v > 0).unwrap_or(DEFAULT_LOG_FILE_COUNT)
+                }
                 "search-domains" => params.search_domains = v.split(',').map(|s| s.trim().to_owned()).collect(),
                 "ignore-search-domains" => {
                     params.ignore_search_domains = v.split(',').map(|s| s.trim().to_owned()).collect();
                 }
+                "max-search-domains" => params.max_search_domains = v.parse().unwrap_or_default(),
+                "search-domains-policy" => params.search_domains_policy = v.parse().unwrap_or_default(),
                 "dns-servers" => params.dns_servers = v.split(',').flat_map(|s| s.trim().parse().ok()).collect(),
-                "ignore-dns-servers" => {
-                    params.ignore_dns_servers = v.split(',').flat_map(|s| s.trim().parse().ok()).collect();
-                }
+                "replace-dns-servers" => params.replace_dns_servers = v.parse().unwrap_or_default(),
+                "ignore-dns-servers" => params.ignore_dns_servers = parse_ipv4_nets(&k, &v),
                 "default-route" => params.default_route = v.parse().unwrap_or_default(),
+                "keep-fallback-default-route" => params.keep_fallback_default_route = v.parse().unwrap_or_default(),
                 "no-routing" => params.no_routing = v.parse().unwrap_or_default(),
-                "add-routes" => params.add_routes = v.split(',').flat_map(|s| s.trim().parse().ok()).collect(),
-                "ignore-routes" => {
-                    params.ignore_routes = v.split(',').flat_map(|s| s.trim().parse().ok()).collect();
+                "add-routes" => params.add_routes = parse_ipv4_nets(&k, &v),
+                "ignore-routes" => params.ignore_routes = parse_ipv4_nets(&k, &v),
+                "on-route-conflict" => params.on_route_conflict = v.parse().unwrap_or_default(),
+                "app-routing-users" => {
+                    params.app_routing_users = v
+                        .split(',')
+                        .map(|s| s.trim().to_owned())
+                        .filter(|s| !s.is_empty())
+                        .collect();
                 }
+                "app-routing-cgroup" => params.app_routing_cgroup = Some(v).filter(|v| !v.is_empty()),
                 "no-dns" => params.no_dns = v.parse().unwrap_or_default(),
+                "disable-ipv6" => params.disable_ipv6 = v.parse().unwrap_or_default(),
+                "teardown-grace" => {
+                    params.teardown_grace = v.parse::<u64>().ok().map_or(Duration::ZERO, Duration::from_secs);
+                }
                 "no-cert-check" => params.no_cert_check = v.parse().unwrap_or_default(),
                 "ipsec-cert-check" => params.ipsec_cert_check = v.parse().unwrap_or_default(),
+                "server-cert-fingerprint" => params.server_cert_fingerprint = Some(v).filter(|v| !v.is_empty()),
                 "ignore-server-cert" => params.ignore_server_cert = v.parse().unwrap_or_default(),
                 "tunnel-type" => params.tunnel_type = v.parse().unwrap_or_default(),
                 "ca-cert" => params.ca_cert = v.split(',').map(|s| s.trim().into()).collect(),
                 "login-type" => params.login_type = v,
+                "allow-hidden-realms" => params.allow_hidden_realms = v.parse().unwrap_or_default(),
                 "cert-type" => params.cert_type = v.parse().unwrap_or_default(),
                 "cert-path" => params.cert_path = Some(v.into()),
+                "cert-pkcs12-b64" => params.cert_pkcs12_b64 = Some(v),
                 "cert-password" => params.cert_password = Some(v),
                 "cert-id" => params.cert_id = Some(v),
                 "if-name" => params.if_name = Some(v),
+                "if-txqueuelen" => params.if_txqueuelen = v.parse().unwrap_or_default(),
+                "if-mtu" => params.if_mtu = v.parse().unwrap_or_default(),
+                "mtu-discovery" => params.mtu_discovery = v.parse().unwrap_or_default(),
+                "mtu-discovery-fallback" => {
+                    params.mtu_discovery_fallback = v.parse().unwrap_or(DEFAULT_MTU_DISCOVERY_FALLBACK);
+                }
+                "if-gro" => params.if_gro = v.parse().ok(),
+                "if-gso" => params.if_gso = v.parse().ok(),
+                "run-as" => params.run_as = v,
+                "post-connect-script" => params.post_connect_script = Some(v.into()),
+                "post-disconnect-script" => params.post_disconnect_script = Some(v.into()),
                 "no-keychain" => params.no_keychain = v.parse().unwrap_or_default(),
                 "server-prompt" => params.server_prompt = v.parse().unwrap_or_default(),
+                "user-agent" => params.user_agent = v,
+                "client-os" if !v.is_empty() => params.client_os = v,
+                "device-id" if !v.is_empty() => match util::parse_device_id(&v) {
+                    Ok(device_id) => params.device_id = Some(device_id),
+                    Err(e) => warn!("Ignoring device-id: {}", e),
+                },
+                "proxy-url" => params.proxy_url = Some(v).filter(|v| !v.is_empty()),
+                "bind-address" => params.bind_address = v.parse().ok(),
                 "esp-lifetime" => {
                     params.esp_lifetime = v.parse::<u64>().ok().map_or(DEFAULT_ESP_LIFETIME, Duration::from_secs);
                 }
@@ -381,11 +917,89 @@ impl TunnelParams {
                 "ike-lifetime" => {
                     params.ike_lifetime = v.parse::<u64>().ok().map_or(DEFAULT_IKE_LIFETIME, Duration::from_secs);
                 }
+                "identity-timeout" => {
+                    params.identity_timeout = v.parse::<u64>().ok().filter(|v| *v > 0).map(Duration::from_secs);
+                }
+                "connect-timeout" => {
+                    params.connect_timeout = v.parse::<u64>().ok().filter(|v| *v > 0).map(Duration::from_secs);
+                }
                 "ike-port" => params.ike_port = v.parse().ok().unwrap_or(DEFAULT_IKE_PORT),
+                "natt-source-port" => params.natt_source_port = v.parse().unwrap_or_default(),
+                "natt-probe-count" => {
+                    params.natt_probe_count = v.parse().ok().filter(|v| *v > 0).unwrap_or(DEFAULT_NATT_PROBE_COUNT);
+                }
+                "natt-probe-timeout" => {
+                    params.natt_probe_timeout = v
+                        .parse::<u64>()
+                        .ok()
+                        .filter(|v| *v > 0)
+                        .map_or(DEFAULT_NATT_PROBE_TIMEOUT, Duration::from_secs);
+                }
                 "ike-persist" => params.ike_persist = v.parse().unwrap_or_default(),
-                "ike-transport" => params.ike_transport = v.parse().unwrap_or_default(),
+                "signout-on-disconnect" => params.signout_on_disconnect = v.parse().unwrap_or_default(),
+                "session-path" if !v.is_empty() => params.session_path = v.into(),
+                "ike-transport" => match TransportType::parse_ike(&v) {
+                    Ok(ike_transport) => params.ike_transport = ike_transport,
+                    Err(e) => warn!("Ignoring ike-transport: {}", e),
+                },
+                "tcpt-only" => params.tcpt_only = v.parse().unwrap_or_default(),
+                "no-server-redirect" => params.no_server_redirect = v.parse().unwrap_or_default(),
+                "server-info-retries" => {
+                    params.server_info_retries = v.parse().unwrap_or(DEFAULT_SERVER_INFO_RETRIES);
+                }
+                "server-info-cache-ttl" => {
+                    params.server_info_cache_ttl = v
+                        .parse::<u64>()
+                        .map(Duration::from_secs)
+                        .unwrap_or(DEFAULT_SERVER_INFO_CACHE_TTL);
+                }
                 "no-keepalive" => params.no_keepalive = v.parse().unwrap_or_default(),
+                "keepalive-interval" => {
+                    params.keepalive_interval = v
+                        .parse::<u64>()
+                        .ok()
+                        .map_or(DEFAULT_KEEPALIVE_INTERVAL, Duration::from_secs);
+                }
+                "keepalive-max-retries" => {
+                    params.keepalive_max_retries = v
+                        .parse()
+                        .ok()
+                        .filter(|v| *v > 0)
+                        .unwrap_or(DEFAULT_KEEPALIVE_MAX_RETRIES);
+                }
+                "keepalive-port" => {
+                    params.keepalive_port = v.parse().ok().filter(|v| *v > 0).unwrap_or(Self::IPSEC_KEEPALIVE_PORT);
+                }
+                "keepalive-thread" => params.keepalive_thread = v.parse().unwrap_or_default(),
+                "verify-connectivity" => params.verify_connectivity = v.parse().unwrap_or_default(),
+                "verify-timeout" => {
+                    params.verify_timeout = v
+                        .parse::<u64>()
+                        .ok()
+                        .map_or(DEFAULT_VERIFY_TIMEOUT, Duration::from_secs);
+                }
                 "icon-theme" => params.icon_theme = v.parse().unwrap_or_default(),
+                "notify-rekey" => params.notify_rekey = v.parse().unwrap_or_default(),
+                "auto-reload" => params.auto_reload = v.parse().unwrap_or_default(),
+                "auto-reauth" => params.auto_reauth = v.parse().unwrap_or_default(),
+                "auto-reconnect" => params.auto_reconnect = v.parse().unwrap_or_default(),
+                "reconnect-max-attempts" => params.reconnect_max_attempts = v.parse().unwrap_or_default(),
+                "reconnect-max-delay" => {
+                    params.reconnect_max_delay = v
+                        .parse::<u64>()
+                        .ok()
+                        .filter(|v| *v > 0)
+                        .map_or(DEFAULT_RECONNECT_MAX_DELAY, Duration::from_secs);
+                }
+                "otp-listen-address" => {
+                    params.otp_listen_address = v.parse().unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+                }
+                "otp-listen-port" => params.otp_listen_port = v.parse().ok().unwrap_or(DEFAULT_OTP_LISTEN_PORT),
+                "otp-https" => params.otp_https = v.parse().unwrap_or_default(),
+                "otp-redirect-path" => params.otp_redirect_path = v,
+                "events-port" => params.events_port = v.parse().ok().unwrap_or(DEFAULT_EVENTS_PORT),
+                "dump-ccc" => params.dump_ccc = Some(v.into()),
+                "dump-raw" => params.dump_raw = v.parse().unwrap_or_default(),
                 other => {
                     warn!("Ignoring unknown option: {}", other);
                 }
@@ -393,21 +1007,181 @@ impl TunnelParams {
         }
         path.as_ref().clone_into(&mut params.config_file);
         params.decode_password()?;
+        params.apply_realm_credentials();
+        params.resolve_password_env();
 
         Ok(params)
     }
 
+    /// Rewrite the renamed keys of the main config file with their current names. The file is replaced atomically
+    /// and only if a renamed key was found, drop-in fragments are only migrated when they are read.
+    /// Returns true if the file was upgraded.
+    pub fn migrate_file<P: AsRef<Path>>(path: P) -> anyhow::Result<bool> {
+        let path = path.as_ref();
+
+        let Some(migrated) = migrate_config(&fs::read_to_string(path)?) else {
+            return Ok(false);
+        };
+
+        // the temporary file in the same directory keeps the permissions and makes the rename atomic
+        let mut tmp_name = path.as_os_str().to_owned();
+        tmp_name.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_name);
+
+        let result = fs::write(&tmp_path, migrated)
+            .and_then(|_| fs::set_permissions(&tmp_path, fs::metadata(path)?.permissions()))
+            .and_then(|_| fs::rename(&tmp_path, path));
+
+        if let Err(e) = result {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(anyhow::Error::from(e).context(format!("Unable to upgrade {}", path.display())));
+        }
+
+        info!("Upgraded {} to config version {}", path.display(), CONFIG_VERSION);
+
+        Ok(true)
+    }
+
+    // drop-in fragments are applied in lexical order after the main file, later ones override earlier keys
+    fn read_config_files(path: &Path) -> anyhow::Result<Vec<(PathBuf, HashMap<String, String>)>> {
+        let mut result = vec![(path.to_owned(), parse_config_keys(&fs::read_to_string(path)?)?)];
+
+        for fragment in Self::drop_in_fragments(path) {
+            let data = fs::read_to_string(&fragment)
+                .with_context(|| format!("Cannot read config fragment {}", fragment.display()))?;
+            result.push((fragment, parse_config_keys(&data)?));
+        }
+
+        Ok(result)
+    }
+
+    /// Files which the configuration keys of the given config file are taken from, drop-in fragments included
+    pub fn config_sources<P: AsRef<Path>>(path: P) -> anyhow::Result<BTreeMap<String, ConfigSource>> {
+        let mut sources = BTreeMap::new();
+
+        for (file, fragment) in Self::read_config_files(path.as_ref())? {
+            for key in fragment.into_keys() {
+                sources.insert(key, ConfigSource::File(file.clone()));
+            }
+        }
+
+        Ok(sources)
+    }
+
+    // key and value of the serialized config line, non-empty secrets are masked
+    fn redacted_line(line: &str) -> Option<(&str, &str)> {
+        let (key, value) = line.split_once('=')?;
+        if SECRET_CONFIG_KEYS.contains(&key) && !value.is_empty() {
+            Some((key, "****"))
+        } else {
+            Some((key, value))
+        }
+    }
+
+    /// Serialize the parameters with masked secrets
+    pub fn to_redacted_config(&self) -> anyhow::Result<String> {
+        let mut buf = Cursor::new(Vec::new());
+
+        for (key, value) in self.to_config()?.lines().filter_map(Self::redacted_line) {
+            writeln!(buf, "{key}={value}")?;
+        }
+
+        Ok(String::from_utf8(buf.into_inner())?)
+    }
+
+    /// Serialize the parameters with masked secrets, annotating each value with its source.
+    /// Keys which are missing from the sources are reported as defaults.
+    pub fn to_annotated_config(&self, sources: &BTreeMap<String, ConfigSource>) -> anyhow::Result<String> {
+        let mut buf = Cursor::new(Vec::new());
+
+        for (key, value) in self.to_config()?.lines().filter_map(Self::redacted_line) {
+            let source = sources.get(key).cloned().unwrap_or(ConfigSource::Default);
+            writeln!(buf, "{key}={value} # {source}")?;
+        }
+
+        Ok(String::from_utf8(buf.into_inner())?)
+    }
+
+    /// Directory with drop-in config fragments for the given config file: `<config>.d`
+    pub fn drop_in_dir<P: AsRef<Path>>(path: P) -> PathBuf {
+        let mut dir = path.as_ref().as_os_str().to_owned();
+        dir.push(".d");
+        dir.into()
+    }
+
+    fn drop_in_fragments(path: &Path) -> Vec<PathBuf> {
+        let Ok(entries) = fs::read_dir(Self::drop_in_dir(path)) else {
+            return Vec::new();
+        };
+
+        let mut fragments = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "conf"))
+            .collect::<Vec<_>>();
+        fragments.sort();
+        fragments
+    }
+
     pub fn save(&self) -> anyhow::Result<()> {
+        let data = self.to_config()?;
+
+        PathBuf::from(&self.config_file).parent().iter().for_each(|dir| {
+            let _ = fs::create_dir_all(dir);
+        });
+        fs::write(&self.config_file, data)?;
+
+        Ok(())
+    }
+
+    /// Serialize the parameters into the config file format
+    pub fn to_config(&self) -> anyhow::Result<String> {
         let mut buf = Cursor::new(Vec::new());
+        writeln!(buf, "config-version={}", CONFIG_VERSION)?;
         writeln!(buf, "server-name={}", self.server_name)?;
-        writeln!(buf, "user-name={}", self.user_name)?;
-        writeln!(
-            buf,
-            "password={}",
-            base64::engine::general_purpose::STANDARD.encode(&self.password)
-        )?;
+        let (user_name, password) = match self.default_credentials {
+            Some(ref credentials) => (&credentials.user_name, &credentials.password),
+            None => (&self.user_name, &self.password),
+        };
+        writeln!(buf, "user-name={}", user_name)?;
+        // the password taken from the environment must never end up in the file
+        if let Some(ref password_env) = self.password_env {
+            writeln!(buf, "password-env={password_env}")?;
+            writeln!(buf, "password=")?;
+        } else {
+            writeln!(
+                buf,
+                "password={}",
+                base64::engine::general_purpose::STANDARD.encode(password)
+            )?;
+        }
+        if !self.realm_credentials.is_empty() {
+            let entries = self
+                .realm_credentials
+                .iter()
+                .map(|(realm, credentials)| {
+                    // the password from the environment may have been remembered for any login type
+                    let password = if self.password_env.is_some() {
+                        String::new()
+                    } else {
+                        base64::engine::general_purpose::STANDARD.encode(&credentials.password)
+                    };
+                    format!("{}:{}:{}", realm, credentials.user_name, password)
+                })
+                .collect::<Vec<_>>();
+            writeln!(buf, "realm-credentials={}", entries.join(","))?;
+        }
+        if let Some(ref totp_secret) = self.totp_secret {
+            writeln!(buf, "totp-secret={totp_secret}")?;
+        }
+        writeln!(buf, "password-factor={}", self.password_factor)?;
+        if let Some(ref otp_source) = self.otp_source {
+            writeln!(buf, "otp-source={otp_source}")?;
+        }
         writeln!(buf, "search-domains={}", self.search_domains.join(","))?;
         writeln!(buf, "ignore-search-domains={}", self.ignore_search_domains.join(","))?;
+        writeln!(buf, "max-search-domains={}", self.max_search_domains)?;
+        writeln!(buf, "search-domains-policy={}", self.search_domains_policy.as_str())?;
         writeln!(
             buf,
             "dns-servers={}",
@@ -417,23 +1191,25 @@ impl TunnelParams {
                 .collect::<Vec<_>>()
                 .join(",")
         )?;
+        writeln!(buf, "replace-dns-servers={}", self.replace_dns_servers)?;
         writeln!(
             buf,
             "ignore-dns-servers={}",
             self.ignore_dns_servers
                 .iter()
-                .map(|r| r.to_string())
+                .map(util::format_ipv4_net)
                 .collect::<Vec<_>>()
                 .join(",")
         )?;
         writeln!(buf, "default-route={}", self.default_route)?;
+        writeln!(buf, "keep-fallback-default-route={}", self.keep_fallback_default_route)?;
         writeln!(buf, "no-routing={}", self.no_routing)?;
         writeln!(
             buf,
             "add-routes={}",
             self.add_routes
                 .iter()
-                .map(|r| r.to_string())
+                .map(|r| r.trunc().to_string())
                 .collect::<Vec<_>>()
                 .join(",")
         )?;
@@ -442,14 +1218,24 @@ impl TunnelParams {
             "ignore-routes={}",
             self.ignore_routes
                 .iter()
-                .map(|r| r.to_string())
+                .map(|r| r.trunc().to_string())
                 .collect::<Vec<_>>()
                 .join(",")
         )?;
+        writeln!(buf, "on-route-conflict={}", self.on_route_conflict.as_str())?;
+        writeln!(buf, "app-routing-users={}", self.app_routing_users.join(","))?;
+        if let Some(ref app_routing_cgroup) = self.app_routing_cgroup {
+            writeln!(buf, "app-routing-cgroup={}", app_routing_cgroup)?;
+        }
         writeln!(buf, "no-dns={}", self.no_dns)?;
+        writeln!(buf, "disable-ipv6={}", self.disable_ipv6)?;
+        writeln!(buf, "teardown-grace={}", self.teardown_grace.as_secs())?;
         writeln!(buf, "no-cert-check={}", self.no_cert_check)?;
         writeln!(buf, "ignore-server-cert={}", self.ignore_server_cert)?;
         writeln!(buf, "ipsec-cert-check={}", self.ipsec_cert_check)?;
+        if let Some(ref fingerprint) = self.server_cert_fingerprint {
+            writeln!(buf, "server-cert-fingerprint={fingerprint}")?;
+        }
         writeln!(buf, "tunnel-type={}", self.tunnel_type.as_str())?;
         writeln!(
             buf,
@@ -461,10 +1247,14 @@ impl TunnelParams {
                 .join(",")
         )?;
         writeln!(buf, "login-type={}", self.login_type)?;
+        writeln!(buf, "allow-hidden-realms={}", self.allow_hidden_realms)?;
         writeln!(buf, "cert-type={}", self.cert_type)?;
         if let Some(ref cert_path) = self.cert_path {
             writeln!(buf, "cert-path={}", cert_path.display())?;
         }
+        if let Some(ref cert_pkcs12_b64) = self.cert_pkcs12_b64 {
+            writeln!(buf, "cert-pkcs12-b64={}", cert_pkcs12_b64)?;
+        }
         if let Some(ref cert_password) = self.cert_password {
             writeln!(buf, "cert-password={cert_password}")?;
         }
@@ -474,25 +1264,106 @@ impl TunnelParams {
         if let Some(ref if_name) = self.if_name {
             writeln!(buf, "if-name={if_name}")?;
         }
+        writeln!(buf, "if-txqueuelen={}", self.if_txqueuelen)?;
+        writeln!(buf, "if-mtu={}", self.if_mtu)?;
+        writeln!(buf, "mtu-discovery={}", self.mtu_discovery)?;
+        writeln!(buf, "mtu-discovery-fallback={}", self.mtu_discovery_fallback)?;
+        if let Some(if_gro) = self.if_gro {
+            writeln!(buf, "if-gro={if_gro}")?;
+        }
+        if let Some(if_gso) = self.if_gso {
+            writeln!(buf, "if-gso={if_gso}")?;
+        }
+        writeln!(buf, "run-as={}", self.run_as)?;
+        if let Some(ref script) = self.post_connect_script {
+            writeln!(buf, "post-connect-script={}", script.display())?;
+        }
+        if let Some(ref script) = self.post_disconnect_script {
+            writeln!(buf, "post-disconnect-script={}", script.display())?;
+        }
         writeln!(buf, "no-keychain={}", self.no_keychain)?;
         writeln!(buf, "server-prompt={}", self.server_prompt)?;
         writeln!(buf, "esp-lifetime={}", self.esp_lifetime.as_secs())?;
         writeln!(buf, "esp-transport={}", self.esp_transport.as_str())?;
         writeln!(buf, "ike-lifetime={}", self.ike_lifetime.as_secs())?;
+        if let Some(identity_timeout) = self.identity_timeout {
+            writeln!(buf, "identity-timeout={}", identity_timeout.as_secs())?;
+        }
+        if let Some(connect_timeout) = self.connect_timeout {
+            writeln!(buf, "connect-timeout={}", connect_timeout.as_secs())?;
+        }
         writeln!(buf, "ike-port={}", self.ike_port)?;
+        writeln!(buf, "natt-source-port={}", self.natt_source_port)?;
+        writeln!(buf, "natt-probe-count={}", self.natt_probe_count)?;
+        writeln!(buf, "natt-probe-timeout={}", self.natt_probe_timeout.as_secs())?;
         writeln!(buf, "ike-persist={}", self.ike_persist)?;
+        writeln!(buf, "signout-on-disconnect={}", self.signout_on_disconnect)?;
+        writeln!(buf, "session-path={}", self.session_path.display())?;
         writeln!(buf, "log-level={}", self.log_level)?;
+        if let Some(ref log_file) = self.log_file {
+            writeln!(buf, "log-file={}", log_file.display())?;
+        }
+        writeln!(buf, "log-file-count={}", self.log_file_count)?;
         writeln!(buf, "client-mode={}", self.client_mode)?;
+        writeln!(buf, "client-os={}", self.client_os)?;
+        if let Some(ref device_id) = self.device_id {
+            writeln!(buf, "device-id={device_id}")?;
+        }
+        writeln!(buf, "user-agent={}", self.user_agent)?;
+        if let Some(ref proxy_url) = self.proxy_url {
+            writeln!(buf, "proxy-url={}", proxy_url)?;
+        }
+        if let Some(bind_address) = self.bind_address {
+            writeln!(buf, "bind-address={}", bind_address)?;
+        }
         writeln!(buf, "no-keepalive={}", self.no_keepalive)?;
+        writeln!(buf, "keepalive-interval={}", self.keepalive_interval.as_secs())?;
+        writeln!(buf, "keepalive-max-retries={}", self.keepalive_max_retries)?;
+        writeln!(buf, "keepalive-port={}", self.keepalive_port)?;
+        writeln!(buf, "keepalive-thread={}", self.keepalive_thread)?;
+        writeln!(buf, "verify-connectivity={}", self.verify_connectivity)?;
+        writeln!(buf, "verify-timeout={}", self.verify_timeout.as_secs())?;
         writeln!(buf, "icon-theme={}", self.icon_theme)?;
+        writeln!(buf, "notify-rekey={}", self.notify_rekey)?;
+        writeln!(buf, "auto-reload={}", self.auto_reload)?;
+        writeln!(buf, "auto-reauth={}", self.auto_reauth)?;
+        writeln!(buf, "auto-reconnect={}", self.auto_reconnect)?;
+        writeln!(buf, "reconnect-max-attempts={}", self.reconnect_max_attempts)?;
+        writeln!(buf, "reconnect-max-delay={}", self.reconnect_max_delay.as_secs())?;
         writeln!(buf, "ike-transport={}", self.ike_transport.as_str())?;
+        writeln!(buf, "tcpt-only={}", self.tcpt_only)?;
+        writeln!(buf, "no-server-redirect={}", self.no_server_redirect)?;
+        writeln!(buf, "server-info-retries={}", self.server_info_retries)?;
+        writeln!(buf, "server-info-cache-ttl={}", self.server_info_cache_ttl.as_secs())?;
+        writeln!(buf, "otp-listen-address={}", self.otp_listen_address)?;
+        writeln!(buf, "otp-listen-port={}", self.otp_listen_port)?;
+        writeln!(buf, "otp-https={}", self.otp_https)?;
+        writeln!(buf, "otp-redirect-path={}", self.otp_redirect_path)?;
+        writeln!(buf, "events-port={}", self.events_port)?;
+        if let Some(ref dump_ccc) = self.dump_ccc {
+            writeln!(buf, "dump-ccc={}", dump_ccc.display())?;
+        }
+        writeln!(buf, "dump-raw={}", self.dump_raw)?;
 
-        PathBuf::from(&self.config_file).parent().iter().for_each(|dir| {
-            let _ = fs::create_dir_all(dir);
-        });
-        fs::write(&self.config_file, buf.into_inner())?;
+        Ok(String::from_utf8(buf.into_inner())?)
+    }
 
-        Ok(())
+    /// Use the credentials remembered for the selected login type instead of the flat ones, if any.
+    /// The flat ones are kept in `default_credentials` so that they are saved back unchanged.
+    pub fn apply_realm_credentials(&mut self) {
+        if let Some(credentials) = self.realm_credentials.get(&self.login_type) {
+            if self.default_credentials.is_none() {
+                self.default_credentials = Some(RealmCredentials {
+                    user_name: self.user_name.clone(),
+                    password: self.password.clone(),
+                });
+            }
+            self.user_name.clone_from(&credentials.user_name);
+            self.password.clone_from(&credentials.password);
+        } else if let Some(credentials) = self.default_credentials.take() {
+            self.user_name = credentials.user_name;
+            self.password = credentials.password;
+        }
     }
 
     pub fn decode_password(&mut self) -> anyhow::Result<()> {
@@ -503,6 +1374,70 @@ impl TunnelParams {
         Ok(())
     }
 
+    /// Take the password from the environment variable given by the `password-env` option, if any.
+    /// The password from the config file is not used in this case, even if the variable is unset.
+    pub fn resolve_password_env(&mut self) {
+        if let Some(ref var) = self.password_env {
+            self.password = std::env::var(var).unwrap_or_default();
+        }
+    }
+
+    /// Fail if the password must be taken from the environment but the variable is unset or empty
+    pub fn check_password_env(&self) -> anyhow::Result<()> {
+        match self.password_env {
+            Some(ref var) if self.password.is_empty() => {
+                anyhow::bail!("Password environment variable {} is not set", var)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// CA root certificate files, directories are expanded to all `.pem` and `.crt` files within them
+    pub fn ca_cert_files(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let mut result = Vec::new();
+
+        for path in &self.ca_cert {
+            if path.is_dir() {
+                let mut files = fs::read_dir(path)
+                    .with_context(|| format!("Cannot read CA root directory {}", path.display()))?
+                    .flatten()
+                    .map(|entry| entry.path())
+                    .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "pem" || ext == "crt"))
+                    .collect::<Vec<_>>();
+                files.sort();
+                result.extend(files);
+            } else if path.exists() {
+                result.push(path.clone());
+            } else {
+                anyhow::bail!("CA root path does not exist: {}", path.display());
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Decode the inline PKCS12 certificate given either as base64 or as `@env:VAR` reference.
+    pub fn inline_pkcs12(&self) -> anyhow::Result<Option<SecretBytes>> {
+        let Some(ref value) = self.cert_pkcs12_b64 else {
+            return Ok(None);
+        };
+
+        if self.cert_path.is_some() {
+            anyhow::bail!("Only one of cert-path and cert-pkcs12-b64 can be specified!");
+        }
+
+        let encoded = match value.strip_prefix("@env:") {
+            Some(var) => std::env::var(var).with_context(|| format!("No environment variable {var}"))?,
+            None => value.clone(),
+        };
+
+        let data = base64::engine::general_purpose::STANDARD
+            .decode(encoded.trim())
+            .context("Invalid base64 PKCS12 data")?;
+
+        Ok(Some(SecretBytes(data)))
+    }
+
     pub fn default_config_dir() -> PathBuf {
         ProjectDirs::from("", "", "snx-rs")
             .expect("No home directory!")
@@ -514,3 +1449,192 @@ impl TunnelParams {
         Self::default_config_dir().join("snx-rs.conf")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_otp_source() {
+        assert_eq!(
+            "pipe:/run/snx-rs/otp".parse::<OtpSource>().unwrap(),
+            OtpSource::Pipe("/run/snx-rs/otp".into())
+        );
+        assert_eq!(
+            "FILE:/tmp/otp.txt".parse::<OtpSource>().unwrap(),
+            OtpSource::File("/tmp/otp.txt".into())
+        );
+        assert_eq!(
+            OtpSource::File("/tmp/otp.txt".into())
+                .to_string()
+                .parse::<OtpSource>()
+                .unwrap(),
+            OtpSource::File("/tmp/otp.txt".into())
+        );
+        assert!("/tmp/otp.txt".parse::<OtpSource>().is_err());
+        assert!("pipe:".parse::<OtpSource>().is_err());
+        assert!("socket:/tmp/otp".parse::<OtpSource>().is_err());
+    }
+
+    #[test]
+    fn test_tunnel_type_index() {
+        for tunnel_type in [TunnelType::Ipsec, TunnelType::Ssl, TunnelType::L2tp] {
+            assert_eq!(TunnelType::from(tunnel_type.as_u32()), tunnel_type);
+        }
+    }
+
+    #[test]
+    fn test_redacted_config() {
+        let params = TunnelParams {
+            user_name: "user1".to_owned(),
+            password: "secret-password".to_owned(),
+            totp_secret: Some("JBSWY3DPEHPK3PXP".to_owned()),
+            cert_password: Some("secret-cert-password".to_owned()),
+            realm_credentials: BTreeMap::from([(
+                "vpn_Username_Password".to_owned(),
+                RealmCredentials {
+                    user_name: "user2".to_owned(),
+                    password: "secret-realm-password".to_owned(),
+                },
+            )]),
+            ..Default::default()
+        };
+
+        let config = params.to_redacted_config().unwrap();
+        assert!(config.contains("user-name=user1\n"));
+        for key in SECRET_CONFIG_KEYS
+            .iter()
+            .filter(|key| **key != "proxy-url" && **key != "cert-pkcs12-b64")
+        {
+            assert!(config.contains(&format!("\n{key}=****\n")), "{key} is not masked");
+        }
+        assert!(!config.contains("secret-"));
+        assert!(!config.contains("JBSWY3DPEHPK3PXP"));
+        assert!(!config.contains("user2"));
+    }
+
+    #[test]
+    fn test_rotate_servers() {
+        let mut params = TunnelParams {
+            server_name: "gw1.example.com, gw2.example.com,,gw3.example.com".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.server_names(),
+            ["gw1.example.com", "gw2.example.com", "gw3.example.com"]
+        );
+
+        params.rotate_servers("GW2.example.com");
+        assert_eq!(params.server_name, "gw3.example.com,gw1.example.com,gw2.example.com");
+
+        params.rotate_servers("unknown.example.com");
+        assert_eq!(params.server_name, "gw3.example.com,gw1.example.com,gw2.example.com");
+
+        params.rotate_servers("gw2.example.com");
+        assert_eq!(params.server_name, "gw3.example.com,gw1.example.com,gw2.example.com");
+    }
+
+    #[test]
+    fn test_migrate_config() {
+        let config = "server-name=vpn.example.com\n# old options\ntransport-type=tcpt\nclient-cert=/etc/snx/user.p12\n";
+        assert_eq!(
+            migrate_config(config).unwrap(),
+            "config-version=1\nserver-name=vpn.example.com\n# old options\nesp-transport=tcpt\ncert-path=/etc/snx/user.p12\n"
+        );
+
+        // the current key wins over the renamed one
+        let config = "esp-transport=udp\ntransport-type=tcpt\n";
+        assert_eq!(migrate_config(config).unwrap(), "config-version=1\nesp-transport=udp\n");
+
+        assert!(migrate_config("config-version=1\nserver-name=vpn.example.com\n").is_none());
+
+        // unversioned files without renamed keys are left as they are
+        assert!(migrate_config("server-name=vpn.example.com\n").is_none());
+    }
+
+    #[test]
+    fn test_load_migrated_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snx-rs.conf");
+        fs::write(&path, "server-name=vpn.example.com\nno-cert-name-check=true\n").unwrap();
+
+        // loading never writes the file
+        let params = TunnelParams::load(&path).unwrap();
+        assert!(params.no_cert_check);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "server-name=vpn.example.com\nno-cert-name-check=true\n"
+        );
+
+        assert!(TunnelParams::migrate_file(&path).unwrap());
+        let config = fs::read_to_string(&path).unwrap();
+        assert!(config.starts_with("config-version=1\n"));
+        assert!(config.contains("no-cert-check=true"));
+        assert!(!dir.path().join("snx-rs.conf.tmp").exists());
+
+        assert!(!TunnelParams::migrate_file(&path).unwrap());
+        assert!(TunnelParams::load(&path).unwrap().no_cert_check);
+    }
+
+    #[test]
+    fn test_transport_types() {
+        assert_eq!(TunnelParams::default().esp_transport, TransportType::Udp);
+        assert_eq!(TunnelParams::default().ike_transport, TransportType::Udp);
+
+        assert_eq!(TransportType::parse_ike("tcpt").unwrap(), TransportType::Tcpt);
+        assert!(TransportType::parse_ike("auto").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snx-rs.conf");
+
+        fs::write(&path, "esp-transport=auto\nike-transport=auto\n").unwrap();
+        let params = TunnelParams::load(&path).unwrap();
+        assert_eq!(params.esp_transport, TransportType::Auto);
+        assert_eq!(params.ike_transport, TransportType::Udp);
+
+        fs::write(&path, "esp-transport=invalid\nike-transport=tcpt\n").unwrap();
+        let params = TunnelParams::load(&path).unwrap();
+        assert_eq!(params.esp_transport, TransportType::Udp);
+        assert_eq!(params.ike_transport, TransportType::Tcpt);
+    }
+
+    #[test]
+    fn test_realm_credentials_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snx-rs.conf");
+        let config = format!(
+            "server-name=vpn.example.com\nlogin-type=vpn_Mfa\nuser-name=user1\npassword={}\nrealm-credentials=vpn_Mfa:user2:{}\n",
+            base64::engine::general_purpose::STANDARD.encode("password1"),
+            base64::engine::general_purpose::STANDARD.encode("password2"),
+        );
+        fs::write(&path, config).unwrap();
+
+        let mut params = TunnelParams::load(&path).unwrap();
+        assert_eq!(params.user_name, "user2");
+        assert_eq!(params.password, "password2");
+
+        // the flat credentials stay the fallback for the other login types
+        params.save().unwrap();
+        assert!(fs::read_to_string(&path).unwrap().contains("\nuser-name=user1\n"));
+        let mut params = TunnelParams::load(&path).unwrap();
+        assert_eq!(params.user_name, "user2");
+        assert_eq!(params.password, "password2");
+
+        params.login_type = "vpn_Username_Password".to_owned();
+        params.apply_realm_credentials();
+        assert_eq!(params.user_name, "user1");
+        assert_eq!(params.password, "password1");
+        assert!(params.default_credentials.is_none());
+
+        params.login_type = "vpn_Mfa".to_owned();
+        params.apply_realm_credentials();
+        assert_eq!(params.user_name, "user2");
+        assert_eq!(
+            params.default_credentials,
+            Some(RealmCredentials {
+                user_name: "user1".to_owned(),
+                password: "password1".to_owned(),
+            })
+        );
+    }
+}
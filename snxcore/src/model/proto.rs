@@ -1,5 +1,6 @@
 use std::collections::BTreeMap;
 use std::net::Ipv4Addr;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
@@ -94,6 +95,7 @@ pub struct OfficeMode {
     pub keep_address: Option<bool>,
     pub dns_servers: Option<Vec<Ipv4Addr>>,
     pub dns_suffix: Option<StringList>,
+    pub wins_servers: Option<Vec<Ipv4Addr>>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -303,6 +305,16 @@ pub struct AuthResponse {
 pub struct ClientSettingsResponse {
     pub gw_internal_ip: Ipv4Addr,
     pub updated_policies: UpdatedPolicies,
+    pub neo_user_re_auth_timeout: Option<u64>,
+}
+
+impl ClientSettingsResponse {
+    /// Interval after which the gateway expects the user to authenticate again
+    pub fn reauth_timeout(&self) -> Option<Duration> {
+        self.neo_user_re_auth_timeout
+            .filter(|timeout| *timeout > 0)
+            .map(Duration::from_secs)
+    }
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -377,6 +389,7 @@ pub struct ConnectivityInfo {
     pub connect_with_certificate_url: String,
     pub cookie_name: String,
     pub internal_ca_fingerprint: BTreeMap<String, String>,
+    pub extended_connect_timeout_for_idp: Option<u64>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -395,6 +408,10 @@ pub struct LoginOption {
 }
 
 impl LoginOption {
+    pub fn is_hidden(&self) -> bool {
+        self.show_realm == 0
+    }
+
     pub fn unspecified() -> Self {
         Self {
             id: "vpn_Username_Password".to_string(),
@@ -1,4 +1,4 @@
-use std::{fmt, marker::PhantomData};
+use std::{fmt, marker::PhantomData, ops::Deref};
 
 use serde::{
     de::{Error, Visitor},
@@ -164,3 +164,31 @@ impl<T: TryFrom<u64>> Visitor<'_> for MaybeVisitor<T> {
         Ok(Maybe(None))
     }
 }
+
+/// Sensitive binary data which is zeroed on drop.
+/// It is intentionally not `Clone`, so that no copies are left behind unzeroed.
+#[derive(Default, PartialEq)]
+pub struct SecretBytes(pub Vec<u8>);
+
+impl Deref for SecretBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        for b in self.0.iter_mut() {
+            // volatile write so that the compiler does not optimize it away
+            unsafe { std::ptr::write_volatile(b, 0) };
+        }
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{} bytes>", self.0.len())
+    }
+}
@@ -1,4 +1,8 @@
-use std::{net::Ipv4Addr, sync::Arc, time::Duration};
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::anyhow;
 use async_trait::async_trait;
@@ -8,12 +12,14 @@ use tokio::net::UdpSocket;
 #[cfg(target_os = "linux")]
 use linux as platform_impl;
 pub use platform_impl::{
-    acquire_password, configure_device, delete_device, get_machine_uuid, init,
+    acquire_password, configure_device, delete_device, discover_device_mtu, drop_privileges, flush_resolver_cleanup,
+    get_device_stats, get_machine_uuid, init,
     net::{
-        add_route, add_routes, get_default_ip, is_online, poll_online, remove_default_route, setup_default_route,
-        start_network_state_monitoring,
+        add_ipv6_address, add_route, add_routes, get_default_ip, is_online, poll_online, remove_app_routing,
+        remove_default_route, setup_app_routing, setup_default_route, start_network_state_monitoring,
     },
-    new_resolver_configurator, new_tun_config, store_password, IpsecImpl, SingleInstance,
+    new_resolver_configurator, new_tun_config, store_password, tune_device, user_home_dir, watch_file, IpsecImpl,
+    SingleInstance,
 };
 
 use crate::model::{params::TunnelParams, IpsecSession};
@@ -68,7 +74,7 @@ async fn udp_send_receive(socket: &UdpSocket, data: &[u8], timeout: Duration) ->
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct ResolverConfig {
     pub search_domains: Vec<String>,
-    pub dns_servers: Vec<Ipv4Addr>,
+    pub dns_servers: Vec<IpAddr>,
 }
 
 #[async_trait]
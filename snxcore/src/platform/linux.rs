@@ -1,26 +1,43 @@
-use std::{collections::HashMap, fs, os::fd::AsRawFd, time::Duration};
+use std::{
+    collections::HashMap,
+    fs,
+    io::Write,
+    net::Ipv4Addr,
+    os::{
+        fd::{AsFd, AsRawFd, RawFd},
+        unix::fs::OpenOptionsExt,
+    },
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use anyhow::{anyhow, Context};
 use nix::{
-    fcntl::{self, FcntlArg, OFlag},
-    sys::stat::Mode,
+    errno::Errno,
+    fcntl::{self, FcntlArg},
+    sys::inotify::{AddWatchFlags, InitFlags, Inotify},
     unistd,
 };
 use secret_service::{EncryptionType, SecretService};
-use tokio::net::UdpSocket;
-use tracing::debug;
+use tokio::{io::unix::AsyncFd, net::UdpSocket, sync::mpsc};
+use tracing::{debug, warn};
 use uuid::Uuid;
 
-pub use resolver::new_resolver_configurator;
+pub use resolver::{flush_resolver_cleanup, keep_resolv_conf_open, new_resolver_configurator};
 pub use xfrm::XfrmConfigurator as IpsecImpl;
 
-use crate::platform::{UdpEncap, UdpSocketExt};
+use crate::{
+    model::{params::TunnelParams, TrafficStats},
+    platform::{UdpEncap, UdpSocketExt},
+};
 
 pub mod net;
 pub mod resolver;
 pub mod xfrm;
 
 const UDP_ENCAP_ESPINUDP: libc::c_int = 2; // from /usr/include/linux/udp.h
+const MTU_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_TUNNEL_MTU: u16 = 1500;
 
 pub fn init() {
     #[cfg(openssl3)]
@@ -135,42 +152,257 @@ pub async fn store_password(user_name: &str, password: &str) -> anyhow::Result<(
     Ok(())
 }
 
+struct InotifyFd(Inotify);
+
+impl AsRawFd for InotifyFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_fd().as_raw_fd()
+    }
+}
+
+/// Watch the given file for modifications. The parent directory is watched so that
+/// editors which replace the file via rename are also detected.
+/// The watch runs as a task of the current runtime and stops when the receiver is dropped.
+pub fn watch_file<P: AsRef<Path>>(path: P) -> anyhow::Result<mpsc::Receiver<()>> {
+    let path = path.as_ref().to_owned();
+    let dir = path.parent().context("No parent directory")?;
+    let file_name = path.file_name().context("No file name")?.to_owned();
+
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC | InitFlags::IN_NONBLOCK)?;
+    inotify.add_watch(
+        dir,
+        AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO | AddWatchFlags::IN_CREATE,
+    )?;
+    let inotify = AsyncFd::new(InotifyFd(inotify))?;
+
+    debug!("Watching {} for changes", path.display());
+
+    let (sender, receiver) = mpsc::channel(1);
+
+    tokio::spawn(async move {
+        loop {
+            let mut guard = tokio::select! {
+                _ = sender.closed() => break,
+                guard = inotify.readable() => match guard {
+                    Ok(guard) => guard,
+                    Err(e) => {
+                        warn!("File watch error: {}", e);
+                        break;
+                    }
+                },
+            };
+
+            let events = match guard.get_inner().0.read_events() {
+                Ok(events) => events,
+                Err(Errno::EAGAIN) => {
+                    guard.clear_ready();
+                    continue;
+                }
+                Err(e) => {
+                    warn!("File watch error: {}", e);
+                    break;
+                }
+            };
+
+            if events.iter().any(|e| e.name.as_ref() == Some(&file_name)) {
+                let _ = sender.try_send(());
+            }
+        }
+
+        // dropping the watcher closes the inotify descriptor
+        debug!("Stopped watching {}", path.display());
+    });
+
+    Ok(receiver)
+}
+
+const CAP_NET_BIND_SERVICE: u32 = 10;
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_NET_RAW: u32 = 13;
+
+// capabilities required to maintain and tear down the tunnel: routes and xfrm.
+// resolv.conf is opened before the privileges are dropped instead of retaining CAP_DAC_OVERRIDE,
+// which would also be passed as ambient to every spawned process.
+const RETAINED_CAPS: [u32; 3] = [CAP_NET_BIND_SERVICE, CAP_NET_ADMIN, CAP_NET_RAW];
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Home directory of the given user from the password database
+pub fn user_home_dir(user_name: &str) -> anyhow::Result<PathBuf> {
+    Ok(unistd::User::from_name(user_name)?
+        .ok_or_else(|| anyhow!("Unknown user: {}", user_name))?
+        .dir)
+}
+
+/// Switch to the given user, retaining only the network-related capabilities.
+/// Capabilities are per-thread, so they are kept only by the calling thread and the threads
+/// and processes spawned from it afterwards.
+pub fn drop_privileges(user_name: &str) -> anyhow::Result<()> {
+    let user = unistd::User::from_name(user_name)?.ok_or_else(|| anyhow!("Unknown user: {}", user_name))?;
+
+    debug!("Dropping privileges to user {} ({})", user.name, user.uid);
+
+    if let Err(e) = keep_resolv_conf_open() {
+        warn!("The DNS configuration will not be restored: {:#}", e);
+    }
+
+    unsafe {
+        if libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+
+    unistd::setgroups(&[user.gid])?;
+    unistd::setresgid(user.gid, user.gid, user.gid)?;
+    unistd::setresuid(user.uid, user.uid, user.uid)?;
+
+    let mask = RETAINED_CAPS.iter().fold(0u32, |mask, cap| mask | (1 << cap));
+    let header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [
+        CapUserData {
+            effective: mask,
+            permitted: mask,
+            inheritable: mask,
+        },
+        CapUserData::default(),
+    ];
+
+    unsafe {
+        if libc::syscall(libc::SYS_capset, &header, data.as_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error()).context("Cannot set capabilities");
+        }
+
+        // ambient capabilities are passed to the spawned ip and resolvectl processes
+        for cap in RETAINED_CAPS {
+            if libc::prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_RAISE,
+                cap as libc::c_ulong,
+                0,
+                0,
+            ) != 0
+            {
+                return Err(std::io::Error::last_os_error()).context("Cannot set ambient capabilities");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+enum LockState {
+    Acquired(fs::File),
+    Held(libc::pid_t),
+}
+
+fn is_process_alive(pid: libc::pid_t) -> bool {
+    // EPERM means the process exists but belongs to another user
+    unsafe { libc::kill(pid, 0) == 0 }
+    || std::io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+fn read_lock_pid(name: &str) -> Option<libc::pid_t> {
+    fs::read_to_string(name).ok()?.trim().parse().ok()
+}
+
 pub struct SingleInstance {
     name: String,
-    handle: Option<nix::libc::c_int>,
+    handle: Option<fs::File>,
 }
 
-unsafe impl Send for SingleInstance {}
-unsafe impl Sync for SingleInstance {}
-
 impl SingleInstance {
     pub fn new<N: AsRef<str>>(name: N) -> anyhow::Result<Self> {
-        let fd = fcntl::open(
-            name.as_ref(),
-            OFlag::O_RDWR | OFlag::O_CREAT,
-            Mode::from_bits_truncate(0o600),
-        )?;
-
-        let fl = nix::libc::flock {
-            l_type: nix::libc::F_WRLCK as _,
-            l_whence: nix::libc::SEEK_SET as _,
+        Self::acquire(name.as_ref(), false)
+    }
+
+    /// Remove the existing lock file first, even if the instance which holds it is still alive
+    pub fn new_forced<N: AsRef<str>>(name: N) -> anyhow::Result<Self> {
+        Self::acquire(name.as_ref(), true)
+    }
+
+    fn acquire(name: &str, force: bool) -> anyhow::Result<Self> {
+        if force {
+            warn!("Taking over the instance lock {}", name);
+            let _ = fs::remove_file(name);
+        }
+
+        let holder = match Self::try_lock(name) {
+            Ok(LockState::Acquired(file)) => return Ok(Self::with_handle(name, Some(file))),
+            Ok(LockState::Held(pid)) => pid,
+            // a lock file left by an instance of another user cannot be reopened
+            Err(e) => read_lock_pid(name)
+                .ok_or(e)
+                .with_context(|| format!("Cannot open the lock file {}, use --force to remove it", name))?,
+        };
+
+        // the holder may be unknown when it runs in another PID namespace
+        if holder <= 0 || is_process_alive(holder) {
+            debug!("Instance lock {} is held by process {}", name, holder);
+            return Ok(Self::with_handle(name, None));
+        }
+
+        warn!("Reclaiming the stale instance lock {} left by process {}", name, holder);
+        fs::remove_file(name).with_context(|| format!("Cannot remove the stale lock file {}", name))?;
+
+        match Self::try_lock(name)? {
+            LockState::Acquired(file) => Ok(Self::with_handle(name, Some(file))),
+            LockState::Held(_) => Ok(Self::with_handle(name, None)),
+        }
+    }
+
+    fn try_lock(name: &str) -> anyhow::Result<LockState> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(name)?;
+
+        let fl = libc::flock {
+            l_type: libc::F_WRLCK as _,
+            l_whence: libc::SEEK_SET as _,
             l_start: 0,
             l_len: 0,
             l_pid: 0,
         };
 
-        match fcntl::fcntl(fd, FcntlArg::F_SETLK(&fl)) {
-            Ok(_) => Ok(SingleInstance {
-                name: name.as_ref().to_owned(),
-                handle: Some(fd),
-            }),
-            Err(_) => {
-                let _ = unistd::close(fd);
-                Ok(SingleInstance {
-                    name: name.as_ref().to_owned(),
-                    handle: None,
-                })
-            }
+        if fcntl::fcntl(file.as_raw_fd(), FcntlArg::F_SETLK(&fl)).is_ok() {
+            // the owner PID allows to detect a stale lock file later on
+            file.set_len(0)?;
+            write!(&file, "{}", std::process::id())?;
+            return Ok(LockState::Acquired(file));
+        }
+
+        let mut holder = fl;
+        if fcntl::fcntl(file.as_raw_fd(), FcntlArg::F_GETLK(&mut holder)).is_ok() && holder.l_pid > 0 {
+            Ok(LockState::Held(holder.l_pid))
+        } else {
+            Ok(LockState::Held(read_lock_pid(name).unwrap_or_default()))
+        }
+    }
+
+    fn with_handle(name: &str, handle: Option<fs::File>) -> Self {
+        Self {
+            name: name.to_owned(),
+            handle,
         }
     }
 
@@ -181,8 +413,7 @@ impl SingleInstance {
 
 impl Drop for SingleInstance {
     fn drop(&mut self) {
-        if let Some(handle) = self.handle.take() {
-            let _ = unistd::close(handle);
+        if self.handle.take().is_some() {
             let _ = std::fs::remove_file(&self.name);
         }
     }
@@ -192,12 +423,116 @@ pub async fn delete_device(device_name: &str) {
     let _ = crate::util::run_command("ip", ["link", "del", "name", device_name]).await;
 }
 
+/// Byte counters of the network device, as maintained by the kernel
+pub fn get_device_stats(device_name: &str) -> anyhow::Result<TrafficStats> {
+    let read_counter = |name: &str| -> anyhow::Result<u64> {
+        let path = format!("/sys/class/net/{device_name}/statistics/{name}");
+        Ok(fs::read_to_string(path)?.trim().parse()?)
+    };
+
+    Ok(TrafficStats {
+        bytes_in: read_counter("rx_bytes")?,
+        bytes_out: read_counter("tx_bytes")?,
+    })
+}
+
 pub async fn configure_device(device_name: &str) -> anyhow::Result<()> {
     crate::util::run_command("nmcli", ["device", "set", device_name, "managed", "no"]).await?;
     Ok(())
 }
 
+/// Apply the optional queue length and offload settings to the tunnel interface
+pub async fn tune_device(device_name: &str, params: &TunnelParams) {
+    if params.if_mtu != 0 {
+        if let Err(e) = net::set_mtu(device_name, params.if_mtu).await {
+            warn!("Cannot set MTU of {} to {}: {}", device_name, params.if_mtu, e);
+        }
+    }
+
+    if params.if_txqueuelen != 0 {
+        let qlen = params.if_txqueuelen.to_string();
+        if let Err(e) = crate::util::run_command("ip", ["link", "set", "dev", device_name, "txqueuelen", &qlen]).await {
+            warn!("Cannot set txqueuelen of {} to {}: {}", device_name, qlen, e);
+        }
+    }
+
+    let mut features = vec![];
+    for (name, value) in [("gro", params.if_gro), ("gso", params.if_gso)] {
+        if let Some(value) = value {
+            features.push(name);
+            features.push(if value { "on" } else { "off" });
+        }
+    }
+
+    if !features.is_empty() {
+        let mut args = vec!["-K", device_name];
+        args.extend(features);
+        if let Err(e) = crate::util::run_command("ethtool", &args).await {
+            warn!("Cannot set offload features of {}: {}", device_name, e);
+        }
+    }
+}
+
+/// Probe the path MTU to the target through the tunnel device and apply it. The fallback MTU is set before
+/// the probing and kept on failure or timeout. The probes do not go above the configured MTU, if any.
+pub async fn discover_device_mtu(device_name: &str, target: Ipv4Addr, configured_mtu: u16, fallback_mtu: u16) {
+    debug!("Discovering MTU of {} using {} as the target", device_name, target);
+
+    let max_mtu = if configured_mtu == 0 {
+        DEFAULT_TUNNEL_MTU
+    } else {
+        configured_mtu
+    };
+    let fallback_mtu = fallback_mtu.min(max_mtu);
+
+    if let Err(e) = net::set_mtu(device_name, fallback_mtu).await {
+        warn!("Cannot set MTU of {} to {}: {}", device_name, fallback_mtu, e);
+    }
+
+    let discovery = net::discover_mtu(device_name, target, max_mtu);
+    let mtu = match tokio::time::timeout(MTU_DISCOVERY_TIMEOUT, discovery).await {
+        Ok(Ok(mtu)) => {
+            debug!("Discovered MTU of {}: {}", device_name, mtu);
+            mtu
+        }
+        Ok(Err(e)) => {
+            warn!("MTU discovery on {} failed: {}", device_name, e);
+            fallback_mtu
+        }
+        Err(_) => {
+            warn!("MTU discovery on {} timed out", device_name);
+            fallback_mtu
+        }
+    };
+
+    if let Err(e) = net::set_mtu(device_name, mtu).await {
+        warn!("Cannot set MTU of {} to {}: {}", device_name, mtu, e);
+    }
+}
+
 pub fn get_machine_uuid() -> anyhow::Result<Uuid> {
     let data = fs::read_to_string("/etc/machine-id")?;
     Ok(Uuid::try_parse(data.trim())?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_watch_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snx-rs.conf");
+        fs::write(&path, "server-name=vpn.example.com\n").unwrap();
+
+        let mut receiver = watch_file(&path).unwrap();
+
+        fs::write(dir.path().join("other.conf"), "").unwrap();
+        fs::write(&path, "server-name=gw.example.com\n").unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -1,14 +1,14 @@
 use std::{
-    collections::HashSet,
-    net::Ipv4Addr,
+    future::Future,
+    net::{IpAddr, Ipv4Addr},
     sync::{atomic::AtomicBool, atomic::Ordering},
 };
 
-use crate::model::params::TunnelParams;
+use crate::model::params::{RouteConflict, TunnelParams};
 use anyhow::anyhow;
 use futures::StreamExt;
-use ipnet::Ipv4Net;
-use tracing::debug;
+use ipnet::{Ipv4Net, Ipv6Net};
+use tracing::{debug, warn};
 use zbus::Connection;
 
 static ONLINE_STATE: AtomicBool = AtomicBool::new(true);
@@ -119,13 +119,41 @@ pub async fn add_route(route: Ipv4Net, device: &str, _ipaddr: Ipv4Addr) -> anyho
     Ok(())
 }
 
+/// Assign an IPv6 address to the device, the kernel adds the route to its prefix
+pub async fn add_ipv6_address(device: &str, address: Ipv6Net) -> anyhow::Result<()> {
+    debug!("Adding IPv6 address {} to {}", address, device);
+
+    // the address cannot be added while IPv6 is disabled, the error below tells about it
+    let opt = format!("net.ipv6.conf.{}.disable_ipv6=0", device);
+    if let Err(e) = crate::util::run_command("sysctl", ["-qw", &opt]).await {
+        warn!("Cannot enable IPv6 on {}: {}", device, e);
+    }
+
+    crate::util::run_command("ip", ["-6", "addr", "add", &address.to_string(), "dev", device]).await?;
+
+    Ok(())
+}
+
+async fn get_route_device(route: Ipv4Net) -> Option<String> {
+    let output = crate::util::run_command("ip", ["-4", "route", "show", "exact", &route.to_string()])
+        .await
+        .ok()?;
+    let mut parts = output.split_whitespace();
+    while let Some(part) = parts.next() {
+        if part == "dev" {
+            return parts.next().map(ToOwned::to_owned);
+        }
+    }
+    None
+}
+
 pub async fn add_routes(
     routes: &[Ipv4Net],
     device: &str,
     ipaddr: Ipv4Addr,
     ignore_routes: &[Ipv4Net],
+    on_conflict: RouteConflict,
 ) -> anyhow::Result<()> {
-    let routes = routes.iter().collect::<HashSet<_>>();
     debug!("Routes to add: {:?}", routes);
 
     for route in routes {
@@ -133,29 +161,303 @@ pub async fn add_routes(
             debug!("Ignoring route: {}", route);
             continue;
         }
-        let _ = add_route(*route, device, ipaddr).await;
+
+        if let Some(existing) = get_route_device(*route).await.filter(|dev| dev != device) {
+            match on_conflict {
+                RouteConflict::Fail => {
+                    anyhow::bail!("Route {} already exists via {}", route, existing);
+                }
+                RouteConflict::Skip => {
+                    warn!("Route {} already exists via {}, skipping", route, existing);
+                }
+                RouteConflict::Replace => {
+                    warn!("Route {} already exists via {}, replacing", route, existing);
+                    crate::util::run_command("ip", ["route", "replace", &route.to_string(), "dev", device]).await?;
+                }
+            }
+            continue;
+        }
+
+        // the route may be left over from the previous connection through the same device
+        if let Err(e) = add_route(*route, device, ipaddr).await {
+            warn!("Cannot add route {} via {}: {}", route, device, e);
+        }
     }
 
     Ok(())
 }
 
-pub async fn setup_default_route(device: &str, ipaddr: Ipv4Addr) -> anyhow::Result<()> {
+const MIN_MTU: u16 = 576;
+// IPv4 and ICMP headers of the probes
+const PROBE_OVERHEAD: u16 = 28;
+
+pub async fn set_mtu(device: &str, mtu: u16) -> anyhow::Result<()> {
+    crate::util::run_command("ip", ["link", "set", "dev", device, "mtu", &mtu.to_string()]).await?;
+    Ok(())
+}
+
+// the device MTU is raised to the probed size only, the packets larger than it would be refused locally
+async fn probe_mtu(device: &str, target: Ipv4Addr, mtu: u16) -> bool {
+    if set_mtu(device, mtu).await.is_err() {
+        return false;
+    }
+    let size = (mtu - PROBE_OVERHEAD).to_string();
+    let target = target.to_string();
+    let args = [
+        "-n", "-q", "-c", "1", "-W", "1", "-M", "do", "-s", &size, "-I", device, &target,
+    ];
+    crate::util::run_command("ping", args).await.is_ok()
+}
+
+// binary search of the largest MTU in the range for which the probe succeeds
+async fn search_mtu<F, Fut>(min_mtu: u16, max_mtu: u16, mut probe: F) -> Option<u16>
+where
+    F: FnMut(u16) -> Fut,
+    Fut: Future<Output = bool>,
+{
+    if max_mtu < min_mtu || !probe(min_mtu).await {
+        return None;
+    }
+
+    let (mut good, mut bad) = (min_mtu, max_mtu + 1);
+    while bad - good > 1 {
+        let mtu = good + (bad - good) / 2;
+        if probe(mtu).await {
+            good = mtu;
+        } else {
+            bad = mtu;
+        }
+    }
+
+    Some(good)
+}
+
+/// Find the largest MTU up to `max_mtu` for which the ICMP echo probes with the DF bit set reach the target
+/// through the device. The device MTU follows the probed sizes, the caller sets the final value.
+pub async fn discover_mtu(device: &str, target: Ipv4Addr, max_mtu: u16) -> anyhow::Result<u16> {
+    search_mtu(MIN_MTU, max_mtu, |mtu| probe_mtu(device, target, mtu))
+        .await
+        .ok_or_else(|| anyhow!("no reply from {} to the MTU probes", target))
+}
+
+// Two halves of the address space are more specific than the original default route,
+// so they take precedence while the tunnel device exists and disappear together with it.
+const FALLBACK_DEFAULT_SUBNETS: [&str; 2] = ["0.0.0.0/1", "128.0.0.0/1"];
+
+async fn get_gateway_route(ipaddr: Ipv4Addr) -> Option<Vec<String>> {
+    let output = crate::util::run_command("ip", ["-4", "route", "get", &ipaddr.to_string()])
+        .await
+        .ok()?;
+
+    let mut result = Vec::new();
+    let mut parts = output.split_whitespace();
+    while let Some(part) = parts.next() {
+        if part == "via" || part == "dev" {
+            result.push(part.to_owned());
+            result.push(parts.next()?.to_owned());
+        }
+    }
+
+    if result.is_empty() {
+        None
+    } else {
+        Some(result)
+    }
+}
+
+pub async fn setup_default_route(device: &str, ipaddr: IpAddr, keep_fallback: bool) -> anyhow::Result<()> {
     debug!("Setting up default route through {device}");
 
-    let port = TunnelParams::IPSEC_KEEPALIVE_PORT.to_string();
-    let dst = ipaddr.to_string();
+    let table = TunnelParams::ROUTING_TABLE.to_string();
 
-    crate::util::run_command("ip", ["route", "add", "table", &port, "default", "dev", device]).await?;
-    crate::util::run_command("ip", ["rule", "add", "not", "to", &dst, "table", &port]).await?;
+    crate::util::run_command("ip", ["route", "add", "table", &table, "default", "dev", device]).await?;
+
+    if keep_fallback {
+        debug!("Keeping original default route as a fallback");
+
+        // IPv6 gateway traffic is not affected by the IPv4 routes, no host route is needed
+        if let IpAddr::V4(ipaddr) = ipaddr {
+            let gateway_route = get_gateway_route(ipaddr)
+                .await
+                .ok_or_else(|| anyhow!("Cannot determine route to {}", ipaddr))?;
+
+            // replace, as a stale host route to the gateway may be left over from the previous connection
+            let mut args = vec!["route".to_owned(), "replace".to_owned(), ipaddr.to_string()];
+            args.extend(gateway_route);
+            crate::util::run_command("ip", args).await?;
+        }
+
+        for subnet in FALLBACK_DEFAULT_SUBNETS {
+            crate::util::run_command("ip", ["route", "add", subnet, "dev", device]).await?;
+        }
+    } else {
+        match ipaddr {
+            IpAddr::V4(ipaddr) => {
+                let dst = ipaddr.to_string();
+                crate::util::run_command("ip", ["rule", "add", "not", "to", &dst, "table", &table]).await?;
+            }
+            IpAddr::V6(_) => {
+                crate::util::run_command("ip", ["-4", "rule", "add", "table", &table]).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn remove_default_route(ipaddr: IpAddr, keep_fallback: bool) -> anyhow::Result<()> {
+    let table = TunnelParams::ROUTING_TABLE.to_string();
+
+    // the route is removed by the kernel together with the tunnel device, so it may be gone already
+    if let Err(e) = crate::util::run_command("ip", ["route", "del", "table", &table, "default"]).await {
+        debug!("Cannot remove the default route from table {}: {}", table, e);
+    }
+
+    match (ipaddr, keep_fallback) {
+        (IpAddr::V4(ipaddr), true) => {
+            crate::util::run_command("ip", ["route", "del", &ipaddr.to_string()]).await?;
+        }
+        (IpAddr::V4(ipaddr), false) => {
+            let dst = ipaddr.to_string();
+            crate::util::run_command("ip", ["rule", "del", "not", "to", &dst, "table", &table]).await?;
+        }
+        (IpAddr::V6(_), true) => {}
+        (IpAddr::V6(_), false) => {
+            crate::util::run_command("ip", ["-4", "rule", "del", "table", &table]).await?;
+        }
+    }
+
+    Ok(())
+}
+
+const APP_ROUTING_TABLE: &str = "4501";
+const APP_ROUTING_MARK: &str = "0x4501";
+const APP_ROUTING_NFT_TABLE: &str = "snx-rs";
+
+fn resolve_uid(user: &str) -> anyhow::Result<u32> {
+    if let Ok(uid) = user.parse::<u32>() {
+        Ok(uid)
+    } else {
+        Ok(nix::unistd::User::from_name(user)?
+            .ok_or_else(|| anyhow!("Unknown user: {}", user))?
+            .uid
+            .as_raw())
+    }
+}
+
+fn cgroup_level(cgroup: &str) -> usize {
+    cgroup.split('/').filter(|s| !s.is_empty()).count()
+}
+
+/// Route the traffic of the configured users and cgroup through the tunnel using policy routing.
+/// User traffic is selected with uidrange rules, cgroup traffic is marked via nftables.
+pub async fn setup_app_routing(device: &str, routes: &[Ipv4Net], params: &TunnelParams) -> anyhow::Result<()> {
+    debug!("Setting up application routing through {device}");
+
+    if !params.no_routing && params.default_route {
+        crate::util::run_command(
+            "ip",
+            ["route", "add", "table", APP_ROUTING_TABLE, "default", "dev", device],
+        )
+        .await?;
+    }
+
+    for route in routes {
+        if params.ignore_routes.contains(route) {
+            debug!("Ignoring route: {}", route);
+            continue;
+        }
+        if let Err(e) = crate::util::run_command(
+            "ip",
+            [
+                "route",
+                "add",
+                "table",
+                APP_ROUTING_TABLE,
+                &route.to_string(),
+                "dev",
+                device,
+            ],
+        )
+        .await
+        {
+            warn!("Cannot add route {} to the application routing table: {}", route, e);
+        }
+    }
+
+    for user in &params.app_routing_users {
+        let uid = resolve_uid(user)?;
+        let range = format!("{uid}-{uid}");
+        debug!("Routing traffic of user {} through the tunnel", user);
+        crate::util::run_command("ip", ["rule", "add", "uidrange", &range, "table", APP_ROUTING_TABLE]).await?;
+    }
+
+    if let Some(ref cgroup) = params.app_routing_cgroup {
+        let cgroup = cgroup.trim_matches('/');
+        debug!("Routing traffic of cgroup {} through the tunnel", cgroup);
+
+        crate::util::run_command("nft", ["add", "table", "inet", APP_ROUTING_NFT_TABLE]).await?;
+        crate::util::run_command(
+            "nft",
+            [
+                "add",
+                "chain",
+                "inet",
+                APP_ROUTING_NFT_TABLE,
+                "output",
+                "{ type route hook output priority mangle; }",
+            ],
+        )
+        .await?;
+        crate::util::run_command(
+            "nft",
+            [
+                "add",
+                "rule",
+                "inet",
+                APP_ROUTING_NFT_TABLE,
+                "output",
+                "socket",
+                "cgroupv2",
+                "level",
+                &cgroup_level(cgroup).to_string(),
+                &format!("\"{cgroup}\""),
+                "meta",
+                "mark",
+                "set",
+                APP_ROUTING_MARK,
+            ],
+        )
+        .await?;
+        crate::util::run_command(
+            "ip",
+            ["rule", "add", "fwmark", APP_ROUTING_MARK, "table", APP_ROUTING_TABLE],
+        )
+        .await?;
+    }
 
     Ok(())
 }
 
-pub async fn remove_default_route(ipaddr: Ipv4Addr) -> anyhow::Result<()> {
-    let port = TunnelParams::IPSEC_KEEPALIVE_PORT.to_string();
-    let dst = ipaddr.to_string();
+pub async fn remove_app_routing(params: &TunnelParams) -> anyhow::Result<()> {
+    for user in &params.app_routing_users {
+        if let Ok(uid) = resolve_uid(user) {
+            let range = format!("{uid}-{uid}");
+            let _ =
+                crate::util::run_command("ip", ["rule", "del", "uidrange", &range, "table", APP_ROUTING_TABLE]).await;
+        }
+    }
+
+    if params.app_routing_cgroup.is_some() {
+        let _ = crate::util::run_command(
+            "ip",
+            ["rule", "del", "fwmark", APP_ROUTING_MARK, "table", APP_ROUTING_TABLE],
+        )
+        .await;
+        let _ = crate::util::run_command("nft", ["delete", "table", "inet", APP_ROUTING_NFT_TABLE]).await;
+    }
 
-    crate::util::run_command("ip", ["rule", "del", "not", "to", &dst, "table", &port]).await?;
+    let _ = crate::util::run_command("ip", ["route", "flush", "table", APP_ROUTING_TABLE]).await;
 
     Ok(())
 }
@@ -169,4 +471,36 @@ mod tests {
         let ip = get_default_ip().await.unwrap();
         println!("{ip}");
     }
+
+    #[tokio::test]
+    async fn test_search_mtu() {
+        for path_mtu in [MIN_MTU, 1280, 1399, 1400, 1500] {
+            let mut probes = Vec::new();
+            let mtu = search_mtu(MIN_MTU, 1500, |mtu| {
+                probes.push(mtu);
+                async move { mtu <= path_mtu }
+            })
+            .await;
+            assert_eq!(mtu, Some(path_mtu));
+            assert!(probes.iter().all(|mtu| *mtu <= 1500));
+            assert!(probes.len() <= 12);
+        }
+
+        // the search never goes above the configured maximum
+        assert_eq!(search_mtu(MIN_MTU, 1400, |_| async { true }).await, Some(1400));
+        assert_eq!(search_mtu(MIN_MTU, 1500, |_| async { false }).await, None);
+        assert_eq!(search_mtu(MIN_MTU, 500, |_| async { true }).await, None);
+    }
+
+    #[test]
+    fn test_cgroup_level() {
+        assert_eq!(cgroup_level("user.slice/user-1000.slice/app.scope"), 3);
+        assert_eq!(cgroup_level("/vpn.slice/"), 1);
+    }
+
+    #[test]
+    fn test_resolve_uid() {
+        assert_eq!(resolve_uid("1234").unwrap(), 1234);
+        assert_eq!(resolve_uid("root").unwrap(), 0);
+    }
 }
@@ -1,12 +1,91 @@
 use anyhow::Context;
 use async_trait::async_trait;
-use std::{fs, io::Write, path::Path, path::PathBuf};
+use std::{
+    fs,
+    io::{Read, Seek, Write},
+    path::Path,
+    path::PathBuf,
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::task::JoinHandle;
 use tracing::debug;
 
 use crate::platform::{ResolverConfig, ResolverConfigurator};
 
 const RESOLV_CONF: &str = "/etc/resolv.conf";
 
+struct PendingCleanup {
+    configurator: ResolvConfConfigurator,
+    config: ResolverConfig,
+    handle: JoinHandle<()>,
+}
+
+static PENDING_CLEANUP: Mutex<Option<PendingCleanup>> = Mutex::new(None);
+
+// resolv.conf opened before the privileges are dropped, together with its resolved path
+static KEPT_RESOLV_CONF: Mutex<Option<(PathBuf, fs::File)>> = Mutex::new(None);
+
+/// Open the resolv.conf file for writing while the process is still privileged, so that the
+/// resolver configuration can be restored without the DAC override capability. Nothing is kept
+/// if systemd-resolved is used.
+pub fn keep_resolv_conf_open() -> anyhow::Result<()> {
+    if let ResolverType::ResolvConf(path) = detect_resolver(RESOLV_CONF)? {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Cannot open {}", path.display()))?;
+        if let Ok(mut kept) = KEPT_RESOLV_CONF.lock() {
+            *kept = Some((path, file));
+        }
+    }
+    Ok(())
+}
+
+fn read_resolv_conf(path: &Path) -> anyhow::Result<String> {
+    if let Ok(mut kept) = KEPT_RESOLV_CONF.lock() {
+        if let Some((_, file)) = kept.as_mut().filter(|(kept_path, _)| kept_path == path) {
+            let mut conf = String::new();
+            file.rewind()?;
+            file.read_to_string(&mut conf)?;
+            return Ok(conf);
+        }
+    }
+    Ok(fs::read_to_string(path)?)
+}
+
+fn write_resolv_conf(path: &Path, conf: &str) -> anyhow::Result<()> {
+    if let Ok(mut kept) = KEPT_RESOLV_CONF.lock() {
+        if let Some((_, file)) = kept.as_mut().filter(|(kept_path, _)| kept_path == path) {
+            file.rewind()?;
+            file.set_len(0)?;
+            file.write_all(conf.as_bytes())?;
+            return Ok(());
+        }
+    }
+    Ok(fs::write(path, conf)?)
+}
+
+fn take_pending_cleanup<F>(predicate: F) -> Option<PendingCleanup>
+where
+    F: FnOnce(&PendingCleanup) -> bool,
+{
+    PENDING_CLEANUP
+        .lock()
+        .ok()
+        .and_then(|mut pending| pending.take_if(|p| predicate(p)))
+}
+
+/// Immediately perform the resolver cleanup which was postponed by the teardown grace period.
+pub fn flush_resolver_cleanup() {
+    if let Some(pending) = take_pending_cleanup(|_| true) {
+        debug!("Restoring resolver configuration: {:?}", pending.config);
+        pending.handle.abort();
+        let _ = pending.configurator.configure_or_cleanup(&pending.config, false);
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 enum ResolverType {
     SystemdResolved,
@@ -45,7 +124,10 @@ impl ResolverConfigurator for SystemdResolvedConfigurator {
     }
 }
 
-pub fn new_resolver_configurator<S>(device: S) -> anyhow::Result<Box<dyn ResolverConfigurator + Send + Sync>>
+pub fn new_resolver_configurator<S>(
+    device: S,
+    teardown_grace: Duration,
+) -> anyhow::Result<Box<dyn ResolverConfigurator + Send + Sync>>
 where
     S: AsRef<str>,
 {
@@ -53,7 +135,10 @@ where
         ResolverType::SystemdResolved => Ok(Box::new(SystemdResolvedConfigurator {
             device: device.as_ref().to_owned(),
         })),
-        ResolverType::ResolvConf(path) => Ok(Box::new(ResolvConfConfigurator { config_path: path })),
+        ResolverType::ResolvConf(path) => Ok(Box::new(ResolvConfConfigurator {
+            config_path: path,
+            teardown_grace,
+        })),
     }
 }
 
@@ -110,11 +195,12 @@ where
 
 struct ResolvConfConfigurator {
     config_path: PathBuf,
+    teardown_grace: Duration,
 }
 
 impl ResolvConfConfigurator {
     fn configure_or_cleanup(&self, config: &ResolverConfig, configure: bool) -> anyhow::Result<()> {
-        let conf = fs::read_to_string(&self.config_path)?;
+        let conf = read_resolv_conf(&self.config_path)?;
 
         let existing_nameservers = conf
             .lines()
@@ -156,27 +242,60 @@ impl ResolvConfConfigurator {
                 .collect::<Vec<_>>();
         }
 
-        let mut file = fs::File::create(&self.config_path)?;
+        let mut new_conf = Vec::new();
 
-        writeln!(file, "{}", other_lines.join("\n"))?;
-        writeln!(file, "{}", search.join("\n"))?;
+        writeln!(new_conf, "{}", other_lines.join("\n"))?;
+        writeln!(new_conf, "{}", search.join("\n"))?;
         if configure {
-            writeln!(file, "{}", new_nameservers.join("\n"))?;
+            writeln!(new_conf, "{}", new_nameservers.join("\n"))?;
         }
-        writeln!(file, "{}", existing_nameservers.join("\n"))?;
+        writeln!(new_conf, "{}", existing_nameservers.join("\n"))?;
 
-        Ok(())
+        write_resolv_conf(&self.config_path, &String::from_utf8_lossy(&new_conf))
     }
 }
 
 #[async_trait]
 impl ResolverConfigurator for ResolvConfConfigurator {
     async fn configure(&self, config: &ResolverConfig) -> anyhow::Result<()> {
+        if let Some(pending) = take_pending_cleanup(|p| p.configurator.config_path == self.config_path) {
+            pending.handle.abort();
+            if pending.config == *config {
+                debug!("Reusing resolver configuration kept during the teardown grace period");
+            } else {
+                let _ = pending.configurator.configure_or_cleanup(&pending.config, false);
+            }
+        }
         Ok(self.configure_or_cleanup(config, true)?)
     }
 
     async fn cleanup(&self, config: &ResolverConfig) -> anyhow::Result<()> {
-        Ok(self.configure_or_cleanup(config, false)?)
+        if self.teardown_grace.is_zero() {
+            return Ok(self.configure_or_cleanup(config, false)?);
+        }
+
+        flush_resolver_cleanup();
+
+        debug!("Postponing resolver cleanup for {:?}", self.teardown_grace);
+
+        let grace = self.teardown_grace;
+        let handle = tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            flush_resolver_cleanup();
+        });
+
+        if let Ok(mut pending) = PENDING_CLEANUP.lock() {
+            *pending = Some(PendingCleanup {
+                configurator: ResolvConfConfigurator {
+                    config_path: self.config_path.clone(),
+                    teardown_grace: Duration::ZERO,
+                },
+                config: config.clone(),
+                handle,
+            });
+        }
+
+        Ok(())
     }
 }
 
@@ -337,6 +456,7 @@ mod tests {
 
         let cut = ResolvConfConfigurator {
             config_path: conf.to_owned(),
+            teardown_grace: Duration::ZERO,
         };
 
         let config = ResolverConfig {
@@ -356,6 +476,7 @@ mod tests {
 
         let cut = ResolvConfConfigurator {
             config_path: conf.to_owned(),
+            teardown_grace: Duration::ZERO,
         };
 
         let config = ResolverConfig {
@@ -368,4 +489,71 @@ mod tests {
         let new_conf = fs::read_to_string(&conf).unwrap();
         assert_eq!(new_conf, "# comment\nsearch acme.com\nnameserver 10.0.0.1\n");
     }
+
+    #[tokio::test]
+    async fn test_resolv_conf_kept_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf = dir.path().join("resolv.conf");
+        fs::write(&conf, "nameserver 10.0.0.1\n").unwrap();
+
+        let file = fs::OpenOptions::new().read(true).write(true).open(&conf).unwrap();
+        *KEPT_RESOLV_CONF.lock().unwrap() = Some((conf.clone(), file));
+
+        // the kept handle is used even if the path is no longer accessible
+        fs::remove_file(&conf).unwrap();
+
+        let cut = ResolvConfConfigurator {
+            config_path: conf.clone(),
+            teardown_grace: Duration::ZERO,
+        };
+
+        let config = ResolverConfig {
+            search_domains: vec!["dom1.com".to_owned()],
+            dns_servers: vec!["192.168.1.1".parse().unwrap()],
+        };
+
+        cut.configure(&config).await.unwrap();
+        assert_eq!(
+            read_resolv_conf(&conf).unwrap(),
+            "\nsearch dom1.com\nnameserver 192.168.1.1\nnameserver 10.0.0.1\n"
+        );
+
+        cut.cleanup(&config).await.unwrap();
+        assert_eq!(read_resolv_conf(&conf).unwrap(), "\n\nnameserver 10.0.0.1\n");
+
+        KEPT_RESOLV_CONF.lock().unwrap().take();
+        assert!(!conf.exists());
+    }
+
+    // the clock is paused and advanced by the sleeps, so the grace period elapses deterministically
+    #[tokio::test(start_paused = true)]
+    async fn test_resolv_conf_configurator_teardown_grace() {
+        let conf = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let configured = "# comment\nsearch acme.com dom1.com\nnameserver 192.168.1.1\nnameserver 10.0.0.1\n";
+        fs::write(&conf, configured).unwrap();
+
+        let cut = ResolvConfConfigurator {
+            config_path: conf.to_owned(),
+            teardown_grace: Duration::from_secs(10),
+        };
+
+        let config = ResolverConfig {
+            search_domains: vec!["dom1.com".to_owned()],
+            dns_servers: vec!["192.168.1.1".parse().unwrap()],
+        };
+
+        cut.cleanup(&config).await.unwrap();
+        assert_eq!(fs::read_to_string(&conf).unwrap(), configured);
+
+        cut.configure(&config).await.unwrap();
+        tokio::time::sleep(cut.teardown_grace * 2).await;
+        assert_eq!(fs::read_to_string(&conf).unwrap(), configured);
+
+        cut.cleanup(&config).await.unwrap();
+        tokio::time::sleep(cut.teardown_grace * 2).await;
+        assert_eq!(
+            fs::read_to_string(&conf).unwrap(),
+            "# comment\nsearch acme.com\nnameserver 10.0.0.1\n"
+        );
+    }
 }
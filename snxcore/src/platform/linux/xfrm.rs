@@ -1,9 +1,12 @@
-use std::{net::Ipv4Addr, sync::Arc};
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+};
 
 use ipnet::Ipv4Net;
 use isakmp::model::{EspAuthAlgorithm, EspCryptMaterial, TransformId};
 use rand::random;
-use tracing::{debug, trace};
+use tracing::{debug, trace, warn};
 
 use crate::platform::ResolverConfig;
 use crate::{
@@ -12,6 +15,9 @@ use crate::{
     util,
 };
 
+const ANY_IPV4: &str = "0.0.0.0/0";
+const ANY_IPV6: &str = "::/0";
+
 async fn iproute2(args: &[&str]) -> anyhow::Result<String> {
     util::run_command("ip", args).await
 }
@@ -147,6 +153,8 @@ impl XfrmState<'_> {
 
 struct XfrmPolicy {
     dir: PolicyDir,
+    // selector of the inner traffic, IPv6 needs a policy of its own
+    selector: &'static str,
     src: Ipv4Addr,
     dst: Ipv4Addr,
     if_id: u32,
@@ -158,6 +166,10 @@ impl XfrmPolicy {
             "xfrm",
             "policy",
             "add",
+            "src",
+            self.selector,
+            "dst",
+            self.selector,
             "dir",
             self.dir.as_str(),
             "tmpl",
@@ -187,9 +199,9 @@ impl XfrmPolicy {
             "if_id",
             &self.if_id.to_string(),
             "src",
-            "0.0.0.0/0",
+            self.selector,
             "dst",
-            "0.0.0.0/0",
+            self.selector,
         ])
         .await?;
 
@@ -267,7 +279,16 @@ impl XfrmConfigurator {
     }
 
     async fn setup_xfrm_link(&self) -> anyhow::Result<()> {
-        self.new_xfrm_link().add().await
+        self.new_xfrm_link().add().await?;
+
+        if let Some(address) = self.ipsec_session.address_v6 {
+            if let Err(e) = platform::add_ipv6_address(&self.name, address).await {
+                warn!("Unable to assign IPv6 address {}: {}", address, e);
+            }
+        }
+
+        platform::tune_device(&self.name, &self.tunnel_params).await;
+        Ok(())
     }
 
     async fn configure_xfrm_state(
@@ -300,15 +321,24 @@ impl XfrmConfigurator {
         src: Ipv4Addr,
         dst: Ipv4Addr,
     ) -> anyhow::Result<()> {
-        let policy = XfrmPolicy {
-            dir,
-            src,
-            dst,
-            if_id: self.if_id,
+        let selectors = if self.ipsec_session.address_v6.is_some() {
+            &[ANY_IPV4, ANY_IPV6][..]
+        } else {
+            &[ANY_IPV4][..]
         };
-        match command {
-            CommandType::Add => policy.add().await?,
-            CommandType::Delete => policy.delete().await?,
+
+        for &selector in selectors {
+            let policy = XfrmPolicy {
+                dir,
+                selector,
+                src,
+                dst,
+                if_id: self.if_id,
+            };
+            match command {
+                CommandType::Add => policy.add().await?,
+                CommandType::Delete => policy.delete().await?,
+            }
         }
 
         Ok(())
@@ -339,73 +369,51 @@ impl XfrmConfigurator {
     }
 
     async fn setup_routing(&self) -> anyhow::Result<()> {
-        let mut subnets = self.tunnel_params.add_routes.clone();
-
         let dst = self.dest_ip.to_string();
-        let port = TunnelParams::IPSEC_KEEPALIVE_PORT.to_string();
-
-        let mut default_route_set = false;
+        let port = self.tunnel_params.keepalive_port.to_string();
+        let table = TunnelParams::ROUTING_TABLE.to_string();
 
-        if !self.tunnel_params.no_routing {
-            if self.tunnel_params.default_route {
-                platform::setup_default_route(&self.name, self.dest_ip).await?;
-                default_route_set = true;
-            } else {
-                subnets.extend(&self.subnets);
-            }
-        }
-
-        if !default_route_set {
-            iproute2(&["route", "add", "table", &port, &dst, "dev", &self.name]).await?;
+        if util::is_default_route_enabled(&self.tunnel_params) {
+            platform::setup_default_route(
+                &self.name,
+                self.dest_ip.into(),
+                self.tunnel_params.keep_fallback_default_route,
+            )
+            .await?;
+        } else {
+            iproute2(&["route", "add", "table", &table, &dst, "dev", &self.name]).await?;
         }
 
         // route keepalive packets through the tunnel
         iproute2(&[
-            "rule", "add", "to", &dst, "ipproto", "udp", "dport", &port, "table", &port,
+            "rule", "add", "to", &dst, "ipproto", "udp", "dport", &port, "table", &table,
         ])
         .await?;
 
-        subnets.retain(|s| !s.contains(&self.dest_ip));
+        let subnets = util::tunnel_routes(&self.tunnel_params, self.subnets.iter().copied(), self.dest_ip.into());
 
-        if !subnets.is_empty() {
-            let _ = platform::add_routes(
+        if util::is_app_routing_enabled(&self.tunnel_params) {
+            platform::setup_app_routing(&self.name, &subnets, &self.tunnel_params).await?;
+        } else if !subnets.is_empty() {
+            platform::add_routes(
                 &subnets,
                 &self.name,
                 self.ipsec_session.address,
                 &self.tunnel_params.ignore_routes,
+                self.tunnel_params.on_route_conflict,
             )
-            .await;
+            .await?;
         }
 
         Ok(())
     }
 
     async fn setup_dns(&self, cleanup: bool) -> anyhow::Result<()> {
-        let suffixes = self
-            .ipsec_session
-            .domains
-            .iter()
-            .chain(&self.tunnel_params.search_domains)
-            .filter(|s| {
-                !self
-                    .tunnel_params
-                    .ignore_search_domains
-                    .iter()
-                    .any(|d| d.to_lowercase() == s.to_lowercase())
-            })
-            .cloned()
-            .collect::<Vec<_>>();
-
-        let servers = self
-            .ipsec_session
-            .dns
-            .iter()
-            .chain(self.tunnel_params.dns_servers.iter())
-            .filter(|s| !self.tunnel_params.ignore_dns_servers.iter().any(|d| *d == **s))
-            .cloned()
-            .collect::<Vec<_>>();
-
-        let resolver = new_resolver_configurator(&self.name)?;
+        let suffixes = util::merge_search_domains(&self.ipsec_session.domains, &self.tunnel_params);
+
+        let servers = util::select_dns_servers(self.ipsec_session.dns.iter().cloned(), &self.tunnel_params);
+
+        let resolver = new_resolver_configurator(&self.name, self.tunnel_params.teardown_grace)?;
 
         let config = ResolverConfig {
             search_domains: suffixes,
@@ -427,7 +435,10 @@ impl XfrmConfigurator {
 #[async_trait::async_trait]
 impl IpsecConfigurator for XfrmConfigurator {
     async fn configure(&mut self) -> anyhow::Result<()> {
-        self.source_ip = platform::get_default_ip().await?.parse()?;
+        self.source_ip = match self.tunnel_params.bind_address {
+            Some(IpAddr::V4(address)) => address,
+            _ => platform::get_default_ip().await?.parse()?,
+        };
         debug!("Source IP: {}", self.source_ip);
         debug!("Target IP: {}", self.dest_ip);
 
@@ -488,46 +499,75 @@ impl IpsecConfigurator for XfrmConfigurator {
     }
 
     async fn cleanup(&mut self) {
-        let _ = self
+        if self
             .configure_xfrm_state(
                 CommandType::Delete,
                 self.source_ip,
                 self.dest_ip,
                 &self.ipsec_session.esp_out,
             )
-            .await;
+            .await
+            .is_ok()
+        {
+            debug!(
+                "Removed outbound xfrm state, spi: 0x{:x}",
+                self.ipsec_session.esp_out.spi
+            );
+        }
 
-        let _ = self
+        if self
             .configure_xfrm_state(
                 CommandType::Delete,
                 self.dest_ip,
                 self.source_ip,
                 &self.ipsec_session.esp_in,
             )
-            .await;
+            .await
+            .is_ok()
+        {
+            debug!("Removed inbound xfrm state, spi: 0x{:x}", self.ipsec_session.esp_in.spi);
+        }
 
-        let _ = self
+        if self
             .configure_xfrm_policy(CommandType::Delete, PolicyDir::Out, self.source_ip, self.dest_ip)
-            .await;
+            .await
+            .is_ok()
+        {
+            debug!("Removed outbound xfrm policy");
+        }
 
-        let _ = self
+        if self
             .configure_xfrm_policy(CommandType::Delete, PolicyDir::In, self.dest_ip, self.source_ip)
-            .await;
+            .await
+            .is_ok()
+        {
+            debug!("Removed inbound xfrm policy");
+        }
 
         if !self.tunnel_params.no_dns {
             let _ = self.setup_dns(true).await;
         }
 
-        let _ = self.new_xfrm_link().delete().await;
+        if self.new_xfrm_link().delete().await.is_ok() {
+            debug!("Removed xfrm interface {}", self.name);
+        }
 
         let dst = self.dest_ip.to_string();
-        let port = TunnelParams::IPSEC_KEEPALIVE_PORT.to_string();
+        let port = self.tunnel_params.keepalive_port.to_string();
+        let table = TunnelParams::ROUTING_TABLE.to_string();
 
         let _ = iproute2(&[
-            "rule", "del", "to", &dst, "ipproto", "udp", "dport", &port, "table", &port,
+            "rule", "del", "to", &dst, "ipproto", "udp", "dport", &port, "table", &table,
         ])
         .await;
 
-        let _ = platform::remove_default_route(self.dest_ip).await;
+        if util::is_default_route_enabled(&self.tunnel_params) {
+            let _ = platform::remove_default_route(self.dest_ip.into(), self.tunnel_params.keep_fallback_default_route)
+                .await;
+        }
+
+        if util::is_app_routing_enabled(&self.tunnel_params) {
+            let _ = platform::remove_app_routing(&self.tunnel_params).await;
+        }
     }
 }
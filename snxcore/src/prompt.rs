@@ -7,12 +7,96 @@ use std::{
 
 pub const OTP_TIMEOUT: Duration = Duration::from_secs(120);
 
+// Fluent keys of the common Check Point prompts, see assets/i18n/en-US/prompts.ftl.
+// The first pattern contained in the lowercased server prompt wins, so the specific ones come first.
+const PROMPT_KEYS: &[(&str, &str)] = &[
+    ("new password", "prompt-new-password"),
+    ("sms", "prompt-sms-code"),
+    ("one-time", "prompt-otp"),
+    ("one time", "prompt-otp"),
+    ("otp", "prompt-otp"),
+    ("passcode", "prompt-otp"),
+    ("verification code", "prompt-otp"),
+    ("token", "prompt-token-code"),
+    ("user name", "prompt-username"),
+    ("username", "prompt-username"),
+    ("password", "prompt-password"),
+];
+
+// The server prompts are in English, so en-US only lists the keys for the translators
+const TRANSLATIONS: &[(&str, &str)] = &[("de-DE", include_str!("../../assets/i18n/de-DE/prompts.ftl"))];
+
+/// Fluent key of a known server prompt
+pub fn prompt_key(prompt: &str) -> Option<&'static str> {
+    let prompt = prompt.to_lowercase();
+    PROMPT_KEYS
+        .iter()
+        .find(|(pattern, _)| prompt.contains(pattern))
+        .map(|(_, key)| *key)
+}
+
+// Locale name in the form of the i18n directories, e.g. "de-DE" for "de_DE.UTF-8@euro"
+fn locale_name(value: &str) -> Option<String> {
+    let name = value.split(['.', '@']).next().unwrap_or_default();
+    (!name.is_empty() && name != "C" && name != "POSIX").then(|| name.replace('_', "-"))
+}
+
+// Only the simple `key = value` messages of the fluent syntax are used by the prompt files
+fn ftl_message<'a>(data: &'a str, key: &str) -> Option<&'a str> {
+    data.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .find(|(k, _)| k.trim() == key)
+        .map(|(_, v)| v.trim())
+}
+
+/// Bundled translation of the fluent key for the locale, a translation for the same language is used
+/// if there is none for the region
+pub fn bundled_translation(locale: &str, key: &str) -> Option<String> {
+    let language = locale.split('-').next().unwrap_or_default();
+    let data = TRANSLATIONS
+        .iter()
+        .find(|(name, _)| *name == locale)
+        .or_else(|| {
+            TRANSLATIONS
+                .iter()
+                .find(|(name, _)| name.split('-').next() == Some(language))
+        })
+        .map(|(_, data)| *data)?;
+    ftl_message(data, key).map(ToOwned::to_owned)
+}
+
+/// Translation of the fluent key for the locale of the process, taken from LC_ALL, LC_MESSAGES or LANG
+pub fn system_translation(key: &str) -> Option<String> {
+    let locale = ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|value| !value.is_empty())?;
+    bundled_translation(&locale_name(&locale)?, key)
+}
+
+/// Localized text of a known server prompt followed by the server prompt itself, which may have details
+/// the translation does not, e.g. the phone number of the SMS. The server prompt is returned as is if it is
+/// not recognized or there is no translation for it.
+pub fn localize_prompt<P: SecurePrompt + ?Sized>(prompt: &str, translator: &P) -> String {
+    let Some(translation) = prompt_key(prompt).and_then(|key| translator.translate(key)) else {
+        return prompt.to_owned();
+    };
+    let trim = |s: &str| s.trim().trim_end_matches(':').trim_end().to_owned();
+    format!("{} ({}): ", trim(&translation), trim(prompt))
+}
+
 pub trait SecurePrompt {
     fn get_secure_input(&self, prompt: &str) -> anyhow::Result<String>;
 
     fn get_plain_input(&self, prompt: &str) -> anyhow::Result<String>;
 
     fn show_notification(&self, summary: &str, message: &str) -> anyhow::Result<()>;
+
+    /// Translation of the fluent key of a server prompt, see [`localize_prompt`]
+    fn translate(&self, key: &str) -> Option<String> {
+        system_translation(key)
+    }
 }
 
 pub struct TtyPrompt;
@@ -43,3 +127,116 @@ impl SecurePrompt for TtyPrompt {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct GermanPrompt;
+
+    impl SecurePrompt for GermanPrompt {
+        fn get_secure_input(&self, _prompt: &str) -> anyhow::Result<String> {
+            Ok(String::new())
+        }
+
+        fn get_plain_input(&self, _prompt: &str) -> anyhow::Result<String> {
+            Ok(String::new())
+        }
+
+        fn show_notification(&self, _summary: &str, _message: &str) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn translate(&self, key: &str) -> Option<String> {
+            bundled_translation("de-DE", key)
+        }
+    }
+
+    struct UntranslatedPrompt;
+
+    impl SecurePrompt for UntranslatedPrompt {
+        fn get_secure_input(&self, _prompt: &str) -> anyhow::Result<String> {
+            Ok(String::new())
+        }
+
+        fn get_plain_input(&self, _prompt: &str) -> anyhow::Result<String> {
+            Ok(String::new())
+        }
+
+        fn show_notification(&self, _summary: &str, _message: &str) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn translate(&self, _key: &str) -> Option<String> {
+            None
+        }
+    }
+
+    #[test]
+    fn test_prompt_key() {
+        assert_eq!(prompt_key("Enter your OTP:"), Some("prompt-otp"));
+        assert_eq!(
+            prompt_key("Please enter the SMS verification code"),
+            Some("prompt-sms-code")
+        );
+        assert_eq!(prompt_key("User Name:"), Some("prompt-username"));
+        assert_eq!(prompt_key("Enter new password:"), Some("prompt-new-password"));
+        assert_eq!(prompt_key("Password:"), Some("prompt-password"));
+        assert_eq!(prompt_key("Geben Sie den Code ein"), None);
+    }
+
+    #[test]
+    fn test_locale_name() {
+        assert_eq!(locale_name("de_DE.UTF-8").as_deref(), Some("de-DE"));
+        assert_eq!(locale_name("de_AT@euro").as_deref(), Some("de-AT"));
+        assert_eq!(locale_name("en_US").as_deref(), Some("en-US"));
+        assert_eq!(locale_name("C.UTF-8"), None);
+        assert_eq!(locale_name("POSIX"), None);
+        assert_eq!(locale_name(""), None);
+    }
+
+    #[test]
+    fn test_bundled_translation() {
+        assert_eq!(
+            bundled_translation("de-DE", "prompt-otp").as_deref(),
+            Some("Einmalpasswort:")
+        );
+        assert_eq!(
+            bundled_translation("de-AT", "prompt-password").as_deref(),
+            Some("Passwort:")
+        );
+        assert_eq!(bundled_translation("de-DE", "prompt-unknown"), None);
+        assert_eq!(bundled_translation("en-US", "prompt-otp"), None);
+    }
+
+    #[test]
+    fn test_bundled_translations_complete() {
+        let keys = include_str!("../../assets/i18n/en-US/prompts.ftl");
+        for (_, key) in PROMPT_KEYS {
+            assert!(ftl_message(keys, key).is_some(), "{key}");
+            for (locale, data) in TRANSLATIONS {
+                assert!(ftl_message(data, key).is_some(), "{locale}: {key}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_localize_prompt() {
+        assert_eq!(
+            localize_prompt("Enter your OTP:", &GermanPrompt),
+            "Einmalpasswort (Enter your OTP): "
+        );
+        assert_eq!(
+            localize_prompt("Enter the SMS code sent to +49 170 1234567", &GermanPrompt),
+            "Code aus der SMS (Enter the SMS code sent to +49 170 1234567): "
+        );
+        assert_eq!(
+            localize_prompt("Geben Sie den Code ein", &GermanPrompt),
+            "Geben Sie den Code ein"
+        );
+        assert_eq!(
+            localize_prompt("Enter your OTP:", &UntranslatedPrompt),
+            "Enter your OTP:"
+        );
+    }
+}
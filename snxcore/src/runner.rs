@@ -0,0 +1,264 @@
+use std::{collections::VecDeque, io, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Context};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::unix::pipe,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use tracing::debug;
+
+use crate::{
+    browser::{BrowserController, OtpListener},
+    model::{
+        params::{OtpSource, TunnelParams},
+        MfaType, SessionState,
+    },
+    prompt::{localize_prompt, SecurePrompt, OTP_TIMEOUT},
+    server_info, totp,
+    tunnel::{self, TunnelConnector, TunnelEvent},
+};
+
+const CHANNEL_SIZE: usize = 16;
+const OTP_FILE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Tunnel started by [`run_tunnel`]
+pub struct TunnelHandle {
+    connector: Box<dyn TunnelConnector + Send>,
+    events: mpsc::Receiver<TunnelEvent>,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+impl TunnelHandle {
+    /// Wait for the next tunnel event. The event is handled by the connector before it is returned,
+    /// e.g. rekeying is started on `RekeyCheck`. `None` means that the tunnel has stopped,
+    /// [`TunnelHandle::finish`] then returns the reason.
+    pub async fn next_event(&mut self) -> Option<TunnelEvent> {
+        let event = self.events.recv().await?;
+        let _ = self.connector.handle_tunnel_event(event.clone()).await;
+        Some(event)
+    }
+
+    /// Wait until the tunnel stops and return its result
+    pub async fn finish(self) -> anyhow::Result<()> {
+        self.task.await?
+    }
+
+    /// Stop the tunnel and wait for its cleanup up to the timeout, see [`tunnel::shutdown`]
+    pub async fn shutdown(self, timeout: Duration) {
+        tunnel::shutdown(self.connector, self.task, timeout).await;
+    }
+}
+
+async fn get_saml_otp<B>(params: &Arc<TunnelParams>, browser: &B, url: &str) -> anyhow::Result<String>
+where
+    B: BrowserController,
+{
+    let listener = OtpListener::bind(params.clone()).await?;
+    debug!("Waiting for the SAML redirect to {}", listener.callback_url()?);
+
+    let (tx, rx) = oneshot::channel();
+    tokio::spawn(listener.run(tx));
+
+    browser.open(url)?;
+    let otp = tokio::time::timeout(OTP_TIMEOUT, rx).await??;
+    browser.close();
+
+    Ok(otp)
+}
+
+// The code is the first line of the pipe or file content. Files are removed after reading,
+// so that the same code is not sent again with the next connection.
+async fn read_otp_source(source: &OtpSource) -> anyhow::Result<String> {
+    let read = async {
+        let content = match source {
+            // With the read-write access the open does not block until a writer appears and the reads
+            // wait for the data instead of returning EOF, so the timeout drops the whole read.
+            OtpSource::Pipe(path) => {
+                let receiver = pipe::OpenOptions::new()
+                    .read_write(true)
+                    .open_receiver(path)
+                    .with_context(|| format!("Cannot open {}", path.display()))?;
+                let mut lines = BufReader::new(receiver).lines();
+                loop {
+                    match lines
+                        .next_line()
+                        .await
+                        .with_context(|| format!("Cannot read {}", path.display()))?
+                    {
+                        Some(line) if !line.trim().is_empty() => break line,
+                        Some(_) => {}
+                        None => break String::new(),
+                    }
+                }
+            }
+            OtpSource::File(path) => loop {
+                match tokio::fs::read_to_string(path).await {
+                    Ok(content) if !content.trim().is_empty() => {
+                        tokio::fs::remove_file(path)
+                            .await
+                            .with_context(|| format!("Cannot remove {}", path.display()))?;
+                        break content;
+                    }
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e).with_context(|| format!("Cannot read {}", path.display())),
+                }
+                tokio::time::sleep(OTP_FILE_POLL_INTERVAL).await;
+            },
+        };
+
+        content
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .map(ToOwned::to_owned)
+            .ok_or_else(|| anyhow!("No OTP code in {}", source))
+    };
+
+    tokio::time::timeout(OTP_TIMEOUT, read).await.unwrap_or_else(|_| {
+        Err(anyhow!(
+            "No OTP code from {} within {} seconds",
+            source,
+            OTP_TIMEOUT.as_secs()
+        ))
+    })
+}
+
+/// Authenticate to the gateway, go through the MFA challenges and start the tunnel.
+/// User names, passwords and OTP codes are asked from the `prompt`, SAML URLs are opened with the `browser`.
+/// The password prompt selected with `password-factor` is answered with the code from `totp-secret` if it is set,
+/// or with the code read from `otp-source`.
+/// The whole sequence is limited by `connect-timeout` if it is set. The prompts are synchronous and cannot be
+/// interrupted, so the timeout is only reported at the first step after the user has answered.
+pub async fn run_tunnel<P, B>(params: Arc<TunnelParams>, prompt: &P, browser: &B) -> anyhow::Result<TunnelHandle>
+where
+    P: SecurePrompt,
+    B: BrowserController,
+{
+    tunnel::with_connect_timeout(&params, start_tunnel(params.clone(), prompt, browser)).await
+}
+
+async fn start_tunnel<P, B>(params: Arc<TunnelParams>, prompt: &P, browser: &B) -> anyhow::Result<TunnelHandle>
+where
+    P: SecurePrompt,
+    B: BrowserController,
+{
+    let mut mfa_prompts = if params.server_prompt {
+        server_info::get_mfa_prompts(&params).await.unwrap_or_default()
+    } else {
+        VecDeque::default()
+    };
+
+    let mut connector = tunnel::new_tunnel_connector(params.clone()).await?;
+
+    let mut session = if params.ike_persist {
+        debug!("Attempting to load IKE session");
+        match connector.restore_session().await {
+            Ok(session) => session,
+            Err(_) => {
+                connector = tunnel::new_tunnel_connector(params.clone()).await?;
+                connector.authenticate().await?
+            }
+        }
+    } else {
+        connector.authenticate().await?
+    };
+
+    let mut password_prompts = 0;
+
+    while let SessionState::PendingChallenge(challenge) = session.state.clone() {
+        let input = match challenge.mfa_type {
+            MfaType::PasswordInput => {
+                let text = mfa_prompts.pop_front().unwrap_or_else(|| challenge.prompt.clone());
+                password_prompts += 1;
+
+                match (&params.totp_secret, &params.otp_source) {
+                    (Some(secret), _) if password_prompts == params.password_factor => {
+                        debug!("Answering password prompt {} with the TOTP code", password_prompts);
+                        totp::generate(secret)?
+                    }
+                    (None, Some(source)) if password_prompts == params.password_factor => {
+                        debug!(
+                            "Answering password prompt {} with the code from {}",
+                            password_prompts, source
+                        );
+                        read_otp_source(source).await?
+                    }
+                    _ => prompt.get_secure_input(&localize_prompt(&text, prompt))?,
+                }
+            }
+            MfaType::SamlSso => get_saml_otp(&params, browser, &challenge.prompt).await?,
+            MfaType::UserNameInput => prompt.get_plain_input(&localize_prompt(&challenge.prompt, prompt))?,
+        };
+        session = connector.challenge_code(session, &input).await?;
+    }
+
+    let (command_sender, command_receiver) = mpsc::channel(CHANNEL_SIZE);
+    let tunnel = connector.create_tunnel(session, command_sender).await?;
+
+    let (event_sender, events) = mpsc::channel(CHANNEL_SIZE);
+    let task = tokio::spawn(tunnel.run(command_receiver, event_sender));
+
+    Ok(TunnelHandle {
+        connector,
+        events,
+        task,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use nix::sys::stat::Mode;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_otp_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("otp");
+        let source = OtpSource::File(path.clone());
+
+        fs::write(&path, "\n 123456 \n654321\n").unwrap();
+        assert_eq!(read_otp_source(&source).await.unwrap(), "123456");
+        assert!(!path.exists());
+
+        // the file is polled until it has some content
+        fs::write(&path, "").unwrap();
+        let reader = tokio::spawn(async move { read_otp_source(&source).await });
+        tokio::time::sleep(OTP_FILE_POLL_INTERVAL).await;
+        assert!(!reader.is_finished());
+
+        fs::write(&path, "234567\n").unwrap();
+        assert_eq!(reader.await.unwrap().unwrap(), "234567");
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_read_otp_pipe() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("otp");
+        nix::unistd::mkfifo(&path, Mode::S_IRUSR | Mode::S_IWUSR).unwrap();
+        let source = OtpSource::Pipe(path.clone());
+
+        // no writer yet: the reader waits without blocking in the open
+        let reader = tokio::spawn(async move { read_otp_source(&source).await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!reader.is_finished());
+
+        let mut writer = pipe::OpenOptions::new().open_sender(&path).unwrap();
+        writer.write_all(b"\n345678\n").await.unwrap();
+        drop(writer);
+
+        assert_eq!(reader.await.unwrap().unwrap(), "345678");
+
+        // a regular file is not accepted as a pipe
+        let file = dir.path().join("file");
+        fs::write(&file, "456789\n").unwrap();
+        assert!(read_otp_source(&OtpSource::Pipe(file)).await.is_err());
+    }
+}
@@ -1,44 +1,262 @@
-use std::sync::Arc;
+use std::{
+    future::Future,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::anyhow;
+use chrono::Local;
 use futures::pin_mut;
-use tokio::sync::mpsc;
+use ipnet::Ipv4Net;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, UdpSocket},
+    sync::{broadcast, mpsc, oneshot, Semaphore},
+    task::JoinHandle,
+};
 use tracing::{debug, trace, warn};
 
 use crate::{
+    ccc::CccHttpClient,
     model::{
-        params::TunnelParams, ConnectionStatus, SessionState, TunnelServiceRequest, TunnelServiceResponse, VpnSession,
+        params::{SearchDomainPolicy, TunnelParams, TunnelType},
+        ConnectionInfo, ConnectionStatus, DisconnectReason, ServiceEvent, SessionState, TunnelServiceRequest,
+        TunnelServiceResponse, VpnSession,
     },
+    platform,
     tunnel::{self, TunnelConnector, TunnelEvent},
+    util,
 };
 
 pub const LISTEN_PORT: u16 = 7779;
 
 const MAX_PACKET_SIZE: usize = 1_000_000;
+const CONFIG_WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+const VERIFY_SETUP_LEEWAY: Duration = Duration::from_secs(30);
+const REAUTH_LEEWAY: Duration = Duration::from_secs(60);
+const EVENT_CHANNEL_SIZE: usize = 64;
+const MAX_EVENT_SUBSCRIBERS: usize = 16;
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+// Runs the future until it completes or a cancel request arrives. Status requests are answered with the given
+// status, the other requests are rejected meanwhile.
+async fn cancellable<F: Future>(fut: F, socket: &UdpSocket, status: ConnectionStatus) -> Option<F::Output> {
+    let wait_for_cancel = async {
+        let mut buf = vec![0u8; MAX_PACKET_SIZE];
+        loop {
+            let Ok((size, addr)) = socket.recv_from(&mut buf).await else {
+                continue;
+            };
+            let (cancel, resp) = match serde_json::from_slice::<TunnelServiceRequest>(&buf[0..size]) {
+                Ok(TunnelServiceRequest::Cancel) => (true, TunnelServiceResponse::Ok),
+                Ok(TunnelServiceRequest::GetStatus) => (false, TunnelServiceResponse::ConnectionStatus(status.clone())),
+                _ => (false, TunnelServiceResponse::Error("Connection in progress".to_owned())),
+            };
+            if let Ok(json) = serde_json::to_vec(&resp) {
+                let _ = socket.send_to(&json, addr).await;
+            }
+            if cancel {
+                break;
+            }
+        }
+    };
+
+    tokio::select! {
+        output = fut => Some(output),
+        () = wait_for_cancel => None,
+    }
+}
+
+// Every connected client receives the events from the moment it connects until it closes the connection.
+// Any local user may subscribe, so the number of subscribers is limited.
+async fn run_event_stream(listener: TcpListener, events: broadcast::Sender<ServiceEvent>) {
+    let subscribers = Arc::new(Semaphore::new(MAX_EVENT_SUBSCRIBERS));
+    loop {
+        let (mut stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // persistent errors like EMFILE would make the loop spin
+                warn!("Unable to accept event subscriber: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+
+        let Ok(permit) = subscribers.clone().try_acquire_owned() else {
+            warn!("Too many event subscribers, rejecting {}", addr);
+            continue;
+        };
+        debug!("Event subscriber connected from {}", addr);
+
+        let mut receiver = events.subscribe();
+        tokio::spawn(async move {
+            let _permit = permit;
+            loop {
+                let event = match receiver.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(count)) => {
+                        warn!("Event subscriber {} missed {} events", addr, count);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let Ok(mut line) = serde_json::to_vec(&event) else {
+                    continue;
+                };
+                line.push(b'\n');
+                if stream.write_all(&line).await.is_err() {
+                    debug!("Event subscriber {} disconnected", addr);
+                    break;
+                }
+            }
+        });
+    }
+}
+
+// The parameters come from unprivileged clients, so only the scripts from the root-owned scripts directory
+// are accepted, any other path is dropped
+fn trusted_script(script: Option<PathBuf>, event: &str) -> Option<PathBuf> {
+    let script = script?;
+    match util::check_script_permissions(&script, Path::new(util::SCRIPTS_DIR)) {
+        Ok(()) => Some(script),
+        Err(e) => {
+            warn!("Ignoring the {} script {}: {}", event, script.display(), e);
+            None
+        }
+    }
+}
+
+fn with_trusted_scripts(params: TunnelParams) -> TunnelParams {
+    TunnelParams {
+        post_connect_script: trusted_script(params.post_connect_script.clone(), "connect"),
+        post_disconnect_script: trusted_script(params.post_disconnect_script.clone(), "disconnect"),
+        ..params
+    }
+}
+
+// Scripts are run in the background, so that a slow script does not block the command server.
+// The permissions are checked again because the script may have changed since the connection was requested.
+fn spawn_connection_script(script: Option<&PathBuf>, event: &'static str, info: &ConnectionInfo) {
+    if let Some(script) = script {
+        if let Err(e) = util::check_script_permissions(script, Path::new(util::SCRIPTS_DIR)) {
+            warn!("Refusing to run the {} script {}: {}", event, script.display(), e);
+            return;
+        }
+        let script = script.clone();
+        let info = info.clone();
+        tokio::spawn(async move { util::run_connection_script(&script, event, &info).await });
+    }
+}
+
+// Network settings whose effects may outlive a connection, e.g. the resolver configuration
+// which is kept during the teardown grace period
+#[derive(Debug, Clone, PartialEq)]
+struct NetworkProfile {
+    config_file: PathBuf,
+    server_name: String,
+    tunnel_type: TunnelType,
+    no_dns: bool,
+    dns_servers: Vec<Ipv4Addr>,
+    ignore_dns_servers: Vec<Ipv4Net>,
+    search_domains: Vec<String>,
+    ignore_search_domains: Vec<String>,
+    search_domains_policy: SearchDomainPolicy,
+    default_route: bool,
+    no_routing: bool,
+}
+
+impl NetworkProfile {
+    fn new(params: &TunnelParams) -> Self {
+        Self {
+            config_file: params.config_file.clone(),
+            server_name: params.server_name.clone(),
+            tunnel_type: params.tunnel_type,
+            no_dns: params.no_dns,
+            dns_servers: params.dns_servers.clone(),
+            ignore_dns_servers: params.ignore_dns_servers.clone(),
+            search_domains: params.search_domains.clone(),
+            ignore_search_domains: params.ignore_search_domains.clone(),
+            search_domains_policy: params.search_domains_policy,
+            default_route: params.default_route,
+            no_routing: params.no_routing,
+        }
+    }
+}
 
 pub struct CommandServer {
     port: u16,
+    events_port: u16,
     connection_status: ConnectionStatus,
     session: Option<Arc<VpnSession>>,
     connector: Option<Box<dyn TunnelConnector + Send>>,
+    params: Option<Arc<TunnelParams>>,
+    config_snapshot: Option<TunnelParams>,
+    config_watch: Option<JoinHandle<()>>,
+    pending_reload: Option<TunnelParams>,
+    // set after the first successful connection, cleared by an explicit disconnect
+    session_active: bool,
+    reconnects: u32,
+    // profile of the last connection attempt, survives the reset
+    network_profile: Option<NetworkProfile>,
+    // properties of the connected tunnel, passed to the post-disconnect script
+    connection_info: Option<ConnectionInfo>,
+    // the office mode address was acquired by restoring the persisted IKE session
+    session_restored: bool,
+    // gateway of the tunnel which dropped, the next connection starts with the following one
+    failed_gateway: Option<String>,
+    // last session of the connection, authenticated or waiting for the MFA input
+    last_session: Option<Arc<VpnSession>>,
+    // fires shortly before the re-authentication interval requested by the gateway elapses
+    reauth_timer: Option<JoinHandle<()>>,
+    // task running the last created tunnel, finishes after the tunnel has cleaned up
+    tunnel_task: Option<JoinHandle<()>>,
+    // events for the subscribers of the events port
+    events: broadcast::Sender<ServiceEvent>,
 }
 
 impl CommandServer {
-    pub fn new(port: u16) -> Self {
+    pub fn new(port: u16, events_port: u16) -> Self {
         Self {
             port,
+            events_port,
             connection_status: ConnectionStatus::default(),
             session: None,
             connector: None,
+            params: None,
+            config_snapshot: None,
+            config_watch: None,
+            pending_reload: None,
+            session_active: false,
+            reconnects: 0,
+            network_profile: None,
+            failed_gateway: None,
+            last_session: None,
+            connection_info: None,
+            session_restored: false,
+            reauth_timer: None,
+            tunnel_task: None,
+            events: broadcast::channel(EVENT_CHANNEL_SIZE).0,
         }
     }
 
-    pub async fn run(mut self) -> anyhow::Result<()> {
+    pub async fn run(&mut self) -> anyhow::Result<()> {
         debug!("Starting command server on port {}", self.port);
 
-        let socket = Arc::new(tokio::net::UdpSocket::bind(("127.0.0.1", self.port)).await?);
+        let socket = Arc::new(UdpSocket::bind(("127.0.0.1", self.port)).await?);
         let (event_sender, mut event_receiver) = mpsc::channel::<TunnelEvent>(16);
 
+        match TcpListener::bind(("127.0.0.1", self.events_port)).await {
+            Ok(listener) => {
+                tokio::spawn(run_event_stream(listener, self.events.clone()));
+            }
+            Err(e) => warn!(
+                "Unable to listen for event subscribers on port {}: {}",
+                self.events_port, e
+            ),
+        }
+
         loop {
             let recv = async {
                 let mut buf = vec![0u8; MAX_PACKET_SIZE];
@@ -55,15 +273,56 @@ impl CommandServer {
                     if let Some(event) = event {
                         if let Some(ref mut connector) = self.connector {
                             if connector.handle_tunnel_event(event.clone()).await.is_err() {
+                                let reason = if event == TunnelEvent::RekeyCheck {
+                                    DisconnectReason::SessionExpired
+                                } else {
+                                    DisconnectReason::Error
+                                };
                                 self.reset();
+                                self.connection_status = ConnectionStatus::disconnected_with_reason(reason);
                             }
                         }
                         match event {
-                            TunnelEvent::Connected => {
-                                self.connection_status = ConnectionStatus::connected();
+                            TunnelEvent::Connected(info) => {
+                                self.set_connected();
+                                if std::mem::take(&mut self.session_restored) {
+                                    self.connection_status.lease_renewed = Some(Local::now());
+                                }
+                                let _ = self.events.send(ServiceEvent::from(&info));
+                                self.connection_status.server_name = Some(info.server_name.clone());
+                                self.connection_status.transport = Some(info.transport);
+                                self.connection_status.routes.clone_from(&info.routes);
+                                self.connection_status.address = Some(info.address);
+                                self.connection_status.dns_servers.clone_from(&info.dns_servers);
+                                self.connection_status.search_domains.clone_from(&info.search_domains);
+                                if let Some(ref params) = self.params {
+                                    spawn_connection_script(params.post_connect_script.as_ref(), "connect", &info);
+                                }
+                                self.connection_info = Some(info);
+                                self.start_reauth_timer(event_sender.clone());
                             }
-                            TunnelEvent::Disconnected => {
+                            TunnelEvent::Disconnected(reason) => {
+                                // keep the original reason if the tunnel was already torn down
+                                let reason = self.connection_status.disconnect_reason.unwrap_or(reason);
+                                self.failed_gateway = self.connection_info.as_ref().map(|info| info.server_name.clone());
                                 self.reset();
+                                self.connection_status = ConnectionStatus::disconnected_with_reason(reason);
+                                let _ = self.events.send(ServiceEvent::Disconnected { reason });
+                                if let Some(params) = self.pending_reload.take() {
+                                    self.reconnect(params, event_sender.clone()).await;
+                                }
+                            }
+                            TunnelEvent::Rekeyed => {
+                                let _ = self.events.send(ServiceEvent::Rekeyed);
+                                self.connection_status.last_rekey = Some(Local::now());
+                            }
+                            TunnelEvent::ConfigChanged => {
+                                self.handle_config_change(event_sender.clone()).await;
+                            }
+                            TunnelEvent::ReauthDue => {
+                                if let Err(e) = self.reauth(event_sender.clone()).await {
+                                    warn!("Re-authentication failed: {}", e);
+                                }
                             }
                             _ => {}
                         }
@@ -71,7 +330,7 @@ impl CommandServer {
                 }
                 result = recv => {
                     let (data, addr) = result?;
-                    let resp = self.handle(&data, event_sender.clone()).await;
+                    let resp = self.handle(&data, &socket, event_sender.clone()).await;
                     trace!("Response: {:?}", resp);
                     let json = serde_json::to_vec(&resp)?;
                     let _ = socket.send_to(&json, addr).await;
@@ -80,7 +339,12 @@ impl CommandServer {
         }
     }
 
-    async fn handle(&mut self, packet: &[u8], event_sender: mpsc::Sender<TunnelEvent>) -> TunnelServiceResponse {
+    async fn handle(
+        &mut self,
+        packet: &[u8],
+        socket: &UdpSocket,
+        event_sender: mpsc::Sender<TunnelEvent>,
+    ) -> TunnelServiceResponse {
         trace!("Command received");
         let req = match serde_json::from_slice::<TunnelServiceRequest>(packet) {
             Ok(req) => req,
@@ -93,16 +357,32 @@ impl CommandServer {
         match req {
             TunnelServiceRequest::Connect(params) => {
                 trace!("Handling connect command");
-                match self.connect(Arc::new(params), event_sender).await {
-                    Ok(()) => TunnelServiceResponse::Ok,
-                    Err(e) => {
+                // the service writes the sessions as root, so the directory is never taken from the client
+                let params = Arc::new(with_trusted_scripts(TunnelParams {
+                    session_path: TunnelParams::default().session_path,
+                    ..params
+                }));
+                let status = ConnectionStatus {
+                    profile: Some(params.config_file.clone()),
+                    ..ConnectionStatus::disconnected()
+                };
+                let connect = tunnel::with_connect_timeout(&params, self.connect(params.clone(), event_sender));
+                match cancellable(connect, socket, status).await {
+                    Some(Ok(())) => TunnelServiceResponse::Ok,
+                    Some(Err(e)) => {
                         self.reset();
-                        TunnelServiceResponse::Error(e.to_string())
+                        TunnelServiceResponse::from_error(&e)
+                    }
+                    None => {
+                        self.cancel().await;
+                        TunnelServiceResponse::Error("Connection canceled".to_owned())
                     }
                 }
             }
             TunnelServiceRequest::Disconnect => {
                 debug!("Handling disconnect command");
+                self.session_active = false;
+                self.signout().await;
 
                 match self.disconnect().await {
                     Ok(()) => TunnelServiceResponse::Ok,
@@ -111,19 +391,52 @@ impl CommandServer {
             }
             TunnelServiceRequest::GetStatus => {
                 trace!("Handling get status command");
-                TunnelServiceResponse::ConnectionStatus(self.get_status().clone())
+                TunnelServiceResponse::ConnectionStatus(self.get_status())
             }
             TunnelServiceRequest::ChallengeCode(code, _) => {
                 debug!("Handling challenge code command");
-                match self.challenge_code(&code, event_sender).await {
-                    Ok(()) => TunnelServiceResponse::Ok,
-                    Err(e) => {
+                let params = self.params.clone().unwrap_or_default();
+                let status = self.get_status();
+                let challenge = tunnel::with_connect_timeout(&params, self.challenge_code(&code, event_sender));
+                match cancellable(challenge, socket, status).await {
+                    Some(Ok(())) => TunnelServiceResponse::Ok,
+                    Some(Err(e)) => {
                         warn!("Challenge code error: {:#}", e);
                         self.reset();
-                        TunnelServiceResponse::Error(e.to_string())
+                        TunnelServiceResponse::from_error(&e)
+                    }
+                    None => {
+                        self.cancel().await;
+                        TunnelServiceResponse::Error("Connection canceled".to_owned())
                     }
                 }
             }
+            TunnelServiceRequest::ClearTransportCache(params) => {
+                debug!("Clearing transport cache for {}", params.server_name);
+                // only the server names are taken from the client, the cache is always in the service's own directory
+                let params = TunnelParams {
+                    server_name: params.server_name,
+                    ..Default::default()
+                };
+                tunnel::clear_transport_cache(&params);
+                TunnelServiceResponse::Ok
+            }
+            TunnelServiceRequest::Reauth => {
+                debug!("Handling reauth command");
+                match self.reauth(event_sender).await {
+                    Ok(()) => TunnelServiceResponse::Ok,
+                    Err(e) => TunnelServiceResponse::from_error(&e),
+                }
+            }
+            TunnelServiceRequest::Cancel => {
+                debug!("Handling cancel command");
+                if self.is_connected() {
+                    TunnelServiceResponse::Error("No connection in progress".to_owned())
+                } else {
+                    self.cancel().await;
+                    TunnelServiceResponse::Ok
+                }
+            }
         }
     }
 
@@ -131,11 +444,34 @@ impl CommandServer {
         self.connection_status.connected_since.is_some()
     }
 
+    fn set_connected(&mut self) {
+        if self.is_connected() {
+            return;
+        }
+
+        if self.session_active {
+            self.reconnects += 1;
+        } else {
+            self.session_active = true;
+            self.reconnects = 0;
+        }
+
+        self.connection_status = ConnectionStatus::connected(self.reconnects);
+    }
+
     async fn connect_for_session(
         &mut self,
         session: Arc<VpnSession>,
         event_sender: mpsc::Sender<TunnelEvent>,
     ) -> anyhow::Result<()> {
+        let verify_timeout = self
+            .params
+            .as_ref()
+            .filter(|params| params.verify_connectivity)
+            .map(|params| params.verify_timeout);
+
+        self.last_session = Some(session.clone());
+
         let Some(ref mut connector) = self.connector else {
             anyhow::bail!("No tunnel connector!");
         };
@@ -151,15 +487,51 @@ impl CommandServer {
 
         let tunnel = connector.create_tunnel(session, command_sender).await?;
 
+        let Some(verify_timeout) = verify_timeout else {
+            self.tunnel_task = Some(tokio::spawn(async move {
+                if let Err(e) = tunnel.run(command_receiver, event_sender).await {
+                    warn!("Tunnel error: {}", e);
+                }
+            }));
+
+            self.set_connected();
+
+            return Ok(());
+        };
+
+        // the tunnel reports Connected only after the connectivity check, so wait for the first state event
+        let (tunnel_sender, mut tunnel_receiver) = mpsc::channel::<TunnelEvent>(16);
+        let (state_sender, state_receiver) = oneshot::channel();
+
         tokio::spawn(async move {
-            if let Err(e) = tunnel.run(command_receiver, event_sender).await {
-                warn!("Tunnel error: {}", e);
+            let mut state_sender = Some(state_sender);
+            while let Some(event) = tunnel_receiver.recv().await {
+                if matches!(event, TunnelEvent::Connected(..) | TunnelEvent::Disconnected(_)) {
+                    if let Some(sender) = state_sender.take() {
+                        let _ = sender.send(event.clone());
+                    }
+                }
+                if event_sender.send(event).await.is_err() {
+                    break;
+                }
             }
         });
 
-        self.connection_status = ConnectionStatus::connected();
+        self.tunnel_task = Some(tokio::spawn(async move {
+            if let Err(e) = tunnel.run(command_receiver, tunnel_sender).await {
+                warn!("Tunnel error: {}", e);
+            }
+        }));
 
-        Ok(())
+        match tokio::time::timeout(verify_timeout + VERIFY_SETUP_LEEWAY, state_receiver).await {
+            Ok(Ok(TunnelEvent::Connected(..))) => {
+                self.set_connected();
+                Ok(())
+            }
+            Ok(Ok(TunnelEvent::Disconnected(reason))) => Err(anyhow!("Tunnel is not usable: {}", reason)),
+            Ok(_) => Err(anyhow!("Tunnel terminated before it was connected")),
+            Err(_) => Err(anyhow!("Timeout while verifying tunnel connectivity")),
+        }
     }
 
     async fn connect(
@@ -171,12 +543,26 @@ impl CommandServer {
             Ok(())
         } else {
             self.reset();
+            self.switch_network_profile(&params);
+
+            let params = match self.failed_gateway.take() {
+                Some(gateway) if params.server_names().len() > 1 => {
+                    debug!("Gateway {} dropped the tunnel, trying the next one first", gateway);
+                    let mut params = (*params).clone();
+                    params.rotate_servers(&gateway);
+                    Arc::new(params)
+                }
+                _ => params,
+            };
 
             let mut connector = tunnel::new_tunnel_connector(params.clone()).await?;
             let session = if params.ike_persist {
                 debug!("Attempting to load IKE session");
                 match connector.restore_session().await {
-                    Ok(session) => session,
+                    Ok(session) => {
+                        self.session_restored = true;
+                        session
+                    }
                     Err(_) => {
                         connector = tunnel::new_tunnel_connector(params.clone()).await?;
                         connector.authenticate().await?
@@ -186,10 +572,167 @@ impl CommandServer {
                 connector.authenticate().await?
             };
             self.connector = Some(connector);
+            self.start_config_watch(&params, event_sender.clone());
+            self.params = Some(params);
             self.connect_for_session(session, event_sender).await
         }
     }
 
+    // Nothing from the previous profile may leak into a connection made with different network settings
+    fn switch_network_profile(&mut self, params: &TunnelParams) {
+        let profile = NetworkProfile::new(params);
+
+        if let Some(previous) = self.network_profile.replace(profile.clone()) {
+            if previous != profile {
+                debug!("Network profile changed, discarding the state of the previous connection");
+                platform::flush_resolver_cleanup();
+                self.session_active = false;
+            }
+        }
+    }
+
+    fn start_config_watch(&mut self, params: &TunnelParams, event_sender: mpsc::Sender<TunnelEvent>) {
+        if !params.config_file.is_file() {
+            return;
+        }
+
+        self.config_snapshot = TunnelParams::load(&params.config_file).ok();
+
+        match platform::watch_file(&params.config_file) {
+            Ok(mut receiver) => {
+                self.config_watch = Some(tokio::spawn(async move {
+                    while receiver.recv().await.is_some() {
+                        // a single save usually produces a burst of events
+                        tokio::time::sleep(CONFIG_WATCH_DEBOUNCE).await;
+                        while receiver.try_recv().is_ok() {}
+
+                        if event_sender.send(TunnelEvent::ConfigChanged).await.is_err() {
+                            break;
+                        }
+                    }
+                }));
+            }
+            Err(e) => warn!("Unable to watch {}: {}", params.config_file.display(), e),
+        }
+    }
+
+    async fn handle_config_change(&mut self, event_sender: mpsc::Sender<TunnelEvent>) {
+        let Some(params) = self.params.clone() else {
+            return;
+        };
+
+        let mut new_params = match TunnelParams::load(&params.config_file) {
+            Ok(new_params) => new_params,
+            Err(e) => {
+                warn!("Unable to reload {}: {}", params.config_file.display(), e);
+                return;
+            }
+        };
+
+        let Some(old_params) = self.config_snapshot.replace(new_params.clone()) else {
+            return;
+        };
+
+        if new_params == old_params {
+            trace!("Configuration file touched but not changed");
+            return;
+        }
+
+        // GUI-only settings do not affect the tunnel
+        let mut normalized = new_params.clone();
+        normalized.icon_theme = old_params.icon_theme;
+        normalized.notify_rekey = old_params.notify_rekey;
+        normalized.auto_reload = old_params.auto_reload;
+        if normalized == old_params {
+            debug!("Configuration file changed, no reconnect needed");
+            return;
+        }
+
+        if !new_params.auto_reload {
+            warn!(
+                "Configuration file {} changed, reconnect to apply the changes",
+                params.config_file.display()
+            );
+            return;
+        }
+
+        debug!("Configuration file changed, reconnecting");
+
+        // the password may come from the keychain or the client rather than from the file
+        if new_params.password.is_empty() {
+            new_params.password.clone_from(&params.password);
+        }
+        new_params.session_path.clone_from(&params.session_path);
+        let new_params = with_trusted_scripts(new_params);
+
+        if self.is_connected() {
+            self.pending_reload = Some(new_params);
+            let _ = self.disconnect().await;
+        } else {
+            let _ = self.disconnect().await;
+            self.reconnect(new_params, event_sender).await;
+        }
+    }
+
+    async fn reconnect(&mut self, params: TunnelParams, event_sender: mpsc::Sender<TunnelEvent>) {
+        if let Err(e) = self.connect(Arc::new(params), event_sender).await {
+            warn!("Reconnect failed: {}", e);
+            self.reset();
+        }
+    }
+
+    // The running tunnel is kept if the gateway accepts the new authentication,
+    // otherwise the connection is established from scratch
+    async fn reauth(&mut self, event_sender: mpsc::Sender<TunnelEvent>) -> anyhow::Result<()> {
+        if !self.is_connected() || self.connection_status.mfa.is_some() {
+            anyhow::bail!("No active tunnel");
+        }
+
+        let (Some(connector), Some(params)) = (self.connector.as_mut(), self.params.clone()) else {
+            anyhow::bail!("No active tunnel");
+        };
+
+        match connector.reauthenticate().await {
+            Ok(()) => {
+                debug!("Re-authentication succeeded");
+                self.start_reauth_timer(event_sender);
+            }
+            Err(e) => {
+                warn!("Re-authentication rejected: {}, reconnecting", e);
+                self.pending_reload = Some((*params).clone());
+                let _ = self.disconnect().await;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn start_reauth_timer(&mut self, event_sender: mpsc::Sender<TunnelEvent>) {
+        if let Some(handle) = self.reauth_timer.take() {
+            handle.abort();
+        }
+
+        let Some(params) = self.params.as_ref().filter(|params| params.auto_reauth) else {
+            return;
+        };
+
+        let Some(timeout) = self.connection_info.as_ref().and_then(|info| info.reauth_timeout) else {
+            if params.tunnel_type == TunnelType::Ipsec {
+                debug!("The gateway did not provide a re-authentication timeout");
+            }
+            return;
+        };
+
+        let delay = timeout.checked_sub(REAUTH_LEEWAY).unwrap_or(timeout);
+
+        debug!("Scheduling re-authentication in {} seconds", delay.as_secs());
+
+        self.reauth_timer = Some(tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let _ = event_sender.send(TunnelEvent::ReauthDue).await;
+        }));
+    }
+
     async fn challenge_code(&mut self, code: &str, event_sender: mpsc::Sender<TunnelEvent>) -> anyhow::Result<()> {
         if let Some(ref mut connector) = self.connector {
             match self.session.as_ref() {
@@ -204,6 +747,40 @@ impl CommandServer {
         }
     }
 
+    // dropping the connector deletes the IKE SA, the tunnel cleans up its partial state on drop
+    async fn cancel(&mut self) {
+        debug!("Canceling connection in progress");
+        self.session_active = false;
+        self.signout().await;
+        let _ = self.disconnect().await;
+    }
+
+    // The tunnel cleanup signs out only if the tunnel was created, with signout-on-disconnect
+    // the user-initiated teardown also ends the sessions which are authenticated or waiting for MFA.
+    async fn signout(&self) {
+        let (Some(params), Some(session)) = (self.params.as_ref(), self.last_session.as_ref()) else {
+            return;
+        };
+
+        if !params.signout_on_disconnect {
+            return;
+        }
+
+        // the session belongs to the gateway selected from the server list
+        let mut params = (**params).clone();
+        if let Some(ref info) = self.connection_info {
+            params.server_name.clone_from(&info.server_name);
+        }
+
+        debug!("Signing out from {}", params.server_name);
+        if let Err(e) = CccHttpClient::new(Arc::new(params), Some(session.clone()))
+            .signout()
+            .await
+        {
+            warn!("Signout failed: {:#}", e);
+        }
+    }
+
     async fn disconnect(&mut self) -> anyhow::Result<()> {
         if let Some(ref mut connector) = self.connector {
             connector.delete_session().await;
@@ -214,12 +791,198 @@ impl CommandServer {
     }
 
     fn reset(&mut self) {
+        if let Some(handle) = self.config_watch.take() {
+            handle.abort();
+        }
+        if let Some(handle) = self.reauth_timer.take() {
+            handle.abort();
+        }
+        if let (Some(params), Some(info)) = (self.params.as_ref(), self.connection_info.take()) {
+            spawn_connection_script(params.post_disconnect_script.as_ref(), "disconnect", &info);
+        }
         self.session = None;
+        self.last_session = None;
+        self.session_restored = false;
         self.connector = None;
+        self.params = None;
+        self.config_snapshot = None;
         self.connection_status = ConnectionStatus::disconnected();
     }
 
-    fn get_status(&self) -> &ConnectionStatus {
-        &self.connection_status
+    /// Tear down the active tunnel before the process exits, waiting for the cleanup up to the timeout
+    pub async fn shutdown(&mut self, timeout: Duration) {
+        if let (Some(connector), Some(task)) = (self.connector.take(), self.tunnel_task.take()) {
+            tunnel::shutdown(connector, task, timeout).await;
+        }
+        self.reset();
+    }
+
+    fn get_status(&self) -> ConnectionStatus {
+        // counters of the tunnel interface cover all tunnel types, including the kernel-side native IPSec.
+        // The interface is taken from the connected tunnel, as the transport may differ from the configured one.
+        let traffic = self
+            .connection_info
+            .as_ref()
+            .filter(|_| self.is_connected() && self.connection_status.mfa.is_none())
+            .and_then(|info| platform::get_device_stats(&info.if_name).ok());
+
+        ConnectionStatus {
+            profile: self.params.as_ref().map(|params| params.config_file.clone()),
+            traffic,
+            ..self.connection_status.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::tunnel::{TunnelCommand, VpnTunnel};
+
+    // connector of an established tunnel, only the re-authentication result matters
+    struct ReauthConnector {
+        accept: bool,
+        terminated: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl TunnelConnector for ReauthConnector {
+        async fn authenticate(&mut self) -> anyhow::Result<Arc<VpnSession>> {
+            Err(anyhow!("Not implemented"))
+        }
+
+        async fn delete_session(&mut self) {}
+
+        async fn restore_session(&mut self) -> anyhow::Result<Arc<VpnSession>> {
+            Err(anyhow!("Not implemented"))
+        }
+
+        async fn challenge_code(&mut self, _: Arc<VpnSession>, _: &str) -> anyhow::Result<Arc<VpnSession>> {
+            Err(anyhow!("Not implemented"))
+        }
+
+        async fn create_tunnel(
+            &mut self,
+            _: Arc<VpnSession>,
+            _: mpsc::Sender<TunnelCommand>,
+        ) -> anyhow::Result<Box<dyn VpnTunnel + Send>> {
+            Err(anyhow!("Not implemented"))
+        }
+
+        async fn terminate_tunnel(&mut self) -> anyhow::Result<()> {
+            self.terminated.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+
+        async fn reauthenticate(&mut self) -> anyhow::Result<()> {
+            if self.accept {
+                Ok(())
+            } else {
+                Err(anyhow!("Re-authentication is not supported for SSL tunnels"))
+            }
+        }
+
+        async fn handle_tunnel_event(&mut self, _: TunnelEvent) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn connected_server(accept: bool, terminated: Arc<AtomicBool>) -> CommandServer {
+        let mut server = CommandServer::new(0, 0);
+        server.connector = Some(Box::new(ReauthConnector { accept, terminated }));
+        server.params = Some(Arc::new(TunnelParams {
+            server_name: "gw.example.com".to_owned(),
+            auto_reauth: true,
+            ..Default::default()
+        }));
+        server.connection_status = ConnectionStatus::connected(0);
+        server
+    }
+
+    #[test]
+    fn test_untrusted_scripts_ignored() {
+        let params = with_trusted_scripts(TunnelParams {
+            post_connect_script: Some(PathBuf::from("/sbin/reboot")),
+            post_disconnect_script: Some(std::env::temp_dir().join("disconnect.sh")),
+            ..Default::default()
+        });
+        assert!(params.post_connect_script.is_none());
+        assert!(params.post_disconnect_script.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reauth() {
+        let (sender, _receiver) = mpsc::channel(1);
+
+        let mut server = CommandServer::new(0, 0);
+        assert!(server.reauth(sender.clone()).await.is_err());
+
+        // the accepted re-authentication keeps the tunnel
+        let terminated = Arc::new(AtomicBool::new(false));
+        let mut server = connected_server(true, terminated.clone());
+        server.reauth(sender.clone()).await.unwrap();
+        assert!(server.is_connected());
+        assert!(server.connector.is_some());
+        assert!(server.pending_reload.is_none());
+        assert!(!terminated.load(Ordering::SeqCst));
+
+        // SSL tunnels and rejected re-authentications reconnect with the same parameters
+        let terminated = Arc::new(AtomicBool::new(false));
+        let mut server = connected_server(false, terminated.clone());
+        server.reauth(sender).await.unwrap();
+        assert!(!server.is_connected());
+        assert!(server.connector.is_none());
+        assert_eq!(
+            server.pending_reload.map(|params| params.server_name),
+            Some("gw.example.com".to_owned())
+        );
+        assert!(terminated.load(Ordering::SeqCst));
+    }
+
+    async fn request(client: &UdpSocket, request: &TunnelServiceRequest) -> TunnelServiceResponse {
+        client.send(&serde_json::to_vec(request).unwrap()).await.unwrap();
+        let mut buf = vec![0u8; MAX_PACKET_SIZE];
+        let size = client.recv(&mut buf).await.unwrap();
+        serde_json::from_slice(&buf[0..size]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cancellable() {
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        client.connect(server.local_addr().unwrap()).await.unwrap();
+
+        let status = ConnectionStatus {
+            profile: Some(PathBuf::from("/etc/snx-rs/snx-rs.conf")),
+            ..ConnectionStatus::disconnected()
+        };
+
+        let requests = async {
+            // the status is reported while the connection is in progress
+            let resp = request(&client, &TunnelServiceRequest::GetStatus).await;
+            assert!(matches!(
+                resp,
+                TunnelServiceResponse::ConnectionStatus(ConnectionStatus { profile: Some(ref profile), .. })
+                    if *profile == PathBuf::from("/etc/snx-rs/snx-rs.conf")
+            ));
+
+            let resp = request(&client, &TunnelServiceRequest::Disconnect).await;
+            assert!(matches!(resp, TunnelServiceResponse::Error(_)));
+
+            let resp = request(&client, &TunnelServiceRequest::Cancel).await;
+            assert!(matches!(resp, TunnelServiceResponse::Ok));
+        };
+
+        let (result, ()) = tokio::join!(
+            cancellable(std::future::pending::<()>(), &server, status.clone()),
+            requests
+        );
+        assert!(result.is_none());
+
+        assert_eq!(cancellable(async { 1 }, &server, status).await, Some(1));
     }
 }
@@ -3,15 +3,211 @@ use crate::{
     ccc::CccHttpClient,
     model::{
         params::TunnelParams,
-        proto::{LoginFactor, ServerInfoResponse},
+        proto::{LoginFactor, LoginOption, ServerInfoResponse},
     },
     sexpr::SExpression,
+    util,
 };
-use cached::proc_macro::cached;
-use std::{collections::VecDeque, sync::Arc};
-use tracing::trace;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io,
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio_native_tls::native_tls;
+use tracing::{debug, info, trace, warn};
+
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(8);
+const CLIENT_DECIDE_METHOD: &str = "client_decide";
+
+// request parameters -> time of the request and the server reply
+static SERVER_INFO_CACHE: Mutex<BTreeMap<CacheKey, (Instant, ServerInfoResponse)>> = Mutex::new(BTreeMap::new());
+
+// Everything which affects the ClientHello request and the trust in its reply. A reply fetched with
+// relaxed certificate checks or through another proxy is never reused with stricter parameters.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct CacheKey {
+    server_name: String,
+    client_type: String,
+    no_cert_check: bool,
+    ignore_server_cert: bool,
+    server_cert_fingerprint: Option<String>,
+    ca_cert: Vec<PathBuf>,
+    proxy_url: Option<String>,
+    bind_address: Option<IpAddr>,
+    user_agent: String,
+}
+
+impl CacheKey {
+    fn new(params: &TunnelParams) -> Self {
+        Self {
+            server_name: params.server_name.clone(),
+            client_type: params.tunnel_type.as_client_type().to_owned(),
+            no_cert_check: params.no_cert_check,
+            ignore_server_cert: params.ignore_server_cert,
+            server_cert_fingerprint: params.server_cert_fingerprint.clone(),
+            ca_cert: params.ca_cert.clone(),
+            proxy_url: params.proxy_url.clone(),
+            bind_address: params.bind_address,
+            user_agent: params.user_agent.clone(),
+        }
+    }
+}
 
+/// Fetch the server info with the ClientHello request, from the first responding gateway of the `server-name` list.
+/// Transient network failures are retried up to `server-info-retries` times with exponential backoff.
+/// Replies are cached per gateway and connection settings for `server-info-cache-ttl`.
 pub async fn get(params: &TunnelParams) -> anyhow::Result<ServerInfoResponse> {
+    if params.server_names().len() > 1 {
+        Ok(first_available_gateway(params).await?.1)
+    } else {
+        get_with_retries(params).await
+    }
+}
+
+/// Parameters with the first gateway of the `server-name` list which answers the ClientHello request
+/// as the only server. Parameters with a single server are returned unchanged.
+pub async fn select_gateway(params: &TunnelParams) -> anyhow::Result<TunnelParams> {
+    if params.server_names().len() > 1 {
+        let (params, _) = first_available_gateway(params).await?;
+        info!("Selected gateway {}", params.server_name);
+        Ok(params)
+    } else {
+        Ok(params.clone())
+    }
+}
+
+/// Parameters with the realm chosen by the client when `login-type` is empty and the server leaves
+/// the choice to the client with the `client_decide` authentication method. Otherwise the parameters
+/// are returned unchanged.
+pub async fn resolve_login_type(params: &TunnelParams) -> anyhow::Result<TunnelParams> {
+    if !params.login_type.is_empty() {
+        return Ok(params.clone());
+    }
+
+    let info = get(params).await?;
+
+    match client_decide_realm(&info) {
+        Some(option) => {
+            info!("Selected login type {} ({})", option.id, option.display_name);
+            Ok(TunnelParams {
+                login_type: option.id.clone(),
+                ..params.clone()
+            })
+        }
+        None => Ok(params.clone()),
+    }
+}
+
+// the first visible realm with several login factors, or the first visible realm if none of them has,
+// in the order of the server response
+fn client_decide_realm(info: &ServerInfoResponse) -> Option<&LoginOption> {
+    if info.connectivity_info.default_authentication_method != CLIENT_DECIDE_METHOD {
+        return None;
+    }
+
+    let options = util::by_index(&info.login_options_data.as_ref()?.login_options_list)
+        .into_iter()
+        .filter(|option| !option.is_hidden())
+        .collect::<Vec<_>>();
+
+    options
+        .iter()
+        .find(|option| option.factors.len() > 1)
+        .or_else(|| options.first())
+        .copied()
+}
+
+async fn first_available_gateway(params: &TunnelParams) -> anyhow::Result<(TunnelParams, ServerInfoResponse)> {
+    let mut last_error = None;
+
+    for name in params.server_names() {
+        let candidate = TunnelParams {
+            server_name: name.to_owned(),
+            ..params.clone()
+        };
+        match get_with_retries(&candidate).await {
+            Ok(info) => return Ok((candidate, info)),
+            Err(e) => {
+                warn!("Gateway {} is not available: {:#}", name, e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No server name configured")))
+}
+
+/// Drop the cached server info of the given `server-name`, all gateways of a comma-separated list are dropped.
+/// The next request goes to the server again, e.g. to pick up the login options after a gateway upgrade.
+pub fn invalidate(server_name: &str) {
+    let names = server_name.split(',').map(str::trim).collect::<Vec<_>>();
+    SERVER_INFO_CACHE.lock().unwrap().retain(|key, _| {
+        let keep = !names.contains(&key.server_name.as_str());
+        if !keep {
+            debug!("Invalidated cached server info of {}", key.server_name);
+        }
+        keep
+    });
+}
+
+fn get_cached(params: &TunnelParams) -> Option<ServerInfoResponse> {
+    let key = CacheKey::new(params);
+    let mut cache = SERVER_INFO_CACHE.lock().unwrap();
+    match cache.get(&key) {
+        Some((fetched, info)) if fetched.elapsed() < params.server_info_cache_ttl => Some(info.clone()),
+        Some(_) => {
+            cache.remove(&key);
+            None
+        }
+        None => None,
+    }
+}
+
+async fn get_with_retries(params: &TunnelParams) -> anyhow::Result<ServerInfoResponse> {
+    if let Some(info) = get_cached(params) {
+        trace!("Using cached server info of {}", params.server_name);
+        return Ok(info);
+    }
+
+    let info = fetch_with_retries(params).await?;
+
+    if !params.server_info_cache_ttl.is_zero() {
+        SERVER_INFO_CACHE
+            .lock()
+            .unwrap()
+            .insert(CacheKey::new(params), (Instant::now(), info.clone()));
+    }
+
+    Ok(info)
+}
+
+async fn fetch_with_retries(params: &TunnelParams) -> anyhow::Result<ServerInfoResponse> {
+    let mut attempt = 0;
+
+    loop {
+        match get_once(params).await {
+            Err(e) if attempt < params.server_info_retries && is_transient_error(&e) => {
+                let delay = retry_delay(attempt);
+                attempt += 1;
+                warn!(
+                    "Server info request failed: {:#}, retry {}/{} in {} ms",
+                    e,
+                    attempt,
+                    params.server_info_retries,
+                    delay.as_millis()
+                );
+                tokio::time::sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn get_once(params: &TunnelParams) -> anyhow::Result<ServerInfoResponse> {
     let client = CccHttpClient::new(Arc::new(params.clone()), None);
 
     let info = client.get_server_info().await?;
@@ -22,12 +218,103 @@ pub async fn get(params: &TunnelParams) -> anyhow::Result<ServerInfoResponse> {
         .try_into()
 }
 
-#[cached(
-    result = true,
-    ty = "cached::UnboundCache<String, VecDeque<String>>",
-    create = "{ cached::UnboundCache::new() }",
-    convert = r#"{ params.server_name.to_owned() }"#
-)]
+// up to 50% of random jitter is added so that many clients don't retry in lockstep after a gateway hiccup
+fn retry_delay(attempt: u32) -> Duration {
+    let delay = RETRY_BASE_DELAY
+        .saturating_mul(1 << attempt.min(16))
+        .min(RETRY_MAX_DELAY);
+    delay + delay.mul_f64(rand::random::<f64>() / 2.0)
+}
+
+// Connection failures, timeouts and server-side errors are worth another attempt.
+// TLS failures such as certificate problems, rejected requests and malformed replies fail immediately.
+// reqwest reports a failed TLS handshake as a connect error, so the TLS error is looked up first.
+fn is_transient_error(error: &anyhow::Error) -> bool {
+    if error.chain().any(|cause| cause.is::<native_tls::Error>()) {
+        return false;
+    }
+
+    error.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            e.is_timeout() || e.is_connect() || e.status().is_some_and(|status| status.is_server_error())
+        } else if let Some(e) = cause.downcast_ref::<io::Error>() {
+            matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::BrokenPipe
+            )
+        } else {
+            cause.is::<tokio::time::error::Elapsed>()
+        }
+    })
+}
+
+/// Unparsed `CCCserverResponse` of the ClientHello request, intended to be attached to bug reports.
+/// With `redact` set the values of the cookie and session fields are masked.
+pub async fn get_raw(params: &TunnelParams, redact: bool) -> anyhow::Result<SExpression> {
+    let client = CccHttpClient::new(Arc::new(select_gateway(params).await?), None);
+
+    let mut info = client.get_server_info().await?;
+
+    if redact {
+        info.redact(&|name| {
+            let name = name.to_lowercase();
+            name.contains("cookie") || name.contains("session_id")
+        });
+    }
+
+    Ok(info)
+}
+
+/// Address of the gateway node advertised by the server in the connectivity info, if it differs
+/// from the configured server. Addresses from the private ranges are ignored when the server itself
+/// is public, as they are usually the internal addresses of a gateway behind NAT.
+pub async fn get_redirect_address(params: &TunnelParams) -> Option<Ipv4Addr> {
+    if params.no_server_redirect {
+        return None;
+    }
+
+    let server_ip = get(params).await.ok()?.connectivity_info.server_ip;
+
+    let resolved = tokio::net::lookup_host(util::split_host_port(&params.server_name))
+        .await
+        .ok()?
+        .map(|addr| addr.ip())
+        .collect::<Vec<_>>();
+
+    let redirect = redirect_address(server_ip, &resolved)?;
+
+    debug!(
+        "Server {} redirects to gateway address {}",
+        params.server_name, redirect
+    );
+
+    Some(redirect)
+}
+
+// the advertised address unless it is one of the resolved ones or a private address of a public server
+fn redirect_address(server_ip: Ipv4Addr, resolved: &[IpAddr]) -> Option<Ipv4Addr> {
+    if server_ip.is_unspecified() || resolved.contains(&IpAddr::V4(server_ip)) {
+        return None;
+    }
+
+    let is_private = |addr: &IpAddr| match addr {
+        IpAddr::V4(v4) => v4.is_private(),
+        IpAddr::V6(_) => false,
+    };
+
+    if server_ip.is_private() && !resolved.iter().any(is_private) {
+        debug!("Ignoring private gateway address {}", server_ip);
+        return None;
+    }
+
+    Some(server_ip)
+}
+
 pub async fn get_mfa_prompts(params: &TunnelParams) -> anyhow::Result<VecDeque<String>> {
     let factors = get_login_factors(params).await?;
 
@@ -46,12 +333,6 @@ pub async fn get_mfa_prompts(params: &TunnelParams) -> anyhow::Result<VecDeque<S
     Ok(result)
 }
 
-#[cached(
-    result = true,
-    ty = "cached::UnboundCache<String, Vec<LoginFactor>>",
-    create = "{ cached::UnboundCache::new() }",
-    convert = r#"{ format!("{}/{}", params.server_name, params.login_type) }"#
-)]
 pub async fn get_login_factors(params: &TunnelParams) -> anyhow::Result<Vec<LoginFactor>> {
     let info = get(params).await?;
 
@@ -70,3 +351,198 @@ pub async fn get_login_factors(params: &TunnelParams) -> anyhow::Result<Vec<Logi
 
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Context};
+
+    use super::*;
+
+    #[test]
+    fn test_cache_key() {
+        let params = TunnelParams {
+            server_name: "vpn.example.com".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(CacheKey::new(&params), CacheKey::new(&params.clone()));
+
+        let relaxed = TunnelParams {
+            ignore_server_cert: true,
+            ..params.clone()
+        };
+        assert_ne!(CacheKey::new(&params), CacheKey::new(&relaxed));
+
+        let proxied = TunnelParams {
+            proxy_url: Some("http://proxy:3128".to_owned()),
+            ..params.clone()
+        };
+        assert_ne!(CacheKey::new(&params), CacheKey::new(&proxied));
+
+        // the credentials do not affect the ClientHello request
+        let other_user = TunnelParams {
+            user_name: "user1".to_owned(),
+            ..params.clone()
+        };
+        assert_eq!(CacheKey::new(&params), CacheKey::new(&other_user));
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        for attempt in 0..20 {
+            let base = RETRY_BASE_DELAY
+                .saturating_mul(1 << attempt.min(16))
+                .min(RETRY_MAX_DELAY);
+            let delay = retry_delay(attempt);
+            assert!(delay >= base && delay <= base.mul_f64(1.5));
+        }
+        assert!(retry_delay(20) <= RETRY_MAX_DELAY.mul_f64(1.5));
+    }
+
+    #[test]
+    fn test_transient_errors() {
+        let reset = anyhow::Error::from(io::Error::from(io::ErrorKind::ConnectionReset));
+        assert!(is_transient_error(&reset));
+
+        let wrapped = Err::<(), _>(io::Error::from(io::ErrorKind::TimedOut))
+            .context("ClientHello failed")
+            .unwrap_err();
+        assert!(is_transient_error(&wrapped));
+
+        let cert = Err::<(), _>(native_tls::Certificate::from_pem(b"invalid").unwrap_err())
+            .context("ClientHello failed")
+            .unwrap_err();
+        assert!(!is_transient_error(&cert));
+
+        // only the type of the error counts, not its text
+        assert!(!is_transient_error(&anyhow!("Connection timeout")));
+        let wrapped = Err::<(), _>(io::Error::from(io::ErrorKind::ConnectionRefused))
+            .context("Invalid certificate path")
+            .unwrap_err();
+        assert!(is_transient_error(&wrapped));
+
+        assert!(!is_transient_error(&anyhow!("Authentication failed")));
+        assert!(!is_transient_error(&anyhow::Error::from(io::Error::from(
+            io::ErrorKind::InvalidData
+        ))));
+    }
+
+    #[test]
+    fn test_redirect_address() {
+        let public = [IpAddr::V4(Ipv4Addr::new(203, 0, 113, 10))];
+        let private = [IpAddr::V4(Ipv4Addr::new(10, 1, 0, 10))];
+
+        // another public node of the cluster
+        assert_eq!(
+            redirect_address(Ipv4Addr::new(203, 0, 113, 11), &public),
+            Some(Ipv4Addr::new(203, 0, 113, 11))
+        );
+
+        // the configured server itself
+        assert_eq!(redirect_address(Ipv4Addr::new(203, 0, 113, 10), &public), None);
+        assert_eq!(redirect_address(Ipv4Addr::UNSPECIFIED, &public), None);
+
+        // the internal address of a public gateway behind NAT
+        assert_eq!(redirect_address(Ipv4Addr::new(192, 168, 1, 1), &public), None);
+
+        // a private address is followed when the server is reached over a private network
+        assert_eq!(
+            redirect_address(Ipv4Addr::new(10, 1, 0, 11), &private),
+            Some(Ipv4Addr::new(10, 1, 0, 11))
+        );
+
+        // IPv6 addresses of the server do not make it private
+        let dual_stack = [public[0], "2001:db8::10".parse().unwrap()];
+        assert_eq!(redirect_address(Ipv4Addr::new(10, 1, 0, 11), &dual_stack), None);
+        assert_eq!(
+            redirect_address(Ipv4Addr::new(203, 0, 113, 11), &dual_stack),
+            Some(Ipv4Addr::new(203, 0, 113, 11))
+        );
+    }
+
+    fn login_option(id: &str, show_realm: u32, factor_count: usize) -> LoginOption {
+        let factor = LoginFactor {
+            factor_type: "password".to_owned(),
+            securid_card_type: String::new(),
+            certificate_storage_type: String::new(),
+            custom_display_labels: LoginDisplayLabelSelect::LoginDisplayLabel(BTreeMap::new()),
+        };
+        LoginOption {
+            id: id.to_owned(),
+            secondary_realm_hash: String::new(),
+            display_name: id.to_owned(),
+            show_realm,
+            factors: (1..=factor_count).map(|i| (i.to_string(), factor.clone())).collect(),
+        }
+    }
+
+    // the options are keyed by their position in the server response
+    fn server_info(options: Vec<LoginOption>) -> ServerInfoResponse {
+        let data = std::fs::read_to_string("tests/gateway/ClientHello.txt").unwrap();
+        let mut info = data
+            .parse::<SExpression>()
+            .unwrap()
+            .get("CCCserverResponse:ResponseData")
+            .cloned()
+            .unwrap()
+            .try_into::<ServerInfoResponse>()
+            .unwrap();
+        info.login_options_data.as_mut().unwrap().login_options_list = options
+            .into_iter()
+            .enumerate()
+            .map(|(i, option)| (i.to_string(), option))
+            .collect();
+        info
+    }
+
+    fn decided_realm(info: &ServerInfoResponse) -> Option<&str> {
+        client_decide_realm(info).map(|option| option.id.as_str())
+    }
+
+    #[test]
+    fn test_client_decide_realm() {
+        let info = server_info(vec![
+            login_option("vpn_Username_Password", 1, 1),
+            login_option("vpn_Certificate", 1, 1),
+        ]);
+        assert_eq!(decided_realm(&info), Some("vpn_Username_Password"));
+
+        // a multi-factor realm is preferred, the hidden ones are skipped
+        let info = server_info(vec![
+            login_option("vpn_Username_Password", 1, 1),
+            login_option("vpn_Hidden_Mfa", 0, 2),
+            login_option("vpn_Mfa", 1, 2),
+            login_option("vpn_Another_Mfa", 1, 3),
+        ]);
+        assert_eq!(decided_realm(&info), Some("vpn_Mfa"));
+
+        let info = server_info(vec![
+            login_option("vpn_Hidden", 0, 1),
+            login_option("vpn_Visible", 1, 1),
+        ]);
+        assert_eq!(decided_realm(&info), Some("vpn_Visible"));
+
+        let info = server_info(vec![login_option("vpn_Hidden", 0, 2)]);
+        assert_eq!(decided_realm(&info), None);
+
+        let mut info = server_info(vec![login_option("vpn_Username_Password", 1, 1)]);
+        info.connectivity_info.default_authentication_method = "password".to_owned();
+        assert_eq!(decided_realm(&info), None);
+    }
+
+    #[test]
+    fn test_client_decide_realm_server_order() {
+        // neither the alphabetical order of the ids nor of the position keys ("10" < "2") is the server order
+        let mut options = (0..11)
+            .map(|i| login_option(&format!("vpn_Single_{i}"), 1, 1))
+            .collect::<Vec<_>>();
+        options[2] = login_option("vpn_Z_Mfa", 1, 2);
+        options[10] = login_option("vpn_A_Mfa", 1, 2);
+        assert_eq!(decided_realm(&server_info(options)), Some("vpn_Z_Mfa"));
+
+        let mut options = (0..11)
+            .map(|i| login_option(&format!("vpn_{}", (b'z' - i as u8) as char), 1, 1))
+            .collect::<Vec<_>>();
+        options[0].show_realm = 0;
+        assert_eq!(decided_realm(&server_info(options)), Some("vpn_y"));
+    }
+}
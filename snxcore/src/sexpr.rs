@@ -26,6 +26,24 @@ impl SExpression {
         self.as_object().and_then(|(n, _)| n.as_deref())
     }
 
+    /// Replace the values of all fields matching the predicate, at any depth, with a placeholder.
+    pub fn redact(&mut self, is_sensitive: &dyn Fn(&str) -> bool) {
+        match self {
+            Self::Object(_, fields) => {
+                for (name, value) in fields.iter_mut() {
+                    let is_empty = matches!(value, Self::Null) || matches!(value, Self::Value(v) if v.is_empty());
+                    if is_sensitive(name) && !is_empty {
+                        *value = Self::Value("<redacted>".to_owned());
+                    } else {
+                        value.redact(is_sensitive);
+                    }
+                }
+            }
+            Self::Array(elements) => elements.iter_mut().for_each(|v| v.redact(is_sensitive)),
+            Self::Null | Self::Value(_) => {}
+        }
+    }
+
     pub fn try_into<D>(self) -> anyhow::Result<D>
     where
         for<'a> D: Deserialize<'a>,
@@ -445,4 +463,18 @@ mod tests {
         let s_expr = expr.to_string();
         assert!(s_expr.contains("RequestData ()"));
     }
+
+    #[test]
+    fn test_redact() {
+        let data = std::fs::read_to_string("tests/server_response.txt").unwrap();
+        let mut expr = data.parse::<SExpression>().unwrap();
+
+        expr.redact(&|name| name == "session_id");
+
+        assert_eq!(
+            expr.get("CCCserverResponse:ResponseData:session_id"),
+            Some(&SExpression::Value("<redacted>".to_string()))
+        );
+        assert!(!expr.to_string().contains("xxyyzz"));
+    }
 }
@@ -1,19 +1,25 @@
-use std::sync::Arc;
+use std::{future::Future, net::Ipv4Addr, sync::Arc, time::Duration};
 
+use anyhow::anyhow;
 use async_trait::async_trait;
 use bytes::Bytes;
-use tokio::sync::mpsc;
+use tokio::{sync::mpsc, task::JoinHandle, time::Instant};
+use tracing::{debug, warn};
 
 use crate::{
     model::{
         params::{TunnelParams, TunnelType},
         *,
     },
+    server_info,
     tunnel::{ipsec::connector::IpsecTunnelConnector, ssl::connector::CccTunnelConnector},
 };
 
+pub use ipsec::connector::StoredSession;
+
 pub mod device;
 mod ipsec;
+pub(crate) mod proxy;
 mod ssl;
 
 #[derive(Debug, Clone, PartialEq)]
@@ -24,10 +30,15 @@ pub enum TunnelCommand {
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TunnelEvent {
-    Connected,
-    Disconnected,
+    Connected(ConnectionInfo),
+    Disconnected(DisconnectReason),
     RekeyCheck,
+    /// New IPSec SAs are installed in the tunnel
+    Rekeyed,
     RemoteControlData(Bytes),
+    ConfigChanged,
+    /// The re-authentication interval requested by the gateway is about to elapse
+    ReauthDue,
 }
 
 #[async_trait]
@@ -51,12 +62,152 @@ pub trait TunnelConnector {
         command_sender: mpsc::Sender<TunnelCommand>,
     ) -> anyhow::Result<Box<dyn VpnTunnel + Send>>;
     async fn terminate_tunnel(&mut self) -> anyhow::Result<()>;
+    /// Authenticate again and install the new keys into the running tunnel
+    async fn reauthenticate(&mut self) -> anyhow::Result<()>;
     async fn handle_tunnel_event(&mut self, event: TunnelEvent) -> anyhow::Result<()>;
 }
 
+/// Check whether the gateway answers the NAT-T probes required by the ESP-in-UDP transport
+pub async fn probe_natt(address: Ipv4Addr, params: &TunnelParams) -> anyhow::Result<()> {
+    ipsec::natt::NattProber::new(address, params.natt_probe_count, params.natt_probe_timeout)
+        .with_bind_address(params.bind_address)
+        .probe()
+        .await
+}
+
+/// Forget the IPSec transport which last worked for the server, see `TunnelServiceRequest::ClearTransportCache`
+pub fn clear_transport_cache(params: &TunnelParams) {
+    ipsec::connector::clear_transport_cache(params);
+}
+
+/// IKE sessions stored with `ike-persist` in the session directory
+pub fn stored_sessions(params: &TunnelParams) -> anyhow::Result<Vec<StoredSession>> {
+    ipsec::connector::stored_sessions(&params.session_path)
+}
+
+/// Delete the stored IKE session of the given server
+pub fn remove_stored_session(params: &TunnelParams, server_name: &str) -> anyhow::Result<()> {
+    ipsec::connector::remove_stored_session(&params.session_path, server_name)
+}
+
+/// Stop the tunnel running in the given task and wait until it has removed its xfrm state, routes and DNS settings,
+/// then delete the IKE SA. Unlike the cleanup on drop, this is bounded by the timeout, so that it can run
+/// before the process exits on a termination signal.
+pub async fn shutdown<T: Send + 'static>(
+    mut connector: Box<dyn TunnelConnector + Send>,
+    tunnel_task: JoinHandle<T>,
+    timeout: Duration,
+) {
+    let deadline = Instant::now() + timeout;
+
+    debug!("Shutting down the tunnel");
+    let _ = connector.terminate_tunnel().await;
+
+    if tokio::time::timeout_at(deadline, tunnel_task).await.is_err() {
+        warn!("Tunnel cleanup did not finish within {} seconds", timeout.as_secs());
+        return;
+    }
+
+    // dropping the connector deletes the IKE SA, which blocks until the gateway is notified
+    let drop_connector = tokio::task::spawn_blocking(move || drop(connector));
+    if tokio::time::timeout_at(deadline, drop_connector).await.is_err() {
+        warn!("IKE SA deletion did not finish within {} seconds", timeout.as_secs());
+    } else {
+        debug!("Tunnel is shut down");
+    }
+}
+
+/// Run the connection sequence, failing it with a timeout error if `connect-timeout` is set and elapses first
+pub async fn with_connect_timeout<F, T>(params: &TunnelParams, fut: F) -> anyhow::Result<T>
+where
+    F: Future<Output = anyhow::Result<T>>,
+{
+    let Some(timeout) = params.connect_timeout else {
+        return fut.await;
+    };
+
+    tokio::time::timeout(timeout, fut).await.unwrap_or_else(|_| {
+        Err(ConnectionError::Timeout(format!("Connection timed out after {} seconds", timeout.as_secs())).into())
+    })
+}
+
+/// Create the connector for the first responding gateway of the `server-name` list
 pub async fn new_tunnel_connector(params: Arc<TunnelParams>) -> anyhow::Result<Box<dyn TunnelConnector + Send>> {
+    params.check_password_env()?;
+
+    let params = if params.server_names().len() > 1 {
+        Arc::new(server_info::select_gateway(&params).await?)
+    } else {
+        params
+    };
+
+    let params = if params.login_type.is_empty() {
+        Arc::new(server_info::resolve_login_type(&params).await?)
+    } else {
+        params
+    };
+
     match params.tunnel_type {
         TunnelType::Ssl => Ok(Box::new(CccTunnelConnector::new(params).await?)),
         TunnelType::Ipsec => Ok(Box::new(IpsecTunnelConnector::new(params).await?)),
+        TunnelType::L2tp => Err(l2tp_unsupported_error(&params).await),
+    }
+}
+
+// the error tells which of the implemented tunnel types the gateway offers, if any
+async fn l2tp_unsupported_error(params: &TunnelParams) -> anyhow::Error {
+    let protocols = server_info::get(params)
+        .await
+        .map(|info| info.connectivity_info.supported_data_tunnel_protocols)
+        .unwrap_or_default();
+
+    let alternatives = [TunnelType::Ipsec, TunnelType::Ssl]
+        .into_iter()
+        .filter(|t| protocols.iter().any(|p| p.eq_ignore_ascii_case(t.as_str())))
+        .map(|t| t.as_str())
+        .collect::<Vec<_>>();
+
+    if alternatives.is_empty() {
+        anyhow!("L2TP tunnels are not supported, the gateway does not offer IPSec or SSL tunnels either")
+    } else {
+        anyhow!(
+            "L2TP tunnels are not supported, use tunnel-type={} which the gateway also offers",
+            alternatives.join(" or tunnel-type=")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_with_connect_timeout() {
+        let params = TunnelParams {
+            connect_timeout: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+        let error = with_connect_timeout(&params, std::future::pending::<anyhow::Result<()>>())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            ConnectionError::find(&error),
+            Some(ConnectionError::Timeout(_))
+        ));
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+
+        let connect = async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok(1)
+        };
+        assert_eq!(with_connect_timeout(&params, connect).await.unwrap(), 1);
+
+        // no limit without the option
+        let unlimited = with_connect_timeout(&TunnelParams::default(), std::future::pending::<anyhow::Result<()>>());
+        assert!(tokio::time::timeout(Duration::from_secs(3600), unlimited)
+            .await
+            .is_err());
     }
 }
@@ -1,5 +1,33 @@
+use std::net::Ipv4Addr;
+
+use tokio::task::JoinHandle;
+
+use crate::{
+    model::{params::TunnelParams, ConnectionInfo},
+    platform,
+};
+
 pub mod connector;
 pub mod keepalive;
 pub mod native;
 pub mod natt;
 pub mod tcpt;
+
+// The probes go through the tunnel, so the discovery runs in the background once it is connected.
+// The tunnel owns the returned task and aborts it on cleanup.
+fn start_mtu_discovery(
+    params: &TunnelParams,
+    info: &ConnectionInfo,
+    gateway_internal_ip: Ipv4Addr,
+) -> Option<JoinHandle<()>> {
+    if params.mtu_discovery {
+        let if_name = info.if_name.clone();
+        let configured_mtu = params.if_mtu;
+        let fallback_mtu = params.mtu_discovery_fallback;
+        Some(tokio::spawn(async move {
+            platform::discover_device_mtu(&if_name, gateway_internal_ip, configured_mtu, fallback_mtu).await
+        }))
+    } else {
+        None
+    }
+}
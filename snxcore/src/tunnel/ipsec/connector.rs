@@ -1,5 +1,7 @@
 use std::{
-    net::{IpAddr, Ipv4Addr, ToSocketAddrs},
+    io::{self, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs},
+    os::fd::{AsRawFd, OwnedFd},
     path::{Path, PathBuf},
     sync::Arc,
     time::{Duration, SystemTime},
@@ -9,6 +11,7 @@ use anyhow::{anyhow, Context};
 use async_trait::async_trait;
 use byteorder::{BigEndian, ReadBytesExt};
 use bytes::{Buf, Bytes};
+use ipnet::Ipv6Net;
 use isakmp::{
     ikev1::{service::Ikev1Service, session::Ikev1Session},
     model::{ConfigAttributeType, EspAttributeType, Identity, IdentityRequest, PayloadType},
@@ -16,6 +19,7 @@ use isakmp::{
     session::IsakmpSession,
     transport::{IsakmpTransport, TcptDataType, TcptTransport, UdpTransport},
 };
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
 use tokio::{net::UdpSocket, sync::mpsc::Sender};
 use tracing::{debug, trace, warn};
 
@@ -23,7 +27,7 @@ use crate::{
     model::{
         params::{CertType, TransportType, TunnelParams},
         proto::{AuthenticationRealm, ClientLoggingData},
-        IpsecSession, MfaChallenge, MfaType, SessionState, VpnSession,
+        ConnectionError, IpsecSession, MfaChallenge, MfaType, SessionState, VpnSession,
     },
     platform, server_info,
     sexpr::SExpression,
@@ -31,11 +35,61 @@ use crate::{
         ipsec::{native::NativeIpsecTunnel, natt::NattProber, tcpt::TcptIpsecTunnel},
         TunnelCommand, TunnelConnector, TunnelEvent, VpnTunnel,
     },
+    util,
 };
 
 const MIN_ESP_LIFETIME: Duration = Duration::from_secs(60);
+const DEFAULT_IDENTITY_TIMEOUT: Duration = Duration::from_secs(120);
+
+// mode config attribute types from draft-dukes-ike-mode-cfg, IKEv1 mode config was never published as an RFC
+const IPV4_NBNS_ATTRIBUTE: u16 = 4;
+const IPV6_ADDRESS_ATTRIBUTE: u16 = 8;
+const IPV6_NETMASK_ATTRIBUTE: u16 = 9;
+const IPV6_DNS_ATTRIBUTE: u16 = 10;
+const DEFAULT_IPV6_PREFIX: u8 = 64;
+
+// Negotiation failures are reported by the isakmp layer either as a NO-PROPOSAL-CHOSEN
+// notification or, if the gateway silently drops the proposal, as a receive timeout.
+// The notification is only available as the text of the isakmp error, the timeouts are typed.
+fn map_proposal_error(stage: &str, error: anyhow::Error) -> anyhow::Error {
+    let message = format!("{:#}", error).to_lowercase();
+
+    if message.contains("no_proposal_chosen")
+        || message.contains("no-proposal-chosen")
+        || message.contains("noproposalchosen")
+    {
+        warn!("{} proposal rejected: {:#}", stage, error);
+        ConnectionError::ProposalRejected {
+            stage: stage.to_owned(),
+        }
+        .into()
+    } else if is_timeout_error(&error) {
+        ConnectionError::Timeout(format!(
+            "No reply to the {} proposal: the gateway is unreachable or silently refused the offered ciphers ({:#})",
+            stage, error
+        ))
+        .into()
+    } else {
+        error
+    }
+}
+
+fn is_timeout_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause.is::<tokio::time::error::Elapsed>()
+            || cause
+                .downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut)
+    })
+}
 
-const SESSIONS_PATH: &str = "/var/cache/snx-rs/sessions";
+// The isakmp layer loads the PKCS12 identity from a path, so the inline certificate
+// is exposed via an anonymous in-memory file which never touches the disk.
+fn inline_cert_fd(data: &[u8]) -> anyhow::Result<OwnedFd> {
+    let fd = memfd_create(c"snx-rs-cert", MemFdCreateFlag::MFD_CLOEXEC)?;
+    std::fs::File::from(fd.try_clone()?).write_all(data)?;
+    Ok(fd)
+}
 
 fn get_challenge_attribute_type(payload: &AttributesPayload) -> ConfigAttributeType {
     payload
@@ -55,6 +109,84 @@ fn get_challenge_attribute_type(payload: &AttributesPayload) -> ConfigAttributeT
         .unwrap_or(ConfigAttributeType::Other(0))
 }
 
+const TRANSPORT_CACHE_SUFFIX: &str = ".transport";
+
+// Server names end up as file names in the session directory, so anything that could
+// point outside of it is rejected
+fn session_dir_file(session_path: &Path, server_name: &str, suffix: &str) -> anyhow::Result<PathBuf> {
+    if server_name.is_empty() || server_name.starts_with('.') || server_name.contains(['/', '\\', '\0']) {
+        anyhow::bail!("Invalid server name: {:?}", server_name);
+    }
+    Ok(session_path.join(format!("{}{}", server_name, suffix)))
+}
+
+fn transport_cache_file(session_path: &Path, server_name: &str) -> anyhow::Result<PathBuf> {
+    session_dir_file(session_path, server_name, TRANSPORT_CACHE_SUFFIX)
+}
+
+fn load_cached_transport(params: &TunnelParams) -> Option<TransportType> {
+    std::fs::read_to_string(transport_cache_file(&params.session_path, &params.server_name).ok()?)
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+}
+
+fn save_cached_transport(params: &TunnelParams, transport: TransportType) -> anyhow::Result<()> {
+    let filename = transport_cache_file(&params.session_path, &params.server_name)?;
+    std::fs::create_dir_all(&params.session_path)?;
+    std::fs::write(filename, transport.as_str())?;
+    Ok(())
+}
+
+/// Forget the ESP transport which last worked for each gateway of the `server-name` list,
+/// so that the next connection probes it again
+pub fn clear_transport_cache(params: &TunnelParams) {
+    for server_name in params.server_names() {
+        if let Ok(filename) = transport_cache_file(&params.session_path, server_name) {
+            let _ = std::fs::remove_file(filename);
+        }
+    }
+}
+
+/// IKE session saved with `ike-persist`, one per server
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredSession {
+    pub server_name: String,
+    pub saved: SystemTime,
+}
+
+/// Sessions stored in the directory, sorted by the server name
+pub fn stored_sessions(session_path: &Path) -> anyhow::Result<Vec<StoredSession>> {
+    let entries = match std::fs::read_dir(session_path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Cannot read {}", session_path.display())),
+    };
+
+    let mut sessions = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let server_name = entry.file_name().into_string().ok()?;
+            let metadata = entry.metadata().ok()?;
+            (metadata.is_file() && !server_name.ends_with(TRANSPORT_CACHE_SUFFIX)).then_some(StoredSession {
+                server_name,
+                saved: metadata.modified().ok()?,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    sessions.sort_by(|a, b| a.server_name.cmp(&b.server_name));
+
+    Ok(sessions)
+}
+
+/// Delete the stored session of the server, the cached transport is kept
+pub fn remove_stored_session(session_path: &Path, server_name: &str) -> anyhow::Result<()> {
+    let filename = session_dir_file(session_path, server_name, "")?;
+    std::fs::remove_file(&filename).with_context(|| format!("Cannot delete {}", filename.display()))?;
+    debug!("Removed IKE session: {}", filename.display());
+    Ok(())
+}
+
 fn get_long_attributes(payload: &AttributesPayload, attr: ConfigAttributeType) -> Vec<Bytes> {
     let attr: u16 = attr.into();
     payload
@@ -74,6 +206,23 @@ fn get_long_attribute(payload: &AttributesPayload, attr: ConfigAttributeType) ->
     get_long_attributes(payload, attr).first().cloned()
 }
 
+// The IPv6 address attribute holds 16 bytes of address, optionally followed by the prefix length.
+// Without the prefix length it is taken from the IPv6 netmask attribute.
+fn parse_ipv6_address(address: &[u8], netmask: Option<&[u8]>) -> Option<Ipv6Net> {
+    let ip: [u8; 16] = address.get(..16)?.try_into().ok()?;
+
+    let prefix = match (address.get(16), netmask) {
+        (Some(prefix), _) => *prefix,
+        (None, Some(netmask)) => {
+            let netmask: [u8; 16] = netmask.get(..16)?.try_into().ok()?;
+            u128::from_be_bytes(netmask).leading_ones() as u8
+        }
+        (None, None) => DEFAULT_IPV6_PREFIX,
+    };
+
+    Ipv6Net::new(Ipv6Addr::from(ip), prefix).ok()
+}
+
 fn get_short_attribute(payload: &AttributesPayload, attr: ConfigAttributeType) -> Option<u16> {
     let attr: u16 = attr.into();
     payload
@@ -82,32 +231,107 @@ fn get_short_attribute(payload: &AttributesPayload, attr: ConfigAttributeType) -
         .find_map(|a| if a.attribute_type == attr { a.as_short() } else { None })
 }
 
+// Parameters with the ESP transport which was detected for this connection
+fn with_esp_transport(params: &TunnelParams, esp_transport: TransportType) -> Arc<TunnelParams> {
+    Arc::new(TunnelParams {
+        esp_transport,
+        ..params.clone()
+    })
+}
+
+// Only the automatic transport is detected, remembered and allowed to fall back to TCPT,
+// an explicit UDP transport fails if the gateway doesn't answer the NAT-T probes
+async fn select_esp_transport(params: &TunnelParams, gateway_address: IpAddr) -> anyhow::Result<TransportType> {
+    match params.esp_transport {
+        TransportType::Tcpt => Ok(TransportType::Tcpt),
+        TransportType::Udp if gateway_address.is_ipv6() => {
+            anyhow::bail!("IPv6 gateway {} requires TCPT ESP transport", params.server_name)
+        }
+        TransportType::Auto if load_cached_transport(params) == Some(TransportType::Tcpt) => {
+            debug!("ESP-in-UDP was not usable last time, using cached TCPT transport");
+            Ok(TransportType::Tcpt)
+        }
+        TransportType::Auto if gateway_address.is_ipv6() => {
+            debug!("IPv6 gateway, using TCPT transport");
+            Ok(TransportType::Tcpt)
+        }
+        transport => {
+            let prober = NattProber::new(
+                ipv4_gateway(gateway_address, &params.server_name)?,
+                params.natt_probe_count,
+                params.natt_probe_timeout,
+            )
+            .with_bind_address(params.bind_address);
+            match prober.probe().await {
+                Ok(()) => Ok(TransportType::Udp),
+                Err(e) if transport == TransportType::Auto => {
+                    warn!("{}, falling back from ESP-in-UDP to TCPT transport", e);
+                    Ok(TransportType::Tcpt)
+                }
+                Err(e) => Err(e),
+            }
+        }
+    }
+}
+
+// ESP-in-UDP and the native tunnel need an IPv4 gateway, IPv6 gateways are reachable over TCPT only
+fn ipv4_gateway(address: IpAddr, server_name: &str) -> anyhow::Result<Ipv4Addr> {
+    match address {
+        IpAddr::V4(address) => Ok(address),
+        IpAddr::V6(_) => {
+            Err(ConnectionError::NetworkUnreachable(format!("No IPv4 address for {}", server_name)).into())
+        }
+    }
+}
+
 pub struct IpsecTunnelConnector {
     params: Arc<TunnelParams>,
     service: Ikev1Service,
-    gateway_address: Ipv4Addr,
+    gateway_address: IpAddr,
+    redirect: Option<Ipv4Addr>,
+    // ESP transport is detected automatically and remembered for the next connections
+    detect_transport: bool,
+    cached_transport: bool,
     last_message_id: u32,
     last_identifier: u16,
     last_challenge_type: ConfigAttributeType,
     ccc_session: String,
     ipsec_session: IpsecSession,
     last_rekey: Option<SystemTime>,
+    identity_timeout: Duration,
     command_sender: Option<Sender<TunnelCommand>>,
+    // keeps the in-memory file with the inline certificate alive
+    _cert_fd: Option<OwnedFd>,
 }
 
 impl IpsecTunnelConnector {
     pub async fn new(params: Arc<TunnelParams>) -> anyhow::Result<Self> {
+        let mut cert_fd = None;
+
         let identity = match params.cert_type {
-            CertType::Pkcs12 => match (&params.cert_path, &params.cert_password) {
-                (Some(path), Some(password)) => Identity::Pkcs12 {
+            CertType::Pkcs12 => match (params.inline_pkcs12()?, &params.cert_path, &params.cert_password) {
+                (Some(data), _, Some(password)) => {
+                    let fd = inline_cert_fd(&data)?;
+                    let path = PathBuf::from(format!("/proc/self/fd/{}", fd.as_raw_fd()));
+                    cert_fd = Some(fd);
+                    Identity::Pkcs12 {
+                        path,
+                        password: password.clone(),
+                    }
+                }
+                (None, Some(path), Some(password)) => Identity::Pkcs12 {
                     path: path.clone(),
                     password: password.clone(),
                 },
-                _ => anyhow::bail!("No PKCS12 path and password provided!"),
+                _ => {
+                    return Err(
+                        ConnectionError::CertError("No PKCS12 path or data and password provided!".into()).into(),
+                    )
+                }
             },
             CertType::Pkcs8 => match params.cert_path {
                 Some(ref path) => Identity::Pkcs8 { path: path.clone() },
-                None => anyhow::bail!("No PKCS8 PEM path provided!"),
+                None => return Err(ConnectionError::CertError("No PKCS8 PEM path provided!".into()).into()),
             },
             CertType::Pkcs11 => match params.cert_password {
                 Some(ref pin) => Identity::Pkcs11 {
@@ -118,36 +342,60 @@ impl IpsecTunnelConnector {
                         .as_ref()
                         .map(|s| hex::decode(s.replace(':', "")).unwrap_or_default().into()),
                 },
-                None => anyhow::bail!("No PKCS11 pin provided!"),
+                None => return Err(ConnectionError::CertError("No PKCS11 pin provided!".into()).into()),
             },
 
             CertType::None => Identity::None,
         };
 
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        socket
-            .connect(format!("{}:{}", params.server_name, params.ike_port))
-            .await?;
-
-        let IpAddr::V4(gateway_address) = socket.peer_addr()?.ip() else {
-            anyhow::bail!("No IPv4 address for {}", params.server_name);
+        let params = if params.tcpt_only {
+            debug!("TCPT-only mode, skipping UDP transport detection");
+            Arc::new(TunnelParams {
+                ike_transport: TransportType::Tcpt,
+                esp_transport: TransportType::Tcpt,
+                ..(*params).clone()
+            })
+        } else {
+            params
         };
 
-        if params.esp_transport == TransportType::Udp {
-            let prober = NattProber::new(gateway_address);
-            prober.probe().await?;
+        if params.proxy_url.is_some() {
+            warn!("The IKE exchange does not go through the proxy, only the HTTPS requests and TCPT ESP traffic do");
         }
 
+        // clustered gateways may hand off IKE and ESP to a specific node, HTTPS still goes to the server name
+        let redirect = server_info::get_redirect_address(&params).await;
+        let (server_host, server_port) = util::split_host_port(&params.server_name);
+        let gateway_host = redirect.map_or_else(|| server_host.to_owned(), |address| address.to_string());
+
+        let (gateway_address, socket) = if params.ike_transport != TransportType::Tcpt {
+            let socket = UdpSocket::bind(params.local_address(0)).await?;
+            socket.connect(format!("{}:{}", gateway_host, params.ike_port)).await?;
+
+            let gateway_address = ipv4_gateway(socket.peer_addr()?.ip(), &params.server_name)?;
+            (IpAddr::V4(gateway_address), Some(socket))
+        } else {
+            let gateway_address = util::resolve_host(&format!("{}:{}", gateway_host, params.ike_port))?;
+            if gateway_address.is_ipv6() {
+                debug!("Using IPv6 gateway address: {}", gateway_address);
+            }
+            (gateway_address, None)
+        };
+
+        let detect_transport = params.esp_transport == TransportType::Auto;
+        let cached_transport = detect_transport && load_cached_transport(&params) == Some(TransportType::Tcpt);
+        let params = with_esp_transport(&params, select_esp_transport(&params, gateway_address).await?);
+
         debug!("Using ESP transport: {}", params.esp_transport);
 
         let ikev1_session = Box::new(Ikev1Session::new(identity)?);
 
         debug!("Using IKE transport: {}", params.ike_transport);
 
-        let transport: Box<dyn IsakmpTransport + Send + Sync> = if params.ike_transport == TransportType::Udp {
+        let transport: Box<dyn IsakmpTransport + Send + Sync> = if let Some(socket) = socket {
             Box::new(UdpTransport::new(socket, ikev1_session.new_codec()))
         } else {
-            let socket_address = format!("{}:443", params.server_name)
+            let socket_address = format!("{}:{}", gateway_host, server_port)
                 .to_socket_addrs()?
                 .next()
                 .context("No address!")?;
@@ -164,13 +412,18 @@ impl IpsecTunnelConnector {
             params,
             service,
             gateway_address,
+            redirect,
+            detect_transport,
+            cached_transport,
             last_message_id: 0,
             last_identifier: 0,
             last_challenge_type: ConfigAttributeType::Other(0),
             ccc_session: String::new(),
             ipsec_session: IpsecSession::default(),
             last_rekey: None,
+            identity_timeout: DEFAULT_IDENTITY_TIMEOUT,
             command_sender: None,
+            _cert_fd: cert_fd,
         })
     }
 
@@ -201,14 +454,28 @@ impl IpsecTunnelConnector {
 
         debug!("Challenge ID: {}", id);
 
-        let prompt = inner
+        let mut prompt = inner
             .get_value::<String>("msg_obj:def_msg")
             .context("No challenge prompt!")?;
 
         debug!("Challenge prompt: {}", prompt);
 
+        // the gateway does not report the number of remaining attempts, only the reason of the failure
+        if state == "failed_attempt" {
+            let reason = msg_obj
+                .get_value::<String>("msg_obj:def_msg")
+                .filter(|reason| !reason.is_empty() && *reason != prompt);
+
+            warn!("Failed authentication attempt, reason: {:?}", reason);
+
+            if let Some(reason) = reason {
+                prompt = format!("{}\n{}", reason, prompt);
+            }
+        }
+
         Ok(Arc::new(VpnSession {
             ccc_session_id: self.ccc_session.clone(),
+            ccc_cookie: None,
             ipsec_session: None,
             state: SessionState::PendingChallenge(MfaChallenge {
                 mfa_type: MfaType::from_id(&id),
@@ -236,18 +503,49 @@ impl IpsecTunnelConnector {
             .read_u32::<BigEndian>()?
             .into();
 
-        self.ipsec_session.dns = get_long_attributes(&om_reply, ConfigAttributeType::Ipv4Dns)
+        // dual-stack gateways may assign an IPv6 office mode address in addition to the IPv4 one
+        self.ipsec_session.address_v6 = if self.params.disable_ipv6 {
+            None
+        } else {
+            get_long_attribute(&om_reply, ConfigAttributeType::Other(IPV6_ADDRESS_ATTRIBUTE)).and_then(|address| {
+                let netmask = get_long_attribute(&om_reply, ConfigAttributeType::Other(IPV6_NETMASK_ATTRIBUTE));
+                parse_ipv6_address(&address, netmask.as_deref())
+            })
+        };
+
+        if let Some(address) = self.ipsec_session.address_v6 {
+            debug!("Acquired IPv6 address: {}", address);
+        }
+
+        let ipv4_dns = get_long_attributes(&om_reply, ConfigAttributeType::Ipv4Dns)
+            .into_iter()
+            .flat_map(|b| b.reader().read_u32::<BigEndian>().ok())
+            .map(|v| IpAddr::V4(v.into()));
+
+        // IPv6 resolvers are advertised with a separate attribute as raw 16-byte addresses
+        let ipv6_dns = get_long_attributes(&om_reply, ConfigAttributeType::Other(IPV6_DNS_ATTRIBUTE))
+            .into_iter()
+            .flat_map(|b| b.reader().read_u128::<BigEndian>().ok())
+            .map(|v| IpAddr::V6(v.into()));
+
+        self.ipsec_session.dns = ipv4_dns.chain(ipv6_dns).collect();
+
+        self.ipsec_session.wins = get_long_attributes(&om_reply, ConfigAttributeType::Other(IPV4_NBNS_ATTRIBUTE))
             .into_iter()
             .flat_map(|b| b.reader().read_u32::<BigEndian>().ok())
             .map(Into::into)
             .collect();
 
+        if !self.ipsec_session.wins.is_empty() {
+            debug!(
+                "Gateway provided WINS servers which are not applied: {:?}",
+                self.ipsec_session.wins
+            );
+        }
+
         self.ipsec_session.domains = get_long_attribute(&om_reply, ConfigAttributeType::InternalDomainName)
-            .map(|v| String::from_utf8_lossy(&v).into_owned())
-            .unwrap_or_default()
-            .split([',', ';'])
-            .map(ToOwned::to_owned)
-            .collect();
+            .map(|v| util::parse_domain_list(&String::from_utf8_lossy(&v)))
+            .unwrap_or_default();
 
         self.do_esp_proposal().await?;
 
@@ -255,6 +553,7 @@ impl IpsecTunnelConnector {
 
         let session = Arc::new(VpnSession {
             ccc_session_id: self.ccc_session.clone(),
+            ccc_cookie: None,
             ipsec_session: Some(self.ipsec_session.clone()),
             state: SessionState::Authenticated(String::new()),
         });
@@ -282,7 +581,7 @@ impl IpsecTunnelConnector {
             }
             Some(status) => {
                 warn!("IPSec authentication failed, status: {}", status);
-                Err(anyhow!("IPSec authentication failed, status: {}", status))
+                Err(ConnectionError::AuthFailed(format!("IPSec authentication failed, status: {}", status)).into())
             }
             None => {
                 let attr = get_challenge_attribute_type(&id_reply);
@@ -293,6 +592,7 @@ impl IpsecTunnelConnector {
                 } else if attr == ConfigAttributeType::UserName {
                     Arc::new(VpnSession {
                         ccc_session_id: self.ccc_session.clone(),
+                        ccc_cookie: None,
                         ipsec_session: None,
                         state: SessionState::PendingChallenge(MfaChallenge {
                             mfa_type: MfaType::UserNameInput,
@@ -346,7 +646,8 @@ impl IpsecTunnelConnector {
         let attributes = self
             .service
             .do_esp_proposal(self.ipsec_session.address, self.params.esp_lifetime)
-            .await?;
+            .await
+            .map_err(|e| map_proposal_error("ESP", e))?;
 
         let lifetime = attributes
             .iter()
@@ -370,6 +671,9 @@ impl IpsecTunnelConnector {
     }
 
     async fn parse_isakmp(&mut self, data: Bytes) -> anyhow::Result<()> {
+        #[cfg(feature = "packet-dump")]
+        trace!("ISAKMP message, {} bytes:\n{}", data.len(), util::hex_dump(&data));
+
         let mut codec = self.service.session().new_codec();
 
         if let Some(msg) = codec.decode(&data)? {
@@ -429,17 +733,29 @@ impl IpsecTunnelConnector {
             .any(|factor| factor.factor_type != "certificate"))
     }
 
-    fn session_file_name(&self) -> PathBuf {
-        Path::new(SESSIONS_PATH).join(&self.params.server_name)
+    // IdP flows may keep the user in the browser for a while, the server advertises a longer timeout for them
+    async fn get_identity_timeout(&self) -> Duration {
+        if let Some(timeout) = self.params.identity_timeout {
+            return timeout;
+        }
+
+        server_info::get(&self.params)
+            .await
+            .ok()
+            .and_then(|info| info.connectivity_info.extended_connect_timeout_for_idp)
+            .filter(|timeout| *timeout > 0)
+            .map_or(DEFAULT_IDENTITY_TIMEOUT, Duration::from_secs)
+    }
+
+    fn session_file_name(&self) -> anyhow::Result<PathBuf> {
+        session_dir_file(&self.params.session_path, &self.params.server_name, "")
     }
 
     fn save_ike_session(&mut self) -> anyhow::Result<()> {
         let data = self.service.session().save()?;
-        let dir = Path::new(SESSIONS_PATH);
-
-        std::fs::create_dir_all(dir)?;
 
-        let filename = self.session_file_name();
+        let filename = self.session_file_name()?;
+        std::fs::create_dir_all(&self.params.session_path)?;
         std::fs::write(&filename, &data)?;
 
         debug!("Saved IKE session to: {}", filename.display());
@@ -448,7 +764,7 @@ impl IpsecTunnelConnector {
     }
 
     fn load_ike_session(&mut self) -> anyhow::Result<()> {
-        let filename = self.session_file_name();
+        let filename = self.session_file_name()?;
         let data = std::fs::read(&filename)?;
         self.service.session().load(&data)?;
 
@@ -466,9 +782,28 @@ impl IpsecTunnelConnector {
 #[async_trait]
 impl TunnelConnector for IpsecTunnelConnector {
     async fn authenticate(&mut self) -> anyhow::Result<Arc<VpnSession>> {
-        let my_address = platform::get_default_ip().await?.parse::<Ipv4Addr>()?;
-        self.service.do_sa_proposal(self.params.ike_lifetime).await?;
-        self.service.do_key_exchange(my_address, self.gateway_address).await?;
+        let default_ip = match self.params.bind_address {
+            Some(IpAddr::V4(address)) => Ok(address.to_string()),
+            _ => platform::get_default_ip().await,
+        };
+        let my_address = match default_ip {
+            Ok(address) => address.parse::<Ipv4Addr>()?,
+            Err(_) if self.gateway_address.is_ipv6() => Ipv4Addr::UNSPECIFIED,
+            Err(e) => return Err(e),
+        };
+        // NAT-D payloads carry IPv4 addresses only, NAT detection is irrelevant for IPv6 gateways over TCPT
+        let gateway_address = match self.gateway_address {
+            IpAddr::V4(address) => address,
+            IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+        };
+        self.service
+            .do_sa_proposal(self.params.ike_lifetime)
+            .await
+            .map_err(|e| map_proposal_error("IKE", e))?;
+        self.service
+            .do_key_exchange(my_address, gateway_address)
+            .await
+            .map_err(|e| map_proposal_error("IKE", e))?;
 
         let realm = AuthenticationRealm {
             client_type: self.params.tunnel_type.as_client_type().to_owned(),
@@ -478,8 +813,8 @@ impl TunnelConnector for IpsecTunnelConnector {
             selected_realm_id: self.params.login_type.clone(),
             secondary_realm_hash: None,
             client_logging_data: Some(ClientLoggingData {
-                os_name: Some("Windows".to_owned()),
-                device_id: Some(crate::util::get_device_id()),
+                os_name: Some(self.params.client_os.clone()),
+                device_id: Some(self.params.device_id.clone().unwrap_or_else(util::get_device_id)),
                 ..Default::default()
             }),
         };
@@ -491,12 +826,30 @@ impl TunnelConnector for IpsecTunnelConnector {
         let identity_request = IdentityRequest {
             auth_blob: Bytes::copy_from_slice(realm_expr.to_string().as_bytes()),
             verify_certs: self.params.ipsec_cert_check,
-            ca_certs: self.params.ca_cert.clone(),
+            ca_certs: self.params.ca_cert_files()?,
             with_mfa: self.params.cert_type == CertType::None
                 || self.is_multi_factor_login_type().await.unwrap_or(false),
         };
 
-        if let Some((attrs_reply, message_id)) = self.service.do_identity_protection(identity_request).await? {
+        self.identity_timeout = self.get_identity_timeout().await;
+        debug!(
+            "Identity protection timeout: {} seconds",
+            self.identity_timeout.as_secs()
+        );
+
+        let reply = tokio::time::timeout(
+            self.identity_timeout,
+            self.service.do_identity_protection(identity_request),
+        )
+        .await
+        .map_err(|_| {
+            ConnectionError::Timeout(format!(
+                "No reply to the identity protection exchange within {} seconds",
+                self.identity_timeout.as_secs()
+            ))
+        })??;
+
+        if let Some((attrs_reply, message_id)) = reply {
             self.last_message_id = message_id;
 
             self.process_auth_attributes(attrs_reply).await
@@ -514,7 +867,9 @@ impl TunnelConnector for IpsecTunnelConnector {
     }
 
     async fn delete_session(&mut self) {
-        let _ = std::fs::remove_file(self.session_file_name());
+        if let Ok(filename) = self.session_file_name() {
+            let _ = std::fs::remove_file(filename);
+        }
     }
 
     async fn restore_session(&mut self) -> anyhow::Result<Arc<VpnSession>> {
@@ -535,7 +890,7 @@ impl TunnelConnector for IpsecTunnelConnector {
                 self.last_message_id,
                 self.last_challenge_type,
                 Bytes::copy_from_slice(user_input.trim().as_bytes()),
-                Some(Duration::from_secs(120)),
+                Some(self.identity_timeout),
             )
             .await?
             .0;
@@ -548,9 +903,37 @@ impl TunnelConnector for IpsecTunnelConnector {
         command_sender: Sender<TunnelCommand>,
     ) -> anyhow::Result<Box<dyn VpnTunnel + Send>> {
         self.command_sender = Some(command_sender);
-        match self.params.esp_transport {
-            TransportType::Udp => Ok(Box::new(NativeIpsecTunnel::create(self.params.clone(), session).await?)),
-            TransportType::Tcpt => Ok(Box::new(TcptIpsecTunnel::create(self.params.clone(), session).await?)),
+        if self.params.esp_transport == TransportType::Udp {
+            let gateway_address = ipv4_gateway(self.gateway_address, &self.params.server_name)?;
+            match NativeIpsecTunnel::create(self.params.clone(), session.clone(), gateway_address).await {
+                Ok(tunnel) => {
+                    clear_transport_cache(&self.params);
+                    return Ok(Box::new(tunnel));
+                }
+                Err(e) if self.detect_transport => {
+                    warn!("Cannot create ESP-in-UDP tunnel: {}, falling back to TCPT transport", e);
+                    self.params = with_esp_transport(&self.params, TransportType::Tcpt);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        match TcptIpsecTunnel::create(self.params.clone(), session, self.redirect).await {
+            Ok(tunnel) => {
+                if self.detect_transport && !self.cached_transport {
+                    if let Err(e) = save_cached_transport(&self.params, TransportType::Tcpt) {
+                        warn!("Cannot save transport cache: {}", e);
+                    }
+                }
+                Ok(Box::new(tunnel))
+            }
+            Err(e) => {
+                // the cached transport is not usable anymore, probe again next time
+                if self.cached_transport {
+                    clear_transport_cache(&self.params);
+                }
+                Err(e)
+            }
         }
     }
 
@@ -561,13 +944,43 @@ impl TunnelConnector for IpsecTunnelConnector {
         Ok(())
     }
 
+    // the identity protection exchange is repeated with the current credentials, the tunnel keeps running
+    // and only receives the new ESP SAs
+    async fn reauthenticate(&mut self) -> anyhow::Result<()> {
+        let address = self.ipsec_session.address;
+
+        debug!("Re-authenticating IPSec session {}", self.ccc_session);
+
+        self.last_challenge_type = ConfigAttributeType::Other(0);
+
+        let session = self.authenticate().await?;
+
+        if let SessionState::PendingChallenge(_) = session.state {
+            return Err(ConnectionError::Challenge("Re-authentication requires user input".into()).into());
+        }
+
+        if self.ipsec_session.address != address {
+            anyhow::bail!(
+                "Office mode address changed from {} to {}",
+                address,
+                self.ipsec_session.address
+            );
+        }
+
+        if let Some(ref mut sender) = self.command_sender {
+            Ok(sender.send(TunnelCommand::ReKey(self.ipsec_session.clone())).await?)
+        } else {
+            Err(anyhow!("No sender!"))
+        }
+    }
+
     async fn handle_tunnel_event(&mut self, event: TunnelEvent) -> anyhow::Result<()> {
         match event {
-            TunnelEvent::Connected => {
-                debug!("Tunnel connected");
+            TunnelEvent::Connected(info) => {
+                debug!("Tunnel connected using {}", info.transport);
             }
-            TunnelEvent::Disconnected => {
-                debug!("Tunnel disconnected");
+            TunnelEvent::Disconnected(reason) => {
+                debug!("Tunnel disconnected: {}", reason);
                 let _ = self.delete_sa().await;
             }
             TunnelEvent::RekeyCheck => {
@@ -576,6 +989,10 @@ impl TunnelConnector for IpsecTunnelConnector {
             TunnelEvent::RemoteControlData(data) => {
                 self.parse_isakmp(data).await?;
             }
+            TunnelEvent::Rekeyed => {
+                debug!("Tunnel rekeyed");
+            }
+            TunnelEvent::ConfigChanged | TunnelEvent::ReauthDue => {}
         }
         Ok(())
     }
@@ -593,3 +1010,193 @@ impl Drop for IpsecTunnelConnector {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_select_esp_transport() {
+        let dir = tempfile::tempdir().unwrap();
+        // nothing answers the NAT-T probes on the loopback interface
+        let gateway = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let params = |esp_transport| TunnelParams {
+            server_name: "gw.example.com".to_owned(),
+            session_path: dir.path().to_owned(),
+            esp_transport,
+            natt_probe_count: 1,
+            natt_probe_timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+
+        let tcpt = params(TransportType::Tcpt);
+        assert_eq!(select_esp_transport(&tcpt, gateway).await.unwrap(), TransportType::Tcpt);
+
+        let auto = params(TransportType::Auto);
+        assert_eq!(select_esp_transport(&auto, gateway).await.unwrap(), TransportType::Tcpt);
+        assert_eq!(
+            select_esp_transport(&auto, "2001:db8::1".parse().unwrap())
+                .await
+                .unwrap(),
+            TransportType::Tcpt
+        );
+
+        // an explicit UDP transport never falls back
+        let udp = params(TransportType::Udp);
+        assert!(select_esp_transport(&udp, gateway).await.is_err());
+        assert!(select_esp_transport(&udp, "2001:db8::1".parse().unwrap())
+            .await
+            .is_err());
+
+        // the cached transport skips probing
+        let auto = TunnelParams {
+            natt_probe_timeout: Duration::from_secs(60),
+            ..auto
+        };
+        save_cached_transport(&auto, TransportType::Tcpt).unwrap();
+        let transport = tokio::time::timeout(Duration::from_secs(5), select_esp_transport(&auto, gateway)).await;
+        assert_eq!(transport.unwrap().unwrap(), TransportType::Tcpt);
+    }
+
+    #[test]
+    fn test_map_proposal_error() {
+        let error = map_proposal_error("IKE", anyhow!("Notify: NoProposalChosen"));
+        assert_eq!(
+            ConnectionError::find(&error),
+            Some(&ConnectionError::ProposalRejected {
+                stage: "IKE".to_owned()
+            })
+        );
+        assert!(error.to_string().starts_with("The gateway rejected the IKE proposal"));
+
+        let elapsed = Err::<(), _>(io::Error::from(io::ErrorKind::TimedOut))
+            .context("Receive failed")
+            .unwrap_err();
+        assert!(matches!(
+            ConnectionError::find(&map_proposal_error("ESP", elapsed)),
+            Some(ConnectionError::Timeout(_))
+        ));
+
+        // the text of an unrelated error does not make it a timeout
+        let error = map_proposal_error("IKE", anyhow!("Invalid timeout attribute"));
+        assert!(ConnectionError::find(&error).is_none());
+        assert_eq!(error.to_string(), "Invalid timeout attribute");
+    }
+
+    #[tokio::test]
+    async fn test_map_proposal_elapsed() {
+        let elapsed = tokio::time::timeout(Duration::ZERO, std::future::pending::<()>())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            ConnectionError::find(&map_proposal_error("IKE", elapsed.into())),
+            Some(ConnectionError::Timeout(_))
+        ));
+    }
+
+    #[test]
+    fn test_ipv4_gateway() {
+        let address = ipv4_gateway("192.0.2.1".parse().unwrap(), "gw.example.com").unwrap();
+        assert_eq!(address, Ipv4Addr::new(192, 0, 2, 1));
+
+        let err = ipv4_gateway("2001:db8::1".parse().unwrap(), "gw.example.com").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ConnectionError>(),
+            Some(ConnectionError::NetworkUnreachable(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_ipv6_address() {
+        let ip = "fd00:10::5".parse::<Ipv6Addr>().unwrap();
+
+        let mut with_prefix = ip.octets().to_vec();
+        with_prefix.push(96);
+        assert_eq!(
+            parse_ipv6_address(&with_prefix, None),
+            Some(Ipv6Net::new(ip, 96).unwrap())
+        );
+
+        let netmask = "ffff:ffff:ffff:ffff:ffff:ffff::".parse::<Ipv6Addr>().unwrap().octets();
+        assert_eq!(
+            parse_ipv6_address(&ip.octets(), Some(&netmask)),
+            Some(Ipv6Net::new(ip, 96).unwrap())
+        );
+
+        assert_eq!(
+            parse_ipv6_address(&ip.octets(), None),
+            Some(Ipv6Net::new(ip, 64).unwrap())
+        );
+
+        assert_eq!(parse_ipv6_address(&[0; 4], None), None);
+    }
+
+    #[test]
+    fn test_stored_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(stored_sessions(&dir.path().join("missing")).unwrap().is_empty());
+
+        std::fs::write(dir.path().join("vpn.example.com"), b"session").unwrap();
+        std::fs::write(dir.path().join("vpn.example.com.transport"), b"tcpt").unwrap();
+        std::fs::write(dir.path().join("gw.example.org"), b"session").unwrap();
+
+        let sessions = stored_sessions(dir.path()).unwrap();
+        let names = sessions.iter().map(|s| s.server_name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["gw.example.org", "vpn.example.com"]);
+
+        remove_stored_session(dir.path(), "vpn.example.com").unwrap();
+        assert_eq!(stored_sessions(dir.path()).unwrap().len(), 1);
+        assert!(dir.path().join("vpn.example.com.transport").exists());
+        assert!(remove_stored_session(dir.path(), "vpn.example.com").is_err());
+    }
+
+    #[test]
+    fn test_clear_transport_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let params = TunnelParams {
+            server_name: "gw1.example.com, gw2.example.com".to_owned(),
+            session_path: dir.path().to_owned(),
+            ..Default::default()
+        };
+
+        for name in ["gw1.example.com", "gw2.example.com", "other.example.com"] {
+            let gateway = TunnelParams {
+                server_name: name.to_owned(),
+                ..params.clone()
+            };
+            save_cached_transport(&gateway, TransportType::Tcpt).unwrap();
+            assert_eq!(load_cached_transport(&gateway), Some(TransportType::Tcpt));
+        }
+
+        clear_transport_cache(&params);
+        assert!(!dir.path().join("gw1.example.com.transport").exists());
+        assert!(!dir.path().join("gw2.example.com.transport").exists());
+        assert!(dir.path().join("other.example.com.transport").exists());
+    }
+
+    #[test]
+    fn test_session_dir_file() {
+        let dir = Path::new("/var/cache/snx-rs/sessions");
+        assert_eq!(
+            session_dir_file(dir, "gw.example.com", TRANSPORT_CACHE_SUFFIX).unwrap(),
+            dir.join("gw.example.com.transport")
+        );
+        assert_eq!(
+            session_dir_file(dir, "10.0.0.1:4443", "").unwrap(),
+            dir.join("10.0.0.1:4443")
+        );
+        for name in [
+            "",
+            ".",
+            "..",
+            "../../etc/passwd",
+            "/etc/passwd",
+            "a/b",
+            ".hidden",
+            "a\\b",
+            "a\0b",
+        ] {
+            assert!(session_dir_file(dir, name, "").is_err(), "{name:?}");
+        }
+    }
+}
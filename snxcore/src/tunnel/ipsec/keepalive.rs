@@ -8,30 +8,37 @@ use std::{
 };
 
 use anyhow::anyhow;
+use chrono::{DateTime, Local};
+use tokio::net::UdpSocket;
 use tracing::{debug, trace, warn};
 
 use crate::{
-    model::params::TunnelParams,
+    model::params::{TunnelParams, DEFAULT_KEEPALIVE_INTERVAL, DEFAULT_KEEPALIVE_MAX_RETRIES},
     platform::{self, UdpSocketExt},
+    util,
 };
 
-const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(20);
 const KEEPALIVE_RETRY_INTERVAL: Duration = Duration::from_secs(5);
 const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(5);
-const KEEPALIVE_MAX_RETRIES: u32 = 5;
+
+const KEEPALIVE_PACKET_SIZE: usize = 84;
+const KEEPALIVE_PACKET_TYPE: u32 = 0x0000_0011;
+const KEEPALIVE_DIRECTION_REQUEST: u16 = 0x0001;
+const KEEPALIVE_DIRECTION_RESPONSE: u16 = 0x0002;
+const KEEPALIVE_CONTENT_TIMESTAMP: u16 = 0x0002;
 
 // picked from wireshark logs
-fn make_keepalive_packet() -> [u8; 84] {
-    let mut data = [0u8; 84];
+fn make_keepalive_packet() -> [u8; KEEPALIVE_PACKET_SIZE] {
+    let mut data = [0u8; KEEPALIVE_PACKET_SIZE];
 
     // 0x00000011 looks like a packet type, KEEPALIVE in this case
-    data[0..4].copy_from_slice(&0x0000_0011u32.to_be_bytes());
+    data[0..4].copy_from_slice(&KEEPALIVE_PACKET_TYPE.to_be_bytes());
 
     // 0x0001 is probably a direction: request or response. We get 0x0002 as a response back.
-    data[4..6].copy_from_slice(&0x0001u16.to_be_bytes());
+    data[4..6].copy_from_slice(&KEEPALIVE_DIRECTION_REQUEST.to_be_bytes());
 
     // this looks like a content type, probably means TIMESTAMP
-    data[6..8].copy_from_slice(&0x0002u16.to_be_bytes());
+    data[6..8].copy_from_slice(&KEEPALIVE_CONTENT_TIMESTAMP.to_be_bytes());
 
     // timestamp
     let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
@@ -39,28 +46,134 @@ fn make_keepalive_packet() -> [u8; 84] {
     data
 }
 
+// a response echoes the packet type with 0x0002 in the direction field
+fn is_keepalive_response(reply: &[u8]) -> bool {
+    reply.len() >= 6
+        && reply[0..4] == KEEPALIVE_PACKET_TYPE.to_be_bytes()
+        && reply[4..6] == KEEPALIVE_DIRECTION_RESPONSE.to_be_bytes()
+}
+
+fn format_last_response(last_response: Option<DateTime<Local>>) -> String {
+    last_response.map_or_else(
+        || "never".to_owned(),
+        |time| time.format("%Y-%m-%d %H:%M:%S").to_string(),
+    )
+}
+
+#[derive(Clone)]
 pub struct KeepaliveRunner {
     src: Ipv4Addr,
     dst: Ipv4Addr,
+    port: u16,
     ready: Arc<AtomicBool>,
+    enabled: bool,
+    dedicated_thread: bool,
+    interval: Duration,
+    max_retries: u32,
 }
 
 impl KeepaliveRunner {
     pub fn new(src: Ipv4Addr, dst: Ipv4Addr, ready: Arc<AtomicBool>) -> Self {
-        Self { src, dst, ready }
+        Self {
+            src,
+            dst,
+            port: TunnelParams::IPSEC_KEEPALIVE_PORT,
+            ready,
+            enabled: true,
+            dedicated_thread: false,
+            interval: DEFAULT_KEEPALIVE_INTERVAL,
+            max_retries: DEFAULT_KEEPALIVE_MAX_RETRIES,
+        }
     }
 
-    pub async fn run(&self) -> anyhow::Result<()> {
+    /// Disabled keepalive sends no requests at all, some gateways are configured to drop them
+    pub fn with_enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    /// Keepalive interval and the number of failed requests before giving up. Zero interval disables keepalive.
+    pub fn with_limits(mut self, interval: Duration, max_retries: u32) -> Self {
+        self.interval = interval;
+        self.max_retries = max_retries.max(1);
+        self
+    }
+
+    /// UDP port of the keepalive requests, the same port is used on both sides
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn with_dedicated_thread(mut self, dedicated_thread: bool) -> Self {
+        self.dedicated_thread = dedicated_thread;
+        self
+    }
+
+    async fn bind(&self) -> anyhow::Result<UdpSocket> {
         let src = self.src.to_string();
 
-        let udp = tokio::net::UdpSocket::bind((src, TunnelParams::IPSEC_KEEPALIVE_PORT)).await?;
-        udp.connect((self.dst, TunnelParams::IPSEC_KEEPALIVE_PORT)).await?;
+        let udp = UdpSocket::bind((src, self.port)).await?;
+        udp.connect((self.dst, self.port)).await?;
 
         // disable UDP checksum validation for incoming packets.
         // Checkpoint gateway doesn't set it correctly.
         udp.set_no_check(true)?;
 
+        Ok(udp)
+    }
+
+    /// Send keepalive requests until the gateway replies or the timeout expires.
+    /// The gateway never replies when keepalive is disabled, so the check is skipped.
+    pub async fn probe(&self, timeout: Duration) -> anyhow::Result<()> {
+        if !self.enabled {
+            warn!("Keepalive is disabled, skipping the connectivity check");
+            return Ok(());
+        }
+
+        let udp = self.bind().await?;
+
+        let probe = async {
+            loop {
+                trace!("Sending connectivity probe to {}", self.dst);
+                let data = make_keepalive_packet();
+                if let Ok(reply) = udp.send_receive(&data, KEEPALIVE_TIMEOUT).await {
+                    if is_keepalive_response(&reply) {
+                        break;
+                    }
+                }
+            }
+        };
+
+        tokio::time::timeout(timeout, probe)
+            .await
+            .map_err(|_| anyhow!("No response from {} through the tunnel", self.dst))
+    }
+
+    pub async fn run(&self) -> anyhow::Result<()> {
+        if !self.enabled {
+            debug!("Keepalive is disabled");
+            return std::future::pending().await;
+        }
+
+        if self.interval.is_zero() {
+            debug!("Keepalive interval is zero, keepalive is disabled");
+            return std::future::pending().await;
+        }
+
+        if self.dedicated_thread {
+            let runner = self.clone();
+            util::run_on_dedicated_thread("snx-keepalive", async move { runner.run_loop().await }).await?
+        } else {
+            self.run_loop().await
+        }
+    }
+
+    async fn run_loop(&self) -> anyhow::Result<()> {
+        let udp = self.bind().await?;
+
         let mut num_failures = 0;
+        let mut last_response = None;
 
         loop {
             if platform::is_online() && self.ready.load(Ordering::SeqCst) {
@@ -69,19 +182,28 @@ impl KeepaliveRunner {
                 let data = make_keepalive_packet();
                 let result = udp.send_receive(&data, KEEPALIVE_TIMEOUT).await;
 
-                if let Ok(reply) = result {
-                    trace!("Received keepalive response from {}, size: {}", self.dst, reply.len());
-                    num_failures = 0;
-                } else {
-                    num_failures += 1;
-                    if num_failures >= KEEPALIVE_MAX_RETRIES {
-                        warn!("Maximum number of keepalive retries reached, exiting");
-                        break;
+                match result {
+                    Ok(reply) if is_keepalive_response(&reply) => {
+                        trace!("Received keepalive response from {}, size: {}", self.dst, reply.len());
+                        num_failures = 0;
+                        last_response = Some(Local::now());
+                    }
+                    _ => {
+                        num_failures += 1;
+                        if num_failures >= self.max_retries {
+                            warn!(
+                                "Maximum number of keepalive retries reached, last response: {}",
+                                format_last_response(last_response)
+                            );
+                            break;
+                        }
+                        warn!(
+                            "Keepalive failed ({}/{}), retrying in {} secs",
+                            num_failures,
+                            self.max_retries,
+                            KEEPALIVE_RETRY_INTERVAL.as_secs()
+                        );
                     }
-                    warn!(
-                        "Keepalive failed, retrying in {} secs",
-                        KEEPALIVE_RETRY_INTERVAL.as_secs()
-                    );
                 }
             } else {
                 num_failures = 0;
@@ -89,7 +211,7 @@ impl KeepaliveRunner {
             }
 
             let interval = if num_failures == 0 {
-                KEEPALIVE_INTERVAL
+                self.interval
             } else {
                 KEEPALIVE_RETRY_INTERVAL
             };
@@ -97,8 +219,72 @@ impl KeepaliveRunner {
             tokio::time::sleep(interval).await;
         }
 
-        debug!("Keepalive failed!");
+        Err(anyhow!(
+            "No keepalive response from {} after {} retries, last response: {}",
+            self.dst,
+            self.max_retries,
+            format_last_response(last_response)
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keepalive_response() {
+        let mut reply = make_keepalive_packet();
+        assert!(!is_keepalive_response(&reply));
+
+        reply[4..6].copy_from_slice(&KEEPALIVE_DIRECTION_RESPONSE.to_be_bytes());
+        assert!(is_keepalive_response(&reply));
+
+        assert!(!is_keepalive_response(&reply[0..4]));
+        assert!(!is_keepalive_response(&[0u8; KEEPALIVE_PACKET_SIZE]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_disabled_keepalive() {
+        // nothing listens on the gateway side, so any request would fail
+        let runner = KeepaliveRunner::new(
+            Ipv4Addr::LOCALHOST,
+            Ipv4Addr::new(127, 0, 0, 2),
+            Arc::new(AtomicBool::new(true)),
+        )
+        .with_port(0)
+        .with_enabled(false);
+
+        runner.probe(Duration::from_secs(1)).await.unwrap();
+
+        let run = tokio::time::timeout(DEFAULT_KEEPALIVE_INTERVAL * 10, runner.run()).await;
+        assert!(run.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_probe_custom_port() {
+        // the gateway side answers the requests with the response direction
+        let gateway = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        let port = gateway.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 128];
+            while let Ok((size, from)) = gateway.recv_from(&mut buf).await {
+                let mut reply = buf[..size].to_vec();
+                if reply.len() >= 6 && reply[4..6] == KEEPALIVE_DIRECTION_REQUEST.to_be_bytes() {
+                    reply[4..6].copy_from_slice(&KEEPALIVE_DIRECTION_RESPONSE.to_be_bytes());
+                    let _ = gateway.send_to(&reply, from).await;
+                }
+            }
+        });
+
+        let runner = KeepaliveRunner::new(
+            Ipv4Addr::LOCALHOST,
+            Ipv4Addr::new(127, 0, 0, 2),
+            Arc::new(AtomicBool::new(true)),
+        )
+        .with_port(port);
 
-        Err(anyhow!("Keepalive failed!"))
+        runner.probe(Duration::from_secs(5)).await.unwrap();
     }
 }
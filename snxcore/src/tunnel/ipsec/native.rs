@@ -1,4 +1,5 @@
 use std::{
+    net::Ipv4Addr,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -7,20 +8,31 @@ use std::{
 };
 
 use anyhow::Context;
-use tokio::{net::UdpSocket, sync::mpsc, time::MissedTickBehavior};
-use tracing::debug;
+use tokio::{net::UdpSocket, sync::mpsc, task::JoinHandle, time::MissedTickBehavior};
+use tracing::{debug, warn};
 
 use crate::{
     ccc::CccHttpClient,
-    model::{params::TunnelParams, VpnSession},
+    model::{params::TunnelParams, ConnectionInfo, DisconnectReason, TunnelTransport, VpnSession},
     platform::{self, IpsecConfigurator, UdpEncap, UdpSocketExt},
     tunnel::{
-        ipsec::{keepalive::KeepaliveRunner, natt::start_natt_listener},
+        ipsec::{keepalive::KeepaliveRunner, natt::start_natt_listener, start_mtu_discovery},
         TunnelCommand, TunnelEvent, VpnTunnel,
     },
     util,
 };
 
+async fn bind_natt_socket(params: &TunnelParams) -> anyhow::Result<UdpSocket> {
+    let port = params.natt_source_port;
+    if port != 0 {
+        match UdpSocket::bind(params.local_address(port)).await {
+            Ok(socket) => return Ok(socket),
+            Err(e) => warn!("Cannot bind NAT-T socket to port {}: {}, using a random port", port, e),
+        }
+    }
+    Ok(UdpSocket::bind(params.local_address(0)).await?)
+}
+
 pub(crate) struct NativeIpsecTunnel {
     configurator: Box<dyn IpsecConfigurator + Send + Sync>,
     keepalive_runner: KeepaliveRunner,
@@ -28,58 +40,81 @@ pub(crate) struct NativeIpsecTunnel {
     ready: Arc<AtomicBool>,
     params: Arc<TunnelParams>,
     session: Arc<VpnSession>,
+    connection_info: ConnectionInfo,
+    gateway_internal_ip: Ipv4Addr,
+    mtu_discovery: Option<JoinHandle<()>>,
 }
 
 impl NativeIpsecTunnel {
-    pub(crate) async fn create(params: Arc<TunnelParams>, session: Arc<VpnSession>) -> anyhow::Result<Self> {
+    pub(crate) async fn create(
+        params: Arc<TunnelParams>,
+        session: Arc<VpnSession>,
+        gateway_address: Ipv4Addr,
+    ) -> anyhow::Result<Self> {
         let ipsec_session = session.ipsec_session.as_ref().context("No IPSEC session!")?;
 
         let client = CccHttpClient::new(params.clone(), Some(session.clone()));
         let client_settings = client.get_client_settings().await?;
 
-        let gateway_address = util::resolve_ipv4_host(&format!("{}:{}", params.server_name, params.ike_port))?;
-
         debug!(
-            "Resolved gateway address: {}, acquired internal address: {}",
+            "Gateway address: {}, acquired internal address: {}",
             gateway_address, client_settings.gw_internal_ip
         );
 
         let ready = Arc::new(AtomicBool::new(false));
-        let keepalive_runner = KeepaliveRunner::new(
-            ipsec_session.address,
-            gateway_address,
-            if params.no_keepalive {
-                Arc::new(AtomicBool::new(false))
-            } else {
-                ready.clone()
-            },
-        );
-
-        let natt_socket = UdpSocket::bind("0.0.0.0:0").await?;
+        let keepalive_runner = KeepaliveRunner::new(ipsec_session.address, gateway_address, ready.clone())
+            .with_enabled(!params.no_keepalive)
+            .with_port(params.keepalive_port)
+            .with_dedicated_thread(params.keepalive_thread)
+            .with_limits(params.keepalive_interval, params.keepalive_max_retries);
+
+        let natt_socket = bind_natt_socket(&params).await?;
+        debug!("NAT-T socket bound to port {}", natt_socket.local_addr()?.port());
         natt_socket.set_encap(UdpEncap::EspInUdp)?;
 
-        let mut configurator = platform::new_ipsec_configurator(
+        let subnets = util::ranges_to_subnets(&client_settings.updated_policies.range.settings).collect::<Vec<_>>();
+        let connection_info = ConnectionInfo {
+            server_name: params.server_name.clone(),
+            transport: TunnelTransport::IpsecNative,
+            if_name: params.tunnel_if_name().to_owned(),
+            address: ipsec_session.address,
+            dns_servers: ipsec_session.dns.clone(),
+            search_domains: ipsec_session.domains.clone(),
+            routes: util::gateway_routes(&params, subnets.iter().copied()),
+            reauth_timeout: client_settings.reauth_timeout(),
+        };
+
+        let configurator = platform::new_ipsec_configurator(
             params.clone(),
             ipsec_session.clone(),
             natt_socket.local_addr()?.port(),
             gateway_address,
-            util::ranges_to_subnets(&client_settings.updated_policies.range.settings).collect(),
+            subnets,
         )?;
 
-        configurator.configure().await?;
-        ready.store(true, Ordering::SeqCst);
-
-        Ok(Self {
+        let mut tunnel = Self {
             configurator: Box::new(configurator),
             keepalive_runner,
             natt_socket: Arc::new(natt_socket),
             ready,
             params,
             session,
-        })
+            connection_info,
+            gateway_internal_ip: client_settings.gw_internal_ip,
+            mtu_discovery: None,
+        };
+
+        // if configuration fails or the connection is canceled, partial state is cleaned up on drop
+        tunnel.configurator.configure().await?;
+        tunnel.ready.store(true, Ordering::SeqCst);
+
+        Ok(tunnel)
     }
 
     async fn cleanup(&mut self) {
+        if let Some(task) = self.mtu_discovery.take() {
+            task.abort();
+        }
         self.configurator.cleanup().await;
         let client = CccHttpClient::new(self.params.clone(), Some(self.session.clone()));
         let _ = client.signout().await;
@@ -97,18 +132,39 @@ impl VpnTunnel for NativeIpsecTunnel {
 
         let natt_stopper = start_natt_listener(self.natt_socket.clone(), event_sender.clone()).await?;
 
-        let _ = event_sender.send(TunnelEvent::Connected).await;
+        if self.params.verify_connectivity {
+            debug!("Verifying tunnel connectivity");
+            if let Err(e) = self.keepalive_runner.probe(self.params.verify_timeout).await {
+                warn!("Connectivity check failed: {}", e);
+                let _ = natt_stopper.send(());
+                let _ = event_sender
+                    .send(TunnelEvent::Disconnected(DisconnectReason::ConnectivityCheckFailed))
+                    .await;
+                anyhow::bail!("Connectivity check failed: {}", e);
+            }
+        }
+
+        let _ = event_sender
+            .send(TunnelEvent::Connected(self.connection_info.clone()))
+            .await;
+
+        self.mtu_discovery = start_mtu_discovery(&self.params, &self.connection_info, self.gateway_internal_ip);
 
         let sender = event_sender.clone();
 
-        tokio::task::spawn(async move {
+        let rekey_timer = async move {
             let mut interval = tokio::time::interval(Duration::from_secs(10));
             interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
             while sender.send(TunnelEvent::RekeyCheck).await.is_ok() {
                 interval.tick().await;
             }
-            Ok::<_, anyhow::Error>(())
-        });
+        };
+
+        if self.params.keepalive_thread {
+            tokio::spawn(util::run_on_dedicated_thread("snx-rekey", rekey_timer));
+        } else {
+            tokio::spawn(rekey_timer);
+        }
 
         let fut = async {
             while let Some(cmd) = command_receiver.recv().await {
@@ -120,26 +176,29 @@ impl VpnTunnel for NativeIpsecTunnel {
                             session.lifetime.as_secs()
                         );
                         self.ready.store(false, Ordering::SeqCst);
-                        let _ = self.configurator.rekey(&session).await;
+                        let result = self.configurator.rekey(&session).await;
                         self.ready.store(true, Ordering::SeqCst);
+                        if result.is_ok() {
+                            let _ = event_sender.send(TunnelEvent::Rekeyed).await;
+                        }
                     }
                 }
             }
         };
-        let result = tokio::select! {
+        let (result, reason) = tokio::select! {
             () = fut => {
                 debug!("Terminating IPSec tunnel due to stop command");
-                Ok(())
+                (Ok(()), DisconnectReason::User)
             }
 
             err = self.keepalive_runner.run() => {
                 debug!("Terminating IPSec tunnel due to keepalive failure");
-                err
+                (err, DisconnectReason::KeepaliveTimeout)
             }
         };
 
         let _ = natt_stopper.send(());
-        let _ = event_sender.send(TunnelEvent::Disconnected).await;
+        let _ = event_sender.send(TunnelEvent::Disconnected(reason)).await;
 
         result
     }
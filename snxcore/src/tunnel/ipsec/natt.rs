@@ -1,4 +1,8 @@
-use std::{net::Ipv4Addr, sync::Arc, time::Duration};
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::anyhow;
 use bytes::Bytes;
@@ -10,8 +14,6 @@ use tracing::debug;
 
 use crate::{platform::UdpSocketExt, tunnel::TunnelEvent};
 
-const MAX_NATT_PROBES: usize = 3;
-
 // Both packets are IKE SA requests which do some magic of unblocking port 4500 for some users.
 const NMAP_KNOCK: &[&[u8]] = &[
     &[
@@ -44,11 +46,31 @@ const NMAP_KNOCK: &[&[u8]] = &[
 pub struct NattProber {
     address: Ipv4Addr,
     port: u16,
+    probe_count: usize,
+    probe_timeout: Duration,
+    bind_address: Option<IpAddr>,
 }
 
 impl NattProber {
-    pub fn new(address: Ipv4Addr) -> Self {
-        Self { address, port: 4500 }
+    pub fn new(address: Ipv4Addr, probe_count: usize, probe_timeout: Duration) -> Self {
+        Self {
+            address,
+            port: 4500,
+            probe_count,
+            probe_timeout,
+            bind_address: None,
+        }
+    }
+
+    /// Send the probes from the given local address instead of the one picked by the kernel
+    pub fn with_bind_address(mut self, bind_address: Option<IpAddr>) -> Self {
+        self.bind_address = bind_address;
+        self
+    }
+
+    async fn bind(&self) -> anyhow::Result<UdpSocket> {
+        let local = self.bind_address.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        Ok(UdpSocket::bind((local, 0)).await?)
     }
 
     pub async fn probe(&self) -> anyhow::Result<()> {
@@ -57,7 +79,7 @@ impl NattProber {
             self.send_nmap_knock().await?;
             self.send_nmap_knock().await?;
 
-            for _ in 0..MAX_NATT_PROBES {
+            for _ in 0..self.probe_count {
                 if self.send_probe().await.is_ok() {
                     return Ok(());
                 }
@@ -71,12 +93,17 @@ impl NattProber {
     async fn send_probe(&self) -> anyhow::Result<()> {
         debug!("Sending NAT-T probe to {}", self.address);
 
-        let udp = UdpSocket::bind("0.0.0.0:0").await?;
+        let udp = self.bind().await?;
         udp.connect(format!("{}:{}", self.address, self.port)).await?;
 
         let data = vec![0u8; 32];
 
-        let result = udp.send_receive(&data, Duration::from_secs(2)).await;
+        let result = udp.send_receive(&data, self.probe_timeout).await;
+
+        #[cfg(feature = "packet-dump")]
+        if let Ok(ref reply) = result {
+            tracing::trace!("NAT-T reply, {} bytes:\n{}", reply.len(), crate::util::hex_dump(reply));
+        }
 
         match result {
             Ok(reply) if reply.len() == 32 => {
@@ -98,7 +125,7 @@ impl NattProber {
     async fn send_nmap_knock(&self) -> anyhow::Result<()> {
         debug!("Sending magic knock IKE SAs to {}", self.address);
 
-        let udp = UdpSocket::bind("0.0.0.0:0").await?;
+        let udp = self.bind().await?;
         udp.connect(format!("{}:500", self.address)).await?;
 
         for probe in NMAP_KNOCK {
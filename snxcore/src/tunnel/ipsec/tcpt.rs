@@ -1,5 +1,5 @@
 use std::{
-    net::Ipv4Addr,
+    net::{IpAddr, Ipv4Addr},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc, RwLock,
@@ -22,15 +22,20 @@ use isakmp::{
 };
 use tokio::{
     io::{AsyncRead, AsyncWrite},
+    task::JoinHandle,
     time::MissedTickBehavior,
 };
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
 use crate::{
     ccc::CccHttpClient,
     model::{params::TunnelParams, proto::*, *},
     platform::{self, new_resolver_configurator, ResolverConfig},
-    tunnel::{device::TunDevice, ipsec::keepalive::KeepaliveRunner, TunnelCommand, TunnelEvent, VpnTunnel},
+    tunnel::{
+        device::TunDevice,
+        ipsec::{keepalive::KeepaliveRunner, start_mtu_discovery},
+        proxy, TunnelCommand, TunnelEvent, VpnTunnel,
+    },
     util,
 };
 
@@ -80,18 +85,37 @@ pub(crate) struct TcptIpsecTunnel {
     ready: Arc<AtomicBool>,
     client_settings: ClientSettingsResponse,
     gateway_address: Ipv4Addr,
+    redirect: Option<Ipv4Addr>,
+    mtu_discovery: Option<JoinHandle<()>>,
 }
 
 impl TcptIpsecTunnel {
-    pub(crate) async fn create(params: Arc<TunnelParams>, session: Arc<VpnSession>) -> anyhow::Result<Self> {
-        let mut tcp = tokio::net::TcpStream::connect((params.server_name.as_str(), 443)).await?;
+    pub(crate) async fn create(
+        params: Arc<TunnelParams>,
+        session: Arc<VpnSession>,
+        redirect: Option<Ipv4Addr>,
+    ) -> anyhow::Result<Self> {
+        let mut tcp = proxy::connect_gateway(&params, redirect).await?;
 
         handshake(TcptDataType::Esp, &mut tcp).await?;
 
         let client = CccHttpClient::new(params.clone(), Some(session.clone()));
         let client_settings = client.get_client_settings().await?;
 
-        let gateway_address = util::resolve_ipv4_host(&format!("{}:{}", params.server_name, params.ike_port))?;
+        // the gateway IPv4 address is used as an ESP peer and keepalive destination inside the tunnel,
+        // with IPv6-only gateways the internal gateway address is used instead
+        let gateway_address = match redirect {
+            Some(address) => IpAddr::V4(address),
+            None => util::resolve_host(&format!("{}:{}", params.server_name, params.ike_port))?,
+        };
+
+        let gateway_address = match gateway_address {
+            IpAddr::V4(address) => address,
+            IpAddr::V6(address) => {
+                debug!("Gateway has IPv6 address {}, using internal gateway address", address);
+                client_settings.gw_internal_ip
+            }
+        };
 
         debug!(
             "Resolved gateway address: {}, acquired internal address: {}",
@@ -114,9 +138,19 @@ impl TcptIpsecTunnel {
             ready,
             client_settings,
             gateway_address,
+            redirect,
+            mtu_discovery: None,
         })
     }
 
+    // address of the TCPT peer which must stay reachable outside of the tunnel
+    fn dest_ip(&self) -> anyhow::Result<IpAddr> {
+        match self.redirect {
+            Some(address) => Ok(address.into()),
+            None => util::resolve_server(&self.params.server_name),
+        }
+    }
+
     async fn send(&mut self, packet: Bytes) -> anyhow::Result<()> {
         tokio::time::timeout(SEND_TIMEOUT, self.sender.send(packet)).await??;
 
@@ -124,18 +158,29 @@ impl TcptIpsecTunnel {
     }
 
     async fn cleanup(&mut self) {
+        if let Some(task) = self.mtu_discovery.take() {
+            task.abort();
+        }
         if let Some(device) = self.tun_device.take() {
-            if let Ok(dest_ip) = util::resolve_ipv4_host(&format!("{}:443", self.params.server_name)) {
-                let _ = platform::remove_default_route(dest_ip).await;
-
-                let dst = dest_ip.to_string();
-                let port = TunnelParams::IPSEC_KEEPALIVE_PORT.to_string();
+            if util::is_default_route_enabled(&self.params) {
+                if let Ok(dest_ip) = self.dest_ip() {
+                    let _ = platform::remove_default_route(dest_ip, self.params.keep_fallback_default_route).await;
+                }
+            }
 
-                let _ = iproute2(&[
-                    "rule", "del", "to", &dst, "ipproto", "udp", "dport", &port, "table", &port,
-                ])
-                .await;
+            if util::is_app_routing_enabled(&self.params) {
+                let _ = platform::remove_app_routing(&self.params).await;
             }
+
+            let dst = self.gateway_address.to_string();
+            let port = self.params.keepalive_port.to_string();
+            let table = TunnelParams::ROUTING_TABLE.to_string();
+
+            let _ = iproute2(&[
+                "rule", "del", "to", &dst, "ipproto", "udp", "dport", &port, "table", &table,
+            ])
+            .await;
+
             if !self.params.no_dns {
                 let _ = self.setup_dns(device.name(), true).await;
             }
@@ -147,40 +192,45 @@ impl TcptIpsecTunnel {
     }
 
     pub async fn setup_routing(&self, dev_name: &str) -> anyhow::Result<()> {
-        let dest_ip = util::resolve_ipv4_host(&format!("{}:443", self.params.server_name))?;
+        let dest_ip = self.dest_ip()?;
 
-        let mut subnets = self.params.add_routes.clone();
+        let default_route_set = util::is_default_route_enabled(&self.params);
 
-        let mut default_route_set = false;
-
-        if !self.params.no_routing {
-            if self.params.default_route {
-                platform::setup_default_route(dev_name, dest_ip).await?;
-                default_route_set = true;
-            } else {
-                subnets.extend(util::ranges_to_subnets(
-                    &self.client_settings.updated_policies.range.settings,
-                ));
-            }
+        if default_route_set {
+            platform::setup_default_route(dev_name, dest_ip, self.params.keep_fallback_default_route).await?;
         }
 
-        let port = TunnelParams::IPSEC_KEEPALIVE_PORT.to_string();
-        let dst = dest_ip.to_string();
+        let port = self.params.keepalive_port.to_string();
+        let table = TunnelParams::ROUTING_TABLE.to_string();
+        let dst = self.gateway_address.to_string();
 
         if !default_route_set {
-            iproute2(&["route", "add", "table", &port, &dst, "dev", dev_name]).await?;
+            iproute2(&["route", "add", "table", &table, &dst, "dev", dev_name]).await?;
         }
 
         // route keepalive packets through the tunnel
         iproute2(&[
-            "rule", "add", "to", &dst, "ipproto", "udp", "dport", &port, "table", &port,
+            "rule", "add", "to", &dst, "ipproto", "udp", "dport", &port, "table", &table,
         ])
         .await?;
 
-        subnets.retain(|s| !s.contains(&dest_ip));
+        let subnets = util::tunnel_routes(
+            &self.params,
+            util::ranges_to_subnets(&self.client_settings.updated_policies.range.settings),
+            dest_ip,
+        );
 
-        if !subnets.is_empty() {
-            let _ = platform::add_routes(&subnets, dev_name, self.ip_address, &self.params.ignore_routes).await;
+        if util::is_app_routing_enabled(&self.params) {
+            platform::setup_app_routing(dev_name, &subnets, &self.params).await?;
+        } else if !subnets.is_empty() {
+            platform::add_routes(
+                &subnets,
+                dev_name,
+                self.ip_address,
+                &self.params.ignore_routes,
+                self.params.on_route_conflict,
+            )
+            .await?;
         }
 
         Ok(())
@@ -188,32 +238,13 @@ impl TcptIpsecTunnel {
 
     pub async fn setup_dns(&self, dev_name: &str, cleanup: bool) -> anyhow::Result<()> {
         let search_domains = if let Some(ref session) = self.session.ipsec_session {
-            session
-                .domains
-                .iter()
-                .chain(self.params.search_domains.iter())
-                .filter(|s| {
-                    !s.is_empty()
-                        && !self
-                            .params
-                            .ignore_search_domains
-                            .iter()
-                            .any(|d| d.to_lowercase() == s.to_lowercase())
-                })
-                .cloned()
-                .collect::<Vec<_>>()
+            util::merge_search_domains(&session.domains, &self.params)
         } else {
             Vec::new()
         };
 
         let dns_servers = if let Some(ref session) = self.session.ipsec_session {
-            session
-                .dns
-                .iter()
-                .chain(self.params.dns_servers.iter())
-                .filter(|s| !self.params.ignore_dns_servers.iter().any(|d| *d == **s))
-                .cloned()
-                .collect::<Vec<_>>()
+            util::select_dns_servers(session.dns.iter().cloned(), &self.params)
         } else {
             Vec::new()
         };
@@ -223,7 +254,7 @@ impl TcptIpsecTunnel {
             dns_servers,
         };
 
-        let resolver = new_resolver_configurator(dev_name)?;
+        let resolver = new_resolver_configurator(dev_name, self.params.teardown_grace)?;
 
         if cleanup {
             resolver.cleanup(&config).await?;
@@ -257,7 +288,27 @@ impl VpnTunnel for TcptIpsecTunnel {
             anyhow::bail!("No IPSEC session!");
         };
 
-        let mut tun = TunDevice::new(tun_name, ipsec_session.address, Some(ipsec_session.netmask))?;
+        let connection_info = ConnectionInfo {
+            server_name: self.params.server_name.clone(),
+            transport: TunnelTransport::IpsecTcpt,
+            if_name: tun_name.to_owned(),
+            address: ipsec_session.address,
+            dns_servers: ipsec_session.dns.clone(),
+            search_domains: ipsec_session.domains.clone(),
+            routes: util::gateway_routes(
+                &self.params,
+                util::ranges_to_subnets(&self.client_settings.updated_policies.range.settings),
+            ),
+            reauth_timeout: self.client_settings.reauth_timeout(),
+        };
+
+        // stored before the setup so that a failed or canceled setup is cleaned up on drop
+        let tun = self.tun_device.insert(TunDevice::new(
+            tun_name,
+            ipsec_session.address,
+            Some(ipsec_session.netmask),
+        )?);
+        let inner = tun.take_inner().context("No tun device")?;
 
         self.setup_routing(tun_name).await?;
 
@@ -266,10 +317,15 @@ impl VpnTunnel for TcptIpsecTunnel {
         }
 
         let _ = platform::configure_device(tun_name).await;
+        platform::tune_device(tun_name, &self.params).await;
 
-        let (mut tun_sender, mut tun_receiver) = tun.take_inner().context("No tun device")?.into_framed().split();
+        if let Some(address) = ipsec_session.address_v6 {
+            if let Err(e) = platform::add_ipv6_address(tun_name, address).await {
+                warn!("Unable to assign IPv6 address {}: {}", address, e);
+            }
+        }
 
-        self.tun_device = Some(tun);
+        let (mut tun_sender, mut tun_receiver) = inner.into_framed().split();
 
         let mut snx_receiver = self.receiver.take().context("No receiver")?;
 
@@ -287,14 +343,19 @@ impl VpnTunnel for TcptIpsecTunnel {
 
         let sender = event_sender.clone();
 
-        tokio::task::spawn(async move {
+        let rekey_timer = async move {
             let mut interval = tokio::time::interval(Duration::from_secs(10));
             interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
             while sender.send(TunnelEvent::RekeyCheck).await.is_ok() {
                 interval.tick().await;
             }
-            Ok::<_, anyhow::Error>(())
-        });
+        };
+
+        if self.params.keepalive_thread {
+            tokio::spawn(util::run_on_dedicated_thread("snx-rekey", rekey_timer));
+        } else {
+            tokio::spawn(rekey_timer);
+        }
 
         let esp_codec = esp_codec_in.clone();
 
@@ -318,7 +379,6 @@ impl VpnTunnel for TcptIpsecTunnel {
             Ok::<_, anyhow::Error>(())
         });
 
-        let _ = event_sender.send(TunnelEvent::Connected).await;
         let ready = self.ready.clone();
 
         let esp_codec_in = esp_codec_in.clone();
@@ -346,35 +406,58 @@ impl VpnTunnel for TcptIpsecTunnel {
                             .set_params(session.esp_out.spi, session.esp_out.clone());
 
                         ready.store(true, Ordering::SeqCst);
+                        let _ = event_sender.send(TunnelEvent::Rekeyed).await;
                     }
                 }
             }
         };
         pin_mut!(command_fut);
 
-        let keepalive_runner = KeepaliveRunner::new(
-            ipsec_session.address,
-            self.gateway_address,
-            if self.params.no_keepalive {
-                Arc::new(AtomicBool::new(false))
-            } else {
-                ready.clone()
-            },
-        );
+        let keepalive_runner = KeepaliveRunner::new(ipsec_session.address, self.gateway_address, ready.clone())
+            .with_enabled(!self.params.no_keepalive)
+            .with_port(self.params.keepalive_port)
+            .with_dedicated_thread(self.params.keepalive_thread)
+            .with_limits(self.params.keepalive_interval, self.params.keepalive_max_retries);
 
         let ka_run = keepalive_runner.run();
         pin_mut!(ka_run);
 
-        let result = loop {
+        // the probe goes through the tunnel, so it must be polled together with the packet loop
+        let mut verified = !self.params.verify_connectivity;
+        let verify_fut = keepalive_runner.probe(self.params.verify_timeout);
+        pin_mut!(verify_fut);
+
+        if verified {
+            let _ = event_sender.send(TunnelEvent::Connected(connection_info.clone())).await;
+            self.mtu_discovery =
+                start_mtu_discovery(&self.params, &connection_info, self.client_settings.gw_internal_ip);
+        } else {
+            debug!("Verifying tunnel connectivity");
+        }
+
+        let (result, reason) = loop {
             tokio::select! {
                 () = &mut command_fut => {
                     debug!("Terminating IPSec tunnel due to stop command");
-                    break Ok(());
+                    break (Ok(()), DisconnectReason::User);
+                }
+
+                result = &mut verify_fut, if !verified => {
+                    if let Err(e) = result {
+                        warn!("Connectivity check failed: {}", e);
+                        let reason = DisconnectReason::ConnectivityCheckFailed;
+                        break (Err(anyhow!("Connectivity check failed: {}", e)), reason);
+                    }
+                    verified = true;
+                    let _ = event_sender.send(TunnelEvent::Connected(connection_info.clone())).await;
+                    self.mtu_discovery =
+                        start_mtu_discovery(&self.params, &connection_info, self.client_settings.gw_internal_ip);
                 }
 
-                err = &mut ka_run => {
+                // both use the keepalive port, so the keepalive starts once the probe is done
+                err = &mut ka_run, if verified => {
                     debug!("Terminating IPSec tunnel due to keepalive failure");
-                    break err;
+                    break (err, DisconnectReason::KeepaliveTimeout);
                 }
 
                 result = tun_receiver.next() => {
@@ -391,13 +474,13 @@ impl VpnTunnel for TcptIpsecTunnel {
                             }
                         }
                     } else {
-                        break Err(anyhow!("Receive failed"));
+                        break (Err(anyhow!("Receive failed")), DisconnectReason::Error);
                     }
                 }
             }
         };
 
-        let _ = event_sender.send(TunnelEvent::Disconnected).await;
+        let _ = event_sender.send(TunnelEvent::Disconnected(reason)).await;
 
         result
     }
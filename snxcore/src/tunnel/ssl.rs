@@ -1,4 +1,5 @@
 use std::{
+    net::{IpAddr, Ipv4Addr},
     sync::{
         atomic::{AtomicI64, Ordering},
         Arc,
@@ -11,7 +12,10 @@ use futures::{
     channel::mpsc::{self, Receiver, Sender},
     pin_mut, SinkExt, StreamExt, TryStreamExt,
 };
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::Notify,
+};
 use tokio_native_tls::native_tls::{Certificate, TlsConnector};
 use tracing::{debug, trace, warn};
 
@@ -23,9 +27,9 @@ use crate::tunnel::device;
 use crate::tunnel::device::TunDevice;
 use crate::{
     model::{params::TunnelParams, proto::*, *},
-    platform,
+    platform, server_info,
     sexpr::SExpression,
-    tunnel::{ssl::keepalive::KeepaliveRunner, TunnelCommand, TunnelEvent, VpnTunnel},
+    tunnel::{proxy, ssl::keepalive::KeepaliveRunner, TunnelCommand, TunnelEvent, VpnTunnel},
     util,
 };
 
@@ -77,15 +81,18 @@ pub(crate) struct SslTunnel {
     keepalive_counter: Arc<AtomicI64>,
     tun_device: Option<TunDevice>,
     hello_reply: HelloReplyData,
+    redirect: Option<Ipv4Addr>,
 }
 
 impl SslTunnel {
     pub(crate) async fn create(params: Arc<TunnelParams>, session: Arc<VpnSession>) -> anyhow::Result<Self> {
-        let tcp = tokio::net::TcpStream::connect((params.server_name.as_str(), 443)).await?;
+        // the connection goes to the advertised gateway node, while TLS is still verified against the server name
+        let redirect = server_info::get_redirect_address(&params).await;
+        let tcp = proxy::connect_gateway(&params, redirect).await?;
 
         let mut builder = TlsConnector::builder();
 
-        for ca_cert in &params.ca_cert {
+        for ca_cert in params.ca_cert_files()? {
             let data = tokio::fs::read(ca_cert).await?;
             let cert = Certificate::from_pem(&data).or_else(|_| Certificate::from_der(&data))?;
             builder.add_root_certificate(cert);
@@ -101,7 +108,15 @@ impl SslTunnel {
         }
 
         let tls: tokio_native_tls::TlsConnector = builder.build()?.into();
-        let stream = tls.connect(params.server_name.as_str(), tcp).await?;
+        let (host, _) = util::split_host_port(&params.server_name);
+        let stream = tls.connect(host, tcp).await?;
+
+        let peer_cert = stream
+            .get_ref()
+            .peer_certificate()?
+            .map(|cert| cert.to_der())
+            .transpose()?;
+        util::check_server_fingerprint(&params, peer_cert.as_deref())?;
 
         let (sender, receiver) = make_channel(stream);
 
@@ -118,9 +133,18 @@ impl SslTunnel {
             keepalive_counter: Arc::new(AtomicI64::default()),
             tun_device: None,
             hello_reply: HelloReplyData::default(),
+            redirect,
         })
     }
 
+    // address of the tunnel peer which must stay reachable outside of the tunnel
+    fn dest_ip(&self) -> anyhow::Result<IpAddr> {
+        match self.redirect {
+            Some(address) => Ok(address.into()),
+            None => util::resolve_server(&self.params.server_name),
+        }
+    }
+
     fn new_hello_request(&self, keep_address: bool) -> ClientHelloData {
         ClientHelloData {
             client_version: 1,
@@ -131,6 +155,7 @@ impl SslTunnel {
                 keep_address: Some(keep_address),
                 dns_servers: None,
                 dns_suffix: None,
+                wins_servers: None,
             },
             optional: Some(OptionalRequest {
                 client_type: "4".to_string(),
@@ -158,6 +183,9 @@ impl SslTunnel {
                 self.ip_address.clone_from(&hello_reply.data.office_mode.ipaddr);
                 self.auth_timeout = Duration::from_secs(hello_reply.data.timeouts.authentication) - REAUTH_LEEWAY;
                 self.keepalive = Duration::from_secs(hello_reply.data.timeouts.keepalive);
+                if let Some(ref wins) = hello_reply.data.office_mode.wins_servers {
+                    debug!("Gateway provided WINS servers which are not applied: {:?}", wins);
+                }
                 hello_reply
             }
             _ => anyhow::bail!("Unexpected reply"),
@@ -177,8 +205,14 @@ impl SslTunnel {
 
     async fn cleanup(&mut self) {
         if let Some(device) = self.tun_device.take() {
-            if let Ok(dest_ip) = util::resolve_ipv4_host(&format!("{}:443", self.params.server_name)) {
-                let _ = platform::remove_default_route(dest_ip).await;
+            if util::is_default_route_enabled(&self.params) {
+                if let Ok(dest_ip) = self.dest_ip() {
+                    let _ = platform::remove_default_route(dest_ip, self.params.keep_fallback_default_route).await;
+                }
+            }
+
+            if util::is_app_routing_enabled(&self.params) {
+                let _ = platform::remove_app_routing(&self.params).await;
             }
             if !self.params.no_dns {
                 let _ = self.setup_dns(device.name(), true).await;
@@ -193,22 +227,25 @@ impl SslTunnel {
     pub async fn setup_routing(&self, dev_name: &str) -> anyhow::Result<()> {
         let ipaddr = self.hello_reply.office_mode.ipaddr.parse()?;
 
-        let dest_ip = util::resolve_ipv4_host(&format!("{}:443", self.params.server_name))?;
+        let dest_ip = self.dest_ip()?;
 
-        let mut subnets = self.params.add_routes.clone();
-
-        if !self.params.no_routing {
-            if self.params.default_route {
-                platform::setup_default_route(dev_name, dest_ip).await?;
-            } else {
-                subnets.extend(util::ranges_to_subnets(&self.hello_reply.range));
-            }
+        if util::is_default_route_enabled(&self.params) {
+            platform::setup_default_route(dev_name, dest_ip, self.params.keep_fallback_default_route).await?;
         }
 
-        subnets.retain(|s| !s.contains(&dest_ip));
-
-        if !subnets.is_empty() {
-            let _ = platform::add_routes(&subnets, dev_name, ipaddr, &self.params.ignore_routes).await;
+        let subnets = util::tunnel_routes(&self.params, util::ranges_to_subnets(&self.hello_reply.range), dest_ip);
+
+        if util::is_app_routing_enabled(&self.params) {
+            platform::setup_app_routing(dev_name, &subnets, &self.params).await?;
+        } else if !subnets.is_empty() {
+            platform::add_routes(
+                &subnets,
+                dev_name,
+                ipaddr,
+                &self.params.ignore_routes,
+                self.params.on_route_conflict,
+            )
+            .await?;
         }
 
         Ok(())
@@ -216,42 +253,20 @@ impl SslTunnel {
 
     pub async fn setup_dns(&self, dev_name: &str, cleanup: bool) -> anyhow::Result<()> {
         let search_domains = if let Some(ref suffixes) = self.hello_reply.office_mode.dns_suffix {
-            suffixes
-                .0
-                .iter()
-                .chain(self.params.search_domains.iter())
-                .filter(|s| {
-                    !s.is_empty()
-                        && !self
-                            .params
-                            .ignore_search_domains
-                            .iter()
-                            .any(|d| d.to_lowercase() == s.to_lowercase())
-                })
-                .cloned()
-                .collect::<Vec<_>>()
+            util::merge_search_domains(&suffixes.0, &self.params)
         } else {
             Vec::new()
         };
 
-        let dns_servers = self
-            .hello_reply
-            .office_mode
-            .dns_servers
-            .clone()
-            .unwrap_or_default()
-            .iter()
-            .chain(self.params.dns_servers.iter())
-            .filter(|s| !self.params.ignore_dns_servers.iter().any(|d| *d == **s))
-            .cloned()
-            .collect::<Vec<_>>();
+        let acquired = self.hello_reply.office_mode.dns_servers.iter().flatten();
+        let dns_servers = util::select_dns_servers(acquired.map(|&s| IpAddr::from(s)), &self.params);
 
         let config = ResolverConfig {
             search_domains,
             dns_servers,
         };
 
-        let resolver = new_resolver_configurator(dev_name)?;
+        let resolver = new_resolver_configurator(dev_name, self.params.teardown_grace)?;
 
         if cleanup {
             resolver.cleanup(&config).await?;
@@ -286,7 +301,38 @@ impl VpnTunnel for SslTunnel {
             .as_deref()
             .unwrap_or(TunnelParams::DEFAULT_SSL_IF_NAME);
 
-        let mut tun = device::TunDevice::new(tun_name, ip_address, netmask)?;
+        if self.params.mtu_discovery {
+            warn!("MTU discovery is supported for IPSec tunnels only");
+        }
+
+        let office_mode = &self.hello_reply.office_mode;
+        let connection_info = ConnectionInfo {
+            server_name: self.params.server_name.clone(),
+            transport: TunnelTransport::Ssl,
+            if_name: tun_name.to_owned(),
+            address: ip_address,
+            dns_servers: office_mode
+                .dns_servers
+                .iter()
+                .flatten()
+                .map(|&s| IpAddr::from(s))
+                .collect(),
+            search_domains: office_mode
+                .dns_suffix
+                .iter()
+                .flat_map(|s| s.0.iter())
+                .filter(|s| !s.is_empty())
+                .cloned()
+                .collect(),
+            routes: util::gateway_routes(&self.params, util::ranges_to_subnets(&self.hello_reply.range)),
+            reauth_timeout: None,
+        };
+
+        // stored before the setup so that a failed or canceled setup is cleaned up on drop
+        let tun = self
+            .tun_device
+            .insert(device::TunDevice::new(tun_name, ip_address, netmask)?);
+        let inner = tun.take_inner().context("No tun device")?;
 
         self.setup_routing(tun_name).await?;
 
@@ -295,17 +341,19 @@ impl VpnTunnel for SslTunnel {
         }
 
         let _ = platform::configure_device(tun_name).await;
+        platform::tune_device(tun_name, &self.params).await;
 
-        let (mut tun_sender, mut tun_receiver) = tun.take_inner().context("No tun device")?.into_framed().split();
-
-        self.tun_device = Some(tun);
+        let (mut tun_sender, mut tun_receiver) = inner.into_framed().split();
 
         let mut snx_receiver = self.receiver.take().unwrap();
 
         let keepalive_counter = self.keepalive_counter.clone();
+        let received = Arc::new(Notify::new());
+        let received_notify = received.clone();
 
         tokio::spawn(async move {
             while let Some(item) = snx_receiver.next().await {
+                received_notify.notify_waiters();
                 match item {
                     SslPacketType::Control(expr) => {
                         debug!("Control packet received");
@@ -326,42 +374,66 @@ impl VpnTunnel for SslTunnel {
             Ok::<_, anyhow::Error>(())
         });
 
-        let _ = event_sender.send(TunnelEvent::Connected).await;
-
         let command_fut = command_receiver.recv();
         pin_mut!(command_fut);
 
-        let keepalive_runner =
-            KeepaliveRunner::new(self.keepalive, self.sender.clone(), self.keepalive_counter.clone());
+        let keepalive = if self.params.keepalive_interval.is_zero() {
+            Duration::ZERO
+        } else {
+            self.keepalive
+        };
+
+        let keepalive_runner = KeepaliveRunner::new(keepalive, self.sender.clone(), self.keepalive_counter.clone())
+            .with_dedicated_thread(self.params.keepalive_thread);
         let ka_run = keepalive_runner.run();
         pin_mut!(ka_run);
 
-        let result = loop {
+        let mut verified = !self.params.verify_connectivity;
+        let verify_fut = keepalive_runner.probe(self.params.verify_timeout, received);
+        pin_mut!(verify_fut);
+
+        if verified {
+            let _ = event_sender.send(TunnelEvent::Connected(connection_info.clone())).await;
+        } else {
+            debug!("Verifying tunnel connectivity");
+        }
+
+        let (result, reason) = loop {
             tokio::select! {
+                result = &mut verify_fut, if !verified => {
+                    if let Err(e) = result {
+                        warn!("Connectivity check failed: {}", e);
+                        let reason = DisconnectReason::ConnectivityCheckFailed;
+                        break (Err(anyhow!("Connectivity check failed: {}", e)), reason);
+                    }
+                    verified = true;
+                    let _ = event_sender.send(TunnelEvent::Connected(connection_info.clone())).await;
+                }
+
                 event = &mut command_fut => {
                     match event {
                         Some(TunnelCommand::Terminate) | None => {
-                            break Ok(());
+                            break (Ok(()), DisconnectReason::User);
                         }
                         _ => {}
                     }
                 }
                 () = &mut ka_run => {
                     warn!("Keepalive failed, exiting");
-                    break Err(anyhow!("Keepalive failed"));
+                    break (Err(anyhow!("Keepalive failed")), DisconnectReason::KeepaliveTimeout);
                 }
 
                 result = tun_receiver.next() => {
                     if let Some(Ok(item)) = result {
                         self.send(item).await?;
                     } else {
-                        break Err(anyhow!("Receive failed"));
+                        break (Err(anyhow!("Receive failed")), DisconnectReason::Error);
                     }
                 }
             }
         };
 
-        let _ = event_sender.send(TunnelEvent::Disconnected).await;
+        let _ = event_sender.send(TunnelEvent::Disconnected(reason)).await;
 
         result
     }
@@ -10,7 +10,7 @@ use crate::{
     model::{
         params::{CertType, TunnelParams},
         proto::AuthResponse,
-        MfaChallenge, MfaType, SessionState, VpnSession,
+        ConnectionError, MfaChallenge, MfaType, SessionState, VpnSession,
     },
     tunnel::{ssl::SslTunnel, TunnelCommand, TunnelConnector, TunnelEvent, VpnTunnel},
 };
@@ -28,13 +28,19 @@ impl CccTunnelConnector {
         })
     }
 
-    async fn process_auth_response(&self, data: AuthResponse) -> anyhow::Result<Arc<VpnSession>> {
+    // the session cookie of the client is carried over to the following requests of the session
+    async fn process_auth_response(
+        &self,
+        data: AuthResponse,
+        ccc_cookie: Option<String>,
+    ) -> anyhow::Result<Arc<VpnSession>> {
         let session_id = data.session_id.unwrap_or_default();
 
         match data.authn_status.as_str() {
             "continue" => {
                 return Ok(Arc::new(VpnSession {
                     ccc_session_id: session_id,
+                    ccc_cookie,
                     state: SessionState::PendingChallenge(MfaChallenge {
                         mfa_type: MfaType::PasswordInput,
                         prompt: data.prompt.map(|p| p.0).unwrap_or_default(),
@@ -45,19 +51,23 @@ impl CccTunnelConnector {
             "done" => {}
             other => {
                 warn!("Authn status: {}", other);
-                anyhow::bail!("Authentication failed!");
+                return Err(ConnectionError::AuthFailed("Authentication failed!".into()).into());
             }
         }
 
         let active_key = match (data.is_authenticated, data.active_key) {
             (Some(true), Some(ref key)) => key.clone(),
             _ => {
-                let msg = match (data.error_message, data.error_id, data.error_code) {
-                    (Some(message), Some(id), Some(code)) => format!("[{} {}] {}", code, id.0, message.0),
-                    _ => "Authentication failed!".to_owned(),
+                let error = match (data.error_message, data.error_id, data.error_code) {
+                    (Some(message), Some(id), Some(code)) => ConnectionError::ServerRejected {
+                        code,
+                        id: id.0,
+                        message: message.0,
+                    },
+                    _ => ConnectionError::AuthFailed("Authentication failed!".into()),
                 };
-                warn!("{}", msg);
-                anyhow::bail!(msg);
+                warn!("{}", error);
+                return Err(error.into());
             }
         };
 
@@ -65,6 +75,7 @@ impl CccTunnelConnector {
 
         let session = Arc::new(VpnSession {
             ccc_session_id: session_id,
+            ccc_cookie,
             state: SessionState::Authenticated(active_key.0),
             ipsec_session: None,
         });
@@ -80,6 +91,7 @@ impl TunnelConnector for CccTunnelConnector {
         if self.params.cert_type == CertType::None && self.params.user_name.is_empty() {
             Ok(Arc::new(VpnSession {
                 ccc_session_id: String::new(),
+                ccc_cookie: None,
                 state: SessionState::PendingChallenge(MfaChallenge {
                     mfa_type: MfaType::UserNameInput,
                     prompt: "User name: ".to_owned(),
@@ -91,7 +103,7 @@ impl TunnelConnector for CccTunnelConnector {
 
             let data = client.authenticate().await?;
 
-            self.process_auth_response(data).await
+            self.process_auth_response(data, client.session_cookie()).await
         }
     }
 
@@ -107,19 +119,19 @@ impl TunnelConnector for CccTunnelConnector {
             self.params.server_name
         );
 
-        let data = if session.ccc_session_id.is_empty() {
+        let (data, ccc_cookie) = if session.ccc_session_id.is_empty() {
             let params = Arc::new(TunnelParams {
                 user_name: user_input.to_owned(),
                 ..(*self.params).clone()
             });
             let client = CccHttpClient::new(params, Some(session.clone()));
-            client.authenticate().await?
+            (client.authenticate().await?, client.session_cookie())
         } else {
             let client = CccHttpClient::new(self.params.clone(), Some(session.clone()));
-            client.challenge_code(user_input).await?
+            (client.challenge_code(user_input).await?, client.session_cookie())
         };
 
-        self.process_auth_response(data).await
+        self.process_auth_response(data, ccc_cookie).await
     }
 
     async fn create_tunnel(
@@ -138,15 +150,21 @@ impl TunnelConnector for CccTunnelConnector {
         Ok(())
     }
 
+    // The gateway binds the SSL tunnel to the active key sent in the client hello, and there is no message
+    // to pass a new key to a running tunnel. The caller reconnects instead, which authenticates again.
+    async fn reauthenticate(&mut self) -> anyhow::Result<()> {
+        Err(anyhow!("Re-authentication is not supported for SSL tunnels"))
+    }
+
     async fn handle_tunnel_event(&mut self, event: TunnelEvent) -> anyhow::Result<()> {
         match event {
-            TunnelEvent::Connected => {
-                debug!("Tunnel connected");
+            TunnelEvent::Connected(info) => {
+                debug!("Tunnel connected using {}", info.transport);
             }
-            TunnelEvent::Disconnected => {
-                debug!("Tunnel disconnected");
+            TunnelEvent::Disconnected(reason) => {
+                debug!("Tunnel disconnected: {}", reason);
             }
-            TunnelEvent::RekeyCheck => {}
+            TunnelEvent::RekeyCheck | TunnelEvent::Rekeyed | TunnelEvent::ConfigChanged | TunnelEvent::ReauthDue => {}
             TunnelEvent::RemoteControlData(_) => {
                 warn!("Tunnel data received: shouldn't happen for SSL tunnel!");
             }
@@ -154,3 +172,21 @@ impl TunnelConnector for CccTunnelConnector {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reauthenticate_unsupported() {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+        let mut connector = CccTunnelConnector::new(Arc::new(TunnelParams::default()))
+            .await
+            .unwrap();
+        connector.command_sender = Some(sender);
+
+        // the tunnel is left to the caller, no command is sent to it
+        assert!(connector.reauthenticate().await.is_err());
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -6,22 +6,27 @@ use std::{
     time::Duration,
 };
 
-use futures::{channel::oneshot, SinkExt};
-use tracing::{trace, warn};
+use anyhow::anyhow;
+use futures::{channel::oneshot, pin_mut, SinkExt};
+use tokio::sync::Notify;
+use tracing::{debug, trace, warn};
 
 use crate::{
     model::proto::KeepaliveRequestData,
     platform::{self},
     tunnel::ssl::PacketSender,
+    util,
 };
 
 const KEEPALIVE_MAX_RETRIES: i64 = 3;
 const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+const PROBE_INTERVAL: Duration = Duration::from_secs(3);
 
 pub struct KeepaliveRunner {
     interval: Duration,
     sender: PacketSender,
     keepalive_counter: Arc<AtomicI64>,
+    dedicated_thread: bool,
 }
 
 impl KeepaliveRunner {
@@ -30,17 +35,57 @@ impl KeepaliveRunner {
             interval,
             sender,
             keepalive_counter: counter,
+            dedicated_thread: false,
         }
     }
 
+    pub fn with_dedicated_thread(mut self, dedicated_thread: bool) -> Self {
+        self.dedicated_thread = dedicated_thread;
+        self
+    }
+
+    /// Send keepalive requests until any packet is received from the gateway or the timeout expires.
+    /// The receiver signals the packets with `notify_waiters`, so only the packets which arrive
+    /// after a request has been sent count.
+    pub async fn probe(&self, timeout: Duration, received: Arc<Notify>) -> anyhow::Result<()> {
+        let mut sender = self.sender.clone();
+
+        let probe = async {
+            loop {
+                // registered before sending, so that an immediate reply is not missed
+                let notified = received.notified();
+                pin_mut!(notified);
+                notified.as_mut().enable();
+
+                let req = KeepaliveRequestData { id: "0".to_string() };
+                trace!("Connectivity probe: {:?}", req);
+                sender.send(req.into()).await?;
+
+                if tokio::time::timeout(PROBE_INTERVAL, notified).await.is_ok() {
+                    break Ok::<_, anyhow::Error>(());
+                }
+            }
+        };
+
+        tokio::time::timeout(timeout, probe)
+            .await
+            .map_err(|_| anyhow!("No response from the gateway through the tunnel"))?
+    }
+
     pub async fn run(&self) {
+        // the gateway may advertise a zero keepalive timeout, which means no keepalive at all
+        if self.interval.is_zero() {
+            debug!("Keepalive interval is zero, keepalive is disabled");
+            return std::future::pending().await;
+        }
+
         let (stop_sender, stop_receiver) = oneshot::channel();
 
         let interval = self.interval;
         let keepalive_counter = self.keepalive_counter.clone();
         let mut sender = self.sender.clone();
 
-        tokio::spawn(async move {
+        let keepalive = async move {
             loop {
                 if platform::is_online() {
                     if keepalive_counter.load(Ordering::SeqCst) >= KEEPALIVE_MAX_RETRIES {
@@ -65,8 +110,46 @@ impl KeepaliveRunner {
                 tokio::time::sleep(interval).await;
             }
             let _ = stop_sender.send(());
-        });
+        };
+
+        if self.dedicated_thread {
+            tokio::spawn(util::run_on_dedicated_thread("snx-keepalive", keepalive));
+        } else {
+            tokio::spawn(keepalive);
+        }
 
         let _ = stop_receiver.await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::{channel::mpsc, StreamExt};
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_probe_ignores_earlier_packets() {
+        let (sender, mut requests) = mpsc::channel(16);
+        let runner = KeepaliveRunner::new(Duration::ZERO, sender, Arc::new(AtomicI64::new(0)));
+        let received = Arc::new(Notify::new());
+
+        // a packet received before the probe must not count as the reply
+        received.notify_waiters();
+        assert!(runner.probe(PROBE_INTERVAL * 2, received.clone()).await.is_err());
+
+        while let Ok(Some(_)) = requests.try_next() {}
+
+        // the gateway replies to every request
+        let gateway = tokio::spawn({
+            let received = received.clone();
+            async move {
+                while requests.next().await.is_some() {
+                    received.notify_waiters();
+                }
+            }
+        });
+        runner.probe(PROBE_INTERVAL * 2, received).await.unwrap();
+        gateway.abort();
+    }
+}
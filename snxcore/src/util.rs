@@ -1,19 +1,34 @@
 use anyhow::{anyhow, Context};
 use ipnet::{Ipv4Net, Ipv4Subnets};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::{
     ffi::OsStr,
     fmt,
     future::Future,
     net::{IpAddr, Ipv4Addr, ToSocketAddrs},
+    os::unix::fs::MetadataExt,
     path::Path,
     process::Output,
+    time::Duration,
 };
 use tokio::process::Command;
-use tracing::trace;
+use tracing::{debug, trace, warn};
 use uuid::Uuid;
 
-use crate::{model::proto::NetworkRange, sexpr::SExpression};
+use crate::{
+    model::{
+        params::{SearchDomainPolicy, TunnelParams},
+        proto::{LoginOption, LoginOptionsData, NetworkRange},
+        ConnectionInfo,
+    },
+    sexpr::SExpression,
+};
+
+// a hanging connection script is killed after this time
+/// HTTPS port of the gateway, unless another port is given in the server name
+pub const GATEWAY_PORT: u16 = 443;
+
+const SCRIPT_TIMEOUT: Duration = Duration::from_secs(30);
 
 // reverse engineered from vendor snx utility
 const XOR_TABLE: &[u8] = b"-ODIFIED&W0ROPERTY3HEET7ITH/+4HE3HEET)$3?,$!0?!5?02/0%24)%3.5,,\x10&7?70?/\"*%#43";
@@ -76,6 +91,99 @@ where
     process_output(&command.output().await?)
 }
 
+/// Directory with the connection scripts which the command mode service is allowed to run
+pub const SCRIPTS_DIR: &str = "/etc/snx-rs/scripts";
+
+/// Check that the script is located in the given directory and that it and all its parent directories
+/// are owned by root and not writable by group or others, so that only root can decide what the root service runs
+pub fn check_script_permissions(script: &Path, scripts_dir: &Path) -> anyhow::Result<()> {
+    let script = script
+        .canonicalize()
+        .with_context(|| format!("Cannot resolve {}", script.display()))?;
+
+    if !script.starts_with(scripts_dir) {
+        anyhow::bail!("{} is not located in {}", script.display(), scripts_dir.display());
+    }
+
+    for path in script.ancestors() {
+        let meta = std::fs::metadata(path).with_context(|| format!("Cannot access {}", path.display()))?;
+        if meta.uid() != 0 {
+            anyhow::bail!("{} is not owned by root", path.display());
+        }
+        if meta.mode() & 0o022 != 0 {
+            anyhow::bail!("{} is writable by group or others", path.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// Run the user-provided connection script for the given event ("connect" or "disconnect").
+/// The tunnel properties are passed as environment variables, lists are separated by spaces.
+/// Failures are only logged, they never affect the tunnel. The script is killed after 30 seconds.
+pub async fn run_connection_script(script: &Path, event: &str, info: &ConnectionInfo) {
+    let join = |items: Vec<String>| items.join(" ");
+
+    debug!("Running {} script: {}", event, script.display());
+
+    let mut command = Command::new(script);
+    command.envs([
+        ("SNX_EVENT", event.to_owned()),
+        ("SNX_SERVER", info.server_name.clone()),
+        ("SNX_IF_NAME", info.if_name.clone()),
+        ("SNX_ADDRESS", info.address.to_string()),
+        (
+            "SNX_DNS_SERVERS",
+            join(info.dns_servers.iter().map(ToString::to_string).collect()),
+        ),
+        ("SNX_SEARCH_DOMAINS", join(info.search_domains.clone())),
+        (
+            "SNX_ROUTES",
+            join(info.routes.iter().map(ToString::to_string).collect()),
+        ),
+    ]);
+
+    command.kill_on_drop(true);
+
+    let result = match tokio::time::timeout(SCRIPT_TIMEOUT, command.output()).await {
+        Ok(Ok(output)) => process_output(&output),
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => Err(anyhow!("timed out after {} seconds", SCRIPT_TIMEOUT.as_secs())),
+    };
+
+    if let Err(e) = result {
+        warn!("The {} script {} failed: {}", event, script.display(), e);
+    }
+}
+
+/// Run the future on a separate OS thread with its own single-threaded runtime,
+/// so that it is not delayed by a busy main runtime. The thread stops when the returned future is dropped.
+pub async fn run_on_dedicated_thread<F>(name: &str, fut: F) -> anyhow::Result<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let (result_sender, result_receiver) = tokio::sync::oneshot::channel();
+    let (_stop_sender, stop_receiver) = tokio::sync::oneshot::channel::<()>();
+
+    std::thread::Builder::new().name(name.to_owned()).spawn(move || {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        rt.block_on(async move {
+            tokio::select! {
+                output = fut => {
+                    let _ = result_sender.send(output);
+                }
+                _ = stop_receiver => {}
+            }
+        });
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    result_receiver
+        .await
+        .map_err(|_| anyhow!("Thread {} terminated unexpectedly", name))
+}
+
 pub fn block_on<F, O>(f: F) -> O
 where
     F: Future<Output = O>,
@@ -92,7 +200,68 @@ pub fn ranges_to_subnets(ranges: &[NetworkRange]) -> impl Iterator<Item = Ipv4Ne
     ranges.iter().flat_map(|r| Ipv4Subnets::new(r.from, r.to, 0))
 }
 
-pub fn print_login_options(server_info: &SExpression) {
+/// Exponential backoff delay for the given reconnect attempt, starting at one second and limited by the cap
+pub fn backoff_delay(attempt: u32, cap: Duration) -> Duration {
+    let exp = attempt.saturating_sub(1).min(16);
+    Duration::from_secs(1 << exp).min(cap)
+}
+
+/// Whether the default route should be set through the tunnel
+pub fn is_default_route_enabled(params: &TunnelParams) -> bool {
+    !params.no_routing && params.default_route && !is_app_routing_enabled(params)
+}
+
+/// Whether only the traffic of selected users or cgroup should be routed through the tunnel
+pub fn is_app_routing_enabled(params: &TunnelParams) -> bool {
+    !params.app_routing_users.is_empty() || params.app_routing_cgroup.is_some()
+}
+
+/// Routes imported from the network ranges advertised by the gateway.
+/// Nothing is imported with no-routing or when the default route goes through the tunnel,
+/// ignored routes are subtracted from the result.
+pub fn gateway_routes<I>(params: &TunnelParams, acquired: I) -> Vec<Ipv4Net>
+where
+    I: IntoIterator<Item = Ipv4Net>,
+{
+    if params.no_routing || params.default_route {
+        return Vec::new();
+    }
+
+    let mut subnets = acquired
+        .into_iter()
+        .map(|s| s.trunc())
+        .filter(|s| !params.ignore_routes.iter().any(|ignore| ignore.trunc() == *s))
+        .collect::<Vec<_>>();
+
+    subnets.sort_by_key(|s| (s.prefix_len(), s.network()));
+    subnets.dedup();
+
+    subnets
+}
+
+/// Build the list of routes to install for the tunnel from the configured and acquired routes.
+/// With no-routing, only the explicitly configured routes are returned.
+/// The result is normalized, deduplicated, stripped of ignored routes and sorted by prefix length,
+/// so that more specific routes are always installed after the broader ones.
+pub fn tunnel_routes<I>(params: &TunnelParams, acquired: I, dest_ip: IpAddr) -> Vec<Ipv4Net>
+where
+    I: IntoIterator<Item = Ipv4Net>,
+{
+    let mut subnets = params.add_routes.iter().map(Ipv4Net::trunc).collect::<Vec<_>>();
+    subnets.extend(gateway_routes(params, acquired));
+
+    if let IpAddr::V4(dest_ip) = dest_ip {
+        subnets.retain(|s| !s.contains(&dest_ip));
+    }
+
+    subnets.retain(|s| !params.ignore_routes.iter().any(|ignore| ignore.trunc() == *s));
+    subnets.sort_by_key(|s| (s.prefix_len(), s.network()));
+    subnets.dedup();
+
+    subnets
+}
+
+pub fn print_login_options(server_info: &SExpression, allow_hidden_realms: bool) {
     if let Some(SExpression::Array(items)) =
         server_info.get("CCCserverResponse:ResponseData:connectivity_info:supported_data_tunnel_protocols")
     {
@@ -104,20 +273,83 @@ pub fn print_login_options(server_info: &SExpression) {
         }
     }
 
-    if let Some(options) = server_info.get("CCCserverResponse:ResponseData:login_options_data:login_options_list") {
+    let login_options = server_info
+        .get("CCCserverResponse:ResponseData:login_options_data")
+        .cloned()
+        .and_then(|data| data.try_into::<LoginOptionsData>().ok());
+
+    if let Some(login_options) = login_options {
         println!("Available login types:");
-        let mut i = 0;
-        while let Some(opt) = options.get(&format!("{i}")) {
-            if let (Some(display_name), Some(id)) =
-                (opt.get_value::<String>("display_name"), opt.get_value::<String>("id"))
-            {
-                println!("\t{id} ({display_name})");
+        for option in by_index(&login_options.login_options_list) {
+            if option.is_hidden() && !allow_hidden_realms {
+                continue;
             }
-            i += 1;
+            println!("{}", format_login_option(option));
         }
     }
 }
 
+// login options and factors are keyed by their position in the server response
+pub(crate) fn by_index<T>(items: &BTreeMap<String, T>) -> Vec<&T> {
+    let mut items = items.iter().collect::<Vec<_>>();
+    items.sort_by_key(|(key, _)| key.parse::<u32>().unwrap_or(u32::MAX));
+    items.into_iter().map(|(_, item)| item).collect()
+}
+
+fn format_login_option(option: &LoginOption) -> String {
+    let mut result = format!("\t{} ({})", option.id, option.display_name);
+
+    if option.is_hidden() {
+        result.push_str(" [hidden]");
+    }
+
+    let factors = by_index(&option.factors)
+        .into_iter()
+        .map(|factor| factor.factor_type.as_str())
+        .collect::<Vec<_>>();
+
+    if !factors.is_empty() {
+        result.push_str(&format!("\n\t\tfactors: {}", factors.join(", ")));
+    }
+
+    if !option.secondary_realm_hash.is_empty() {
+        result.push_str(&format!("\n\t\tsecondary realm hash: {}", option.secondary_realm_hash));
+    }
+
+    result
+}
+
+/// SHA-256 fingerprint of a DER-encoded certificate as a lowercase hex string
+pub fn cert_fingerprint(der: &[u8]) -> String {
+    hex::encode(openssl::sha::sha256(der))
+}
+
+/// Check the certificate presented by the server against the pinned fingerprint, if one is configured.
+/// The fingerprint may be given in upper or lower case, with or without colon separators.
+pub fn check_server_fingerprint(params: &TunnelParams, der: Option<&[u8]>) -> anyhow::Result<()> {
+    let Some(ref pinned) = params.server_cert_fingerprint else {
+        return Ok(());
+    };
+
+    let expected = pinned
+        .chars()
+        .filter(|c| *c != ':' && !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase();
+
+    let actual = cert_fingerprint(der.context("Server did not present a certificate")?);
+
+    if actual != expected {
+        anyhow::bail!(
+            "Server certificate fingerprint mismatch: expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    Ok(())
+}
+
 pub fn get_device_id() -> String {
     let machine_uuid = crate::platform::get_machine_uuid().unwrap_or_else(|_| Uuid::new_v4());
     Uuid::new_v5(&Uuid::NAMESPACE_OID, machine_uuid.as_bytes())
@@ -126,6 +358,78 @@ pub fn get_device_id() -> String {
         .to_owned()
 }
 
+/// Validate a device id given in the configuration, it must be a UUID like the generated ones.
+/// The result is normalized to the braced upper-case form which is 38 characters long.
+pub fn parse_device_id(value: &str) -> anyhow::Result<String> {
+    let uuid = Uuid::parse_str(value.trim())
+        .context("Device id must be a UUID, e.g. {01234567-89AB-CDEF-0123-456789ABCDEF}")?;
+    Ok(uuid.braced().encode_upper(&mut Uuid::encode_buffer()).to_owned())
+}
+
+/// Format the bytes like the Wireshark hex view: offset, 16 hex bytes per line split in two groups, ASCII column
+pub fn hex_dump(data: &[u8]) -> String {
+    data.chunks(16)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let hex = chunk
+                .iter()
+                .enumerate()
+                .map(|(i, b)| {
+                    if i == 8 {
+                        format!(" {b:02x}")
+                    } else {
+                        format!("{b:02x}")
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+            let ascii = chunk
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect::<String>();
+            format!("{:04x}  {:<48}  {}", index * 16, hex, ascii)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Resolve the host address, IPv4 is preferred if both address families are available.
+/// Split the optional port from the server name, e.g. "gw.example.com:8443", the default is port 443
+pub fn split_host_port(server_name: &str) -> (&str, u16) {
+    match server_name.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => match port.parse() {
+            Ok(port) => (host, port),
+            Err(_) => (server_name, GATEWAY_PORT),
+        },
+        _ => (server_name, GATEWAY_PORT),
+    }
+}
+
+/// Address of the gateway from the server name, which may contain an explicit port
+pub fn resolve_server(server_name: &str) -> anyhow::Result<IpAddr> {
+    let (host, port) = split_host_port(server_name);
+    resolve_host(&format!("{}:{}", host, port))
+}
+
+pub fn resolve_host(server_name: &str) -> anyhow::Result<IpAddr> {
+    let addresses = server_name.to_socket_addrs()?.map(|addr| addr.ip()).collect::<Vec<_>>();
+
+    let address = addresses
+        .iter()
+        .find(|addr| addr.is_ipv4())
+        .or_else(|| addresses.first())
+        .copied()
+        .context(format!("Cannot resolve {}", server_name))?;
+
+    Ok(address)
+}
+
 pub fn resolve_ipv4_host(server_name: &str) -> anyhow::Result<Ipv4Addr> {
     let address = server_name
         .to_socket_addrs()?
@@ -157,6 +461,194 @@ pub fn parse_config<S: AsRef<str>>(config: S) -> anyhow::Result<HashMap<String,
     Ok(result)
 }
 
+// case-insensitive comparison key, the `~` prefix of the routing-only domains and the trailing dot are not significant
+fn domain_key(domain: &str) -> String {
+    domain
+        .trim()
+        .trim_start_matches('~')
+        .trim_end_matches('.')
+        .to_lowercase()
+}
+
+/// Split the comma or semicolon separated domain list sent by the gateway, keeping the server order
+/// and dropping the empty and duplicate entries
+pub fn parse_domain_list(value: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+    value
+        .split([',', ';'])
+        .map(str::trim)
+        .filter(|s| !s.is_empty() && seen.insert(domain_key(s)))
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+/// Merge the search domains acquired from the gateway with the configured ones for the resolver.
+/// The server-provided domains come first in their original order, the ignored, empty and duplicate entries
+/// are removed and the max-search-domains limit is applied.
+pub fn merge_search_domains<'a, I>(acquired: I, params: &TunnelParams) -> Vec<String>
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    let ignored = params
+        .ignore_search_domains
+        .iter()
+        .map(|s| domain_key(s))
+        .collect::<HashSet<_>>();
+    let mut seen = HashSet::new();
+
+    let domains = acquired
+        .into_iter()
+        .chain(params.search_domains.iter())
+        .map(|s| s.trim())
+        .filter(|s| {
+            let key = domain_key(s);
+            !key.is_empty() && !ignored.contains(&key) && seen.insert(key)
+        })
+        .map(ToOwned::to_owned)
+        .collect();
+
+    limit_search_domains(domains, params)
+}
+
+/// Apply the max-search-domains limit to the merged list of server-provided and configured search domains
+pub fn limit_search_domains(domains: Vec<String>, params: &TunnelParams) -> Vec<String> {
+    if params.max_search_domains == 0 || domains.len() <= params.max_search_domains {
+        return domains;
+    }
+
+    let total = domains.len();
+
+    let result = match params.search_domains_policy {
+        SearchDomainPolicy::Truncate => domains.into_iter().take(params.max_search_domains).collect::<Vec<_>>(),
+        SearchDomainPolicy::KeepConfigured => {
+            let is_configured = |s: &String| {
+                params
+                    .search_domains
+                    .iter()
+                    .any(|d| d.to_lowercase() == s.to_lowercase())
+            };
+            let (configured, acquired): (Vec<_>, Vec<_>) = domains.into_iter().partition(is_configured);
+            acquired
+                .into_iter()
+                .take(params.max_search_domains)
+                .chain(configured)
+                .collect()
+        }
+    };
+
+    if result.len() < total {
+        warn!(
+            "Search domain list truncated from {} to {} entries (policy: {})",
+            total,
+            result.len(),
+            params.search_domains_policy
+        );
+    }
+
+    result
+}
+
+/// Parse either a single IPv4 address or a subnet with a prefix length or a netmask, e.g. 10.0.0.0/255.0.0.0.
+/// The host bits are cleared, so 10.0.0.5/8 becomes 10.0.0.0/8.
+pub fn parse_ipv4_net(s: &str) -> anyhow::Result<Ipv4Net> {
+    let s = s.trim();
+    let (addr, mask) = match s.split_once('/') {
+        Some((addr, mask)) => (addr, Some(mask.trim())),
+        None => (s, None),
+    };
+
+    let addr = addr
+        .trim()
+        .parse::<Ipv4Addr>()
+        .map_err(|_| anyhow!("Invalid IPv4 address or subnet: {}", s))?;
+
+    let prefix = match mask {
+        None => 32,
+        Some(mask) if mask.contains('.') => {
+            let mask = mask
+                .parse::<Ipv4Addr>()
+                .map_err(|_| anyhow!("Invalid netmask in {}", s))?;
+            ipnet::ipv4_mask_to_prefix(mask).map_err(|_| anyhow!("Non-contiguous netmask in {}", s))?
+        }
+        Some(prefix) => match prefix.parse::<u8>() {
+            Ok(prefix) if prefix <= 32 => prefix,
+            _ => anyhow::bail!("Invalid prefix length in {}, expected 0 to 32", s),
+        },
+    };
+
+    Ok(Ipv4Net::new(addr, prefix)?.trunc())
+}
+
+/// Format a subnet, single hosts are formatted as plain addresses
+pub fn format_ipv4_net(net: &Ipv4Net) -> String {
+    if net.prefix_len() == net.max_prefix_len() {
+        net.addr().to_string()
+    } else {
+        net.to_string()
+    }
+}
+
+/// Format a duration in a compact human-readable form: 45s, 12m, 1h23m
+pub fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let (hours, minutes) = (secs / 3600, (secs % 3600) / 60);
+
+    if hours > 0 {
+        format!("{hours}h{minutes:02}m")
+    } else if minutes > 0 {
+        format!("{minutes}m")
+    } else {
+        format!("{secs}s")
+    }
+}
+
+/// Format a byte count in a compact human-readable form: 512 B, 1.5 KiB, 23.0 MiB
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+/// Format a transfer rate in bytes per second: 512 B/s, 1.5 KiB/s
+pub fn format_rate(bytes_per_sec: u64) -> String {
+    format!("{}/s", format_bytes(bytes_per_sec))
+}
+
+pub fn is_dns_server_ignored(params: &TunnelParams, server: &IpAddr) -> bool {
+    match server {
+        IpAddr::V4(server) => params.ignore_dns_servers.iter().any(|net| net.contains(server)),
+        IpAddr::V6(_) => params.disable_ipv6,
+    }
+}
+
+/// DNS servers for the resolver: the acquired servers without the ignored ones followed by the configured ones.
+/// With `replace-dns-servers` the configured servers replace the acquired list if there are any. Duplicates are removed.
+pub fn select_dns_servers<I>(acquired: I, params: &TunnelParams) -> Vec<IpAddr>
+where
+    I: IntoIterator<Item = IpAddr>,
+{
+    let mut seen = HashSet::new();
+    let replace = params.replace_dns_servers && !params.dns_servers.is_empty();
+
+    acquired
+        .into_iter()
+        .filter(|server| !replace && !is_dns_server_ignored(params, server))
+        .chain(params.dns_servers.iter().map(|&server| IpAddr::from(server)))
+        .filter(|server| seen.insert(*server))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -183,4 +675,407 @@ mod tests {
             ])
         );
     }
+
+    #[test]
+    fn test_hex_dump() {
+        let data = b"\x00\x00\x00\x11\x00\x01\x00\x02snx-rs keepalive";
+        assert_eq!(
+            hex_dump(data),
+            "0000  00 00 00 11 00 01 00 02  73 6e 78 2d 72 73 20 6b  ........snx-rs k\n\
+             0010  65 65 70 61 6c 69 76 65                           eepalive"
+        );
+        assert!(hex_dump(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_parse_domain_list() {
+        assert_eq!(
+            parse_domain_list("corp.example.com; lab.example.com,,CORP.example.com, example.com"),
+            ["corp.example.com", "lab.example.com", "example.com"]
+        );
+        assert!(parse_domain_list("").is_empty());
+    }
+
+    #[test]
+    fn test_merge_search_domains() {
+        let acquired = ["b.com", "a.com", "ignored.com", "B.com"].map(ToOwned::to_owned);
+
+        let mut params = TunnelParams {
+            search_domains: vec!["local.lan".to_owned(), "a.com".to_owned(), " ".to_owned()],
+            ignore_search_domains: vec!["Ignored.com".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(
+            merge_search_domains(&acquired, &params),
+            ["b.com", "a.com", "local.lan"]
+        );
+
+        params.search_domains = vec!["~b.com".to_owned(), "c.com.".to_owned()];
+        assert_eq!(merge_search_domains(&acquired, &params), ["b.com", "a.com", "c.com."]);
+
+        params.max_search_domains = 2;
+        assert_eq!(merge_search_domains(&acquired, &params), ["b.com", "a.com"]);
+    }
+
+    #[test]
+    fn test_select_dns_servers() {
+        let acquired = ["10.0.0.1", "10.1.0.1", "192.168.1.1", "10.1.0.1", "fd00::1"].map(|s| s.parse().unwrap());
+
+        let mut params = TunnelParams {
+            ignore_dns_servers: vec!["10.0.0.1/32".parse().unwrap(), "192.168.0.0/16".parse().unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(
+            select_dns_servers(acquired, &params),
+            ["10.1.0.1", "fd00::1"].map(|s| s.parse::<IpAddr>().unwrap())
+        );
+
+        params.disable_ipv6 = true;
+        assert_eq!(
+            select_dns_servers(acquired, &params),
+            ["10.1.0.1".parse::<IpAddr>().unwrap()]
+        );
+
+        // the configured servers are added to the acquired ones by default
+        params.dns_servers = vec!["1.1.1.1".parse().unwrap(), "10.0.0.1".parse().unwrap()];
+        assert_eq!(
+            select_dns_servers(acquired, &params),
+            ["10.1.0.1", "1.1.1.1", "10.0.0.1"].map(|s| s.parse::<IpAddr>().unwrap())
+        );
+
+        params.replace_dns_servers = true;
+        assert_eq!(
+            select_dns_servers(acquired, &params),
+            ["1.1.1.1", "10.0.0.1"].map(|s| s.parse::<IpAddr>().unwrap())
+        );
+
+        params.dns_servers.clear();
+        assert_eq!(
+            select_dns_servers(acquired, &params),
+            ["10.1.0.1".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_limit_search_domains() {
+        let domains = ["a.com", "b.com", "c.com", "local.lan"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+
+        let mut params = TunnelParams {
+            search_domains: vec!["local.lan".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(limit_search_domains(domains.clone(), &params), domains);
+
+        params.max_search_domains = 2;
+        assert_eq!(limit_search_domains(domains.clone(), &params), ["a.com", "b.com"]);
+
+        params.search_domains_policy = SearchDomainPolicy::KeepConfigured;
+        assert_eq!(
+            limit_search_domains(domains.clone(), &params),
+            ["a.com", "b.com", "local.lan"]
+        );
+    }
+
+    #[test]
+    fn test_tunnel_routes() {
+        let acquired: Vec<Ipv4Net> = vec!["10.0.0.0/8".parse().unwrap(), "192.168.1.0/24".parse().unwrap()];
+        let dest_ip = IpAddr::V4("10.1.1.1".parse().unwrap());
+
+        let mut params = TunnelParams {
+            add_routes: vec!["172.16.0.0/16".parse().unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(
+            tunnel_routes(&params, acquired.clone(), dest_ip),
+            [
+                "172.16.0.0/16".parse::<Ipv4Net>().unwrap(),
+                "192.168.1.0/24".parse().unwrap()
+            ]
+        );
+
+        params.default_route = true;
+        assert!(is_default_route_enabled(&params));
+        assert_eq!(
+            tunnel_routes(&params, acquired.clone(), dest_ip),
+            ["172.16.0.0/16".parse::<Ipv4Net>().unwrap()]
+        );
+
+        // with no-routing only the configured routes are added, the DNS servers are still used
+        params.no_routing = true;
+        assert!(!is_default_route_enabled(&params));
+        assert_eq!(
+            tunnel_routes(&params, acquired.clone(), dest_ip),
+            ["172.16.0.0/16".parse::<Ipv4Net>().unwrap()]
+        );
+        let dns = ["10.0.0.53".parse::<IpAddr>().unwrap()];
+        assert_eq!(select_dns_servers(dns, &params), dns);
+
+        params.add_routes.clear();
+        assert!(tunnel_routes(&params, acquired, dest_ip).is_empty());
+    }
+
+    #[test]
+    fn test_tunnel_routes_order() {
+        let acquired: Vec<Ipv4Net> = vec![
+            "10.20.30.0/24".parse().unwrap(),
+            "10.20.0.0/16".parse().unwrap(),
+            "192.168.1.7/24".parse().unwrap(),
+            "10.20.0.0/16".parse().unwrap(),
+            "172.16.5.0/24".parse().unwrap(),
+        ];
+        let dest_ip = IpAddr::V4("1.2.3.4".parse().unwrap());
+
+        let params = TunnelParams {
+            add_routes: vec!["192.168.1.0/24".parse().unwrap(), "10.0.0.0/8".parse().unwrap()],
+            ignore_routes: vec!["172.16.5.0/24".parse().unwrap()],
+            ..Default::default()
+        };
+
+        let expected: Vec<Ipv4Net> = vec![
+            "10.0.0.0/8".parse().unwrap(),
+            "10.20.0.0/16".parse().unwrap(),
+            "10.20.30.0/24".parse().unwrap(),
+            "192.168.1.0/24".parse().unwrap(),
+        ];
+
+        assert_eq!(tunnel_routes(&params, acquired.clone(), dest_ip), expected);
+
+        let reversed = acquired.into_iter().rev().collect::<Vec<_>>();
+        assert_eq!(tunnel_routes(&params, reversed, dest_ip), expected);
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let cap = Duration::from_secs(60);
+        assert_eq!(backoff_delay(1, cap), Duration::from_secs(1));
+        assert_eq!(backoff_delay(2, cap), Duration::from_secs(2));
+        assert_eq!(backoff_delay(5, cap), Duration::from_secs(16));
+        assert_eq!(backoff_delay(7, cap), cap);
+        assert_eq!(backoff_delay(100, cap), cap);
+    }
+
+    #[test]
+    fn test_gateway_routes() {
+        let acquired: Vec<Ipv4Net> = vec![
+            "10.20.30.7/24".parse().unwrap(),
+            "172.16.5.0/24".parse().unwrap(),
+            "10.0.0.0/8".parse().unwrap(),
+            "10.20.30.0/24".parse().unwrap(),
+        ];
+
+        let mut params = TunnelParams {
+            add_routes: vec!["192.168.1.0/24".parse().unwrap()],
+            ignore_routes: vec!["172.16.5.0/24".parse().unwrap()],
+            ..Default::default()
+        };
+
+        let expected: Vec<Ipv4Net> = vec!["10.0.0.0/8".parse().unwrap(), "10.20.30.0/24".parse().unwrap()];
+        assert_eq!(gateway_routes(&params, acquired.clone()), expected);
+
+        params.default_route = true;
+        assert!(gateway_routes(&params, acquired.clone()).is_empty());
+
+        params.default_route = false;
+        params.no_routing = true;
+        assert!(gateway_routes(&params, acquired).is_empty());
+    }
+
+    #[test]
+    fn test_ignored_dns_servers() {
+        let params = TunnelParams {
+            ignore_dns_servers: ["10.0.0.1", "192.168.0.0/16"]
+                .iter()
+                .map(|s| parse_ipv4_net(s).unwrap())
+                .collect(),
+            ..Default::default()
+        };
+
+        assert!(is_dns_server_ignored(&params, &"10.0.0.1".parse().unwrap()));
+        assert!(!is_dns_server_ignored(&params, &"10.0.0.2".parse().unwrap()));
+        assert!(is_dns_server_ignored(&params, &"192.168.10.1".parse().unwrap()));
+        assert!(!is_dns_server_ignored(&params, &"172.16.0.1".parse().unwrap()));
+        assert!(!is_dns_server_ignored(&params, &"fd00::53".parse().unwrap()));
+
+        let params = TunnelParams {
+            disable_ipv6: true,
+            ..params
+        };
+        assert!(is_dns_server_ignored(&params, &"fd00::53".parse().unwrap()));
+        assert!(!is_dns_server_ignored(&params, &"172.16.0.1".parse().unwrap()));
+
+        assert_eq!(format_ipv4_net(&params.ignore_dns_servers[0]), "10.0.0.1");
+        assert_eq!(format_ipv4_net(&params.ignore_dns_servers[1]), "192.168.0.0/16");
+        assert!(parse_ipv4_net("10.0.0.0/33").is_err());
+        assert!(parse_ipv4_net("dns.local").is_err());
+    }
+
+    #[test]
+    fn test_parse_ipv4_net() {
+        let net = |s: &str| parse_ipv4_net(s).unwrap().to_string();
+
+        assert_eq!(net("10.0.0.5/8"), "10.0.0.0/8");
+        assert_eq!(net(" 192.168.1.77/24 "), "192.168.1.0/24");
+        assert_eq!(net("10.0.0.5/255.0.0.0"), "10.0.0.0/8");
+        assert_eq!(net("10.1.2.3/32"), "10.1.2.3/32");
+        assert_eq!(net("10.1.2.3"), "10.1.2.3/32");
+        assert_eq!(net("10.1.2.3/255.255.255.255"), "10.1.2.3/32");
+        assert_eq!(net("0.0.0.0/0"), "0.0.0.0/0");
+
+        let error = |s: &str| parse_ipv4_net(s).unwrap_err().to_string();
+
+        assert_eq!(
+            error("10.0.0.0/33"),
+            "Invalid prefix length in 10.0.0.0/33, expected 0 to 32"
+        );
+        assert_eq!(
+            error("10.0.0.0/"),
+            "Invalid prefix length in 10.0.0.0/, expected 0 to 32"
+        );
+        assert_eq!(
+            error("10.0.0.0/-8"),
+            "Invalid prefix length in 10.0.0.0/-8, expected 0 to 32"
+        );
+        assert_eq!(
+            error("10.0.0.0/255.0.255.0"),
+            "Non-contiguous netmask in 10.0.0.0/255.0.255.0"
+        );
+        assert_eq!(error("10.0.0.0/255.0.0"), "Invalid netmask in 10.0.0.0/255.0.0");
+        assert_eq!(error("10.0.0.256/8"), "Invalid IPv4 address or subnet: 10.0.0.256/8");
+    }
+
+    #[test]
+    fn test_format_uptime() {
+        assert_eq!(format_uptime(Duration::from_secs(45)), "45s");
+        assert_eq!(format_uptime(Duration::from_secs(12 * 60 + 5)), "12m");
+        assert_eq!(format_uptime(Duration::from_secs(3600 + 23 * 60)), "1h23m");
+        assert_eq!(format_uptime(Duration::from_secs(26 * 3600 + 60)), "26h01m");
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(23 * 1024 * 1024), "23.0 MiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024 * 1024 * 1024), "5120.0 TiB");
+        assert_eq!(format_rate(1536), "1.5 KiB/s");
+    }
+
+    #[test]
+    fn test_format_login_option() {
+        let factor = |factor_type: &str| crate::model::proto::LoginFactor {
+            factor_type: factor_type.to_owned(),
+            securid_card_type: String::new(),
+            certificate_storage_type: String::new(),
+            custom_display_labels: crate::model::proto::LoginDisplayLabelSelect::Empty(String::new()),
+        };
+
+        let mut option = LoginOption {
+            id: "vpn_Azure".to_owned(),
+            secondary_realm_hash: String::new(),
+            display_name: "Azure SSO".to_owned(),
+            show_realm: 1,
+            factors: [
+                ("0".to_owned(), factor("identity_provider")),
+                ("1".to_owned(), factor("password")),
+            ]
+            .into_iter()
+            .collect(),
+        };
+
+        assert_eq!(
+            format_login_option(&option),
+            "\tvpn_Azure (Azure SSO)\n\t\tfactors: identity_provider, password"
+        );
+
+        option.show_realm = 0;
+        option.secondary_realm_hash = "abcdef".to_owned();
+        option.factors.clear();
+
+        assert_eq!(
+            format_login_option(&option),
+            "\tvpn_Azure (Azure SSO) [hidden]\n\t\tsecondary realm hash: abcdef"
+        );
+    }
+
+    #[test]
+    fn test_by_index() {
+        let items: BTreeMap<String, u32> = [("10", 10), ("2", 2), ("0", 0)]
+            .into_iter()
+            .map(|(k, v)| (k.to_owned(), v))
+            .collect();
+        assert_eq!(by_index(&items), [&0, &2, &10]);
+    }
+
+    #[test]
+    fn test_check_server_fingerprint() {
+        let der = b"test";
+        let fingerprint = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+        assert_eq!(cert_fingerprint(der), fingerprint);
+
+        let mut params = TunnelParams::default();
+        assert!(check_server_fingerprint(&params, None).is_ok());
+
+        let with_colons = fingerprint
+            .as_bytes()
+            .chunks(2)
+            .map(|c| String::from_utf8_lossy(c).to_uppercase())
+            .collect::<Vec<_>>()
+            .join(":");
+        params.server_cert_fingerprint = Some(with_colons);
+        assert!(check_server_fingerprint(&params, Some(der)).is_ok());
+        assert!(check_server_fingerprint(&params, Some(b"other")).is_err());
+        assert!(check_server_fingerprint(&params, None).is_err());
+    }
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(split_host_port("vpn.example.com"), ("vpn.example.com", 443));
+        assert_eq!(split_host_port("vpn.example.com:8443"), ("vpn.example.com", 8443));
+        assert_eq!(split_host_port("127.0.0.1:4433"), ("127.0.0.1", 4433));
+        assert_eq!(split_host_port("vpn.example.com:abc"), ("vpn.example.com:abc", 443));
+    }
+
+    #[test]
+    fn test_resolve_server() {
+        assert_eq!(resolve_server("127.0.0.1").unwrap(), IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(resolve_server("127.0.0.1:4433").unwrap(), IpAddr::from([127, 0, 0, 1]));
+    }
+
+    #[test]
+    fn test_check_script_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("script.sh");
+        std::fs::write(&script, "#!/bin/sh\n").unwrap();
+
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(check_script_permissions(&script, dir.path()).is_err());
+
+        assert!(check_script_permissions(&dir.path().join("missing.sh"), dir.path()).is_err());
+
+        // system binaries are owned by root and only writable by root
+        let shell = Path::new("/bin/sh").canonicalize().unwrap();
+        assert!(check_script_permissions(&shell, shell.parent().unwrap()).is_ok());
+
+        // but they are not run unless located in the scripts directory
+        assert!(check_script_permissions(&shell, Path::new(SCRIPTS_DIR)).is_err());
+        assert!(check_script_permissions(&shell, dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_parse_device_id() {
+        let expected = "{0E3B1C7A-5F2D-4B8E-9A61-3C4D5E6F7A8B}";
+        assert_eq!(parse_device_id(expected).unwrap(), expected);
+        assert_eq!(
+            parse_device_id("0e3b1c7a-5f2d-4b8e-9a61-3c4d5e6f7a8b").unwrap(),
+            expected
+        );
+        assert_eq!(parse_device_id(&get_device_id()).unwrap(), get_device_id());
+        assert!(parse_device_id("00:11:22:33:44:55").is_err());
+        assert!(parse_device_id("{0E3B1C7A-5F2D-4B8E-9A61}").is_err());
+    }
 }
@@ -0,0 +1,118 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
+
+use snxcore::{model::params::TunnelParams, server_info, tunnel, util};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+type CheckResult = Result<(), String>;
+
+fn print_result(name: &str, result: &CheckResult) {
+    match result {
+        Ok(()) => println!("  {name:<28} pass"),
+        Err(e) => println!("  {name:<28} fail: {e}"),
+    }
+}
+
+async fn check_tcp(address: IpAddr, port: u16) -> CheckResult {
+    match tokio::time::timeout(CONNECT_TIMEOUT, tokio::net::TcpStream::connect((address, port))).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("connection timeout".to_owned()),
+    }
+}
+
+async fn check_natt(params: &TunnelParams, address: IpAddr) -> CheckResult {
+    // the same gateway address as used by the IPSec connector
+    let gateway = match (server_info::get_redirect_address(params).await, address) {
+        (Some(redirect), _) => redirect,
+        (None, IpAddr::V4(address)) => address,
+        (None, IpAddr::V6(_)) => return Err("IPv6 gateway requires TCPT".to_owned()),
+    };
+
+    if gateway == Ipv4Addr::UNSPECIFIED {
+        return Err("no gateway address".to_owned());
+    }
+
+    tunnel::probe_natt(gateway, params).await.map_err(|e| e.to_string())
+}
+
+/// Check the reachability of each gateway of the `server-name` list and of its transports without authenticating
+pub async fn check(params: TunnelParams) -> anyhow::Result<()> {
+    let names = params.server_names();
+    if names.is_empty() {
+        anyhow::bail!("Missing required parameters in the config file: server name");
+    }
+
+    let mut failed = 0;
+    let mut last_error = None;
+
+    for (i, name) in names.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        let gateway = TunnelParams {
+            server_name: (*name).to_owned(),
+            ..params.clone()
+        };
+        if let Err(e) = check_gateway(&gateway).await {
+            println!("Server: {}: {:#}", name, e);
+            failed += 1;
+            last_error = Some(e);
+        }
+    }
+
+    // the check fails only if none of the gateways can be resolved
+    match last_error {
+        Some(e) if failed == names.len() => Err(e),
+        _ => Ok(()),
+    }
+}
+
+async fn check_gateway(params: &TunnelParams) -> anyhow::Result<()> {
+    let (_, port) = util::split_host_port(&params.server_name);
+    let address = util::resolve_server(&params.server_name)?;
+    println!("Server: {} ({})", params.server_name, address);
+
+    let info = server_info::get(params).await;
+
+    let protocols = info
+        .as_ref()
+        .map(|info| info.connectivity_info.supported_data_tunnel_protocols.clone())
+        .unwrap_or_default();
+    let supported = |name: &str| -> CheckResult {
+        if protocols.is_empty() || protocols.iter().any(|p| p.eq_ignore_ascii_case(name)) {
+            Ok(())
+        } else {
+            Err("not supported by the server".to_owned())
+        }
+    };
+
+    let tcp = check_tcp(address, port).await;
+    let natt = match supported("IPSec") {
+        Ok(()) => check_natt(params, address).await,
+        Err(e) => Err(e),
+    };
+
+    println!("Transports:");
+    print_result(
+        "HTTPS (server info)",
+        &info.as_ref().map(|_| ()).map_err(|e| e.to_string()),
+    );
+    print_result("IPSec ESP-in-UDP (NAT-T)", &natt);
+    print_result("IPSec TCPT", &supported("IPSec").and(tcp.clone()));
+    print_result("SSL", &supported("SSL").and(tcp));
+
+    if let Some(data) = info.ok().and_then(|info| info.login_options_data) {
+        println!("Login options:");
+        for option in data.login_options_list.into_values() {
+            if !option.is_hidden() || params.allow_hidden_realms {
+                println!("  {:<28} {}", option.id, option.display_name);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,26 @@
+use std::collections::BTreeMap;
+
+use snxcore::{
+    model::params::{ConfigSource, TunnelParams},
+    platform,
+};
+
+pub async fn print_config(mut params: TunnelParams) -> anyhow::Result<()> {
+    let mut sources = if params.config_file.is_file() {
+        TunnelParams::config_sources(&params.config_file)?
+    } else {
+        BTreeMap::new()
+    };
+
+    // the same lookup as done by the service controller before connecting
+    if !params.user_name.is_empty() && !params.no_keychain && params.password.is_empty() {
+        if let Ok(password) = platform::acquire_password(&params.user_name).await {
+            params.password = password;
+            sources.insert("password".to_owned(), ConfigSource::Keychain);
+        }
+    }
+
+    print!("{}", params.to_annotated_config(&sources)?);
+
+    Ok(())
+}
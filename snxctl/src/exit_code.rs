@@ -0,0 +1,152 @@
+use snxcore::model::ConnectionError;
+
+// Connection failures keep their ConnectionError classification over IPC. Other errors, e.g. a cancelled
+// connection or a failure of a local operation, only have a message, so the reason is derived from the text.
+// The order matters: certificate and network failures are often reported as a cause of an authentication error.
+const CANCEL_PATTERNS: &[&str] = &["canceled", "cancelled"];
+
+const CERT_PATTERNS: &[&str] = &[
+    "certificate",
+    "self-signed",
+    "self signed",
+    "local issuer",
+    "hostname mismatch",
+];
+
+const NETWORK_PATTERNS: &[&str] = &[
+    "unreachable",
+    "connection refused",
+    "connection reset",
+    "no route to host",
+    "network is down",
+    "dns error",
+    "failed to lookup address",
+    "timed out",
+    "timeout",
+    "deadline has elapsed",
+    "no reply to the",
+];
+
+const AUTH_PATTERNS: &[&str] = &[
+    "authentication failed",
+    "invalid credentials",
+    "wrong password",
+    "access denied",
+    "unable to acquire otp",
+];
+
+pub const EXIT_ERROR: i32 = 1;
+pub const EXIT_AUTH: i32 = 10;
+pub const EXIT_NETWORK: i32 = 11;
+pub const EXIT_CERT: i32 = 12;
+pub const EXIT_CANCELLED: i32 = 13;
+pub const EXIT_PROPOSAL: i32 = 14;
+
+pub const EXIT_CODES_HELP: &str = "Exit codes:
+  0   success
+  1   other error
+  10  authentication failed
+  11  gateway is unreachable or the connection timed out
+  12  certificate validation failed
+  13  connection was cancelled
+  14  gateway rejected the offered ciphers";
+
+pub fn for_error(error: &anyhow::Error) -> i32 {
+    if let Some(error) = ConnectionError::find(error) {
+        return match error {
+            ConnectionError::AuthFailed(_) | ConnectionError::Challenge(_) | ConnectionError::ServerRejected { .. } => {
+                EXIT_AUTH
+            }
+            ConnectionError::CertError(_) => EXIT_CERT,
+            ConnectionError::NetworkUnreachable(_) | ConnectionError::Timeout(_) => EXIT_NETWORK,
+            ConnectionError::ProposalRejected { .. } => EXIT_PROPOSAL,
+        };
+    }
+
+    let message = format!("{:#}", error).to_lowercase();
+    let matches = |patterns: &[&str]| patterns.iter().any(|p| message.contains(p));
+
+    if matches(CANCEL_PATTERNS) {
+        EXIT_CANCELLED
+    } else if matches(CERT_PATTERNS) {
+        EXIT_CERT
+    } else if matches(NETWORK_PATTERNS) {
+        EXIT_NETWORK
+    } else if matches(AUTH_PATTERNS) {
+        EXIT_AUTH
+    } else {
+        EXIT_ERROR
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+    use snxcore::model::TunnelServiceResponse;
+
+    use super::*;
+
+    #[test]
+    fn test_typed_errors() {
+        let cases = [
+            (ConnectionError::AuthFailed("Authentication failed!".into()), EXIT_AUTH),
+            (
+                ConnectionError::Challenge("Re-authentication requires user input".into()),
+                EXIT_AUTH,
+            ),
+            (
+                ConnectionError::ServerRejected {
+                    code: 101,
+                    id: "0x1".into(),
+                    message: "Access denied".into(),
+                },
+                EXIT_AUTH,
+            ),
+            (ConnectionError::CertError("No PKCS11 pin provided!".into()), EXIT_CERT),
+            (
+                ConnectionError::NetworkUnreachable("No IPv4 address".into()),
+                EXIT_NETWORK,
+            ),
+            (ConnectionError::ProposalRejected { stage: "ESP".into() }, EXIT_PROPOSAL),
+            // the classification wins over the message
+            (ConnectionError::Timeout("authentication failed".into()), EXIT_NETWORK),
+        ];
+
+        for (error, code) in cases {
+            let error = Err::<(), _>(anyhow::Error::from(error.clone()))
+                .context("Connection failed")
+                .unwrap_err();
+            assert_eq!(for_error(&error), code, "{error:#}");
+
+            // as received from the service
+            let error = TunnelServiceResponse::from_error(&error).into_error().unwrap();
+            assert_eq!(for_error(&error), code, "{error:#}");
+        }
+    }
+
+    #[test]
+    fn test_error_messages() {
+        let cases = [
+            ("Connection canceled", EXIT_CANCELLED),
+            (
+                "Authentication failed: invalid peer certificate: UnknownIssuer",
+                EXIT_CERT,
+            ),
+            (
+                "error sending request: self signed certificate in certificate chain",
+                EXIT_CERT,
+            ),
+            ("Connection refused (os error 111)", EXIT_NETWORK),
+            ("deadline has elapsed", EXIT_NETWORK),
+            ("No reply to the IKE proposal", EXIT_NETWORK),
+            ("Authentication failed!", EXIT_AUTH),
+            ("Unable to acquire OTP", EXIT_AUTH),
+            ("No connection in progress", EXIT_ERROR),
+            ("", EXIT_ERROR),
+        ];
+
+        for (message, code) in cases {
+            assert_eq!(for_error(&anyhow::anyhow!(message)), code, "{message}");
+        }
+    }
+}
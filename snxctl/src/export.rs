@@ -0,0 +1,222 @@
+use std::fmt::Write;
+
+use clap::ValueEnum;
+
+use snxcore::model::params::{CertType, TunnelParams};
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ExportFormat {
+    NmKeyfile,
+    Strongswan,
+}
+
+fn routes(params: &TunnelParams) -> Vec<String> {
+    if params.default_route {
+        vec!["0.0.0.0/0".to_owned()]
+    } else {
+        params
+            .add_routes
+            .iter()
+            .filter(|r| !params.ignore_routes.contains(r))
+            .map(|r| r.to_string())
+            .collect()
+    }
+}
+
+fn export_nm_keyfile(params: &TunnelParams) -> anyhow::Result<String> {
+    let mut out = String::new();
+
+    // the plugin takes a single gateway address
+    let names = params.server_names();
+    let server_name = names.first().copied().unwrap_or_default();
+
+    writeln!(
+        out,
+        "# NetworkManager keyfile for the strongswan plugin, best-effort conversion"
+    )?;
+    if names.len() > 1 {
+        writeln!(out, "# failover gateways are not supported: {}", names[1..].join(", "))?;
+    }
+    writeln!(out, "[connection]")?;
+    writeln!(out, "id=snx-rs {}", server_name)?;
+    writeln!(out, "type=vpn")?;
+    writeln!(out, "autoconnect=false")?;
+    writeln!(out)?;
+
+    writeln!(out, "[vpn]")?;
+    writeln!(out, "service-type=org.freedesktop.NetworkManager.strongswan")?;
+    writeln!(out, "address={}", server_name)?;
+    writeln!(out, "virtual=yes")?;
+    writeln!(out, "encap=yes")?;
+    match params.cert_type {
+        CertType::None => {
+            writeln!(out, "method=eap")?;
+            writeln!(out, "user={}", params.user_name)?;
+        }
+        CertType::Pkcs11 => writeln!(out, "method=smartcard")?,
+        CertType::Pkcs8 | CertType::Pkcs12 => {
+            writeln!(out, "method=key")?;
+            if let Some(ref path) = params.cert_path {
+                writeln!(out, "usercert={}", path.display())?;
+            }
+        }
+    }
+    if let Some(ca_cert) = params.ca_cert.first() {
+        writeln!(out, "certificate={}", ca_cert.display())?;
+    }
+    writeln!(out)?;
+
+    writeln!(out, "[ipv4]")?;
+    writeln!(out, "method=auto")?;
+    if !params.dns_servers.is_empty() {
+        let servers = params.dns_servers.iter().map(|s| format!("{s};")).collect::<String>();
+        writeln!(out, "dns={servers}")?;
+    }
+    if !params.search_domains.is_empty() {
+        let domains = params
+            .search_domains
+            .iter()
+            .map(|s| format!("{s};"))
+            .collect::<String>();
+        writeln!(out, "dns-search={domains}")?;
+    }
+    if !params.no_routing {
+        for (i, route) in routes(params).iter().enumerate() {
+            writeln!(out, "route{}={}", i + 1, route)?;
+        }
+    }
+    writeln!(out, "never-default={}", !params.default_route)?;
+
+    Ok(out)
+}
+
+fn export_strongswan(params: &TunnelParams) -> anyhow::Result<String> {
+    let mut out = String::new();
+
+    writeln!(out, "# strongSwan swanctl.conf, best-effort conversion")?;
+    writeln!(out, "connections {{")?;
+    writeln!(out, "    snx-rs {{")?;
+    writeln!(out, "        version = 1")?;
+    writeln!(out, "        remote_addrs = {}", params.server_names().join(", "))?;
+    writeln!(out, "        vips = 0.0.0.0")?;
+    writeln!(out, "        encap = yes")?;
+    writeln!(out, "        rekey_time = {}s", params.ike_lifetime.as_secs())?;
+    writeln!(out, "        local {{")?;
+    match params.cert_type {
+        CertType::None => {
+            writeln!(out, "            auth = xauth")?;
+            writeln!(out, "            xauth_id = {}", params.user_name)?;
+        }
+        _ => {
+            writeln!(out, "            auth = pubkey")?;
+            if let Some(ref path) = params.cert_path {
+                writeln!(out, "            certs = {}", path.display())?;
+            }
+        }
+    }
+    writeln!(out, "        }}")?;
+    writeln!(out, "        remote {{")?;
+    writeln!(out, "            auth = pubkey")?;
+    writeln!(out, "        }}")?;
+    writeln!(out, "        children {{")?;
+    writeln!(out, "            snx-rs {{")?;
+    let routes = if params.no_routing { Vec::new() } else { routes(params) };
+    if !routes.is_empty() {
+        writeln!(out, "                remote_ts = {}", routes.join(","))?;
+    }
+    writeln!(out, "                rekey_time = {}s", params.esp_lifetime.as_secs())?;
+    writeln!(out, "                start_action = none")?;
+    writeln!(out, "            }}")?;
+    writeln!(out, "        }}")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "}}")?;
+
+    if !params.dns_servers.is_empty() || !params.search_domains.is_empty() {
+        writeln!(out)?;
+        writeln!(out, "# DNS settings are applied by the resolver plugin:")?;
+        for server in &params.dns_servers {
+            writeln!(out, "# nameserver {server}")?;
+        }
+        if !params.search_domains.is_empty() {
+            writeln!(out, "# search {}", params.search_domains.join(" "))?;
+        }
+    }
+
+    Ok(out)
+}
+
+pub fn export(params: &TunnelParams, format: ExportFormat) -> anyhow::Result<String> {
+    if params.server_names().is_empty() {
+        anyhow::bail!("No server name in the configuration!");
+    }
+
+    match format {
+        ExportFormat::NmKeyfile => export_nm_keyfile(params),
+        ExportFormat::Strongswan => export_strongswan(params),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn test_export_nm_keyfile() {
+        let params = TunnelParams {
+            server_name: "gw1.example.com,gw2.example.com".to_owned(),
+            user_name: "user1".to_owned(),
+            dns_servers: vec!["10.0.0.53".parse().unwrap()],
+            search_domains: vec!["corp.example.com".to_owned()],
+            add_routes: vec!["10.0.0.0/8".parse().unwrap(), "172.16.0.0/12".parse().unwrap()],
+            ignore_routes: vec!["172.16.0.0/12".parse().unwrap()],
+            ..Default::default()
+        };
+
+        let out = export(&params, ExportFormat::NmKeyfile).unwrap();
+        let lines = out.lines().collect::<Vec<_>>();
+        assert!(lines.contains(&"# failover gateways are not supported: gw2.example.com"));
+        assert!(lines.contains(&"id=snx-rs gw1.example.com"));
+        assert!(lines.contains(&"address=gw1.example.com"));
+        assert!(lines.contains(&"method=eap"));
+        assert!(lines.contains(&"user=user1"));
+        assert!(lines.contains(&"dns=10.0.0.53;"));
+        assert!(lines.contains(&"dns-search=corp.example.com;"));
+        assert!(lines.contains(&"route1=10.0.0.0/8"));
+        assert!(!out.contains("route2="));
+        assert!(lines.contains(&"never-default=true"));
+
+        let params = TunnelParams {
+            no_routing: true,
+            ..params
+        };
+        assert!(!export(&params, ExportFormat::NmKeyfile).unwrap().contains("route1="));
+    }
+
+    #[test]
+    fn test_export_strongswan() {
+        let params = TunnelParams {
+            server_name: "gw1.example.com,gw2.example.com".to_owned(),
+            cert_type: CertType::Pkcs12,
+            cert_path: Some(PathBuf::from("/etc/snx-rs/user.p12")),
+            default_route: true,
+            ..Default::default()
+        };
+
+        let out = export(&params, ExportFormat::Strongswan).unwrap();
+        let lines = out.lines().map(str::trim).collect::<Vec<_>>();
+        assert!(lines.contains(&"remote_addrs = gw1.example.com, gw2.example.com"));
+        assert!(lines.contains(&"auth = pubkey"));
+        assert!(lines.contains(&"certs = /etc/snx-rs/user.p12"));
+        assert!(lines.contains(&"remote_ts = 0.0.0.0/0"));
+        assert!(lines.contains(&format!("rekey_time = {}s", params.ike_lifetime.as_secs()).as_str()));
+        assert!(!out.contains("# DNS settings"));
+    }
+
+    #[test]
+    fn test_export_no_server() {
+        assert!(export(&TunnelParams::default(), ExportFormat::NmKeyfile).is_err());
+        assert!(export(&TunnelParams::default(), ExportFormat::Strongswan).is_err());
+    }
+}
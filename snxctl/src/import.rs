@@ -0,0 +1,132 @@
+use std::path::{Path, PathBuf};
+
+use snxcore::model::{params::TunnelParams, uri::ConnectionUri};
+
+use crate::profiles;
+
+// the server address with the characters which are not allowed in a file name replaced
+fn default_profile_name(server_name: &str) -> String {
+    server_name
+        .trim_start_matches('.')
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Import the link as the `<name>.conf` profile in the configuration directory.
+/// The profile name defaults to the server address, an existing profile of the same server is updated.
+pub fn import_uri(config_dir: &Path, uri: &str, name: Option<&str>, force: bool) -> anyhow::Result<PathBuf> {
+    let uri = uri.parse::<ConnectionUri>()?;
+
+    let name = name.map_or_else(|| default_profile_name(&uri.server_name), ToOwned::to_owned);
+    let config_file = profiles::profile_path(config_dir, &name)?;
+
+    let new_params = || TunnelParams {
+        config_file: config_file.clone(),
+        ..Default::default()
+    };
+
+    let mut params = if config_file.is_file() {
+        TunnelParams::load(&config_file)?
+    } else {
+        new_params()
+    };
+
+    if !params.server_name.is_empty() && params.server_name != uri.server_name {
+        if !force {
+            anyhow::bail!(
+                "Profile {} is already configured for {}, use another name or --force to replace it",
+                name,
+                params.server_name
+            );
+        }
+        // a different server starts from a clean configuration
+        params = new_params();
+    }
+
+    uri.apply(&mut params);
+    params.save()?;
+
+    println!(
+        "Imported {} as profile {} ({})",
+        params.server_name,
+        name,
+        config_file.display()
+    );
+
+    Ok(config_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_profile_name() {
+        assert_eq!(default_profile_name("vpn.example.com"), "vpn.example.com");
+        assert_eq!(default_profile_name("vpn.example.com:8443"), "vpn.example.com_8443");
+        assert_eq!(default_profile_name("[2001:db8::1]"), "_2001_db8__1_");
+        assert_eq!(default_profile_name("..hidden"), "hidden");
+    }
+
+    #[test]
+    fn test_import_uri() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_dir = dir.path().join("snx-rs");
+
+        // the current configuration is left alone
+        let current = TunnelParams {
+            config_file: config_dir.join("snx-rs.conf"),
+            server_name: "other.example.com".to_owned(),
+            ..Default::default()
+        };
+        current.save().unwrap();
+
+        let path = import_uri(&config_dir, "snx-rs://vpn.example.com?realm=vpn_Mfa", None, false).unwrap();
+        assert_eq!(path, config_dir.join("vpn.example.com.conf"));
+        assert_eq!(
+            profiles::list_profiles(&config_dir).unwrap(),
+            ["snx-rs", "vpn.example.com"]
+        );
+        assert_eq!(
+            TunnelParams::load(config_dir.join("snx-rs.conf")).unwrap().server_name,
+            "other.example.com"
+        );
+
+        let params = TunnelParams::load(&path).unwrap();
+        assert_eq!(params.server_name, "vpn.example.com");
+        assert_eq!(params.login_type, "vpn_Mfa");
+
+        // the same server is updated, keeping the other settings
+        let params = TunnelParams {
+            user_name: "user1".to_owned(),
+            ..params
+        };
+        params.save().unwrap();
+        import_uri(&config_dir, "snx-rs://vpn.example.com?tunnel=ssl", None, false).unwrap();
+        let params = TunnelParams::load(&path).unwrap();
+        assert_eq!(params.login_type, "vpn_Mfa");
+        assert_eq!(params.user_name, "user1");
+
+        // another server under an existing name needs --force
+        assert!(import_uri(
+            &config_dir,
+            "snx-rs://other.example.com",
+            Some("vpn.example.com"),
+            false
+        )
+        .is_err());
+        import_uri(&config_dir, "snx-rs://other.example.com", Some("vpn.example.com"), true).unwrap();
+        let params = TunnelParams::load(&path).unwrap();
+        assert_eq!(params.server_name, "other.example.com");
+        assert!(params.user_name.is_empty());
+
+        assert!(import_uri(&config_dir, "snx-rs://vpn.example.com", Some("../work"), false).is_err());
+    }
+}
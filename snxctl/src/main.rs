@@ -1,17 +1,37 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
+use anyhow::Context;
 use clap::Parser;
-use tracing::level_filters::LevelFilter;
 
 use snxcore::browser::SystemBrowser;
 use snxcore::{
     controller::{ServiceCommand, ServiceController},
     model::params::TunnelParams,
     prompt::TtyPrompt,
+    server_info,
 };
 
+use crate::export::ExportFormat;
+
+mod check;
+mod config;
+mod exit_code;
+mod export;
+mod import;
+mod password;
+mod profiles;
+mod service;
+mod sessions;
+
 #[derive(Parser)]
-#[clap(about = "VPN client for Checkpoint security gateway", name = "snxctl")]
+#[clap(
+    about = "VPN client for Checkpoint security gateway",
+    name = "snxctl",
+    after_help = exit_code::EXIT_CODES_HELP
+)]
 pub struct CmdlineParams {
     #[clap(
         long = "config-file",
@@ -20,6 +40,13 @@ pub struct CmdlineParams {
         help = "Configuration file to use [default: $HOME/.config/snx-rs/snx-rs.conf]"
     )]
     config_file: Option<PathBuf>,
+    #[clap(
+        long = "config-dir",
+        short = 'd',
+        global = true,
+        help = "Directory with the <name>.conf profiles [default: $HOME/.config/snx-rs]"
+    )]
+    config_dir: Option<PathBuf>,
     #[clap(subcommand)]
     command: SnxCommand,
 }
@@ -27,74 +54,346 @@ pub struct CmdlineParams {
 #[derive(Parser)]
 enum SnxCommand {
     #[clap(name = "connect", about = "Connect a tunnel")]
-    Connect,
+    Connect {
+        #[clap(help = "Profile name, resolved to <config-dir>/<profile>.conf")]
+        profile: Option<String>,
+        #[clap(
+            long = "server",
+            conflicts_with = "profile",
+            help = "Connect to the server without a configuration file"
+        )]
+        server: Option<String>,
+        #[clap(
+            long = "login-type",
+            requires = "server",
+            help = "Login type for the --server connection, chosen automatically if the server allows it"
+        )]
+        login_type: Option<String>,
+        #[clap(long = "user", requires = "server", help = "User name for the --server connection")]
+        user: Option<String>,
+    },
     #[clap(name = "disconnect", about = "Disconnect a tunnel")]
     Disconnect,
     #[clap(name = "reconnect", about = "Reconnect a tunnel")]
-    Reconnect,
+    Reconnect {
+        #[clap(
+            long = "reprobe",
+            help = "Forget the IPSec transport which worked last time and probe it again"
+        )]
+        reprobe: bool,
+    },
+    #[clap(
+        name = "reauth",
+        about = "Authenticate again without dropping the tunnel, reconnect if the gateway rejects it"
+    )]
+    Reauth,
+    #[clap(name = "cancel", about = "Abort a connection which is in progress")]
+    Cancel,
     #[clap(name = "status", about = "Show connection status")]
-    Status,
+    Status {
+        #[clap(help = "Profile name, the status is shown only if this profile is active")]
+        profile: Option<String>,
+    },
+    #[clap(name = "profiles", about = "List the profiles in the configuration directory")]
+    Profiles,
+    #[clap(
+        name = "events",
+        about = "Print the tunnel events of the service as newline-delimited JSON until interrupted"
+    )]
+    Events,
     #[clap(name = "info", about = "Show server information")]
-    Info,
+    Info {
+        #[clap(
+            long = "raw",
+            help = "Print the unparsed server response, e.g. to attach it to a bug report"
+        )]
+        raw: bool,
+        #[clap(
+            long = "no-redact",
+            requires = "raw",
+            help = "Do not mask the cookie and session fields in the raw response"
+        )]
+        no_redact: bool,
+    },
+    #[clap(
+        name = "active-profile",
+        about = "Show the configuration file of the active connection"
+    )]
+    ActiveProfile,
+    #[clap(
+        name = "switch-profile",
+        about = "Disconnect the active connection and connect with another configuration file"
+    )]
+    SwitchProfile {
+        #[clap(help = "Configuration file of the profile to connect")]
+        config: PathBuf,
+    },
+    #[clap(
+        name = "export",
+        about = "Export the configuration as a NetworkManager or strongSwan connection"
+    )]
+    Export {
+        #[clap(long = "format", short = 'f', help = "Output format")]
+        format: ExportFormat,
+        #[clap(help = "Profile name, resolved to <config-dir>/<profile>.conf")]
+        profile: Option<String>,
+    },
+    #[clap(
+        name = "check",
+        about = "Check the reachability of the server and its transports without connecting"
+    )]
+    Check,
+    #[clap(
+        name = "print-config",
+        about = "Print the effective configuration with masked secrets and the source of each value"
+    )]
+    PrintConfig,
+    #[clap(
+        name = "import-uri",
+        about = "Create or update a profile in the configuration directory from a snx-rs:// onboarding link"
+    )]
+    ImportUri {
+        #[clap(help = "Link in the form of snx-rs://server?realm=<login-type>&tunnel=<ipsec|ssl>")]
+        uri: String,
+        #[clap(long = "name", help = "Profile name [default: the server address]")]
+        name: Option<String>,
+        #[clap(
+            long = "force",
+            help = "Replace an existing profile which points to a different server"
+        )]
+        force: bool,
+    },
+    #[clap(name = "sessions", about = "Manage the IKE sessions stored with ike-persist")]
+    Sessions {
+        #[clap(subcommand)]
+        command: SessionsCommand,
+    },
+    #[clap(
+        name = "install-service",
+        about = "Write a systemd unit which runs snx-rs in command mode with the current configuration"
+    )]
+    InstallService,
+    #[clap(
+        name = "uninstall-service",
+        about = "Remove the systemd unit written by install-service"
+    )]
+    UninstallService,
+    #[clap(name = "set-password", about = "Update the stored password without connecting")]
+    SetPassword {
+        #[clap(
+            long = "verify",
+            help = "Verify the new password against the server before storing it"
+        )]
+        verify: bool,
+    },
 }
 
-impl From<SnxCommand> for ServiceCommand {
-    fn from(value: SnxCommand) -> Self {
-        match value {
-            SnxCommand::Connect => ServiceCommand::Connect,
-            SnxCommand::Disconnect => ServiceCommand::Disconnect,
-            SnxCommand::Reconnect => ServiceCommand::Reconnect,
-            SnxCommand::Status => ServiceCommand::Status,
-            SnxCommand::Info => ServiceCommand::Info,
-        }
-    }
+#[derive(Parser)]
+enum SessionsCommand {
+    #[clap(name = "list", about = "Show the stored sessions with their age")]
+    List,
+    #[clap(
+        name = "prune",
+        about = "Delete the stale sessions, by default the ones older than the IKE lifetime"
+    )]
+    Prune {
+        #[clap(
+            long = "older-than",
+            value_name = "DAYS",
+            help = "Delete the sessions older than DAYS"
+        )]
+        older_than: Option<u64>,
+        #[clap(
+            long = "clear",
+            conflicts_with = "older_than",
+            help = "Delete the session of the current profile regardless of its age"
+        )]
+        clear: bool,
+    },
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let params = CmdlineParams::parse();
 
-    let config_file = params
-        .config_file
+    let config_dir = params
+        .config_dir
         .clone()
-        .unwrap_or_else(TunnelParams::default_config_path);
+        .unwrap_or_else(TunnelParams::default_config_dir);
 
-    let tunnel_params = Arc::new(TunnelParams::load(config_file).unwrap_or_default());
+    let profile = match params.command {
+        SnxCommand::Connect { ref profile, .. }
+        | SnxCommand::Status { ref profile }
+        | SnxCommand::Export { ref profile, .. } => profile.as_deref(),
+        _ => None,
+    };
 
-    let mut service_controller = ServiceController::new(TtyPrompt, SystemBrowser, tunnel_params)?;
+    let config_file = match (profile, params.config_file.clone()) {
+        (Some(_), Some(_)) => anyhow::bail!("A profile name cannot be combined with --config-file"),
+        (Some(name), None) => profiles::resolve_profile(&config_dir, name)?,
+        (None, Some(config_file)) => config_file,
+        (None, None) if params.config_dir.is_some() => config_dir.join("snx-rs.conf"),
+        (None, None) if matches!(params.command, SnxCommand::InstallService) => service::default_config_path()?,
+        (None, None) => TunnelParams::default_config_path(),
+    };
 
-    let subscriber = tracing_subscriber::fmt()
-        .with_max_level(
-            service_controller
-                .params
-                .log_level
-                .parse::<LevelFilter>()
-                .unwrap_or(LevelFilter::OFF),
-        )
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)?;
+    let status_profile = match params.command {
+        SnxCommand::Status { profile: Some(_) } => Some(config_file.clone()),
+        _ => None,
+    };
 
-    let command = params.command.into();
+    let tunnel_params = match params.command {
+        SnxCommand::Connect {
+            server: Some(ref server),
+            ref login_type,
+            ref user,
+            ..
+        } => {
+            if params.config_file.is_some() {
+                anyhow::bail!("The --server option cannot be combined with --config-file");
+            }
+            Arc::new(adhoc_params(server, login_type.as_deref(), user.as_deref()))
+        }
+        _ => Arc::new(TunnelParams::load(config_file).unwrap_or_default()),
+    };
 
-    match service_controller.command(command).await {
+    let command = match params.command {
+        SnxCommand::Connect { .. } => ServiceCommand::Connect,
+        SnxCommand::Disconnect => ServiceCommand::Disconnect,
+        SnxCommand::Reconnect { reprobe: false } => ServiceCommand::Reconnect,
+        SnxCommand::Reconnect { reprobe: true } => ServiceCommand::Reprobe,
+        SnxCommand::Reauth => ServiceCommand::Reauth,
+        SnxCommand::Cancel => ServiceCommand::Cancel,
+        SnxCommand::Status { .. } => ServiceCommand::Status,
+        SnxCommand::Profiles => {
+            for name in profiles::list_profiles(&config_dir)? {
+                println!("{name}");
+            }
+            return Ok(());
+        }
+        SnxCommand::Events => {
+            let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", tunnel_params.events_port))
+                .await
+                .context("The service is not running")?;
+            tokio::io::copy(&mut stream, &mut tokio::io::stdout()).await?;
+            return Ok(());
+        }
+        SnxCommand::Info { raw: false, .. } => ServiceCommand::Info,
+        SnxCommand::Info { raw: true, no_redact } => {
+            println!("{}", server_info::get_raw(&tunnel_params, !no_redact).await?);
+            return Ok(());
+        }
+        SnxCommand::ActiveProfile => ServiceCommand::GetActiveProfile,
+        SnxCommand::SwitchProfile { config } => ServiceCommand::SwitchProfile(config),
+        SnxCommand::Export { format, .. } => {
+            print!("{}", export::export(&tunnel_params, format)?);
+            return Ok(());
+        }
+        SnxCommand::Check => {
+            return check::check((*tunnel_params).clone()).await;
+        }
+        SnxCommand::PrintConfig => {
+            return config::print_config((*tunnel_params).clone()).await;
+        }
+        SnxCommand::ImportUri { uri, name, force } => {
+            if params.config_file.is_some() {
+                anyhow::bail!("The import-uri command creates a profile, use --config-dir instead of --config-file");
+            }
+            return import::import_uri(&config_dir, &uri, name.as_deref(), force).map(|_| ());
+        }
+        SnxCommand::Sessions { command } => {
+            return match command {
+                SessionsCommand::List => sessions::list(&tunnel_params),
+                SessionsCommand::Prune { older_than, clear } => sessions::prune(&tunnel_params, older_than, clear),
+            };
+        }
+        SnxCommand::InstallService => {
+            return service::install(&tunnel_params, &config_file);
+        }
+        SnxCommand::UninstallService => {
+            return service::uninstall();
+        }
+        SnxCommand::SetPassword { verify } => {
+            return password::set_password((*tunnel_params).clone(), verify).await;
+        }
+    };
+
+    let mut service_controller = ServiceController::new(TtyPrompt, SystemBrowser, tunnel_params)?;
+
+    snxcore::logging::init_logging(&service_controller.params)?;
+
+    match service_controller.command(command.clone()).await {
+        Ok(status) if command == ServiceCommand::GetActiveProfile => match status.profile {
+            Some(profile) => println!("{}", profile.display()),
+            None => println!("No active profile"),
+        },
         Ok(status) if command != ServiceCommand::Info => {
-            if let Some(since) = status.connected_since {
-                println!(
-                    "{} since: {}",
-                    if status.mfa.is_some() {
-                        "MFA pending"
-                    } else {
-                        "Connected"
-                    },
-                    since
-                );
+            let other_profile =
+                status_profile.is_some_and(|profile| !is_same_file(status.profile.as_deref(), &profile));
+
+            if other_profile {
+                println!("Disconnected");
+            } else if let Some(since) = status.connected_since {
+                if status.mfa.is_some() {
+                    println!("MFA pending since: {since}");
+                } else {
+                    println!(
+                        "Connected since: {} [{}]",
+                        since,
+                        status.uptime_summary().unwrap_or_default()
+                    );
+                    if let Some(ref server_name) = status.server_name {
+                        println!("Server: {server_name}");
+                    }
+                    if let Some(transport) = status.transport {
+                        println!("Transport: {transport}");
+                    }
+                    if let Some(address) = status.address {
+                        println!("Address: {address}");
+                    }
+                    if !status.dns_servers.is_empty() {
+                        let servers = status.dns_servers.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+                        println!("DNS servers: {}", servers.join(", "));
+                    }
+                    if !status.search_domains.is_empty() {
+                        println!("Search domains: {}", status.search_domains.join(", "));
+                    }
+                    if let Some(traffic) = status.traffic {
+                        println!("Traffic: {traffic}");
+                    }
+                    if !status.routes.is_empty() {
+                        let routes = status.routes.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+                        println!("Gateway routes: {}", routes.join(", "));
+                    }
+                }
+            } else if let Some(reason) = status.disconnect_reason {
+                println!("Disconnected: {reason}");
             } else {
                 println!("Disconnected");
             }
         }
-        Err(e) => println!("Error: {e}"),
+        Err(e) => {
+            eprintln!("Error: {e}");
+            std::process::exit(exit_code::for_error(&e));
+        }
         _ => {}
     }
 
     Ok(())
 }
+
+// one-shot connection parameters, nothing is read from or stored to the configuration or the keychain
+fn adhoc_params(server: &str, login_type: Option<&str>, user: Option<&str>) -> TunnelParams {
+    TunnelParams {
+        server_name: server.to_owned(),
+        login_type: login_type.unwrap_or_default().to_owned(),
+        user_name: user.unwrap_or_default().to_owned(),
+        no_keychain: true,
+        config_file: PathBuf::new(),
+        ..Default::default()
+    }
+}
+
+fn is_same_file(active: Option<&Path>, profile: &Path) -> bool {
+    active.and_then(|path| path.canonicalize().ok()) == profile.canonicalize().ok()
+}
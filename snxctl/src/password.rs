@@ -0,0 +1,74 @@
+use std::sync::Arc;
+
+use snxcore::{
+    ccc::CccHttpClient,
+    model::{params::TunnelParams, VpnSession},
+    platform,
+    prompt::{SecurePrompt, TtyPrompt},
+};
+
+async fn verify_password(params: &TunnelParams) -> anyhow::Result<()> {
+    let client = CccHttpClient::new(Arc::new(params.clone()), None);
+    let data = client.authenticate().await?;
+
+    match (data.authn_status.as_str(), data.is_authenticated) {
+        // password accepted, the server asks for the next factor
+        ("continue", _) => Ok(()),
+        ("done", Some(true)) => {
+            let session = VpnSession {
+                ccc_session_id: data.session_id.unwrap_or_default(),
+                ccc_cookie: client.session_cookie(),
+                ..VpnSession::empty()
+            };
+            let client = CccHttpClient::new(Arc::new(params.clone()), Some(Arc::new(session)));
+            let _ = client.signout().await;
+            Ok(())
+        }
+        _ => {
+            let message = data.error_message.map(|m| m.0).unwrap_or_default();
+            anyhow::bail!("Password verification failed! {}", message)
+        }
+    }
+}
+
+pub async fn set_password(mut params: TunnelParams, verify: bool) -> anyhow::Result<()> {
+    if params.user_name.is_empty() {
+        anyhow::bail!("No user name in the configuration!");
+    }
+
+    let prompt = TtyPrompt;
+
+    let password = prompt.get_secure_input(&format!("New password for {}: ", params.user_name))?;
+    let confirmation = prompt.get_secure_input("Confirm password: ")?;
+
+    if password.is_empty() {
+        anyhow::bail!("Password cannot be empty!");
+    }
+
+    if password != confirmation {
+        anyhow::bail!("Passwords do not match!");
+    }
+
+    let old_password = std::mem::replace(&mut params.password, password);
+
+    if verify {
+        verify_password(&params).await?;
+        println!("Password verified");
+    }
+
+    if params.no_keychain {
+        params.save()?;
+        println!("Password saved to {}", params.config_file.display());
+    } else {
+        platform::store_password(&params.user_name, &params.password).await?;
+        println!("Password stored in the keychain");
+        if !old_password.is_empty() {
+            println!(
+                "Warning: {} also contains a password which takes precedence over the keychain",
+                params.config_file.display()
+            );
+        }
+    }
+
+    Ok(())
+}
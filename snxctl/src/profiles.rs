@@ -0,0 +1,50 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+const PROFILE_EXTENSION: &str = "conf";
+
+/// Names of the `*.conf` files in the directory, sorted
+pub fn list_profiles(dir: &Path) -> anyhow::Result<Vec<String>> {
+    let entries = std::fs::read_dir(dir).with_context(|| format!("Cannot read {}", dir.display()))?;
+
+    let mut profiles = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == PROFILE_EXTENSION))
+        .filter_map(|path| path.file_stem().map(|stem| stem.to_string_lossy().into_owned()))
+        .collect::<Vec<_>>();
+
+    profiles.sort();
+
+    Ok(profiles)
+}
+
+/// Path of the profile `<dir>/<name>.conf`, the file may not exist yet
+pub fn profile_path(dir: &Path, name: &str) -> anyhow::Result<PathBuf> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        anyhow::bail!("Invalid profile name: {}", name);
+    }
+
+    Ok(dir.join(format!("{name}.{PROFILE_EXTENSION}")))
+}
+
+/// Resolve the profile name to `<dir>/<name>.conf`, the file must exist
+pub fn resolve_profile(dir: &Path, name: &str) -> anyhow::Result<PathBuf> {
+    let path = profile_path(dir, name)?;
+
+    if !path.is_file() {
+        let available = list_profiles(dir).unwrap_or_default();
+        anyhow::bail!(
+            "Profile {} not found in {}, available profiles: {}",
+            name,
+            dir.display(),
+            if available.is_empty() {
+                "none".to_owned()
+            } else {
+                available.join(", ")
+            }
+        );
+    }
+
+    Ok(path)
+}
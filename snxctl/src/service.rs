@@ -0,0 +1,216 @@
+use std::{
+    fs,
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use snxcore::model::params::TunnelParams;
+
+const UNIT_NAME: &str = "snx-rs.service";
+const SYSTEM_UNIT_DIR: &str = "/etc/systemd/system";
+const WANTED_BY: &str = "multi-user.target";
+const SERVICE_BINARY: &str = "snx-rs";
+// units without this line were not written by snxctl and are never replaced or removed
+const UNIT_MARKER: &str = "# Generated by snxctl install-service";
+
+fn unit_path() -> PathBuf {
+    Path::new(SYSTEM_UNIT_DIR).join(UNIT_NAME)
+}
+
+// the service executable is shipped next to snxctl
+fn service_binary() -> anyhow::Result<PathBuf> {
+    let binary = std::env::current_exe()?.with_file_name(SERVICE_BINARY);
+
+    let executable = fs::metadata(&binary).is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0);
+    if !executable {
+        anyhow::bail!("No {} executable found at {}", SERVICE_BINARY, binary.display());
+    }
+
+    Ok(binary)
+}
+
+// systemd splits the command line on whitespace unless the argument is quoted
+fn quote_arg(path: &Path) -> String {
+    let arg = path.display().to_string();
+    if arg.contains(char::is_whitespace) {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        arg
+    }
+}
+
+fn unit_contents(binary: &Path, config_file: Option<&Path>) -> String {
+    let mut exec_start = format!("{} -m command -l info", quote_arg(binary));
+    if let Some(config_file) = config_file {
+        exec_start.push_str(&format!(" -c {}", quote_arg(config_file)));
+    }
+
+    format!(
+        "{UNIT_MARKER}\n\
+         [Unit]\n\
+         Description=VPN client for Checkpoint security gateway\n\
+         After=network.target network-online.target\n\
+         Wants=network-online.target\n\
+         \n\
+         [Service]\n\
+         Type=simple\n\
+         ExecStart={exec_start}\n\
+         \n\
+         [Install]\n\
+         WantedBy={WANTED_BY}\n"
+    )
+}
+
+fn is_generated_unit(path: &Path) -> anyhow::Result<bool> {
+    let contents = fs::read_to_string(path).with_context(|| format!("Cannot read {}", path.display()))?;
+    Ok(contents.lines().next() == Some(UNIT_MARKER))
+}
+
+/// Default configuration file for the service: under `sudo` it is the one of the invoking user, not root's
+pub fn default_config_path() -> anyhow::Result<PathBuf> {
+    match std::env::var("SUDO_USER") {
+        Ok(user_name) if !user_name.is_empty() && user_name != "root" => {
+            Ok(snxcore::platform::user_home_dir(&user_name)?
+                .join(".config")
+                .join("snx-rs")
+                .join("snx-rs.conf"))
+        }
+        _ => Ok(TunnelParams::default_config_path()),
+    }
+}
+
+/// Write the `snx-rs.service` unit which runs the service in command mode with the given configuration.
+/// The unit is only written, enabling and starting it is left to the user.
+/// There is no user unit: the command mode needs root privileges to configure the tunnel.
+pub fn install(params: &TunnelParams, config_file: &Path) -> anyhow::Result<()> {
+    let binary = service_binary()?;
+    let unit_path = unit_path();
+
+    if unit_path.exists() && !is_generated_unit(&unit_path)? {
+        anyhow::bail!(
+            "{} was not created by snxctl, remove it first to install a new one",
+            unit_path.display()
+        );
+    }
+
+    let config_file = if config_file.is_file() {
+        Some(
+            config_file
+                .canonicalize()
+                .with_context(|| format!("Cannot resolve {}", config_file.display()))?,
+        )
+    } else {
+        println!(
+            "No configuration file at {}, the service will use the default settings",
+            config_file.display()
+        );
+        None
+    };
+
+    if let Some(dir) = unit_path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Cannot create {}", dir.display()))?;
+    }
+
+    // the stored IKE sessions contain keys, only the service may read them
+    if !params.session_path.exists() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&params.session_path)
+            .with_context(|| format!("Cannot create {}", params.session_path.display()))?;
+    }
+
+    fs::write(&unit_path, unit_contents(&binary, config_file.as_deref()))
+        .with_context(|| format!("Cannot write {}", unit_path.display()))?;
+
+    println!("Installed {}", unit_path.display());
+    println!("Next steps:");
+    println!("  systemctl daemon-reload");
+    println!("  systemctl enable --now {}", UNIT_NAME);
+
+    Ok(())
+}
+
+/// Remove the `snx-rs.service` unit written by `install`
+pub fn uninstall() -> anyhow::Result<()> {
+    let unit_path = unit_path();
+
+    if !unit_path.exists() {
+        println!("No {} found", unit_path.display());
+        return Ok(());
+    }
+
+    if !is_generated_unit(&unit_path)? {
+        anyhow::bail!("{} was not created by snxctl, it is left in place", unit_path.display());
+    }
+
+    // an enabled unit leaves a dangling link behind which cannot be disabled anymore
+    let wants_link = unit_path.with_file_name(format!("{}.wants", WANTED_BY)).join(UNIT_NAME);
+    if wants_link.symlink_metadata().is_ok() {
+        anyhow::bail!(
+            "{} is enabled, run `systemctl disable --now {}` first",
+            UNIT_NAME,
+            UNIT_NAME
+        );
+    }
+
+    fs::remove_file(&unit_path).with_context(|| format!("Cannot remove {}", unit_path.display()))?;
+
+    println!("Removed {}", unit_path.display());
+    println!("Next step:");
+    println!("  systemctl daemon-reload");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote_arg() {
+        assert_eq!(quote_arg(Path::new("/usr/bin/snx-rs")), "/usr/bin/snx-rs");
+        assert_eq!(
+            quote_arg(Path::new("/home/user/my config/snx-rs.conf")),
+            "\"/home/user/my config/snx-rs.conf\""
+        );
+        assert_eq!(
+            quote_arg(Path::new("/home/user/\"my\" config")),
+            "\"/home/user/\\\"my\\\" config\""
+        );
+    }
+
+    #[test]
+    fn test_unit_exec_start() {
+        let contents = unit_contents(
+            Path::new("/opt/snx rs/snx-rs"),
+            Some(Path::new("/home/user/snx-rs.conf")),
+        );
+        assert!(contents.starts_with(UNIT_MARKER));
+        assert!(contents
+            .lines()
+            .any(|line| line == "ExecStart=\"/opt/snx rs/snx-rs\" -m command -l info -c /home/user/snx-rs.conf"));
+        assert!(contents.lines().any(|line| line == "WantedBy=multi-user.target"));
+
+        let contents = unit_contents(Path::new("/usr/bin/snx-rs"), None);
+        assert!(contents
+            .lines()
+            .any(|line| line == "ExecStart=/usr/bin/snx-rs -m command -l info"));
+    }
+
+    #[test]
+    fn test_is_generated_unit() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let generated = dir.path().join("generated.service");
+        fs::write(&generated, unit_contents(Path::new("/usr/bin/snx-rs"), None)).unwrap();
+        assert!(is_generated_unit(&generated).unwrap());
+
+        let foreign = dir.path().join("foreign.service");
+        fs::write(&foreign, format!("[Unit]\n{UNIT_MARKER}\n")).unwrap();
+        assert!(!is_generated_unit(&foreign).unwrap());
+
+        assert!(is_generated_unit(&dir.path().join("missing.service")).is_err());
+    }
+}
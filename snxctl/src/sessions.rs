@@ -0,0 +1,65 @@
+use std::time::{Duration, SystemTime};
+
+use snxcore::{model::params::TunnelParams, tunnel, util};
+
+const SECS_PER_DAY: u64 = 86400;
+
+pub fn list(params: &TunnelParams) -> anyhow::Result<()> {
+    let sessions = tunnel::stored_sessions(params)?;
+
+    if sessions.is_empty() {
+        println!("No stored sessions in {}", params.session_path.display());
+        return Ok(());
+    }
+
+    let now = SystemTime::now();
+
+    for session in sessions {
+        let age = now.duration_since(session.saved).unwrap_or_default();
+        let mut line = format!("{}: saved {} ago", session.server_name, util::format_uptime(age));
+        if age > params.ike_lifetime {
+            line.push_str(", expired");
+        }
+        if params.server_names().contains(&session.server_name.as_str()) {
+            line.push_str(" [current profile]");
+        }
+        println!("{line}");
+    }
+
+    Ok(())
+}
+
+/// Delete the sessions older than the given number of days, by default older than the IKE lifetime.
+/// With `clear` only the sessions of the gateways of the current profile are deleted, regardless of their age.
+pub fn prune(params: &TunnelParams, older_than: Option<u64>, clear: bool) -> anyhow::Result<()> {
+    let server_names = params.server_names();
+    if clear && server_names.is_empty() {
+        anyhow::bail!("No server is configured in {}", params.config_file.display());
+    }
+
+    let max_age = older_than.map_or(params.ike_lifetime, |days| {
+        Duration::from_secs(days.saturating_mul(SECS_PER_DAY))
+    });
+    let now = SystemTime::now();
+    let mut removed = 0;
+
+    for session in tunnel::stored_sessions(params)? {
+        let remove = if clear {
+            server_names.contains(&session.server_name.as_str())
+        } else {
+            now.duration_since(session.saved).unwrap_or_default() > max_age
+        };
+
+        if remove {
+            tunnel::remove_stored_session(params, &session.server_name)?;
+            println!("Removed session for {}", session.server_name);
+            removed += 1;
+        }
+    }
+
+    if removed == 0 {
+        println!("No sessions to remove");
+    }
+
+    Ok(())
+}